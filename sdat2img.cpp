@@ -4,30 +4,71 @@
  *
  */
 
+// 64-bit off_t for pread, pwrite and lseek on 32-bit hosts too, whose
+// images may well be over 4 GiB.
+#ifndef _FILE_OFFSET_BITS
+#define _FILE_OFFSET_BITS 64
+#endif
+
 #include <algorithm>
 #include <array>
+#include <atomic>
+#include <cctype>
+#include <charconv>
 #include <cerrno>
+#include <chrono>
+#include <condition_variable>
+#include <cmath>
 #include <cstddef>
+#include <cstdint>
 #include <cstdlib>
 #include <cstring>
+#include <ctime>
+#include <deque>
+#include <exception>
 #include <filesystem>
 #include <fstream>
 #include <functional>
+#include <iomanip>
 #include <iostream>
+#include <limits>
 #include <map>
+#include <memory>
+#include <mutex>
+#include <numeric>
+#include <optional>
+#include <random>
 #include <ostream>
+#include <set>
 #include <sstream>
 #include <stdexcept>
 #include <string>
 #include <string_view>
 #include <system_error>
+#include <thread>
+#include <tuple>
 #include <type_traits>
+#include <unordered_map>
 #include <utility>
+#include <variant>
 #include <vector>
 
 #ifdef HAVE_BROTLI
 #include <brotli/decode.h>
 #endif
+#ifdef HAVE_BROTLI_ENCODER
+#include <brotli/encode.h>
+#endif
+#ifdef HAVE_BZIP2
+#include <bzlib.h>
+#endif
+#ifdef HAVE_ZLIB
+#include <zlib.h>
+#endif
+#ifdef HAVE_FUSE
+#define FUSE_USE_VERSION 31
+#include <fuse.h>
+#endif
 
 #if defined _POSIX_C_SOURCE && _POSIX_C_SOURCE >= 200112L
 #define HAS_FADVISE
@@ -38,9 +79,67 @@
 #include <unistd.h>
 #endif
 
+#if defined(__unix__) || defined(__APPLE__)
+#define HAS_MMAP
+#include <poll.h>
+#include <sys/file.h>
+#include <sys/mman.h>
+#include <sys/stat.h>
+#include <sys/wait.h>
+#include <unistd.h>
+#ifndef NO_SERVER
+#define HAS_SOCKETS
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <sys/socket.h>
+#include <sys/time.h>
+#endif
+#endif
+
+// Linux ioctls on files and devices: where the blocks of a file are on the
+// disk, for --block-map, and secure discards, for --secure-erase.
+#ifdef __linux__
+#define HAS_LINUX_FS
+#include <linux/fiemap.h>
+#include <linux/fs.h>
+// Which has a BLOCK_SIZE of its own, of 1 KiB.
+#undef BLOCK_SIZE
+#include <sys/ioctl.h>
+#include <sys/sysmacros.h>
+#endif
+
+// Opt-in (-DSDAT2IMG_FAST_IO=ON) copying with pread and pwrite on raw file
+// descriptors, through buffers which aren't cleared first.
+#if defined(FAST_IO) && !defined(HAS_MMAP)
+#undef FAST_IO
+#endif
+#if defined(HAVE_FUSE) && !defined(HAS_MMAP)
+#undef HAVE_FUSE
+#endif
+
+// Build metadata for --version, passed in by CMake. Other builds tell what
+// they can.
+#ifndef SDAT2IMG_VERSION
+#define SDAT2IMG_VERSION "dev"
+#endif
+#ifndef SDAT2IMG_GIT_COMMIT
+#define SDAT2IMG_GIT_COMMIT "unknown"
+#endif
+#ifndef SDAT2IMG_BUILD_DATE
+#define SDAT2IMG_BUILD_DATE __DATE__ " " __TIME__
+#endif
+// Where self-update looks for releases, <owner>/<repo> on GitHub.
+#ifndef SDAT2IMG_RELEASES_REPO
+#define SDAT2IMG_RELEASES_REPO "Roynas-Android-Playground/sdat2img"
+#endif
+
 constexpr static std::string_view DEFAULT_OUTPUT = "system.img";
 constexpr static int BLOCK_SIZE = 4096;
 using FileSizeT = std::fstream::off_type;
+static_assert(sizeof(FileSizeT) >= 8, "Images may be larger than 4 GiB");
+// Offsets into the decompressed and the compressed data of a brotli stream,
+// which were reached together while decoding.
+using DecodeCheckpoints = std::vector<std::pair<uint64_t, uint64_t>>;
 
 // Define likely/unlikely based on the compiler used
 // FOR MAX PERFORMANCE
@@ -52,516 +151,14260 @@ using FileSizeT = std::fstream::off_type;
 #define unlikely(x) (x)
 #endif
 
-// Represents the transfer.list file
-struct TransferList {
-  enum class Command { Erase, New, Zero };
-  struct ByteSegments;
-  using OperationsList = std::multimap<Command, ByteSegments>;
-  using ForEachCommand = std::function<void(Command, const ByteSegments &)>;
+// Block and byte counts are 64-bit everywhere, and only narrowed where they
+// meet memory (buffer sizes, mappings) or the OS, which may be 32-bit.
+template <typename To, typename From> To checkedCast(const From value) {
+  static_assert(std::is_integral_v<To> && std::is_integral_v<From>);
+  if (unlikely(value < 0 ? std::is_unsigned_v<To> ||
+                               static_cast<intmax_t>(value) <
+                                   static_cast<intmax_t>(
+                                       std::numeric_limits<To>::min())
+                         : static_cast<uintmax_t>(value) >
+                               static_cast<uintmax_t>(
+                                   std::numeric_limits<To>::max()))) {
+    throw std::overflow_error(std::to_string(value) +
+                              " doesn't fit on this platform");
+  }
+  return static_cast<To>(value);
+}
 
-  struct ByteSegments {
-  private:
-    FileSizeT _begin;
-    FileSizeT _end;
+// Sums and products of sizes, throwing instead of wrapping around. Super
+// images and bogus lists can get close enough to the limits for that.
+template <typename T> T checkedAdd(const T a, const T b) {
+  static_assert(std::is_integral_v<T>);
+  if (unlikely(b > 0 ? a > std::numeric_limits<T>::max() - b
+                     : a < std::numeric_limits<T>::min() - b)) {
+    throw std::overflow_error("Size overflow: " + std::to_string(a) + " + " +
+                              std::to_string(b));
+  }
+  return a + b;
+}
 
-  public:
-    ByteSegments(FileSizeT begin, FileSizeT end) : _begin(begin), _end(end) {}
+template <typename T> T checkedMul(const T a, const T b) {
+  static_assert(std::is_integral_v<T>);
+  if (unlikely(a < 0 || b < 0 ||
+               (b != 0 && a > std::numeric_limits<T>::max() / b))) {
+    throw std::overflow_error("Size overflow: " + std::to_string(a) + " * " +
+                              std::to_string(b));
+  }
+  return a * b;
+}
 
-    void writeToFile(std::istream &in, std::ostream &out) const {
-      FileSizeT block_count = _end - _begin;
-      std::cout << "Copying " << block_count << " blocks into position "
-                << _begin << "..." << std::endl;
-      out.seekp(_begin * BLOCK_SIZE, std::ios::beg);
-      while (block_count > 0) {
-        std::array<char, BLOCK_SIZE> buffer{};
-        in.read(buffer.data(), BLOCK_SIZE);
-        out.write(buffer.data(), BLOCK_SIZE);
-        block_count--;
+// Plain SHA-256, so hashing doesn't pull in a crypto library.
+class Sha256 {
+public:
+  using Digest = std::array<uint8_t, 32>;
+
+  void update(const void *data, size_t size) {
+    const auto *bytes = static_cast<const uint8_t *>(data);
+    total += size;
+    while (size > 0) {
+      const size_t n = std::min(size, block.size() - used);
+      std::memcpy(block.data() + used, bytes, n);
+      used += n;
+      bytes += n;
+      size -= n;
+      if (used == block.size()) {
+        transform();
+        used = 0;
       }
     }
+  }
 
-    [[nodiscard]] FileSizeT end() const noexcept { return _end; }
-    [[nodiscard]] FileSizeT begin() const noexcept { return _begin; }
-    [[nodiscard]] FileSizeT size() const noexcept { return _end - _begin; }
-  };
+  Digest digest() {
+    const uint64_t bits = total * 8;
+    const uint8_t pad = 0x80;
+    update(&pad, 1);
+    const uint8_t zero = 0;
+    while (used != 56) {
+      update(&zero, 1);
+    }
+    std::array<uint8_t, 8> length{};
+    for (int i = 0; i < 8; ++i) {
+      length[i] = static_cast<uint8_t>(bits >> (56 - 8 * i));
+    }
+    update(length.data(), length.size());
+    Digest out{};
+    for (int i = 0; i < 32; ++i) {
+      out[i] = static_cast<uint8_t>(state[i / 4] >> (24 - 8 * (i % 4)));
+    }
+    return out;
+  }
+
+  static Digest of(const void *data, size_t size) {
+    Sha256 hasher;
+    hasher.update(data, size);
+    return hasher.digest();
+  }
 
 private:
-  // Version of the transfer.list scheme.
-  int version{};
-  // Commands list
-  OperationsList commands;
+  std::array<uint32_t, 8> state{0x6a09e667, 0xbb67ae85, 0x3c6ef372,
+                                0xa54ff53a, 0x510e527f, 0x9b05688c,
+                                0x1f83d9ab, 0x5be0cd19};
+  std::array<uint8_t, 64> block{};
+  size_t used = 0;
+  uint64_t total = 0;
 
-public:
-  // parser taking a transfer list file path.
-  void parse(const std::filesystem::path &transfer_list_file);
-  inline void forEachCommand(const ForEachCommand &callbacks);
-  FileSizeT max();
+  static uint32_t rotr(uint32_t x, int n) { return (x >> n) | (x << (32 - n)); }
 
-  // Convert string to Operations, throwing an error if invalid.
-  static Command toOperations(const std::string &command) {
-    if (command == "erase") {
-      return Command::Erase;
-    } else if (command == "new") {
-      return Command::New;
-    } else if (command == "zero") {
-      return Command::Zero;
-    } else {
-      throw std::invalid_argument("Invalid operation: " + command);
+  void transform() {
+    static constexpr std::array<uint32_t, 64> k{
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+        0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+        0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+        0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+        0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+        0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+        0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+        0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2};
+    std::array<uint32_t, 64> w{};
+    for (int i = 0; i < 16; ++i) {
+      w[i] = static_cast<uint32_t>(block[i * 4]) << 24 |
+             static_cast<uint32_t>(block[i * 4 + 1]) << 16 |
+             static_cast<uint32_t>(block[i * 4 + 2]) << 8 | block[i * 4 + 3];
+    }
+    for (int i = 16; i < 64; ++i) {
+      const uint32_t s0 =
+          rotr(w[i - 15], 7) ^ rotr(w[i - 15], 18) ^ (w[i - 15] >> 3);
+      const uint32_t s1 =
+          rotr(w[i - 2], 17) ^ rotr(w[i - 2], 19) ^ (w[i - 2] >> 10);
+      w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+    }
+    auto [a, b, c, d, e, f, g, h] = state;
+    for (int i = 0; i < 64; ++i) {
+      const uint32_t t1 = h + (rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25)) +
+                          ((e & f) ^ (~e & g)) + k[i] + w[i];
+      const uint32_t t2 = (rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22)) +
+                          ((a & b) ^ (a & c) ^ (b & c));
+      h = g;
+      g = f;
+      f = e;
+      e = d + t1;
+      d = c;
+      c = b;
+      b = a;
+      a = t1 + t2;
+    }
+    const std::array<uint32_t, 8> add{a, b, c, d, e, f, g, h};
+    for (int i = 0; i < 8; ++i) {
+      state[i] += add[i];
     }
   }
 };
 
-// std::ostream operator for TransferList::Command enum.
-std::ostream &operator<<(std::ostream &self,
-                         const TransferList::Command &operation) {
-  switch (operation) {
-  case TransferList::Command::Erase:
-    return self << "erase";
-  case TransferList::Command::New:
-    return self << "new";
-  case TransferList::Command::Zero:
-    return self << "zero";
-  }
-  return self;
-}
-
-// Declare a exception within file operations failure
-class IOException : public std::runtime_error {
+// Plain SHA-1, which transfer lists of version 3 and up hash blocks with.
+class Sha1 {
 public:
-  explicit IOException(const std::filesystem::path &path,
-                       const std::string &message)
-      : std::runtime_error("Couldn't " + message + " file: " + path.string()) {}
-};
+  using Digest = std::array<uint8_t, 20>;
 
-// Represent a text file with lines
-struct TextFile {
-private:
-  std::ifstream file;
-  int line_num{};
-  std::filesystem::path path;
+  void update(const void *data, size_t size) {
+    const auto *bytes = static_cast<const uint8_t *>(data);
+    total += size;
+    while (size > 0) {
+      const size_t n = std::min(size, block.size() - used);
+      std::memcpy(block.data() + used, bytes, n);
+      used += n;
+      bytes += n;
+      size -= n;
+      if (used == block.size()) {
+        transform();
+        used = 0;
+      }
+    }
+  }
 
-public:
-  explicit TextFile(const std::filesystem::path &path)
-      : file(path), path(path) {
-    if (unlikely(!file.is_open())) {
-      throw IOException(path, "open");
+  Digest digest() {
+    const uint64_t bits = total * 8;
+    const uint8_t pad = 0x80;
+    update(&pad, 1);
+    const uint8_t zero = 0;
+    while (used != 56) {
+      update(&zero, 1);
+    }
+    std::array<uint8_t, 8> length{};
+    for (int i = 0; i < 8; ++i) {
+      length[i] = static_cast<uint8_t>(bits >> (56 - 8 * i));
     }
+    update(length.data(), length.size());
+    Digest out{};
+    for (int i = 0; i < 20; ++i) {
+      out[i] = static_cast<uint8_t>(state[i / 4] >> (24 - 8 * (i % 4)));
+    }
+    return out;
   }
 
-  template <typename T = std::string> bool takeOneLine(T *out) {
-    std::string line;
-    std::stringstream stream;
-    if (unlikely(!getline(file, line))) {
-      return false;
+  static Digest of(const void *data, size_t size) {
+    Sha1 hasher;
+    hasher.update(data, size);
+    return hasher.digest();
+  }
+
+private:
+  std::array<uint32_t, 5> state{0x67452301, 0xefcdab89, 0x98badcfe,
+                                0x10325476, 0xc3d2e1f0};
+  std::array<uint8_t, 64> block{};
+  size_t used = 0;
+  uint64_t total = 0;
+
+  static uint32_t rotl(uint32_t x, int n) { return (x << n) | (x >> (32 - n)); }
+
+  void transform() {
+    std::array<uint32_t, 80> w{};
+    for (int i = 0; i < 16; ++i) {
+      w[i] = static_cast<uint32_t>(block[i * 4]) << 24 |
+             static_cast<uint32_t>(block[i * 4 + 1]) << 16 |
+             static_cast<uint32_t>(block[i * 4 + 2]) << 8 | block[i * 4 + 3];
     }
-    ++line_num;
-    if constexpr (std::is_same_v<T, std::string>) {
-      *out = line;
-      return true;
-    } else {
-      stream << line;
-      if (likely(stream >> *out)) {
-        return true;
+    for (int i = 16; i < 80; ++i) {
+      w[i] = rotl(w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16], 1);
+    }
+    auto [a, b, c, d, e] = state;
+    for (int i = 0; i < 80; ++i) {
+      uint32_t f = 0, k = 0;
+      if (i < 20) {
+        f = (b & c) | (~b & d);
+        k = 0x5a827999;
+      } else if (i < 40) {
+        f = b ^ c ^ d;
+        k = 0x6ed9eba1;
+      } else if (i < 60) {
+        f = (b & c) | (b & d) | (c & d);
+        k = 0x8f1bbcdc;
+      } else {
+        f = b ^ c ^ d;
+        k = 0xca62c1d6;
       }
-      std::cerr << "Couldn't convert line to type T";
-      return false;
+      const uint32_t t = rotl(a, 5) + f + e + k + w[i];
+      e = d;
+      d = c;
+      c = rotl(b, 30);
+      b = a;
+      a = t;
+    }
+    const std::array<uint32_t, 5> add{a, b, c, d, e};
+    for (int i = 0; i < 5; ++i) {
+      state[i] += add[i];
     }
   }
-  void ignoreLine() {
-    std::string line;
-    if (likely(getline(file, line))) {
-      ++line_num;
+};
+
+// Plain SHA-512, which Ed25519 signatures are built on.
+class Sha512 {
+public:
+  using Digest = std::array<uint8_t, 64>;
+
+  void update(const void *data, size_t size) {
+    const auto *bytes = static_cast<const uint8_t *>(data);
+    total += size;
+    while (size > 0) {
+      const size_t n = std::min(size, block.size() - used);
+      std::memcpy(block.data() + used, bytes, n);
+      used += n;
+      bytes += n;
+      size -= n;
+      if (used == block.size()) {
+        transform();
+        used = 0;
+      }
     }
   }
-  template <int X> void ignoreLine() {
-    for (int i = 0; i < X; ++i) {
-      ignoreLine();
+
+  Digest digest() {
+    const uint64_t bits = total * 8;
+    const uint8_t pad = 0x80;
+    update(&pad, 1);
+    const uint8_t zero = 0;
+    while (used != 120) {
+      update(&zero, 1);
+    }
+    std::array<uint8_t, 8> length{};
+    for (int i = 0; i < 8; ++i) {
+      length[i] = static_cast<uint8_t>(bits >> (56 - 8 * i));
+    }
+    update(length.data(), length.size());
+    Digest out{};
+    for (int i = 0; i < 64; ++i) {
+      out[i] = static_cast<uint8_t>(state[i / 8] >> (56 - 8 * (i % 8)));
     }
+    return out;
   }
 
-  std::string current() const noexcept {
-    std::stringstream stream;
-    stream << "Line " << line_num << " of file: " << path;
-    return stream.str();
+  static Digest of(const void *data, size_t size) {
+    Sha512 hasher;
+    hasher.update(data, size);
+    return hasher.digest();
   }
 
-  // Disable move constructors
-  TextFile(TextFile &&) = delete;
-  TextFile &operator=(TextFile &&) = delete;
+private:
+  std::array<uint64_t, 8> state{
+      0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b,
+      0xa54ff53a5f1d36f1, 0x510e527fade682d1, 0x9b05688c2b3e6c1f,
+      0x1f83d9abfb41bd6b, 0x5be0cd19137e2179};
+  std::array<uint8_t, 128> block{};
+  size_t used = 0;
+  uint64_t total = 0;
+
+  static uint64_t rotr(uint64_t x, int n) { return (x >> n) | (x << (64 - n)); }
+
+  void transform() {
+    static constexpr std::array<uint64_t, 80> k{
+        0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f,
+        0xe9b5dba58189dbbc, 0x3956c25bf348b538, 0x59f111f1b605d019,
+        0x923f82a4af194f9b, 0xab1c5ed5da6d8118, 0xd807aa98a3030242,
+        0x12835b0145706fbe, 0x243185be4ee4b28c, 0x550c7dc3d5ffb4e2,
+        0x72be5d74f27b896f, 0x80deb1fe3b1696b1, 0x9bdc06a725c71235,
+        0xc19bf174cf692694, 0xe49b69c19ef14ad2, 0xefbe4786384f25e3,
+        0x0fc19dc68b8cd5b5, 0x240ca1cc77ac9c65, 0x2de92c6f592b0275,
+        0x4a7484aa6ea6e483, 0x5cb0a9dcbd41fbd4, 0x76f988da831153b5,
+        0x983e5152ee66dfab, 0xa831c66d2db43210, 0xb00327c898fb213f,
+        0xbf597fc7beef0ee4, 0xc6e00bf33da88fc2, 0xd5a79147930aa725,
+        0x06ca6351e003826f, 0x142929670a0e6e70, 0x27b70a8546d22ffc,
+        0x2e1b21385c26c926, 0x4d2c6dfc5ac42aed, 0x53380d139d95b3df,
+        0x650a73548baf63de, 0x766a0abb3c77b2a8, 0x81c2c92e47edaee6,
+        0x92722c851482353b, 0xa2bfe8a14cf10364, 0xa81a664bbc423001,
+        0xc24b8b70d0f89791, 0xc76c51a30654be30, 0xd192e819d6ef5218,
+        0xd69906245565a910, 0xf40e35855771202a, 0x106aa07032bbd1b8,
+        0x19a4c116b8d2d0c8, 0x1e376c085141ab53, 0x2748774cdf8eeb99,
+        0x34b0bcb5e19b48a8, 0x391c0cb3c5c95a63, 0x4ed8aa4ae3418acb,
+        0x5b9cca4f7763e373, 0x682e6ff3d6b2b8a3, 0x748f82ee5defb2fc,
+        0x78a5636f43172f60, 0x84c87814a1f0ab72, 0x8cc702081a6439ec,
+        0x90befffa23631e28, 0xa4506cebde82bde9, 0xbef9a3f7b2c67915,
+        0xc67178f2e372532b, 0xca273eceea26619c, 0xd186b8c721c0c207,
+        0xeada7dd6cde0eb1e, 0xf57d4f7fee6ed178, 0x06f067aa72176fba,
+        0x0a637dc5a2c898a6, 0x113f9804bef90dae, 0x1b710b35131c471b,
+        0x28db77f523047d84, 0x32caab7b40c72493, 0x3c9ebe0a15c9bebc,
+        0x431d67c49c100d4c, 0x4cc5d4becb3e42b6, 0x597f299cfc657e2a,
+        0x5fcb6fab3ad6faec, 0x6c44198c4a475817};
+    std::array<uint64_t, 80> w{};
+    for (int i = 0; i < 16; ++i) {
+      for (int j = 0; j < 8; ++j) {
+        w[i] = w[i] << 8 | block[i * 8 + j];
+      }
+    }
+    for (int i = 16; i < 80; ++i) {
+      const uint64_t s0 =
+          rotr(w[i - 15], 1) ^ rotr(w[i - 15], 8) ^ (w[i - 15] >> 7);
+      const uint64_t s1 =
+          rotr(w[i - 2], 19) ^ rotr(w[i - 2], 61) ^ (w[i - 2] >> 6);
+      w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+    }
+    auto [a, b, c, d, e, f, g, h] = state;
+    for (int i = 0; i < 80; ++i) {
+      const uint64_t t1 = h + (rotr(e, 14) ^ rotr(e, 18) ^ rotr(e, 41)) +
+                          ((e & f) ^ (~e & g)) + k[i] + w[i];
+      const uint64_t t2 = (rotr(a, 28) ^ rotr(a, 34) ^ rotr(a, 39)) +
+                          ((a & b) ^ (a & c) ^ (b & c));
+      h = g;
+      g = f;
+      f = e;
+      e = d + t1;
+      d = c;
+      c = b;
+      b = a;
+      a = t1 + t2;
+    }
+    const std::array<uint64_t, 8> add{a, b, c, d, e, f, g, h};
+    for (int i = 0; i < 8; ++i) {
+      state[i] += add[i];
+    }
+  }
 };
 
-// Create exception with the TextFile object
-class TextFileError : public std::runtime_error {
+// BLAKE3, for checking images faster than SHA-256 can. The input is hashed
+// as a tree of 1 KiB chunks, so large updates are split between threads;
+// the hash is the same however many there are.
+class Blake3 {
 public:
-  explicit TextFileError(const TextFile &file, const std::string &message)
-      : std::runtime_error(message + ". Parser is at " + file.current()) {}
-};
+  using Digest = std::array<uint8_t, 32>;
 
-// Helper function like in GTest.
-template <typename IntT>
-inline void expected_eq(const std::string_view expection, const IntT l_op,
-                        const IntT r_op) {
-  std::cerr << "Expected " << expection << ", but " << l_op << " != " << r_op
-            << std::endl;
-}
-#define EXPECTED_EQ(l_op, r_op) expected_eq(#l_op " == " #r_op, l_op, r_op)
-#define ABORT_PARSING_IF(tfile, cond)                                          \
-  if (unlikely((cond))) {                                                      \
-    throw TextFileError(tfile,                                                 \
-                        "Couldn't parse line, " #cond " condition has met");   \
-  }
+  explicit Blake3(const unsigned threads = 1)
+      : threads(std::max(threads, 1U)) {}
 
-inline std::vector<std::string> split(const std::string &str,
-                                      const char &delimiter) {
-  std::vector<std::string> tokens;
-  std::stringstream ss(str);
-  std::string token;
-  while (getline(ss, token, delimiter)) {
-    tokens.emplace_back(token);
+  void update(const void *data, size_t size) {
+    const auto *bytes = static_cast<const uint8_t *>(data);
+    // The chunk is only finished once more input shows it isn't the last.
+    if (chunk.size() > 0) {
+      const size_t n = std::min(size, kChunkSize - chunk.size());
+      chunk.update(bytes, n);
+      bytes += n;
+      size -= n;
+      if (size == 0) {
+        return;
+      }
+      const uint64_t next = chunk.counter + 1;
+      push(chainingValue(chunkNode(chunk)), chunk.counter);
+      chunk = Chunk{};
+      chunk.counter = next;
+    }
+    // Whole subtrees are hashed straight from the input, as long as some of
+    // it is left over, so that none of them is the root.
+    while (size > kChunkSize) {
+      uint64_t subtree = uint64_t{1} << (63 - __builtin_clzll(size));
+      while ((chunk.counter * kChunkSize) & (subtree - 1)) {
+        subtree /= 2;
+      }
+      if (subtree == kChunkSize) {
+        Chunk whole;
+        whole.counter = chunk.counter;
+        whole.update(bytes, kChunkSize);
+        push(chainingValue(chunkNode(whole)), chunk.counter);
+      } else {
+        const auto [left, right] =
+            children(bytes, subtree, chunk.counter, threads);
+        push(left, chunk.counter);
+        push(right, chunk.counter + subtree / 2 / kChunkSize);
+      }
+      chunk.counter += subtree / kChunkSize;
+      bytes += subtree;
+      size -= static_cast<size_t>(subtree);
+    }
+    if (size > 0) {
+      chunk.update(bytes, size);
+      merge(chunk.counter);
+    }
   }
-  return tokens;
-}
-
-std::vector<FileSizeT> parseRanges(const std::string &src) {
-  std::vector<std::string> src_set = split(src, ',');
-  std::vector<FileSizeT> ret;
 
-  std::transform(src_set.begin(), src_set.end(), std::back_inserter(ret),
-                 [&src_set](const auto &src) {
-                   FileSizeT num = 0;
-                   std::stringstream ss(src);
-                   if (unlikely(!(ss >> num))) {
-                     throw std::invalid_argument(
-                         "Error parsing following data to rangeset: " + src);
-                   }
-                   return num;
-                 });
-  if (unlikely(ret.size() != ret[0] + 1)) {
-    EXPECTED_EQ(ret.size(), static_cast<size_t>(ret[0] + 1));
-    return {};
+  Digest digest() const {
+    Node node;
+    size_t left = stack.size();
+    if (chunk.size() > 0 || stack.empty()) {
+      node = chunkNode(chunk);
+    } else {
+      // The input ended with a subtree, whose halves are still on the
+      // stack.
+      node = parentNode(stack[left - 2], stack[left - 1]);
+      left -= 2;
+    }
+    while (left > 0) {
+      --left;
+      node = parentNode(stack[left], chainingValue(node));
+    }
+    const auto words = compress(node.cv, node.block.data(), 0, node.size,
+                                node.flags | kRoot);
+    Digest out{};
+    for (size_t i = 0; i < out.size(); ++i) {
+      out[i] = static_cast<uint8_t>(words[i / 4] >> (8 * (i % 4)));
+    }
+    return out;
   }
-  if (unlikely((ret.size() - 1) % 2 != 0)) {
-    EXPECTED_EQ(ret.size() % 2, static_cast<size_t>(0));
-    return {};
+
+  static Digest of(const void *data, size_t size) {
+    Blake3 hasher;
+    hasher.update(data, size);
+    return hasher.digest();
   }
-  // Remove first element
-  ret.erase(ret.begin());
-  return ret;
-}
 
-void TransferList::parse(const std::filesystem::path &transfer_list_file) {
-  std::string line;
-  std::vector<FileSizeT> nums;
-  TextFile transfer_list(transfer_list_file);
+private:
+  using Words = std::array<uint32_t, 8>;
+  using Block = std::array<uint8_t, 64>;
+
+  static constexpr size_t kChunkSize = 1024;
+  // Less than this isn't worth starting a thread for.
+  static constexpr uint64_t kMinThreadSize = uint64_t{64} << 10;
+  static constexpr uint32_t kChunkStart = 1;
+  static constexpr uint32_t kChunkEnd = 2;
+  static constexpr uint32_t kParent = 4;
+  static constexpr uint32_t kRoot = 8;
+  static constexpr Words kIv{0x6a09e667, 0xbb67ae85, 0x3c6ef372,
+                             0xa54ff53a, 0x510e527f, 0x9b05688c,
+                             0x1f83d9ab, 0x5be0cd19};
+
+  // What is compressed into the chaining value of a chunk or parent, or
+  // into the hash if it's the root.
+  struct Node {
+    Words cv;
+    Block block;
+    uint64_t counter;
+    uint32_t size;
+    uint32_t flags;
+  };
+
+  // The chunk being hashed, a block at a time. The last block is kept
+  // back, it's compressed differently.
+  struct Chunk {
+    Words cv = kIv;
+    uint64_t counter = 0;
+    Block block{};
+    size_t used = 0;
+    size_t compressed = 0;
+
+    size_t size() const { return compressed * block.size() + used; }
+
+    uint32_t startFlag() const { return compressed == 0 ? kChunkStart : 0; }
+
+    void update(const uint8_t *bytes, size_t n) {
+      while (n > 0) {
+        if (used == block.size()) {
+          const auto words = compress(cv, block.data(), counter,
+                                      static_cast<uint32_t>(block.size()),
+                                      startFlag());
+          std::copy_n(words.begin(), cv.size(), cv.begin());
+          ++compressed;
+          used = 0;
+        }
+        const size_t take = std::min(n, block.size() - used);
+        std::memcpy(block.data() + used, bytes, take);
+        used += take;
+        bytes += take;
+        n -= take;
+      }
+    }
+  };
+
+  unsigned threads;
+  Chunk chunk;
+  // Chaining values of the subtrees before the chunk, largest first.
+  std::vector<Words> stack;
 
-  // First line is the version
-  if (unlikely(!transfer_list.takeOneLine(&version))) {
-    throw TextFileError(transfer_list, "Failed to read version");
+  static uint32_t rotr(uint32_t x, int n) { return (x >> n) | (x << (32 - n)); }
+
+  static std::array<uint32_t, 16> compress(const Words &cv,
+                                           const uint8_t *block,
+                                           const uint64_t counter,
+                                           const uint32_t size,
+                                           const uint32_t flags) {
+    static constexpr std::array<size_t, 16> kPermutation{
+        2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8};
+    std::array<uint32_t, 16> m{};
+    for (size_t i = 0; i < m.size(); ++i) {
+      m[i] = static_cast<uint32_t>(block[4 * i]) |
+             static_cast<uint32_t>(block[4 * i + 1]) << 8 |
+             static_cast<uint32_t>(block[4 * i + 2]) << 16 |
+             static_cast<uint32_t>(block[4 * i + 3]) << 24;
+    }
+    std::array<uint32_t, 16> s{cv[0],     cv[1],
+                               cv[2],     cv[3],
+                               cv[4],     cv[5],
+                               cv[6],     cv[7],
+                               kIv[0],    kIv[1],
+                               kIv[2],    kIv[3],
+                               static_cast<uint32_t>(counter),
+                               static_cast<uint32_t>(counter >> 32),
+                               size,      flags};
+    const auto g = [&s](const size_t a, const size_t b, const size_t c,
+                        const size_t d, const uint32_t x, const uint32_t y) {
+      s[a] += s[b] + x;
+      s[d] = rotr(s[d] ^ s[a], 16);
+      s[c] += s[d];
+      s[b] = rotr(s[b] ^ s[c], 12);
+      s[a] += s[b] + y;
+      s[d] = rotr(s[d] ^ s[a], 8);
+      s[c] += s[d];
+      s[b] = rotr(s[b] ^ s[c], 7);
+    };
+    for (int round = 0; round < 7; ++round) {
+      g(0, 4, 8, 12, m[0], m[1]);
+      g(1, 5, 9, 13, m[2], m[3]);
+      g(2, 6, 10, 14, m[4], m[5]);
+      g(3, 7, 11, 15, m[6], m[7]);
+      g(0, 5, 10, 15, m[8], m[9]);
+      g(1, 6, 11, 12, m[10], m[11]);
+      g(2, 7, 8, 13, m[12], m[13]);
+      g(3, 4, 9, 14, m[14], m[15]);
+      std::array<uint32_t, 16> permuted{};
+      for (size_t i = 0; i < m.size(); ++i) {
+        permuted[i] = m[kPermutation[i]];
+      }
+      m = permuted;
+    }
+    for (size_t i = 0; i < 8; ++i) {
+      s[i] ^= s[i + 8];
+      s[i + 8] ^= cv[i];
+    }
+    return s;
   }
-  switch (version) {
-  case 1:
-    std::cout << "Android 5.0 detected" << std::endl;
-    break;
-  case 2:
-    std::cout << "Android 5.1 detected" << std::endl;
-    break;
-  case 3:
-    std::cout << "Android 6.x detected" << std::endl;
-    break;
-  case 4:
-    std::cout << "Android 7.x or above detected" << std::endl;
-    break;
-  default:
-    throw TextFileError(transfer_list,
-                        "Unknown version: " + std::to_string(version));
+
+  static Words chainingValue(const Node &node) {
+    const auto words = compress(node.cv, node.block.data(), node.counter,
+                                node.size, node.flags);
+    Words cv{};
+    std::copy_n(words.begin(), cv.size(), cv.begin());
+    return cv;
   }
 
-  // Second line is total number of blocks. Ignore it though.
-  // We are going to calculate it by ourselves.
-  transfer_list.ignoreLine();
+  static Node chunkNode(const Chunk &chunk) {
+    return {chunk.cv, chunk.block, chunk.counter,
+            static_cast<uint32_t>(chunk.used),
+            chunk.startFlag() | kChunkEnd};
+  }
 
-  // Skip those 2 lines if version >= 2
-  if (version >= 2) {
-    transfer_list.ignoreLine<2>();
+  static Node parentNode(const Words &left, const Words &right) {
+    Node node{kIv, {}, 0, static_cast<uint32_t>(Block{}.size()), kParent};
+    for (size_t i = 0; i < 2 * left.size(); ++i) {
+      const uint32_t word = i < left.size() ? left[i] : right[i - left.size()];
+      for (size_t j = 0; j < 4; ++j) {
+        node.block[4 * i + j] = static_cast<uint8_t>(word >> (8 * j));
+      }
+    }
+    return node;
   }
 
-  // Loop through all lines
-  while (transfer_list.takeOneLine(&line)) {
-    const auto &split_line = split(line, ' ');
-    ABORT_PARSING_IF(transfer_list, split_line.size() != 2);
-    nums = parseRanges(split_line[1]);
-    ABORT_PARSING_IF(transfer_list, nums.empty());
-    const auto command = toOperations(split_line[0]);
+  // Chaining value of size bytes of whole chunks, a power of two of them,
+  // the first one being chunk number counter.
+  static Words subtree(const uint8_t *data, const uint64_t size,
+                       const uint64_t counter, const unsigned threads) {
+    if (size == kChunkSize) {
+      Chunk whole;
+      whole.counter = counter;
+      whole.update(data, kChunkSize);
+      return chainingValue(chunkNode(whole));
+    }
+    const auto [left, right] = children(data, size, counter, threads);
+    return chainingValue(parentNode(left, right));
+  }
 
-    for (size_t i = 0; i < nums.size(); i += 2) {
-      commands.emplace(command,
-                       TransferList::ByteSegments(nums[i], nums[i + 1]));
+  // Chaining values of both halves of a subtree, side by side when there
+  // are threads for it.
+  static std::pair<Words, Words> children(const uint8_t *data,
+                                          const uint64_t size,
+                                          const uint64_t counter,
+                                          const unsigned threads) {
+    const uint64_t half = size / 2;
+    const uint64_t right_counter = counter + half / kChunkSize;
+    Words left{};
+    if (threads < 2 || half < kMinThreadSize) {
+      left = subtree(data, half, counter, 1);
+      return {left, subtree(data + half, half, right_counter, 1)};
     }
+    std::thread worker(
+        [&] { left = subtree(data, half, counter, threads / 2); });
+    const auto right =
+        subtree(data + half, half, right_counter, threads - threads / 2);
+    worker.join();
+    return {left, right};
   }
-  std::cout << "Parsed " << commands.size() << " commands" << std::endl;
-}
 
-void TransferList::forEachCommand(const ForEachCommand &callbacks) {
-  // Commands is reversed, so we need to use cbegin, cend
-  for (const auto &it : commands) {
-    callbacks(it.first, it.second);
+  // Merge the chaining values of subtrees which can't grow anymore, with
+  // total chunks before the next one.
+  void merge(const uint64_t total) {
+    while (stack.size() > static_cast<size_t>(__builtin_popcountll(total))) {
+      const auto right = stack.back();
+      stack.pop_back();
+      const auto left = stack.back();
+      stack.pop_back();
+      stack.push_back(chainingValue(parentNode(left, right)));
+    }
+  }
+
+  void push(const Words &cv, const uint64_t counter) {
+    merge(counter);
+    stack.push_back(cv);
   }
+};
+
+// Hashes of what was written, for reading it back against. BLAKE3 where
+// only sdat2img reads them, SHA-256 where other tools may check them too.
+enum class HashAlgorithm { Sha256, Blake3 };
+
+std::string_view hashName(const HashAlgorithm algorithm) {
+  return algorithm == HashAlgorithm::Blake3 ? "blake3" : "sha256";
 }
 
-FileSizeT TransferList::max() {
-  return std::max_element(commands.begin(), commands.end(),
-                          [](const auto &a, const auto &b) {
-                            return a.second.end() < b.second.end();
-                          })
-      ->second.end();
-}
+// Either hash, picked at runtime. Both have 32 byte digests.
+class DataHash {
+public:
+  using Digest = Sha256::Digest;
 
-[[noreturn]] void usage(const char *exe) {
-  std::cout << "Usage: " << exe
-            << " <transfer_list> <system_new_file> <system_img>" << std::endl;
-  std::cout << "    <transfer_list>: transfer list file" << std::endl;
-  std::cout << "    <system_new_file>: system new dat file ";
-#ifdef HAVE_BROTLI
-  std::cout << "(Can support brotli compressed)";
-#endif
-  std::cout << std::endl;
-  std::cout << "    <system_img>: output system image" << std::endl;
-  std::cout << "If you are lazy, then just provide directory and filename, I "
-               "will try to auto detect them."
-            << std::endl;
-  exit(EXIT_SUCCESS);
-}
+  explicit DataHash(const HashAlgorithm algorithm, const unsigned threads = 1) {
+    if (algorithm == HashAlgorithm::Blake3) {
+      hash.emplace<Blake3>(threads);
+    }
+  }
 
-#ifdef HAVE_BROTLI
+  void update(const void *data, size_t size) {
+    std::visit([&](auto &hash) { hash.update(data, size); }, hash);
+  }
 
-class BrotliManager {
+  Digest digest() {
+    return std::visit([](auto &hash) { return hash.digest(); }, hash);
+  }
+
+private:
+  std::variant<Sha256, Blake3> hash;
+};
+
+// Ed25519 signatures (RFC 8032), after TweetNaCl: small and slow, which is
+// plenty for signing a manifest or two. Field elements are 16 limbs of 16
+// bits, points are extended coordinates (X, Y, Z, T).
+class Ed25519 {
 public:
-  BrotliManager(const std::filesystem::path &input_file)
-      : file_path(input_file) {}
+  using Seed = std::array<uint8_t, 32>;
+  using PublicKey = std::array<uint8_t, 32>;
+  using Signature = std::array<uint8_t, 64>;
 
-  bool isValidBrotli() const {
-    // TODO: Check actual, for now we are doing the same as the brotli
-    // executable does Checking the br file extension.
-    return file_path.filename().extension() == ".br";
+  static PublicKey publicKey(const Seed &seed) {
+    const auto secret = expand(seed);
+    Point p;
+    scalarBase(p, secret.data());
+    PublicKey key{};
+    pack(key.data(), p);
+    return key;
   }
-  bool decompress(const std::filesystem::path &output_file) const {
-    // Open the input file in binary mode
-    std::ifstream file(file_path, std::ios::binary | std::ios::ate);
 
-    if (unlikely(!file.is_open())) {
-      std::cerr << "Error opening input file: " << file_path << std::endl;
-      return false;
-    }
+  static Signature sign(const Seed &seed, const void *message,
+                        const size_t size) {
+    const auto secret = expand(seed);
+    const auto key = publicKey(seed);
+    Sha512 nonce_hash;
+    nonce_hash.update(secret.data() + 32, 32);
+    nonce_hash.update(message, size);
+    auto r = nonce_hash.digest();
+    reduce(r.data());
 
-    // Get the size of the file and read the content
-    std::ifstream::pos_type file_size = file.tellg();
-    file.seekg(0, std::ios::beg);
-
-    std::vector<uint8_t> compressed_data(file_size);
-    file.read(reinterpret_cast<char *>(compressed_data.data()), file_size);
+    Signature signature{};
+    Point p;
+    scalarBase(p, r.data());
+    pack(signature.data(), p);
 
-    if (unlikely(!file)) {
-      std::cerr << "Error reading input file: " << file_path << std::endl;
-      return false;
+    auto h = challenge(signature.data(), key, message, size);
+    std::array<int64_t, 64> x{};
+    for (int i = 0; i < 32; ++i) {
+      x[i] = r[i];
     }
-
-    // Initialize the Brotli decoder
-    BrotliDecoderState *state =
-        BrotliDecoderCreateInstance(nullptr, nullptr, nullptr);
-    if (unlikely(!state)) {
-      std::cerr << "Error creating Brotli decoder state." << std::endl;
-      return false;
+    for (int i = 0; i < 32; ++i) {
+      for (int j = 0; j < 32; ++j) {
+        x[i + j] += int64_t{h[i]} * secret[j];
+      }
     }
+    modL(signature.data() + 32, x);
+    return signature;
+  }
 
-    // Prepare output file
-    std::ofstream output(output_file, std::ios::binary);
-    if (unlikely(!output.is_open())) {
-      std::cerr << "Error opening output file: " << output_file << std::endl;
-      BrotliDecoderDestroyInstance(state);
+  static bool verify(const PublicKey &key, const void *message,
+                     const size_t size, const Signature &signature) {
+    Point q;
+    if ((signature[63] & 0xe0) != 0 || !unpackNegative(q, key)) {
       return false;
     }
+    auto h = challenge(signature.data(), key, message, size);
+    Point p, s;
+    scalarMult(p, q, h.data());
+    scalarBase(s, signature.data() + 32);
+    add(p, s);
+    std::array<uint8_t, 32> check{};
+    pack(check.data(), p);
+    uint8_t diff = 0;
+    for (int i = 0; i < 32; ++i) {
+      diff |= check[i] ^ signature[i];
+    }
+    return diff == 0;
+  }
 
-    // Decompression buffer
-    const size_t kBufferSize = 4096;
-    std::vector<uint8_t> output_buffer(kBufferSize);
+private:
+  using Field = std::array<int64_t, 16>;
+  using Point = std::array<Field, 4>;
 
-    size_t input_pos = 0;
-    size_t available_out = kBufferSize;
-    uint8_t *output_ptr = output_buffer.data();
+  static constexpr Field kZero{};
+  static constexpr Field kOne{1};
+  // d, 2d, the base point and sqrt(-1).
+  static constexpr Field kD{0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141,
+                            0x0a4d, 0x0070, 0xe898, 0x7779, 0x4079, 0x8cc7,
+                            0xfe73, 0x2b6f, 0x6cee, 0x5203};
+  static constexpr Field kD2{0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283,
+                             0x149a, 0x00e0, 0xd130, 0xeef3, 0x80f2, 0x198e,
+                             0xfce7, 0x56df, 0xd9dc, 0x2406};
+  static constexpr Field kX{0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525,
+                            0xc760, 0x692c, 0xdc5c, 0xfdd6, 0xe231, 0xc0a4,
+                            0x53fe, 0xcd6e, 0x36d3, 0x2169};
+  static constexpr Field kY{0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+                            0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+                            0x6666, 0x6666, 0x6666, 0x6666};
+  static constexpr Field kI{0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f,
+                            0x1806, 0x2f43, 0xd7a7, 0x3dfb, 0x0099, 0x2b4d,
+                            0xdf0b, 0x4fc1, 0x2480, 0x2b83};
+  // The order of the base point, little endian.
+  static constexpr std::array<int64_t, 32> kL{
+      0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7,
+      0xa2, 0xde, 0xf9, 0xde, 0x14, 0,    0,    0,    0,    0,    0,
+      0,    0,    0,    0,    0,    0,    0,    0,    0,    0x10};
 
-    // Decompress the data
-    BrotliDecoderResult result = BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT;
+  // The clamped scalar, then the nonce prefix.
+  static Sha512::Digest expand(const Seed &seed) {
+    auto secret = Sha512::of(seed.data(), seed.size());
+    secret[0] &= 248;
+    secret[31] &= 127;
+    secret[31] |= 64;
+    return secret;
+  }
 
-    while (result != BROTLI_DECODER_RESULT_SUCCESS &&
-           result != BROTLI_DECODER_RESULT_ERROR) {
-      size_t available_in = compressed_data.size() - input_pos;
-      const uint8_t *next_in = compressed_data.data() + input_pos;
-      result = BrotliDecoderDecompressStream(
-          state, &available_in, &next_in, &available_out, &output_ptr, nullptr);
+  static Sha512::Digest challenge(const uint8_t *r, const PublicKey &key,
+                                  const void *message, const size_t size) {
+    Sha512 hash;
+    hash.update(r, 32);
+    hash.update(key.data(), key.size());
+    hash.update(message, size);
+    auto h = hash.digest();
+    reduce(h.data());
+    return h;
+  }
 
-      // Write the decompressed data to the output file
-      if (output_ptr != output_buffer.data()) {
-        output.write(reinterpret_cast<char *>(output_buffer.data()),
-                     kBufferSize - available_out);
-        available_out = kBufferSize;
-        output_ptr = output_buffer.data();
-      }
+  static void carry(Field &o) {
+    for (int i = 0; i < 16; ++i) {
+      o[i] += int64_t{1} << 16;
+      const int64_t c = o[i] >> 16;
+      o[i < 15 ? i + 1 : 0] += c - 1 + (i == 15 ? 37 * (c - 1) : 0);
+      o[i] -= c * (int64_t{1} << 16);
+    }
+  }
 
-      // Move the input position
-      input_pos += (compressed_data.size() - available_in - input_pos);
+  // Swap p and q if b is 1, in constant time.
+  static void select(Field &p, Field &q, const int64_t b) {
+    const int64_t mask = ~(b - 1);
+    for (int i = 0; i < 16; ++i) {
+      const int64_t t = mask & (p[i] ^ q[i]);
+      p[i] ^= t;
+      q[i] ^= t;
     }
+  }
 
-    // Final check for success
-    if (result == BROTLI_DECODER_RESULT_SUCCESS) {
-      std::cout << "Decompression successful." << std::endl;
-    } else {
-      std::cerr << "Decompression failed with error code: "
-                << BrotliDecoderGetErrorCode(state) << std::endl;
+  static void packField(uint8_t *o, const Field &n) {
+    Field t = n, m{};
+    carry(t);
+    carry(t);
+    carry(t);
+    for (int j = 0; j < 2; ++j) {
+      m[0] = t[0] - 0xffed;
+      for (int i = 1; i < 15; ++i) {
+        m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+        m[i - 1] &= 0xffff;
+      }
+      m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+      const int64_t b = (m[15] >> 16) & 1;
+      m[14] &= 0xffff;
+      select(t, m, 1 - b);
     }
+    for (int i = 0; i < 16; ++i) {
+      o[2 * i] = static_cast<uint8_t>(t[i] & 0xff);
+      o[2 * i + 1] = static_cast<uint8_t>(t[i] >> 8);
+    }
+  }
 
-    // Clean up
-    BrotliDecoderDestroyInstance(state);
-    return result == BROTLI_DECODER_RESULT_SUCCESS;
+  static bool equal(const Field &a, const Field &b) {
+    std::array<uint8_t, 32> c{}, d{};
+    packField(c.data(), a);
+    packField(d.data(), b);
+    return c == d;
   }
 
-private:
-  std::filesystem::path file_path;
-};
+  static uint8_t parity(const Field &a) {
+    std::array<uint8_t, 32> d{};
+    packField(d.data(), a);
+    return d[0] & 1;
+  }
 
-#endif
+  static Field plus(const Field &a, const Field &b) {
+    Field o{};
+    for (int i = 0; i < 16; ++i) {
+      o[i] = a[i] + b[i];
+    }
+    return o;
+  }
 
-int main(int argc, const char *argv[]) {
-  std::filesystem::path transfer_list_file, new_dat_file, output_img;
-  int block_count = 0;
-  std::error_code ec;
+  static Field minus(const Field &a, const Field &b) {
+    Field o{};
+    for (int i = 0; i < 16; ++i) {
+      o[i] = a[i] - b[i];
+    }
+    return o;
+  }
 
-  if (argc != 4 && argc != 3) {
-    usage(argv[0]);
+  static Field times(const Field &a, const Field &b) {
+    std::array<int64_t, 31> t{};
+    for (int i = 0; i < 16; ++i) {
+      for (int j = 0; j < 16; ++j) {
+        t[i + j] += a[i] * b[j];
+      }
+    }
+    for (int i = 0; i < 15; ++i) {
+      t[i] += 38 * t[i + 16];
+    }
+    Field o{};
+    std::copy_n(t.begin(), 16, o.begin());
+    carry(o);
+    carry(o);
+    return o;
   }
 
-  // Scheme 1. The user provides all files
-  if (std::filesystem::is_regular_file(argv[1], ec)) {
-    transfer_list_file = argv[1];
-    new_dat_file = argv[2];
-    if (argc == 3) {
-      output_img = DEFAULT_OUTPUT;
-    } else {
-      output_img = argv[3];
+  static Field inverse(const Field &i) {
+    Field c = i;
+    for (int a = 253; a >= 0; --a) {
+      c = times(c, c);
+      if (a != 2 && a != 4) {
+        c = times(c, i);
+      }
     }
+    return c;
   }
 
-  // Scheme 2. The user provides a directory and filename
-  else if (const std::filesystem::path dirObj = argv[1];
-           std::filesystem::is_directory(dirObj)) {
-    const std::string commonPrefix = argv[2];
-    transfer_list_file = dirObj / (commonPrefix + ".transfer.list");
-    new_dat_file = dirObj / (commonPrefix + ".new.dat");
-    if (!std::filesystem::exists(new_dat_file)) {
-      new_dat_file = dirObj / (commonPrefix + ".new.dat.br");
-    }
-    if (argc == 3) {
-      output_img = dirObj / (commonPrefix + ".img");
-    } else {
-      output_img = argv[3];
+  // i^((p-5)/8), for square roots.
+  static Field pow2523(const Field &i) {
+    Field c = i;
+    for (int a = 250; a >= 0; --a) {
+      c = times(c, c);
+      if (a != 1) {
+        c = times(c, i);
+      }
     }
+    return c;
   }
 
-  // Else, invalid arguments
-  else {
-    usage(argv[0]);
+  static void add(Point &p, const Point &q) {
+    const Field a = times(minus(p[1], p[0]), minus(q[1], q[0]));
+    const Field b = times(plus(p[0], p[1]), plus(q[0], q[1]));
+    const Field c = times(times(p[3], q[3]), kD2);
+    Field d = times(p[2], q[2]);
+    d = plus(d, d);
+    const Field e = minus(b, a), f = minus(d, c), g = plus(d, c),
+                h = plus(b, a);
+    p = {times(e, f), times(h, g), times(g, f), times(e, h)};
   }
 
-  typedef const int cint;
-#ifdef HAS_FADVISE
-  cint fd = open(new_dat_file.c_str(), O_RDONLY);
-  if (fd != -1) {
-    cint rc =
-        posix_fadvise(fd, 0, 0, POSIX_FADV_SEQUENTIAL | POSIX_FADV_WILLNEED);
-    if (rc != 0) {
-      std::cerr << "Warning: Failed to set file advise: " << strerror(errno)
-                << std::endl;
-    }
-    close(fd);
+  static void pack(uint8_t *r, const Point &p) {
+    const Field zi = inverse(p[2]);
+    packField(r, times(p[1], zi));
+    r[31] ^= static_cast<uint8_t>(parity(times(p[0], zi)) << 7);
   }
-#endif
 
-#ifdef HAVE_BROTLI
-  BrotliManager brotli_manager(new_dat_file);
-  if (!brotli_manager.isValidBrotli()) {
-    std::cerr << "Warning: The input file " << new_dat_file
-              << " is not a valid Brotli-compressed file." << std::endl;
-  } else {
-    std::cout << "Decompressing Brotli-compressed file to "
-              << new_dat_file.replace_extension() << " ... ";
-    // Remove the excepted .br suffix
-    if (!brotli_manager.decompress(new_dat_file)) {
-      return EXIT_FAILURE;
+  static void scalarMult(Point &p, Point q, const uint8_t *s) {
+    p = {kZero, kOne, kOne, kZero};
+    for (int i = 255; i >= 0; --i) {
+      const int64_t b = (s[i / 8] >> (i & 7)) & 1;
+      for (int j = 0; j < 4; ++j) {
+        select(p[j], q[j], b);
+      }
+      add(q, p);
+      add(p, p);
+      for (int j = 0; j < 4; ++j) {
+        select(p[j], q[j], b);
+      }
     }
   }
-#endif
-
-  TransferList tlist;
 
-  try {
-    tlist.parse(transfer_list_file);
-  } catch (const std::exception &e) {
-    std::cerr << "Error: " << e.what() << std::endl;
-    return EXIT_FAILURE;
+  static void scalarBase(Point &p, const uint8_t *s) {
+    scalarMult(p, {kX, kY, kOne, times(kX, kY)}, s);
   }
 
-  if (std::filesystem::exists(output_img, ec)) {
-    std::cerr << "Error: The output file " << output_img << " already exists."
-              << std::endl;
+  // The negated point of a public key, false if it isn't on the curve.
+  static bool unpackNegative(Point &r, const PublicKey &key) {
+    r[2] = kOne;
+    for (int i = 0; i < 16; ++i) {
+      r[1][i] = key[2 * i] + (int64_t{key[2 * i + 1]} << 8);
+    }
+    r[1][15] &= 0x7fff;
+    Field num = times(r[1], r[1]);
+    Field den = times(num, kD);
+    num = minus(num, r[2]);
+    den = plus(r[2], den);
+    const Field den2 = times(den, den);
+    const Field den4 = times(den2, den2);
+    const Field den6 = times(den4, den2);
+    Field t = times(times(den6, num), den);
+    t = times(times(times(pow2523(t), num), den), den);
+    r[0] = times(t, den);
+    if (!equal(times(times(r[0], r[0]), den), num)) {
+      r[0] = times(r[0], kI);
+    }
+    if (!equal(times(times(r[0], r[0]), den), num)) {
+      return false;
+    }
+    if (parity(r[0]) == (key[31] >> 7)) {
+      r[0] = minus(kZero, r[0]);
+    }
+    r[3] = times(r[0], r[1]);
+    return true;
+  }
 
-    std::cout << "Do you want to overwrite it? (y/N): ";
-    std::string answer;
-    std::cin >> answer;
-    if (answer != "y" && answer != "Y") {
-      std::cerr << "Aborting..." << std::endl;
-      return EXIT_FAILURE;
-    } else {
-      std::filesystem::remove(output_img, ec);
-      if (ec) {
-        std::cerr << "Error: Could not remove file " << output_img << ": "
-                  << ec.message() << std::endl;
-        return EXIT_FAILURE;
+  // r = x mod L, for x of up to 64 bytes.
+  static void modL(uint8_t *r, std::array<int64_t, 64> &x) {
+    for (int i = 63; i >= 32; --i) {
+      int64_t c = 0;
+      int j = i - 32;
+      for (; j < i - 12; ++j) {
+        x[j] += c - 16 * x[i] * kL[j - (i - 32)];
+        c = (x[j] + 128) >> 8;
+        x[j] -= c * 256;
       }
+      x[j] += c;
+      x[i] = 0;
+    }
+    int64_t c = 0;
+    for (int j = 0; j < 32; ++j) {
+      x[j] += c - (x[31] >> 4) * kL[j];
+      c = x[j] >> 8;
+      x[j] &= 255;
+    }
+    for (int j = 0; j < 32; ++j) {
+      x[j] -= c * kL[j];
+    }
+    for (int i = 0; i < 32; ++i) {
+      x[i + 1] += x[i] >> 8;
+      r[i] = static_cast<uint8_t>(x[i] & 255);
     }
   }
 
-  std::ofstream output(output_img, std::ios::binary);
-  if (unlikely(!output)) {
-    std::cerr << "Error: Could not open file " << output_img << std::endl;
-    return EXIT_FAILURE;
+  // A 64 byte hash mod L, into its first 32 bytes.
+  static void reduce(uint8_t *r) {
+    std::array<int64_t, 64> x{};
+    for (int i = 0; i < 64; ++i) {
+      x[i] = r[i];
+      r[i] = 0;
+    }
+    modL(r, x);
   }
+};
 
-  std::ifstream input_dat(new_dat_file, std::ios::binary);
-  if (unlikely(!input_dat)) {
-    std::cerr << "Error: Could not open file " << new_dat_file << std::endl;
-    return EXIT_FAILURE;
-  }
+struct Json;
 
-  // Calculate total number of blocks
-  FileSizeT max_file_size = tlist.max() * BLOCK_SIZE;
-  std::cout << "New file size: " << max_file_size << " bytes" << std::endl;
+// Represents the transfer.list file
+struct TransferList {
+  enum class Command { Erase, New, Zero };
+  struct ByteSegments;
+  // Commands in the order of the file, one range each.
+  using OperationsList = std::vector<std::pair<Command, ByteSegments>>;
+  using ForEachCommand = std::function<void(Command, const ByteSegments &)>;
+  // Handles a command the parser doesn't know, like a vendor addition or the
+  // diff and stash commands of incremental OTAs, given the words of its line.
+  // Returns the command its ranges stand for, or nothing to skip the line,
+  // and throws to fail on it.
+  using CommandHandler =
+      std::function<std::optional<Command>(const std::vector<std::string> &)>;
 
-  tlist.forEachCommand([&](const TransferList::Command c,
-                           const TransferList::ByteSegments &seg) {
-    switch (c) {
-    case TransferList::Command::New: {
-      seg.writeToFile(input_dat, output);
-      break;
+  struct ByteSegments {
+  private:
+    FileSizeT _begin;
+    FileSizeT _end;
+
+  public:
+    ByteSegments(FileSizeT begin, FileSizeT end) : _begin(begin), _end(end) {}
+
+    // Reader is a stream, or anything else reading like one. Copies up to
+    // buffer_size bytes at once, a multiple of the block size. What is
+    // written also goes into hash, if given.
+    template <typename Reader>
+    void writeToFile(Reader &in, std::ostream &out,
+                     const size_t buffer_size = BLOCK_SIZE,
+                     DataHash *hash = nullptr) const {
+      std::cout << "Copying " << _end - _begin << " blocks into position "
+                << _begin << "..." << std::endl;
+      out.seekp(_begin * BLOCK_SIZE, std::ios::beg);
+      // Reused by all segments, instead of allocated for each.
+      static thread_local std::vector<char> buffer;
+      if (buffer.size() < buffer_size) {
+        buffer.resize(buffer_size);
+      }
+      for (auto left = static_cast<uint64_t>(_end - _begin) * BLOCK_SIZE;
+           left > 0;) {
+        const auto n = static_cast<size_t>(std::min<uint64_t>(left, buffer_size));
+        // Past the end of the dat, reading it gives zeros too.
+        std::fill_n(buffer.begin(), n, 0);
+        in.read(buffer.data(), static_cast<std::streamsize>(n));
+        out.write(buffer.data(), static_cast<std::streamsize>(n));
+        if (hash) {
+          hash->update(buffer.data(), n);
+        }
+        left -= n;
+      }
     }
-    default:
-      std::cout << "Skipping command " << c << "..." << std::endl;
+
+    // Same, but straight from the memory mapped dat, at the given offset.
+    void writeToFile(const char *dat, const size_t dat_size,
+                     const uint64_t dat_offset, std::ostream &out,
+                     DataHash *hash = nullptr) const {
+      const auto size = static_cast<uint64_t>(_end - _begin) * BLOCK_SIZE;
+      std::cout << "Copying " << _end - _begin << " blocks into position "
+                << _begin << "..." << std::endl;
+      out.seekp(_begin * BLOCK_SIZE, std::ios::beg);
+      // The whole dat is mapped, so whatever is in it fits into a size_t.
+      const auto available = static_cast<size_t>(
+          dat_offset < dat_size ? std::min<uint64_t>(size, dat_size - dat_offset)
+                                : 0);
+      out.write(dat + (dat_offset < dat_size ? dat_offset : 0),
+                static_cast<std::streamsize>(available));
+      if (hash) {
+        hash->update(dat + (dat_offset < dat_size ? dat_offset : 0),
+                     available);
+      }
+      // Past the end of the dat, reading it gives zeros too.
+      const std::array<char, BLOCK_SIZE> zeros{};
+      for (uint64_t left = size - available; left > 0;) {
+        const auto n = static_cast<size_t>(std::min<uint64_t>(left, BLOCK_SIZE));
+        out.write(zeros.data(), static_cast<std::streamsize>(n));
+        if (hash) {
+          hash->update(zeros.data(), n);
+        }
+        left -= n;
+      }
     }
-  });
 
-  output.close();
-  input_dat.close();
+#ifdef FAST_IO
+    // Same, with pread from the dat at the given offset and pwrite into the
+    // output, skipping the stream buffers.
+    // Interrupted calls are counted into retries, if given.
+    void writeToFile(const int in_fd, const uint64_t dat_offset, const int out_fd,
+                     const size_t buffer_size = size_t{1} << 20,
+                     std::atomic<uint64_t> *retries = nullptr,
+                     DataHash *hash = nullptr) const {
+      static thread_local std::vector<char> buffer;
+      if (buffer.size() < buffer_size) {
+        buffer.resize(buffer_size);
+      }
+      const auto size = static_cast<uint64_t>(_end - _begin) * BLOCK_SIZE;
+      std::cout << "Copying " << _end - _begin << " blocks into position "
+                << _begin << "..." << std::endl;
+      const auto out_offset = checkedCast<off_t>(_begin * BLOCK_SIZE);
+      for (uint64_t done = 0; done < size;) {
+        const auto n =
+            static_cast<size_t>(std::min<uint64_t>(buffer_size, size - done));
+        size_t got = 0;
+        while (got < n) {
+          const ssize_t rc =
+              pread(in_fd, buffer.data() + got, n - got,
+                    checkedCast<off_t>(dat_offset + done + got));
+          if (rc < 0 && errno == EINTR) {
+            if (retries) {
+              ++*retries;
+            }
+            continue;
+          }
+          if (unlikely(rc < 0)) {
+            throw std::system_error(errno, std::generic_category(), "pread");
+          }
+          if (rc == 0) {
+            break;
+          }
+          got += static_cast<size_t>(rc);
+        }
+        // Past the end of the dat, reading it gives zeros too.
+        std::memset(buffer.data() + got, 0, n - got);
+        if (hash) {
+          hash->update(buffer.data(), n);
+        }
+        for (size_t put = 0; put < n;) {
+          const ssize_t rc =
+              pwrite(out_fd, buffer.data() + put, n - put,
+                     out_offset + checkedCast<off_t>(done + put));
+          if (rc < 0 && errno == EINTR) {
+            if (retries) {
+              ++*retries;
+            }
+            continue;
+          }
+          if (unlikely(rc <= 0)) {
+            throw std::system_error(errno, std::generic_category(), "pwrite");
+          }
+          put += static_cast<size_t>(rc);
+        }
+        done += n;
+      }
+    }
+#endif
 
-  std::filesystem::resize_file(output_img, max_file_size);
-  std::cout << "Done! Output image: " << output_img << std::endl;
+    [[nodiscard]] FileSizeT end() const noexcept { return _end; }
+    [[nodiscard]] FileSizeT begin() const noexcept { return _begin; }
+    [[nodiscard]] FileSizeT size() const noexcept { return _end - _begin; }
+  };
 
-  return 0;
+private:
+  // Version of the transfer.list scheme.
+  int version{};
+  // Blocks written, as told by the header. 0 if it's unreadable.
+  FileSizeT total_blocks{};
+  // Commands list
+  OperationsList commands;
+  // Handlers of unknown commands by their name, and the one for all others.
+  std::map<std::string, CommandHandler> handlers;
+  CommandHandler fallback_handler;
+  bool python_quirks = false;
+
+public:
+  // parser taking a transfer list file path.
+  void parse(const std::filesystem::path &transfer_list_file);
+  // Handle lines of the named command with the handler instead of failing on
+  // them. Commands the parser knows can't be replaced.
+  void setHandler(const std::string &name, CommandHandler handler) {
+    handlers[name] = std::move(handler);
+  }
+  // Handle all unknown commands without a handler of their own.
+  void setFallbackHandler(CommandHandler handler) {
+    fallback_handler = std::move(handler);
+  }
+  // Read lists like sdat2img.py does: any version number goes, lines
+  // starting with a digit are skipped and reversed ranges are empty ones at
+  // their end.
+  void setPythonQuirks(const bool quirks) { python_quirks = quirks; }
+  // The commands in the order of the file.
+  inline void forEachCommand(const ForEachCommand &callbacks);
+  // The ranges of one command, like all the new data, in the order of the
+  // file.
+  void forEachSegmentOf(
+      Command command,
+      const std::function<void(const ByteSegments &)> &callback) const {
+    for (const auto &[c, seg] : commands) {
+      if (c == command) {
+        callback(seg);
+      }
+    }
+  }
+  // The ranges grouped by command: erase, then new, then zero.
+  [[nodiscard]] std::map<Command, std::vector<ByteSegments>> grouped() const {
+    std::map<Command, std::vector<ByteSegments>> groups;
+    for (const auto &[c, seg] : commands) {
+      groups[c].push_back(seg);
+    }
+    return groups;
+  }
+  FileSizeT max();
+  [[nodiscard]] FileSizeT totalBlocks() const noexcept { return total_blocks; }
+  // As JSON, {"version":4,"total_blocks":N,"commands":[{"command":"new",
+  // "ranges":[[begin,end],...]},...]} with the ranges of consecutive lines
+  // of the same command together. fromJson reads that back, throwing on
+  // anything else, and counts the total again if it's missing.
+  [[nodiscard]] Json toJson() const;
+  static TransferList fromJson(const Json &json);
+  // One command of that, with its ranges.
+  static Json commandJson(Command command,
+                          const std::vector<ByteSegments> &segments);
+
+  // Convert string to Operations, throwing an error if invalid.
+  static Command toOperations(const std::string &command) {
+    if (command == "erase") {
+      return Command::Erase;
+    } else if (command == "new") {
+      return Command::New;
+    } else if (command == "zero") {
+      return Command::Zero;
+    } else {
+      throw std::invalid_argument("Invalid operation: " + command);
+    }
+  }
+  // The header's total with the blocks of one more range added. Like
+  // blockimgdiff, it counts the blocks new and zero write, erase doesn't.
+  static FileSizeT addToTotal(const FileSizeT total, const Command command,
+                              const FileSizeT blocks) {
+    return command == Command::Erase ? total : checkedAdd(total, blocks);
+  }
+};
+
+// std::ostream operator for TransferList::Command enum.
+std::ostream &operator<<(std::ostream &self,
+                         const TransferList::Command &operation) {
+  switch (operation) {
+  case TransferList::Command::Erase:
+    return self << "erase";
+  case TransferList::Command::New:
+    return self << "new";
+  case TransferList::Command::Zero:
+    return self << "zero";
+  }
+  return self;
+}
+
+// Declare a exception within file operations failure
+class IOException : public std::runtime_error {
+public:
+  explicit IOException(const std::filesystem::path &path,
+                       const std::string &message)
+      : std::runtime_error("Couldn't " + message + " file: " + path.string()),
+        file_path(path) {}
+
+  [[nodiscard]] const std::filesystem::path &path() const noexcept {
+    return file_path;
+  }
+
+private:
+  std::filesystem::path file_path;
+};
+
+// Represent a text file with lines
+struct TextFile {
+private:
+  std::ifstream file;
+  int line_num{};
+  std::filesystem::path path;
+  // Text of the line read last, to show in errors.
+  std::string last_line;
+
+public:
+  explicit TextFile(const std::filesystem::path &path)
+      : file(path), path(path) {
+    if (unlikely(!file.is_open())) {
+      throw IOException(path, "open");
+    }
+  }
+
+  template <typename T = std::string> bool takeOneLine(T *out) {
+    std::string line;
+    std::stringstream stream;
+    if (unlikely(!getline(file, line))) {
+      return false;
+    }
+    ++line_num;
+    last_line = line;
+    if constexpr (std::is_same_v<T, std::string>) {
+      *out = line;
+      return true;
+    } else {
+      // The caller tells what it expected instead.
+      stream << line;
+      return static_cast<bool>(stream >> *out);
+    }
+  }
+  void ignoreLine() {
+    std::string line;
+    if (likely(getline(file, line))) {
+      ++line_num;
+    }
+  }
+  template <int X> void ignoreLine() {
+    for (int i = 0; i < X; ++i) {
+      ignoreLine();
+    }
+  }
+
+  std::string current() const noexcept {
+    std::stringstream stream;
+    stream << "Line " << line_num << " of file: " << path;
+    return stream.str();
+  }
+
+  [[nodiscard]] const std::filesystem::path &filePath() const noexcept {
+    return path;
+  }
+  [[nodiscard]] int lineNumber() const noexcept { return line_num; }
+  [[nodiscard]] const std::string &wholeLastLine() const noexcept {
+    return last_line;
+  }
+
+  // The line read last, shortened if it's long.
+  std::string lastLine() const {
+    constexpr size_t kMaxShown = 60;
+    if (last_line.size() <= kMaxShown) {
+      return last_line;
+    }
+    return last_line.substr(0, kMaxShown) + "...";
+  }
+
+  // Disable move constructors
+  TextFile(TextFile &&) = delete;
+  TextFile &operator=(TextFile &&) = delete;
+};
+
+// Create exception with the TextFile object
+class TextFileError : public std::runtime_error {
+public:
+  explicit TextFileError(const TextFile &file, const std::string &message)
+      : TextFileError(file, message + ". Parser is at " + file.current(),
+                      0) {}
+
+  // The line read last isn't what was expected, like
+  // "Line 7 of file: "x": expected '<count>,<r1>,<r2>...', got 'new'".
+  static TextFileError expected(const TextFile &file,
+                                const std::string &what) {
+    return TextFileError(file,
+                         file.current() + ": expected " + what + ", got '" +
+                             file.lastLine() + "'",
+                         0);
+  }
+
+  // Where the parser was, for errors read by programs.
+  [[nodiscard]] const std::filesystem::path &path() const noexcept {
+    return file_path;
+  }
+  [[nodiscard]] int line() const noexcept { return line_num; }
+  [[nodiscard]] const std::string &input() const noexcept { return line_text; }
+
+private:
+  TextFileError(const TextFile &file, const std::string &message, int)
+      : std::runtime_error(message), file_path(file.filePath()),
+        line_num(file.lineNumber()), line_text(file.wholeLastLine()) {}
+
+  std::filesystem::path file_path;
+  int line_num;
+  std::string line_text;
+};
+
+// What went wrong, as the exit code, so scripts can tell failures apart.
+// Anything else exits with 1, EXIT_FAILURE.
+enum class ErrorKind {
+  Other = EXIT_FAILURE,
+  // A transfer list or another input is malformed.
+  Parse = 3,
+  // Reading or writing a file failed.
+  Io = 4,
+  // The image doesn't hold what it should, or its filesystem is broken.
+  Verification = 5,
+  // The user said no when asked.
+  Aborted = 6,
+  // Not possible on this system, with this build or with these options.
+  Unsupported = 7,
+};
+
+int exitCode(const ErrorKind kind) { return static_cast<int>(kind); }
+
+// An error which tells its kind, for those not told by the type already.
+class KindError : public std::runtime_error {
+public:
+  KindError(const ErrorKind kind, const std::string &message)
+      : std::runtime_error(message), error_kind(kind) {}
+  // About a line of a file, like a command of a transfer list.
+  KindError(const ErrorKind kind, const std::string &message,
+            std::filesystem::path file, const int line, std::string input)
+      : std::runtime_error(message), error_kind(kind),
+        file_path(std::move(file)), line_num(line),
+        line_text(std::move(input)) {}
+
+  [[nodiscard]] ErrorKind kind() const noexcept { return error_kind; }
+  // Where it happened, for errors read by programs. No line is 0.
+  [[nodiscard]] const std::filesystem::path &path() const noexcept {
+    return file_path;
+  }
+  [[nodiscard]] int line() const noexcept { return line_num; }
+  [[nodiscard]] const std::string &input() const noexcept { return line_text; }
+
+private:
+  ErrorKind error_kind;
+  std::filesystem::path file_path;
+  int line_num = 0;
+  std::string line_text;
+};
+
+// The kind of an exception: malformed text files and numbers are parse
+// errors, failed file operations I/O errors.
+ErrorKind errorKind(const std::exception &e) {
+  if (const auto *kind_error = dynamic_cast<const KindError *>(&e)) {
+    return kind_error->kind();
+  }
+  if (dynamic_cast<const TextFileError *>(&e) ||
+      dynamic_cast<const std::invalid_argument *>(&e)) {
+    return ErrorKind::Parse;
+  }
+  if (dynamic_cast<const IOException *>(&e) ||
+      dynamic_cast<const std::system_error *>(&e)) {
+    return ErrorKind::Io;
+  }
+  return ErrorKind::Other;
+}
+
+// How errors which end a command are shown, set by --error-format.
+enum class ErrorFormat { Text, Json };
+ErrorFormat error_format = ErrorFormat::Text;
+
+std::string_view errorKindName(const ErrorKind kind) {
+  switch (kind) {
+  case ErrorKind::Parse:
+    return "parse";
+  case ErrorKind::Io:
+    return "io";
+  case ErrorKind::Verification:
+    return "verification";
+  case ErrorKind::Aborted:
+    return "aborted";
+  case ErrorKind::Unsupported:
+    return "unsupported";
+  case ErrorKind::Other:
+    break;
+  }
+  return "other";
+}
+
+// A string as a JSON string literal, quotes included.
+std::string jsonString(const std::string &str) {
+  std::string out = "\"";
+  for (const char c : str) {
+    if (c == '"' || c == '\\') {
+      out += '\\';
+      out += c;
+    } else if (static_cast<unsigned char>(c) < 0x20) {
+      std::array<char, 8> escaped{};
+      std::snprintf(escaped.data(), escaped.size(), "\\u%04x", c);
+      out += escaped.data();
+    } else {
+      out += c;
+    }
+  }
+  return out + "\"";
+}
+
+// The path as it's shown in messages, in quotes.
+std::string quotedPath(const std::filesystem::path &path) {
+  std::ostringstream out;
+  out << path;
+  return out.str();
+}
+
+// Show an error which ended a command, returning its exit code. As JSON,
+// it's a single line like {"kind":"parse","exit_code":3,"message":"..",
+// "file":"system.transfer.list","line":7,"input":"move 2,0,1"}, with null
+// for what isn't known.
+// The message of the last error reported on this thread, for the HTTP
+// server to show with a failed job.
+thread_local std::string last_error;
+
+int reportError(const ErrorKind kind, const std::string &message,
+                const std::filesystem::path &file = {},
+                const std::optional<int> line = std::nullopt,
+                const std::optional<std::string> &input = std::nullopt) {
+  last_error = message;
+  if (error_format == ErrorFormat::Text) {
+    std::cerr << "Error: " << message << std::endl;
+  } else {
+    std::cerr << "{\"kind\":" << jsonString(std::string(errorKindName(kind)))
+              << ",\"exit_code\":" << exitCode(kind)
+              << ",\"message\":" << jsonString(message) << ",\"file\":"
+              << (file.empty() ? "null" : jsonString(file.string()))
+              << ",\"line\":" << (line ? std::to_string(*line) : "null")
+              << ",\"input\":" << (input ? jsonString(*input) : "null") << "}"
+              << std::endl;
+  }
+  return exitCode(kind);
+}
+
+int reportError(const std::exception &e) {
+  if (const auto *text_error = dynamic_cast<const TextFileError *>(&e)) {
+    return reportError(errorKind(e), e.what(), text_error->path(),
+                       text_error->line(), text_error->input());
+  }
+  if (const auto *kind_error = dynamic_cast<const KindError *>(&e);
+      kind_error && kind_error->line() != 0) {
+    return reportError(kind_error->kind(), e.what(), kind_error->path(),
+                       kind_error->line(), kind_error->input());
+  }
+  if (const auto *io_error = dynamic_cast<const IOException *>(&e)) {
+    return reportError(errorKind(e), e.what(), io_error->path());
+  }
+  if (const auto *fs_error =
+          dynamic_cast<const std::filesystem::filesystem_error *>(&e)) {
+    return reportError(errorKind(e), e.what(), fs_error->path1());
+  }
+  return reportError(errorKind(e), e.what());
+}
+
+// A JSON document, so that parsed structures can be saved and read back
+// without pulling in a library. Numbers keep their text, so 64-bit block
+// numbers don't go through a double, and object members keep their order.
+struct Json {
+  enum class Type { Null, Bool, Number, String, Array, Object };
+
+  Type type = Type::Null;
+  bool boolean = false;
+  // The number as written, or the string.
+  std::string text;
+  std::vector<Json> items;
+  std::vector<std::pair<std::string, Json>> members;
+
+  static Json number(const intmax_t value) {
+    Json json;
+    json.type = Type::Number;
+    json.text = std::to_string(value);
+    return json;
+  }
+  static Json string(std::string value) {
+    Json json;
+    json.type = Type::String;
+    json.text = std::move(value);
+    return json;
+  }
+  static Json array() {
+    Json json;
+    json.type = Type::Array;
+    return json;
+  }
+  static Json object() {
+    Json json;
+    json.type = Type::Object;
+    return json;
+  }
+
+  Json &push(Json item) {
+    items.push_back(std::move(item));
+    return *this;
+  }
+  Json &set(const std::string &key, Json value) {
+    members.emplace_back(key, std::move(value));
+    return *this;
+  }
+
+  // The member of an object, or nullptr if it has none of that name.
+  [[nodiscard]] const Json *find(const std::string_view key) const {
+    for (const auto &[name, value] : members) {
+      if (name == key) {
+        return &value;
+      }
+    }
+    return nullptr;
+  }
+
+  // The member of an object, throwing if it's missing or of another type.
+  [[nodiscard]] const Json &at(const std::string_view key,
+                               const Type wanted) const {
+    const auto *value = find(key);
+    if (unlikely(!value || value->type != wanted)) {
+      throw KindError(ErrorKind::Parse,
+                      "Invalid JSON: expected \"" + std::string(key) + "\"" +
+                          " to be " + std::string(typeName(wanted)));
+    }
+    return *value;
+  }
+
+  // The number as an integer, throwing if it isn't one or doesn't fit.
+  template <typename T> [[nodiscard]] T integer() const {
+    static_assert(std::is_integral_v<T>);
+    intmax_t value = 0;
+    const auto *end = text.data() + text.size();
+    const auto [ptr, ec] = std::from_chars(text.data(), end, value);
+    if (unlikely(type != Type::Number || ec != std::errc() || ptr != end)) {
+      throw KindError(ErrorKind::Parse,
+                      "Invalid JSON: expected an integer, not " + dump());
+    }
+    try {
+      return checkedCast<T>(value);
+    } catch (const std::overflow_error &) {
+      throw KindError(ErrorKind::Parse,
+                      "Invalid JSON: " + text + " is out of range");
+    }
+  }
+
+  static std::string_view typeName(const Type type) {
+    switch (type) {
+    case Type::Null:
+      return "null";
+    case Type::Bool:
+      return "a boolean";
+    case Type::Number:
+      return "a number";
+    case Type::String:
+      return "a string";
+    case Type::Array:
+      return "an array";
+    case Type::Object:
+      return "an object";
+    }
+    return "";
+  }
+
+  // Compact, or pretty with two spaces of indent. Arrays of numbers and
+  // strings stay on one line either way, like the ranges of a command.
+  [[nodiscard]] std::string dump(const bool pretty = false) const {
+    std::string out;
+    dumpTo(out, pretty, 0);
+    return out;
+  }
+
+  // As a YAML document. Arrays of numbers and strings are written in flow
+  // style, strings in double quotes, which read the same as in JSON.
+  [[nodiscard]] std::string yaml() const {
+    std::string out;
+    yamlTo(out, 0);
+    // Without the line break or space after the missing key.
+    return out.substr(1);
+  }
+
+  // Throws a parse error telling the offset of what's wrong.
+  static Json parse(const std::string_view text) {
+    size_t pos = 0;
+    auto json = parseValue(text, pos, 0);
+    skipSpace(text, pos);
+    if (unlikely(pos != text.size())) {
+      fail(pos, "the end of the document");
+    }
+    return json;
+  }
+
+private:
+  // Deeper documents are refused instead of running out of stack.
+  static constexpr int kMaxDepth = 256;
+
+  void dumpTo(std::string &out, const bool pretty, const int depth) const {
+    const auto newline = [&](const int level) {
+      if (pretty) {
+        out += '\n';
+        out.append(static_cast<size_t>(level) * 2, ' ');
+      }
+    };
+    switch (type) {
+    case Type::Null:
+      out += "null";
+      break;
+    case Type::Bool:
+      out += boolean ? "true" : "false";
+      break;
+    case Type::Number:
+      out += text;
+      break;
+    case Type::String:
+      out += jsonString(text);
+      break;
+    case Type::Array: {
+      const bool flat = std::all_of(items.begin(), items.end(), [](auto &i) {
+        return i.type != Type::Array && i.type != Type::Object;
+      });
+      out += '[';
+      for (size_t i = 0; i < items.size(); ++i) {
+        out += i == 0 ? "" : flat && pretty ? ", " : ",";
+        if (!flat) {
+          newline(depth + 1);
+        }
+        items[i].dumpTo(out, pretty, depth + 1);
+      }
+      if (!flat && !items.empty()) {
+        newline(depth);
+      }
+      out += ']';
+      break;
+    }
+    case Type::Object:
+      out += '{';
+      for (size_t i = 0; i < members.size(); ++i) {
+        out += i == 0 ? "" : ",";
+        newline(depth + 1);
+        out += jsonString(members[i].first);
+        out += pretty ? ": " : ":";
+        members[i].second.dumpTo(out, pretty, depth + 1);
+      }
+      if (!members.empty()) {
+        newline(depth);
+      }
+      out += '}';
+      break;
+    }
+  }
+
+  [[nodiscard]] bool isScalar() const {
+    return type != Type::Array && type != Type::Object;
+  }
+
+  // After the "key:" or "-" of its line, either the value and the end of
+  // the line, or the lines of a collection indented by indent spaces.
+  void yamlTo(std::string &out, const int indent) const {
+    const std::string pad(static_cast<size_t>(indent), ' ');
+    if (isScalar() || (items.empty() && members.empty()) ||
+        (type == Type::Array &&
+         std::all_of(items.begin(), items.end(),
+                     [](const Json &item) { return item.isScalar(); }))) {
+      out += " " + dump(true) + "\n";
+    } else if (type == Type::Array) {
+      out += "\n";
+      for (const auto &item : items) {
+        std::string lines;
+        item.yamlTo(lines, indent + 2);
+        // The first member of an object goes on the line of the dash.
+        if (item.type == Type::Object && !item.members.empty()) {
+          lines.replace(0, pad.size() + 3, " ");
+        }
+        out += pad + "-" + lines;
+      }
+    } else {
+      out += "\n";
+      for (const auto &[key, value] : members) {
+        const bool plain =
+            !key.empty() && !std::isdigit(static_cast<unsigned char>(key[0])) &&
+            std::all_of(key.begin(), key.end(), [](const char c) {
+              return std::isalnum(static_cast<unsigned char>(c)) || c == '_';
+            });
+        out += pad + (plain ? key : jsonString(key)) + ":";
+        value.yamlTo(out, indent + 2);
+      }
+    }
+  }
+
+  [[noreturn]] static void fail(const size_t pos, const std::string &wanted) {
+    throw KindError(ErrorKind::Parse, "Invalid JSON at offset " +
+                                          std::to_string(pos) + ": expected " +
+                                          wanted);
+  }
+
+  static void skipSpace(const std::string_view text, size_t &pos) {
+    while (pos < text.size() && (text[pos] == ' ' || text[pos] == '\t' ||
+                                 text[pos] == '\n' || text[pos] == '\r')) {
+      ++pos;
+    }
+  }
+
+  static bool consume(const std::string_view text, size_t &pos,
+                      const std::string_view word) {
+    if (text.substr(pos, word.size()) == word) {
+      pos += word.size();
+      return true;
+    }
+    return false;
+  }
+
+  static unsigned hex4(const std::string_view text, size_t &pos) {
+    unsigned value = 0;
+    for (int i = 0; i < 4; ++i, ++pos) {
+      if (unlikely(pos >= text.size() ||
+                   !std::isxdigit(static_cast<unsigned char>(text[pos])))) {
+        fail(pos, "4 hex digits");
+      }
+      const char c = static_cast<char>(std::tolower(text[pos]));
+      value = value * 16 +
+              static_cast<unsigned>(c <= '9' ? c - '0' : c - 'a' + 10);
+    }
+    return value;
+  }
+
+  static std::string parseString(const std::string_view text, size_t &pos) {
+    if (unlikely(pos >= text.size() || text[pos] != '"')) {
+      fail(pos, "a string");
+    }
+    std::string out;
+    for (++pos; pos < text.size() && text[pos] != '"'; ++pos) {
+      const char c = text[pos];
+      if (unlikely(static_cast<unsigned char>(c) < 0x20)) {
+        fail(pos, "an escaped control character");
+      }
+      if (c != '\\') {
+        out += c;
+        continue;
+      }
+      if (unlikely(++pos >= text.size())) {
+        fail(pos, "an escape sequence");
+      }
+      switch (text[pos]) {
+      case '"':
+      case '\\':
+      case '/':
+        out += text[pos];
+        break;
+      case 'b':
+        out += '\b';
+        break;
+      case 'f':
+        out += '\f';
+        break;
+      case 'n':
+        out += '\n';
+        break;
+      case 'r':
+        out += '\r';
+        break;
+      case 't':
+        out += '\t';
+        break;
+      case 'u': {
+        ++pos;
+        auto code = hex4(text, pos);
+        // Characters past the BMP come as a surrogate pair.
+        if (code >= 0xD800 && code < 0xDC00 && consume(text, pos, "\\u")) {
+          const auto low = hex4(text, pos);
+          if (unlikely(low < 0xDC00 || low >= 0xE000)) {
+            fail(pos, "a low surrogate");
+          }
+          code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+        }
+        --pos;
+        // As UTF-8.
+        if (code < 0x80) {
+          out += static_cast<char>(code);
+        } else if (code < 0x800) {
+          out += static_cast<char>(0xC0 | code >> 6);
+          out += static_cast<char>(0x80 | (code & 0x3F));
+        } else if (code < 0x10000) {
+          out += static_cast<char>(0xE0 | code >> 12);
+          out += static_cast<char>(0x80 | (code >> 6 & 0x3F));
+          out += static_cast<char>(0x80 | (code & 0x3F));
+        } else {
+          out += static_cast<char>(0xF0 | code >> 18);
+          out += static_cast<char>(0x80 | (code >> 12 & 0x3F));
+          out += static_cast<char>(0x80 | (code >> 6 & 0x3F));
+          out += static_cast<char>(0x80 | (code & 0x3F));
+        }
+        break;
+      }
+      default:
+        fail(pos, "an escape sequence");
+      }
+    }
+    if (unlikely(pos >= text.size())) {
+      fail(pos, "the end of the string");
+    }
+    ++pos;
+    return out;
+  }
+
+  static Json parseValue(const std::string_view text, size_t &pos,
+                         const int depth) {
+    if (unlikely(depth > kMaxDepth)) {
+      fail(pos, "at most " + std::to_string(kMaxDepth) + " levels of nesting");
+    }
+    skipSpace(text, pos);
+    Json json;
+    if (pos >= text.size()) {
+      fail(pos, "a value");
+    } else if (consume(text, pos, "null")) {
+    } else if (consume(text, pos, "true")) {
+      json.type = Type::Bool;
+      json.boolean = true;
+    } else if (consume(text, pos, "false")) {
+      json.type = Type::Bool;
+    } else if (text[pos] == '"') {
+      json.type = Type::String;
+      json.text = parseString(text, pos);
+    } else if (text[pos] == '[') {
+      json.type = Type::Array;
+      ++pos;
+      skipSpace(text, pos);
+      if (!consume(text, pos, "]")) {
+        do {
+          json.items.push_back(parseValue(text, pos, depth + 1));
+          skipSpace(text, pos);
+        } while (consume(text, pos, ","));
+        if (unlikely(!consume(text, pos, "]"))) {
+          fail(pos, "',' or ']'");
+        }
+      }
+    } else if (text[pos] == '{') {
+      json.type = Type::Object;
+      ++pos;
+      skipSpace(text, pos);
+      if (!consume(text, pos, "}")) {
+        do {
+          skipSpace(text, pos);
+          auto key = parseString(text, pos);
+          skipSpace(text, pos);
+          if (unlikely(!consume(text, pos, ":"))) {
+            fail(pos, "':'");
+          }
+          json.members.emplace_back(std::move(key),
+                                    parseValue(text, pos, depth + 1));
+          skipSpace(text, pos);
+        } while (consume(text, pos, ","));
+        if (unlikely(!consume(text, pos, "}"))) {
+          fail(pos, "',' or '}'");
+        }
+      }
+    } else {
+      // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+      const auto start = pos;
+      const auto digits = [&] {
+        const auto from = pos;
+        while (pos < text.size() &&
+               std::isdigit(static_cast<unsigned char>(text[pos]))) {
+          ++pos;
+        }
+        return pos - from;
+      };
+      consume(text, pos, "-");
+      const auto first = pos;
+      if (unlikely(digits() == 0 || (text[first] == '0' && pos - first > 1))) {
+        fail(start, "a value");
+      }
+      if (consume(text, pos, ".") && unlikely(digits() == 0)) {
+        fail(pos, "digits after '.'");
+      }
+      if (consume(text, pos, "e") || consume(text, pos, "E")) {
+        if (!consume(text, pos, "+")) {
+          consume(text, pos, "-");
+        }
+        if (unlikely(digits() == 0)) {
+          fail(pos, "the digits of the exponent");
+        }
+      }
+      json.type = Type::Number;
+      json.text = std::string(text.substr(start, pos - start));
+    }
+    return json;
+  }
+};
+
+Json TransferList::toJson() const {
+  auto list = Json::object();
+  list.set("version", Json::number(version));
+  list.set("total_blocks", Json::number(total_blocks));
+  auto entries = Json::array();
+  for (size_t i = 0; i < commands.size();) {
+    const auto command = commands[i].first;
+    std::vector<ByteSegments> segments;
+    for (; i < commands.size() && commands[i].first == command; ++i) {
+      segments.push_back(commands[i].second);
+    }
+    entries.push(commandJson(command, segments));
+  }
+  list.set("commands", std::move(entries));
+  return list;
+}
+
+Json TransferList::commandJson(const Command command,
+                               const std::vector<ByteSegments> &segments) {
+  std::ostringstream name;
+  name << command;
+  auto ranges = Json::array();
+  for (const auto &seg : segments) {
+    ranges.push(Json::array()
+                    .push(Json::number(seg.begin()))
+                    .push(Json::number(seg.end())));
+  }
+  return Json::object()
+      .set("command", Json::string(name.str()))
+      .set("ranges", std::move(ranges));
+}
+
+TransferList TransferList::fromJson(const Json &json) {
+  if (unlikely(json.type != Json::Type::Object)) {
+    throw KindError(ErrorKind::Parse,
+                    "Invalid JSON: expected a transfer list object");
+  }
+  TransferList list;
+  list.version = json.at("version", Json::Type::Number).integer<int>();
+  if (unlikely(list.version < 1 || list.version > 4)) {
+    throw KindError(ErrorKind::Parse,
+                    "Invalid JSON: expected version 1, 2, 3 or 4");
+  }
+  // Block numbers are turned into bytes, as when parsing a list.
+  constexpr auto max_blocks =
+      std::numeric_limits<FileSizeT>::max() / BLOCK_SIZE;
+  const auto blocks = [&](const Json &value) {
+    const auto n = value.integer<FileSizeT>();
+    if (unlikely(n < 0 || n > max_blocks)) {
+      throw KindError(ErrorKind::Parse,
+                      "Invalid JSON: expected a block count which fits "
+                      "into 64 bits, not " +
+                          value.text);
+    }
+    return n;
+  };
+  const bool has_total = json.find("total_blocks") != nullptr;
+  if (has_total) {
+    list.total_blocks = blocks(json.at("total_blocks", Json::Type::Number));
+  }
+  for (const auto &entry : json.at("commands", Json::Type::Array).items) {
+    if (unlikely(entry.type != Json::Type::Object)) {
+      throw KindError(ErrorKind::Parse,
+                      "Invalid JSON: expected commands to be objects");
+    }
+    const auto command =
+        toOperations(entry.at("command", Json::Type::String).text);
+    for (const auto &range : entry.at("ranges", Json::Type::Array).items) {
+      if (unlikely(range.type != Json::Type::Array ||
+                   range.items.size() != 2)) {
+        throw KindError(ErrorKind::Parse,
+                        "Invalid JSON: expected ranges as [<begin>, <end>]");
+      }
+      const auto begin = blocks(range.items[0]);
+      const auto end = blocks(range.items[1]);
+      if (unlikely(begin > end)) {
+        throw KindError(ErrorKind::Parse,
+                        "Invalid JSON: expected ranges with <begin> <= "
+                        "<end>, not " +
+                            range.dump());
+      }
+      list.commands.emplace_back(command, ByteSegments(begin, end));
+      if (!has_total) {
+        list.total_blocks = addToTotal(list.total_blocks, command, end - begin);
+      }
+    }
+  }
+  return list;
+}
+
+// Represents the dynamic_partitions_op_list file of a Treble OTA, which
+// describes how the super partition is laid out after the update.
+struct DynamicPartitions {
+  struct Group {
+    std::string name;
+    FileSizeT max_size;
+  };
+  struct Partition {
+    std::string name;
+    std::string group;
+    FileSizeT size;
+  };
+
+private:
+  std::vector<Group> groups;
+  std::vector<Partition> partitions;
+
+  template <typename T>
+  static auto findByName(std::vector<T> &list, const std::string &name) {
+    return std::find_if(list.begin(), list.end(),
+                        [&name](const T &item) { return item.name == name; });
+  }
+
+  static FileSizeT toSize(const TextFile &file, const std::string &str) {
+    FileSizeT size = 0;
+    std::stringstream ss(str);
+    if (unlikely(!(ss >> size) || size < 0)) {
+      throw TextFileError(file, "Invalid size: " + str);
+    }
+    return size;
+  }
+
+public:
+  // Parse and replay every operation of the op list.
+  void parse(const std::filesystem::path &op_list_file) {
+    TextFile op_list(op_list_file);
+    std::string line;
+
+    while (op_list.takeOneLine(&line)) {
+      std::vector<std::string> args;
+      std::stringstream ss(line);
+      for (std::string arg; ss >> arg;) {
+        args.emplace_back(arg);
+      }
+      if (args.empty() || args[0][0] == '#') {
+        continue;
+      }
+      const std::string &op = args[0];
+      const auto want_args = [&](const size_t n) {
+        if (unlikely(args.size() != n + 1)) {
+          throw TextFileError(op_list, "Wrong number of arguments for " + op);
+        }
+      };
+      const auto partition = [&](const std::string &name) {
+        auto it = findByName(partitions, name);
+        if (unlikely(it == partitions.end())) {
+          throw TextFileError(op_list, "Unknown partition: " + name);
+        }
+        return it;
+      };
+      const auto group = [&](const std::string &name) {
+        auto it = findByName(groups, name);
+        if (unlikely(it == groups.end())) {
+          throw TextFileError(op_list, "Unknown group: " + name);
+        }
+        return it;
+      };
+
+      if (op == "remove_all_groups") {
+        want_args(0);
+        groups.clear();
+        partitions.clear();
+      } else if (op == "add_group") {
+        want_args(2);
+        groups.push_back({args[1], toSize(op_list, args[2])});
+      } else if (op == "resize_group") {
+        want_args(2);
+        group(args[1])->max_size = toSize(op_list, args[2]);
+      } else if (op == "remove_group") {
+        want_args(1);
+        groups.erase(group(args[1]));
+        partitions.erase(std::remove_if(partitions.begin(), partitions.end(),
+                                        [&](const Partition &p) {
+                                          return p.group == args[1];
+                                        }),
+                         partitions.end());
+      } else if (op == "add") {
+        want_args(2);
+        group(args[2]);
+        partitions.push_back({args[1], args[2], 0});
+      } else if (op == "remove") {
+        want_args(1);
+        partitions.erase(partition(args[1]));
+      } else if (op == "move") {
+        want_args(2);
+        group(args[2]);
+        partition(args[1])->group = args[2];
+      } else if (op == "resize") {
+        want_args(2);
+        partition(args[1])->size = toSize(op_list, args[2]);
+      } else {
+        throw TextFileError(op_list, "Unknown operation: " + op);
+      }
+    }
+  }
+
+  // Size of the named partition after the update, 0 if it isn't listed.
+  [[nodiscard]] FileSizeT sizeOf(const std::string &name) const {
+    const auto it =
+        std::find_if(partitions.begin(), partitions.end(),
+                     [&name](const Partition &p) { return p.name == name; });
+    return it == partitions.end() ? 0 : it->size;
+  }
+
+  void print(std::ostream &out) const {
+    for (const auto &g : groups) {
+      FileSizeT used = 0;
+      out << "Group " << g.name << " (max " << g.max_size << " bytes)"
+          << std::endl;
+      for (const auto &p : partitions) {
+        if (p.group == g.name) {
+          out << "    " << p.name << ": " << p.size << " bytes" << std::endl;
+          used += p.size;
+        }
+      }
+      out << "    Used " << used << " of " << g.max_size << " bytes"
+          << std::endl;
+      if (used > g.max_size) {
+        out << "    Warning: The group is over its maximum size!" << std::endl;
+      }
+    }
+  }
+};
+
+// Helper function like in GTest.
+template <typename IntT>
+inline void expected_eq(const std::string_view expection, const IntT l_op,
+                        const IntT r_op) {
+  std::cerr << "Expected " << expection << ", but " << l_op << " != " << r_op
+            << std::endl;
+}
+#define EXPECTED_EQ(l_op, r_op) expected_eq(#l_op " == " #r_op, l_op, r_op)
+
+inline std::vector<std::string> split(const std::string &str,
+                                      const char &delimiter) {
+  std::vector<std::string> tokens;
+  std::stringstream ss(str);
+  std::string token;
+  while (getline(ss, token, delimiter)) {
+    tokens.emplace_back(token);
+  }
+  return tokens;
+}
+
+// Parse a range set like "4,0,10,20,30" into its numbers, without the count.
+// Malformed numbers throw, a wrong count gives an empty list.
+std::vector<FileSizeT> parseRanges(const std::string &src) {
+  std::vector<std::string> src_set = split(src, ',');
+  std::vector<FileSizeT> ret;
+
+  // Blocks are turned into byte offsets later, which mustn't overflow.
+  constexpr FileSizeT max_block =
+      std::numeric_limits<FileSizeT>::max() / BLOCK_SIZE;
+  std::transform(src_set.begin(), src_set.end(), std::back_inserter(ret),
+                 [](const auto &src) {
+                   FileSizeT num = 0;
+                   std::stringstream ss(src);
+                   if (unlikely(!(ss >> num) || num < 0 || num > max_block)) {
+                     throw std::invalid_argument(
+                         "Error parsing following data to rangeset: " + src);
+                   }
+                   return num;
+                 });
+  if (unlikely(ret.empty())) {
+    std::cerr << "Expected a range set, but it is empty" << std::endl;
+    return {};
+  }
+  if (unlikely(ret.size() != static_cast<size_t>(ret[0]) + 1)) {
+    EXPECTED_EQ(ret.size(), static_cast<size_t>(ret[0]) + 1);
+    return {};
+  }
+  if (unlikely((ret.size() - 1) % 2 != 0)) {
+    EXPECTED_EQ(ret.size() % 2, static_cast<size_t>(0));
+    return {};
+  }
+  // Remove first element
+  ret.erase(ret.begin());
+  return ret;
+}
+
+// Parse the ranges of a "<command> <count>,<r1>,<r2>..." line split into its
+// words, throwing an error which shows the line if it's malformed.
+std::vector<FileSizeT>
+parseCommandRanges(const TextFile &in, const std::vector<std::string> &words) {
+  const std::string format = "'<command> <count>,<r1>,<r2>...'";
+  if (unlikely(words.size() != 2)) {
+    throw TextFileError::expected(in, format);
+  }
+  std::vector<FileSizeT> nums;
+  try {
+    nums = parseRanges(words[1]);
+  } catch (const std::invalid_argument &) {
+    throw TextFileError::expected(in, "block numbers in " + format);
+  }
+  if (unlikely(nums.empty())) {
+    throw TextFileError::expected(
+        in, "<count> numbers after the count, in pairs, in " + format);
+  }
+  return nums;
+}
+
+// AOSP expects the ranges of a command to be sorted and not to overlap.
+// Describe every place where they aren't.
+std::vector<std::string>
+findRangeOrderViolations(const std::vector<FileSizeT> &nums) {
+  std::vector<std::string> violations;
+  const auto range = [&nums](const size_t i) {
+    return std::to_string(nums[i]) + "-" + std::to_string(nums[i + 1]);
+  };
+  for (size_t i = 0; i + 1 < nums.size(); i += 2) {
+    if (nums[i] >= nums[i + 1]) {
+      violations.emplace_back("range " + range(i) + " is empty or reversed");
+    } else if (i >= 2 && nums[i] < nums[i - 1]) {
+      violations.emplace_back(
+          "range " + range(i) +
+          (nums[i + 1] <= nums[i - 2] ? " comes after " : " overlaps ") +
+          range(i - 2));
+    }
+  }
+  return violations;
+}
+
+// Make the ranges of a command compliant, returning one or more range lists
+// to replace it with. New data goes into its ranges in order, so those are
+// split into several commands instead of being sorted.
+std::vector<std::vector<FileSizeT>>
+fixRangeOrder(const TransferList::Command command,
+              const std::vector<FileSizeT> &nums) {
+  std::vector<std::pair<FileSizeT, FileSizeT>> pairs;
+  for (size_t i = 0; i + 1 < nums.size(); i += 2) {
+    if (nums[i] > nums[i + 1]) {
+      throw std::invalid_argument("Reversed range " + std::to_string(nums[i]) +
+                                  "-" + std::to_string(nums[i + 1]));
+    }
+    if (nums[i] < nums[i + 1]) {
+      pairs.emplace_back(nums[i], nums[i + 1]);
+    }
+  }
+
+  std::vector<std::vector<FileSizeT>> ret;
+  if (command == TransferList::Command::New) {
+    for (const auto &[begin, end] : pairs) {
+      if (ret.empty() || begin < ret.back().back()) {
+        ret.emplace_back();
+      }
+      ret.back().push_back(begin);
+      ret.back().push_back(end);
+    }
+    return ret;
+  }
+
+  // Erasing or zeroing the same block twice does nothing more.
+  std::sort(pairs.begin(), pairs.end());
+  ret.emplace_back();
+  for (const auto &[begin, end] : pairs) {
+    auto &ranges = ret.back();
+    if (!ranges.empty() && begin <= ranges.back()) {
+      ranges.back() = std::max(ranges.back(), end);
+    } else {
+      ranges.push_back(begin);
+      ranges.push_back(end);
+    }
+  }
+  return ret;
+}
+
+// Reads a transfer list one command at a time, in the order of the file,
+// without keeping them. Lists of hundreds of thousands of ranges don't have
+// to be held at once, and the commands can be used before the rest of the
+// list is read.
+class TransferListReader {
+public:
+  // A command with its ranges, which may be out of order.
+  struct Entry {
+    TransferList::Command command;
+    std::vector<TransferList::ByteSegments> segments;
+  };
+
+  // Reads the header right away. Unknown commands go to the handler of
+  // their name, or the fallback one, as in TransferList::setHandler. With
+  // python_quirks, the list is read like sdat2img.py does, see
+  // TransferList::setPythonQuirks.
+  explicit TransferListReader(
+      const std::filesystem::path &transfer_list_file,
+      std::map<std::string, TransferList::CommandHandler> handlers = {},
+      TransferList::CommandHandler fallback_handler = {},
+      const bool python_quirks = false)
+      : transfer_list(transfer_list_file), handlers(std::move(handlers)),
+        fallback_handler(std::move(fallback_handler)),
+        python_quirks(python_quirks) {
+    std::string line;
+    // First line is the version
+    if (unlikely(!transfer_list.takeOneLine(&list_version))) {
+      throw TextFileError::expected(transfer_list, "the version number");
+    }
+    switch (list_version) {
+    case 1:
+      std::cout << "Android 5.0 detected" << std::endl;
+      break;
+    case 2:
+      std::cout << "Android 5.1 detected" << std::endl;
+      break;
+    case 3:
+      std::cout << "Android 6.x detected" << std::endl;
+      break;
+    case 4:
+      std::cout << "Android 7.x or above detected" << std::endl;
+      break;
+    default:
+      if (!python_quirks) {
+        throw TextFileError::expected(transfer_list, "version 1, 2, 3 or 4");
+      }
+      std::cout << "Unknown Android version" << std::endl;
+    }
+
+    // Second line is total number of blocks written. It isn't the size of
+    // the image, the commands tell that, so it's only good for progress.
+    if (transfer_list.takeOneLine(&line) &&
+        !(std::stringstream(line) >> total_blocks)) {
+      total_blocks = 0;
+    }
+    // It's turned into bytes, like the ranges.
+    if (unlikely(total_blocks < 0 ||
+                 total_blocks > std::numeric_limits<FileSizeT>::max() /
+                                    BLOCK_SIZE)) {
+      throw TextFileError::expected(transfer_list,
+                                    "a block count which fits into 64 bits");
+    }
+
+    // Then how many stash entries and blocks are needed at once if version
+    // >= 2, 0 if unreadable. Full OTAs don't stash anything.
+    if (list_version >= 2) {
+      for (auto *count : {&stash_entries, &stash_blocks}) {
+        if (transfer_list.takeOneLine(&line) &&
+            !(std::stringstream(line) >> *count)) {
+          *count = 0;
+        }
+      }
+    }
+  }
+
+  [[nodiscard]] int version() const noexcept { return list_version; }
+  [[nodiscard]] FileSizeT totalBlocks() const noexcept { return total_blocks; }
+  [[nodiscard]] FileSizeT stashEntries() const noexcept {
+    return stash_entries;
+  }
+  [[nodiscard]] FileSizeT stashBlocks() const noexcept { return stash_blocks; }
+  // Lines which their handlers skipped so far.
+  [[nodiscard]] size_t skipped() const noexcept { return skipped_lines; }
+
+  // The next command, or nothing at the end of the list.
+  std::optional<Entry> next() {
+    std::string line;
+    while (transfer_list.takeOneLine(&line)) {
+      const auto &split_line = split(line, ' ');
+      const std::string name = split_line.empty() ? "" : split_line[0];
+      // sdat2img.py skips lines starting with a digit, "they are not
+      // commands anyway".
+      if (python_quirks && !name.empty() &&
+          std::isdigit(static_cast<unsigned char>(name[0]))) {
+        ++skipped_lines;
+        continue;
+      }
+      TransferList::Command command{};
+      try {
+        command = TransferList::toOperations(name);
+      } catch (const std::invalid_argument &) {
+        const auto handler = handlers.find(name);
+        const auto &handle =
+            handler != handlers.end() ? handler->second : fallback_handler;
+        if (!handle) {
+          throw TextFileError::expected(
+              transfer_list, "erase, new or zero (only full OTAs convert)");
+        }
+        const auto handled = handle(split_line);
+        if (!handled) {
+          ++skipped_lines;
+          continue;
+        }
+        command = *handled;
+      }
+      const auto nums = parseCommandRanges(transfer_list, split_line);
+      for (const auto &violation : findRangeOrderViolations(nums)) {
+        std::cerr << "Warning: " << transfer_list.current() << ": "
+                  << violation << std::endl;
+      }
+
+      Entry entry{command, {}};
+      for (size_t i = 0; i < nums.size(); i += 2) {
+        // Nothing can be written into a reversed range. sdat2img.py writes
+        // nothing there, but still sizes the image to its end.
+        if (python_quirks && nums[i] > nums[i + 1]) {
+          entry.segments.emplace_back(nums[i + 1], nums[i + 1]);
+          continue;
+        }
+        if (unlikely(nums[i] > nums[i + 1])) {
+          throw TextFileError::expected(transfer_list,
+                                        "ranges with <begin> <= <end>");
+        }
+        entry.segments.emplace_back(nums[i], nums[i + 1]);
+      }
+      return entry;
+    }
+    return std::nullopt;
+  }
+
+private:
+  TextFile transfer_list;
+  std::map<std::string, TransferList::CommandHandler> handlers;
+  TransferList::CommandHandler fallback_handler;
+  bool python_quirks = false;
+  int list_version{};
+  FileSizeT total_blocks{};
+  FileSizeT stash_entries{};
+  FileSizeT stash_blocks{};
+  size_t skipped_lines = 0;
+};
+
+void TransferList::parse(const std::filesystem::path &transfer_list_file) {
+  TransferListReader reader(transfer_list_file, handlers, fallback_handler,
+                            python_quirks);
+  version = reader.version();
+  total_blocks = reader.totalBlocks();
+  while (const auto entry = reader.next()) {
+    for (const auto &segment : entry->segments) {
+      commands.emplace_back(entry->command, segment);
+    }
+  }
+  std::cout << "Parsed " << commands.size() << " commands";
+  if (reader.skipped() != 0) {
+    std::cout << ", skipped " << reader.skipped() << " lines";
+  }
+  std::cout << std::endl;
+}
+
+// Fallback handler of the lenient mode, skipping unknown commands with a
+// warning. The image lacks whatever they would have written.
+std::optional<TransferList::Command>
+skipUnknownCommand(const std::vector<std::string> &words) {
+  std::cerr << "Warning: Skipping unknown command '"
+            << (words.empty() ? "" : words[0]) << "', the image may miss "
+            << "what it writes" << std::endl;
+  return std::nullopt;
+}
+
+void TransferList::forEachCommand(const ForEachCommand &callbacks) {
+  for (const auto &it : commands) {
+    callbacks(it.first, it.second);
+  }
+}
+
+FileSizeT TransferList::max() {
+  if (commands.empty()) {
+    return 0;
+  }
+  return std::max_element(commands.begin(), commands.end(),
+                          [](const auto &a, const auto &b) {
+                            return a.second.end() < b.second.end();
+                          })
+      ->second.end();
+}
+
+// Represents META-INF/com/android/metadata of an OTA package, key=value
+// lines describing which builds and device it's for.
+struct PackageMetadata {
+private:
+  std::map<std::string, std::string> values;
+
+public:
+  void parse(const std::filesystem::path &metadata_file) {
+    TextFile metadata(metadata_file);
+    for (std::string line; metadata.takeOneLine(&line);) {
+      const auto eq = line.find('=');
+      if (eq != std::string::npos) {
+        values[line.substr(0, eq)] = line.substr(eq + 1);
+      }
+    }
+  }
+
+  // The value of a key, empty if missing.
+  [[nodiscard]] std::string get(const std::string &key) const {
+    const auto it = values.find(key);
+    return it == values.end() ? "" : it->second;
+  }
+
+  void print(std::ostream &out) const {
+    const auto line = [&](const std::string_view label,
+                          const std::string &key) {
+      if (const auto value = get(key); !value.empty()) {
+        out << "    " << label << ": " << value << std::endl;
+      }
+    };
+    out << "OTA package (" << (get("ota-type").empty() ? "unknown type"
+                                                        : get("ota-type"))
+        << ")" << std::endl;
+    line("Device", "pre-device");
+    line("Build", "post-build");
+    line("Incremental from", "pre-build");
+    line("Security patch", "post-security-patch-level");
+    line("SDK level", "post-sdk-level");
+    if (const auto timestamp = get("post-timestamp"); !timestamp.empty()) {
+      const std::time_t time = std::strtoll(timestamp.c_str(), nullptr, 10);
+      std::array<char, 32> date{};
+      std::strftime(date.data(), date.size(), "%Y-%m-%d %H:%M:%S UTC",
+                    std::gmtime(&time));
+      out << "    Timestamp: " << date.data() << std::endl;
+    }
+  }
+};
+
+// Represents care_map.pb or care_map.txt, which lists the blocks of each
+// partition update_verifier reads back after an A/B update. There are no
+// hashes in it, the device relies on dm-verity failing the reads instead.
+struct CareMap {
+  struct Partition {
+    std::string name;
+    std::vector<FileSizeT> ranges;
+    std::string id;
+    std::string fingerprint;
+  };
+
+private:
+  std::vector<Partition> partitions;
+
+  // Read a protobuf varint, returning false at the end of the data.
+  static bool readVarint(const std::string &data, size_t &pos,
+                         uint64_t *out) {
+    *out = 0;
+    for (int shift = 0; pos < data.size() && shift < 64; shift += 7) {
+      const auto byte = static_cast<uint8_t>(data[pos++]);
+      *out |= static_cast<uint64_t>(byte & 0x7f) << shift;
+      if (!(byte & 0x80)) {
+        return true;
+      }
+    }
+    return false;
+  }
+
+  // Split a message into its length-delimited fields, skipping the rest.
+  static std::vector<std::pair<uint64_t, std::string>>
+  readFields(const std::string &data) {
+    std::vector<std::pair<uint64_t, std::string>> fields;
+    size_t pos = 0;
+    uint64_t key = 0, value = 0;
+    while (pos < data.size()) {
+      if (!readVarint(data, pos, &key)) {
+        throw std::invalid_argument("Truncated care_map.pb");
+      }
+      switch (key & 7) {
+      case 0:
+        if (!readVarint(data, pos, &value)) {
+          throw std::invalid_argument("Truncated care_map.pb");
+        }
+        break;
+      case 1:
+        pos += 8;
+        break;
+      case 5:
+        pos += 4;
+        break;
+      case 2:
+        if (!readVarint(data, pos, &value) || value > data.size() - pos) {
+          throw std::invalid_argument("Truncated care_map.pb");
+        }
+        fields.emplace_back(key >> 3, data.substr(pos, value));
+        pos += value;
+        break;
+      default:
+        throw std::invalid_argument("Unsupported wire type in care_map.pb");
+      }
+    }
+    return fields;
+  }
+
+  void addPartition(std::string name, const std::string &ranges,
+                    std::string id, std::string fingerprint) {
+    // Old care maps name the block device instead of the partition.
+    name = std::filesystem::path(name).filename().string();
+    auto nums = parseRanges(ranges);
+    if (unlikely(nums.empty())) {
+      throw std::invalid_argument("Invalid ranges for partition " + name);
+    }
+    partitions.push_back({std::move(name), std::move(nums), std::move(id),
+                          std::move(fingerprint)});
+  }
+
+  void parsePb(const std::string &data) {
+    // message CareMap { repeated PartitionInfo partitions = 1; }
+    // message PartitionInfo {
+    //   string name = 1; string ranges = 2; string id = 3;
+    //   string fingerprint = 4;
+    // }
+    for (const auto &[number, message] : readFields(data)) {
+      if (number != 1) {
+        continue;
+      }
+      std::array<std::string, 4> info;
+      for (const auto &[field, value] : readFields(message)) {
+        if (field >= 1 && field <= info.size()) {
+          info[field - 1] = value;
+        }
+      }
+      addPartition(info[0], info[1], info[2], info[3]);
+    }
+  }
+
+  void parseTxt(const std::filesystem::path &care_map_file) {
+    TextFile care_map(care_map_file);
+    std::vector<std::string> lines;
+    for (std::string line; care_map.takeOneLine(&line);) {
+      if (!line.empty()) {
+        lines.emplace_back(line);
+      }
+    }
+    // Version 2 starts with its version, then name, ranges, id and
+    // fingerprint for each partition. Version 1 only has name and ranges.
+    const bool v2 = !lines.empty() && lines[0] == "2";
+    const size_t stride = v2 ? 4 : 2;
+    if (unlikely((lines.size() - v2) % stride != 0)) {
+      throw TextFileError(care_map, "Unexpected number of lines");
+    }
+    for (size_t i = v2; i < lines.size(); i += stride) {
+      addPartition(lines[i], lines[i + 1], v2 ? lines[i + 2] : "",
+                   v2 ? lines[i + 3] : "");
+    }
+  }
+
+public:
+  void parse(const std::filesystem::path &care_map_file) {
+    std::ifstream file(care_map_file, std::ios::binary);
+    if (unlikely(!file.is_open())) {
+      throw IOException(care_map_file, "open");
+    }
+    std::stringstream data;
+    data << file.rdbuf();
+    const std::string content = data.str();
+    // A protobuf starts with the tag of field 1, which is a newline in text.
+    if (!content.empty() && content[0] == '\n') {
+      parsePb(content);
+    } else {
+      parseTxt(care_map_file);
+    }
+  }
+
+  [[nodiscard]] const std::vector<Partition> &list() const noexcept {
+    return partitions;
+  }
+
+  // Read every cared-for block of the partition from the image, like
+  // update_verifier does. Returns the number of blocks that couldn't be read.
+  static FileSizeT verify(const Partition &partition,
+                          const std::filesystem::path &image_file) {
+    std::ifstream image(image_file, std::ios::binary);
+    if (unlikely(!image.is_open())) {
+      throw IOException(image_file, "open");
+    }
+    const auto image_blocks =
+        static_cast<FileSizeT>(std::filesystem::file_size(image_file)) /
+        BLOCK_SIZE;
+    FileSizeT checked = 0, bad = 0;
+    std::array<char, BLOCK_SIZE> buffer{};
+
+    for (size_t i = 0; i + 1 < partition.ranges.size(); i += 2) {
+      const FileSizeT begin = partition.ranges[i];
+      const FileSizeT end = partition.ranges[i + 1];
+      checked += end - begin;
+      if (end > image_blocks) {
+        std::cerr << "Range " << begin << "-" << end << " is past the end of "
+                  << "the image (" << image_blocks << " blocks)" << std::endl;
+        bad += end - std::max(begin, image_blocks);
+      }
+      image.seekg(begin * BLOCK_SIZE, std::ios::beg);
+      for (FileSizeT block = begin; block < std::min(end, image_blocks);
+           ++block) {
+        if (unlikely(!image.read(buffer.data(), BLOCK_SIZE))) {
+          std::cerr << "Couldn't read block " << block << std::endl;
+          image.clear();
+          image.seekg((block + 1) * BLOCK_SIZE, std::ios::beg);
+          ++bad;
+        }
+      }
+    }
+    std::cout << "Checked " << checked << " cared-for blocks of "
+              << partition.name << ", " << bad << " failed" << std::endl;
+    return bad;
+  }
+};
+
+// Hex helpers for digests and salts.
+std::string toHex(const uint8_t *data, size_t size) {
+  static constexpr std::string_view kDigits = "0123456789abcdef";
+  std::string out;
+  for (size_t i = 0; i < size; ++i) {
+    out += kDigits[data[i] >> 4];
+    out += kDigits[data[i] & 0xf];
+  }
+  return out;
+}
+
+std::vector<uint8_t> fromHex(const std::string &hex) {
+  std::vector<uint8_t> out;
+  if (hex.size() % 2 != 0 ||
+      !std::all_of(hex.begin(), hex.end(),
+                   [](const char c) { return std::isxdigit(c); })) {
+    throw std::invalid_argument("Invalid hex string: " + hex);
+  }
+  for (size_t i = 0; i < hex.size(); i += 2) {
+    out.push_back(static_cast<uint8_t>(
+        std::strtol(hex.substr(i, 2).c_str(), nullptr, 16)));
+  }
+  return out;
+}
+
+// CRC-32 as used by zlib and GPT, continuing from a previous value.
+uint32_t crc32(const void *data, const size_t size, uint32_t crc = 0) {
+  static const auto table = [] {
+    std::array<uint32_t, 256> ret{};
+    for (uint32_t i = 0; i < 256; ++i) {
+      uint32_t c = i;
+      for (int k = 0; k < 8; ++k) {
+        c = c & 1 ? 0xedb88320 ^ (c >> 1) : c >> 1;
+      }
+      ret[i] = c;
+    }
+    return ret;
+  }();
+  const auto *bytes = static_cast<const uint8_t *>(data);
+  crc = ~crc;
+  for (size_t i = 0; i < size; ++i) {
+    crc = table[(crc ^ bytes[i]) & 0xff] ^ (crc >> 8);
+  }
+  return ~crc;
+}
+
+// SHA-256 of a whole file, in hex.
+std::string sha256File(const std::filesystem::path &path) {
+  std::ifstream file(path, std::ios::binary);
+  if (unlikely(!file)) {
+    throw IOException(path, "open");
+  }
+  Sha256 hasher;
+  std::vector<char> buffer(size_t{1} << 20);
+  while (file.read(buffer.data(), buffer.size()) || file.gcount() > 0) {
+    hasher.update(buffer.data(), static_cast<size_t>(file.gcount()));
+  }
+  const auto digest = hasher.digest();
+  return toHex(digest.data(), digest.size());
+}
+
+// dm-verity hash tree, laid out like avbtool does: the top level first, each
+// level padded to a full block, and every hash salted in front.
+struct HashTree {
+  Sha256::Digest root_digest{};
+  std::vector<uint8_t> tree;
+
+  // Size of each level of the tree of an image, the one hashing the data
+  // first.
+  static std::vector<uint64_t> levelSizes(const uint64_t image_size) {
+    constexpr uint64_t kDigestSize = std::tuple_size_v<Sha256::Digest>;
+    std::vector<uint64_t> level_sizes;
+    for (uint64_t size = image_size; size > BLOCK_SIZE;) {
+      size = (size + BLOCK_SIZE - 1) / BLOCK_SIZE * kDigestSize;
+      size = (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+      level_sizes.push_back(size);
+    }
+    return level_sizes;
+  }
+
+  // Size of the whole tree, without computing it.
+  static uint64_t treeSize(const uint64_t image_size) {
+    const auto level_sizes = levelSizes(image_size);
+    return std::accumulate(level_sizes.begin(), level_sizes.end(),
+                           uint64_t{0});
+  }
+
+  static HashTree compute(std::istream &image, const uint64_t image_size,
+                          const std::vector<uint8_t> &salt,
+                          unsigned threads = 1) {
+    constexpr uint64_t kDigestSize = std::tuple_size_v<Sha256::Digest>;
+    const auto roundUp = [](const uint64_t n) {
+      return (n + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    };
+
+    const auto level_sizes = levelSizes(image_size);
+    std::vector<uint64_t> level_offsets(level_sizes.size());
+    uint64_t tree_size = 0;
+    for (size_t n = level_sizes.size(); n-- > 0;) {
+      level_offsets[n] = tree_size;
+      tree_size += level_sizes[n];
+    }
+
+    HashTree ret;
+    ret.tree.resize(tree_size);
+    std::vector<uint8_t> level_output;
+    const auto hashBlock = [&salt](const void *data) {
+      Sha256 hasher;
+      hasher.update(salt.data(), salt.size());
+      hasher.update(data, BLOCK_SIZE);
+      return hasher.digest();
+    };
+
+    // Hash the data in batches, each split between the threads.
+    constexpr uint64_t kBatchBlocks = 1024;
+    threads = std::max(threads, 1U);
+    std::vector<char> batch;
+    image.seekg(0, std::ios::beg);
+    for (uint64_t done = 0; done < image_size;) {
+      const uint64_t bytes = std::min<uint64_t>(
+          kBatchBlocks * BLOCK_SIZE * threads, image_size - done);
+      const uint64_t blocks = (bytes + BLOCK_SIZE - 1) / BLOCK_SIZE;
+      batch.assign(blocks * BLOCK_SIZE, 0);
+      image.read(batch.data(), static_cast<std::streamsize>(bytes));
+      const size_t first = level_output.size();
+      level_output.resize(first + blocks * kDigestSize);
+      const auto hashBlocks = [&](const uint64_t from, const uint64_t to) {
+        for (uint64_t i = from; i < to; ++i) {
+          const auto digest = hashBlock(&batch[i * BLOCK_SIZE]);
+          std::copy(digest.begin(), digest.end(),
+                    level_output.begin() + first + i * kDigestSize);
+        }
+      };
+      const uint64_t per_thread = (blocks + threads - 1) / threads;
+      std::vector<std::thread> workers;
+      for (uint64_t from = per_thread; from < blocks; from += per_thread) {
+        workers.emplace_back(hashBlocks, from,
+                             std::min(blocks, from + per_thread));
+      }
+      hashBlocks(0, std::min(blocks, per_thread));
+      for (auto &worker : workers) {
+        worker.join();
+      }
+      done += bytes;
+    }
+    for (size_t level = 0;; ++level) {
+      level_output.resize(roundUp(level_output.size()));
+      if (level < level_offsets.size()) {
+        std::copy(level_output.begin(), level_output.end(),
+                  ret.tree.begin() + level_offsets[level]);
+      }
+      if (level_output.size() <= BLOCK_SIZE) {
+        break;
+      }
+      std::vector<uint8_t> next;
+      for (size_t i = 0; i < level_output.size(); i += BLOCK_SIZE) {
+        const auto digest = hashBlock(&level_output[i]);
+        next.insert(next.end(), digest.begin(), digest.end());
+      }
+      level_output = std::move(next);
+    }
+    Sha256 hasher;
+    hasher.update(salt.data(), salt.size());
+    hasher.update(level_output.data(), level_output.size());
+    ret.root_digest = hasher.digest();
+    return ret;
+  }
+};
+
+// Read a big-endian integer, as used by AVB.
+template <typename T> T readBigEndian(const char *data) {
+  T value = 0;
+  for (size_t i = 0; i < sizeof(T); ++i) {
+    value = static_cast<T>((value << 8) | static_cast<uint8_t>(data[i]));
+  }
+  return value;
+}
+
+// Read a little-endian integer, as used by ext4.
+template <typename T> T readLittleEndian(const char *data) {
+  T value = 0;
+  for (size_t i = sizeof(T); i-- > 0;) {
+    value = static_cast<T>((value << 8) | static_cast<uint8_t>(data[i]));
+  }
+  return value;
+}
+
+// Represents the AVB footer avbtool appends to a partition image, and the
+// hashtree descriptor of the vbmeta it points to.
+struct AvbFooter {
+  static constexpr size_t kFooterSize = 64;
+  static constexpr size_t kVBMetaHeaderSize = 256;
+
+  uint32_t version_major{};
+  uint32_t version_minor{};
+  uint64_t original_image_size{};
+  uint64_t vbmeta_offset{};
+  uint64_t vbmeta_size{};
+  // Zero if there's no hashtree descriptor.
+  uint64_t tree_offset{};
+  uint64_t tree_size{};
+  uint64_t fec_offset{};
+  uint64_t fec_size{};
+  uint64_t data_size{};
+  std::string hash_algorithm;
+  std::string partition_name;
+  std::vector<uint8_t> salt;
+  std::vector<uint8_t> root_digest;
+
+  // Returns nothing if the image doesn't end with an AVB footer.
+  static std::optional<AvbFooter> read(const std::filesystem::path &image) {
+    std::ifstream file(image, std::ios::binary | std::ios::ate);
+    const auto image_size = static_cast<uint64_t>(file.tellg());
+    std::array<char, kFooterSize> footer{};
+    if (!file || image_size < kFooterSize ||
+        !file.seekg(image_size - kFooterSize)
+             .read(footer.data(), kFooterSize) ||
+        std::string_view(footer.data(), 4) != "AVBf") {
+      return std::nullopt;
+    }
+
+    AvbFooter ret;
+    ret.version_major = readBigEndian<uint32_t>(&footer[4]);
+    ret.version_minor = readBigEndian<uint32_t>(&footer[8]);
+    ret.original_image_size = readBigEndian<uint64_t>(&footer[12]);
+    ret.vbmeta_offset = readBigEndian<uint64_t>(&footer[20]);
+    ret.vbmeta_size = readBigEndian<uint64_t>(&footer[28]);
+    if (ret.original_image_size > image_size ||
+        ret.vbmeta_offset > image_size ||
+        ret.vbmeta_size > image_size - ret.vbmeta_offset ||
+        ret.vbmeta_size < kVBMetaHeaderSize) {
+      std::cerr << "Warning: AVB footer points outside of the image"
+                << std::endl;
+      return ret;
+    }
+
+    std::string vbmeta(ret.vbmeta_size, '\0');
+    if (!file.seekg(ret.vbmeta_offset).read(vbmeta.data(), vbmeta.size()) ||
+        vbmeta.compare(0, 4, "AVB0") != 0) {
+      std::cerr << "Warning: Couldn't read vbmeta of the AVB footer"
+                << std::endl;
+      return ret;
+    }
+    ret.readHashtreeDescriptor(vbmeta);
+    return ret;
+  }
+
+  void print(std::ostream &out, const uint64_t image_size) const {
+    out << "AVB footer v" << version_major << "." << version_minor
+        << " found: filesystem is " << original_image_size << " bytes, "
+        << image_size - original_image_size << " bytes of AVB metadata"
+        << std::endl;
+    out << "    vbmeta: " << vbmeta_size << " bytes at " << vbmeta_offset
+        << std::endl;
+    if (tree_size != 0) {
+      out << "    hashtree (" << hash_algorithm << "): " << tree_size
+          << " bytes at " << tree_offset << std::endl;
+    }
+    if (fec_size != 0) {
+      out << "    FEC: " << fec_size << " bytes at " << fec_offset
+          << std::endl;
+    }
+  }
+
+private:
+  void readHashtreeDescriptor(const std::string &vbmeta) {
+    const auto auth_size = readBigEndian<uint64_t>(&vbmeta[12]);
+    const auto desc_offset = readBigEndian<uint64_t>(&vbmeta[96]);
+    const auto desc_size = readBigEndian<uint64_t>(&vbmeta[104]);
+    // Each of them may be garbage, so compare before adding them up.
+    if (auth_size > vbmeta.size() || desc_offset > vbmeta.size()) {
+      return;
+    }
+    uint64_t pos = kVBMetaHeaderSize + auth_size + desc_offset;
+    if (pos > vbmeta.size() || desc_size > vbmeta.size() - pos) {
+      return;
+    }
+    const uint64_t end = pos + desc_size;
+
+    while (end - pos >= 16) {
+      const auto tag = readBigEndian<uint64_t>(&vbmeta[pos]);
+      const auto length = readBigEndian<uint64_t>(&vbmeta[pos + 8]);
+      if (length > end - pos - 16) {
+        return;
+      }
+      // AvbHashtreeDescriptor
+      if (tag == 1 && length >= 164) {
+        const char *desc = &vbmeta[pos];
+        data_size = readBigEndian<uint64_t>(desc + 20);
+        tree_offset = readBigEndian<uint64_t>(desc + 28);
+        tree_size = readBigEndian<uint64_t>(desc + 36);
+        fec_offset = readBigEndian<uint64_t>(desc + 56);
+        fec_size = readBigEndian<uint64_t>(desc + 64);
+        hash_algorithm.assign(desc + 72, strnlen(desc + 72, 32));
+        const auto name_len = readBigEndian<uint32_t>(desc + 104);
+        const auto salt_len = readBigEndian<uint32_t>(desc + 108);
+        const auto digest_len = readBigEndian<uint32_t>(desc + 112);
+        if (uint64_t{name_len} + salt_len + digest_len <= length - 164) {
+          const char *data = desc + 180;
+          partition_name.assign(data, name_len);
+          salt.assign(data + name_len, data + name_len + salt_len);
+          root_digest.assign(data + name_len + salt_len,
+                             data + name_len + salt_len + digest_len);
+        }
+        return;
+      }
+      pos += 16 + length;
+    }
+  }
+};
+
+// Append a big-endian integer, as used by AVB.
+template <typename T> void appendBigEndian(std::string &out, const T value) {
+  for (size_t i = sizeof(T); i-- > 0;) {
+    out += static_cast<char>(static_cast<uint8_t>(value >> (8 * i)));
+  }
+}
+
+// Same, little-endian.
+template <typename T>
+void appendLittleEndian(std::string &out, const T value) {
+  for (size_t i = 0; i < sizeof(T); ++i) {
+    out += static_cast<char>(static_cast<uint8_t>(value >> (8 * i)));
+  }
+}
+
+// Do what `avbtool add_hashtree_footer --algorithm NONE
+// --do_not_generate_fec` does: append the hashtree and an unsigned vbmeta
+// with its descriptor, then put the footer at the end of the partition. The
+// descriptor can then be included into the signed vbmeta.img.
+void addHashtreeFooter(const std::filesystem::path &image_file,
+                       const uint64_t partition_size,
+                       const std::string &partition_name,
+                       const std::vector<uint8_t> &salt,
+                       const unsigned threads = 1) {
+  if (partition_size % BLOCK_SIZE != 0) {
+    throw std::invalid_argument("Partition size must be a multiple of " +
+                                std::to_string(BLOCK_SIZE));
+  }
+  // Replace the footer if there's already one, like avbtool does. The image
+  // is only changed once it's known to fit.
+  const auto old_footer = AvbFooter::read(image_file);
+  const uint64_t data_size = old_footer
+                                 ? old_footer->original_image_size
+                                 : std::filesystem::file_size(image_file);
+  const uint64_t image_size =
+      (data_size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+  const uint64_t tree_offset = image_size;
+  const uint64_t tree_size = HashTree::treeSize(image_size);
+  const uint64_t vbmeta_offset = tree_offset + tree_size;
+
+  // The vbmeta of the tree with that root digest: an AvbVBMetaImageHeader
+  // with no authentication block, then the auxiliary block holding the
+  // AvbHashtreeDescriptor.
+  const auto makeVBMeta = [&](const Sha256::Digest &root_digest) {
+    // The descriptor, padded to 8 bytes.
+    std::string desc;
+    const uint64_t payload = 164 + partition_name.size() + salt.size() +
+                             root_digest.size();
+    appendBigEndian<uint64_t>(desc, 1);
+    appendBigEndian<uint64_t>(desc, (payload + 7) / 8 * 8);
+    appendBigEndian<uint32_t>(desc, 1);
+    appendBigEndian<uint64_t>(desc, image_size);
+    appendBigEndian<uint64_t>(desc, tree_offset);
+    appendBigEndian<uint64_t>(desc, tree_size);
+    appendBigEndian<uint32_t>(desc, BLOCK_SIZE);
+    appendBigEndian<uint32_t>(desc, BLOCK_SIZE);
+    appendBigEndian<uint32_t>(desc, 0);
+    appendBigEndian<uint64_t>(desc, 0);
+    appendBigEndian<uint64_t>(desc, 0);
+    desc += std::string("sha256").append(26, '\0');
+    appendBigEndian<uint32_t>(desc, partition_name.size());
+    appendBigEndian<uint32_t>(desc, salt.size());
+    appendBigEndian<uint32_t>(desc, root_digest.size());
+    appendBigEndian<uint32_t>(desc, 0);
+    desc.append(60, '\0');
+    desc += partition_name;
+    desc.append(salt.begin(), salt.end());
+    desc.append(root_digest.begin(), root_digest.end());
+    desc.resize((desc.size() + 7) / 8 * 8, '\0');
+
+    // The auxiliary block is padded to 64 bytes.
+    const uint64_t aux_size = (desc.size() + 63) / 64 * 64;
+    std::string vbmeta = "AVB0";
+    appendBigEndian<uint32_t>(vbmeta, 1);
+    appendBigEndian<uint32_t>(vbmeta, 0);
+    appendBigEndian<uint64_t>(vbmeta, 0);
+    appendBigEndian<uint64_t>(vbmeta, aux_size);
+    appendBigEndian<uint32_t>(vbmeta, 0);
+    // Offsets and sizes of hash, signature, public key and its metadata.
+    for (int i = 0; i < 8; ++i) {
+      appendBigEndian<uint64_t>(vbmeta, 0);
+    }
+    appendBigEndian<uint64_t>(vbmeta, 0);
+    appendBigEndian<uint64_t>(vbmeta, desc.size());
+    appendBigEndian<uint64_t>(vbmeta, 0);
+    appendBigEndian<uint32_t>(vbmeta, 0);
+    appendBigEndian<uint32_t>(vbmeta, 0);
+    vbmeta += std::string("sdat2img").append(40, '\0');
+    vbmeta.append(80, '\0');
+    vbmeta += desc;
+    vbmeta.resize(AvbFooter::kVBMetaHeaderSize + aux_size, '\0');
+    return vbmeta;
+  };
+
+  // Its size doesn't depend on the digest.
+  const uint64_t vbmeta_end =
+      vbmeta_offset +
+      (makeVBMeta({}).size() + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+  if (vbmeta_end + BLOCK_SIZE > partition_size) {
+    throw std::invalid_argument(
+        "Partition is too small, it needs at least " +
+        std::to_string(vbmeta_end + BLOCK_SIZE) + " bytes");
+  }
+
+  if (old_footer) {
+    std::cout << "Replacing the existing AVB footer" << std::endl;
+    std::filesystem::resize_file(image_file, data_size);
+  }
+  std::filesystem::resize_file(image_file, image_size);
+  std::fstream image(image_file,
+                     std::ios::binary | std::ios::in | std::ios::out);
+  if (unlikely(!image.is_open())) {
+    throw IOException(image_file, "open");
+  }
+  const auto hash_tree = HashTree::compute(image, image_size, salt, threads);
+  const auto vbmeta = makeVBMeta(hash_tree.root_digest);
+
+  std::string footer = "AVBf";
+  appendBigEndian<uint32_t>(footer, 1);
+  appendBigEndian<uint32_t>(footer, 0);
+  appendBigEndian<uint64_t>(footer, image_size);
+  appendBigEndian<uint64_t>(footer, vbmeta_offset);
+  appendBigEndian<uint64_t>(footer, vbmeta.size());
+  footer.append(28, '\0');
+
+  image.clear();
+  image.seekp(tree_offset, std::ios::beg);
+  image.write(reinterpret_cast<const char *>(hash_tree.tree.data()),
+              hash_tree.tree.size());
+  image.write(vbmeta.data(), vbmeta.size());
+  image.seekp(partition_size - footer.size(), std::ios::beg);
+  image.write(footer.data(), footer.size());
+  if (unlikely(!image.flush())) {
+    throw IOException(image_file, "write");
+  }
+  image.close();
+  std::filesystem::resize_file(image_file, partition_size);
+
+  std::cout << "Added hashtree footer: " << hash_tree.tree.size()
+            << " bytes of hashtree at " << tree_offset << ", root digest "
+            << toHex(hash_tree.root_digest.data(), hash_tree.root_digest.size())
+            << std::endl;
+}
+
+// Compute the dm-verity hash tree of an image and print the device-mapper
+// table for it. With an AVB hashtree footer, its parameters are used and
+// the stored root digest is checked, otherwise the tree is assumed to
+// follow the data like avbtool lays it out. Returns false on mismatch.
+bool printVerityTable(const std::filesystem::path &image_file,
+                      std::optional<std::vector<uint8_t>> salt,
+                      const unsigned threads = 1) {
+  const auto footer = AvbFooter::read(image_file);
+  const bool has_tree = footer && footer->tree_size != 0;
+  if (has_tree && footer->hash_algorithm != "sha256") {
+    throw std::invalid_argument("Unsupported hash algorithm: " +
+                                footer->hash_algorithm);
+  }
+  if (!salt) {
+    salt = has_tree ? footer->salt : std::vector<uint8_t>{};
+  }
+  uint64_t data_size = has_tree ? footer->data_size
+                                : std::filesystem::file_size(image_file);
+  if (data_size % BLOCK_SIZE != 0) {
+    std::cerr << "Warning: Image size isn't a multiple of " << BLOCK_SIZE
+              << ", the last block is zero padded" << std::endl;
+    data_size = (data_size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+  }
+
+  std::ifstream image(image_file, std::ios::binary);
+  if (unlikely(!image.is_open())) {
+    throw IOException(image_file, "open");
+  }
+  const auto tree = HashTree::compute(image, data_size, *salt, threads);
+  const std::string root =
+      toHex(tree.root_digest.data(), tree.root_digest.size());
+  const std::string salt_hex =
+      salt->empty() ? "-" : toHex(salt->data(), salt->size());
+  const std::string device =
+      has_tree && !footer->partition_name.empty()
+          ? "/dev/block/by-name/" + footer->partition_name
+          : image_file.string();
+  const uint64_t hash_start = has_tree ? footer->tree_offset : data_size;
+
+  std::cout << "Root hash: " << root << std::endl;
+  std::cout << "Salt: " << salt_hex << std::endl;
+  std::cout << "Hash tree: " << tree.tree.size() << " bytes" << std::endl;
+  std::cout << "dm-verity table:" << std::endl;
+  std::cout << "0 " << data_size / 512 << " verity 1 " << device << " "
+            << device << " " << BLOCK_SIZE << " " << BLOCK_SIZE << " "
+            << data_size / BLOCK_SIZE << " " << hash_start / BLOCK_SIZE
+            << " sha256 " << root << " " << salt_hex << std::endl;
+
+  if (has_tree && !footer->root_digest.empty()) {
+    const bool match =
+        std::equal(tree.root_digest.begin(), tree.root_digest.end(),
+                   footer->root_digest.begin(), footer->root_digest.end());
+    std::cout << "Root hash " << (match ? "matches" : "DOESN'T match")
+              << " the AVB hashtree descriptor" << std::endl;
+    return match;
+  }
+  return true;
+}
+
+// Write a transfer list as a JSON or YAML document for jq, pandas and the
+// like: the header fields, then the commands with their ranges, one for
+// each line of the list.
+void exportTransferList(const std::filesystem::path &list_file,
+                        const std::string_view format, std::ostream &out,
+                        const bool lenient) {
+  TransferListReader reader(
+      list_file, {},
+      lenient ? skipUnknownCommand : TransferList::CommandHandler{});
+  auto document = Json::object();
+  document.set("version", Json::number(reader.version()));
+  document.set("total_blocks", Json::number(reader.totalBlocks()));
+  if (reader.version() >= 2) {
+    document.set("stash_entries", Json::number(reader.stashEntries()));
+    document.set("stash_blocks", Json::number(reader.stashBlocks()));
+  }
+  auto commands = Json::array();
+  while (const auto entry = reader.next()) {
+    commands.push(TransferList::commandJson(entry->command, entry->segments));
+  }
+  document.set("commands", std::move(commands));
+  out << (format == "yaml" ? document.yaml() : document.dump(true) + "\n");
+  if (unlikely(!out.flush())) {
+    throw std::system_error(errno, std::generic_category(), "write");
+  }
+}
+
+// Rewrite a transfer list for another version of the format, where the
+// commands mean the same thing in both. Full OTAs only have erase, new and
+// zero which never changed, so only the header needs rewriting. Versions 3
+// and 4 share the syntax of the other commands too, the rest is refused.
+void convertTransferList(const std::filesystem::path &in_file,
+                         const std::filesystem::path &out_file,
+                         const int to_version) {
+  TextFile in(in_file);
+  int version = 0;
+  std::string total_blocks, stash_entries = "0", stash_blocks = "0";
+  if (unlikely(!in.takeOneLine(&version) || version < 1 || version > 4)) {
+    throw TextFileError::expected(in, "version 1, 2, 3 or 4");
+  }
+  if (unlikely(to_version < 1 || to_version > 4)) {
+    throw std::invalid_argument("Unsupported target version: " +
+                                std::to_string(to_version));
+  }
+  if (unlikely(!in.takeOneLine(&total_blocks) ||
+               (version >= 2 && (!in.takeOneLine(&stash_entries) ||
+                                 !in.takeOneLine(&stash_blocks))))) {
+    throw TextFileError(in, "Truncated header");
+  }
+
+  std::vector<std::string> commands;
+  bool incremental = false;
+  for (std::string line; in.takeOneLine(&line);) {
+    if (line.empty()) {
+      continue;
+    }
+    const std::string name = line.substr(0, line.find(' '));
+    if (name != "erase" && name != "new" && name != "zero") {
+      incremental = true;
+    }
+    commands.emplace_back(std::move(line));
+  }
+  if (incremental && version != to_version &&
+      (version < 3 || to_version < 3)) {
+    throw std::invalid_argument(
+        "The list has incremental commands, which can't be converted from "
+        "version " +
+        std::to_string(version) + " to " + std::to_string(to_version) +
+        " (only between 3 and 4)");
+  }
+  if (to_version == 1 && (stash_entries != "0" || stash_blocks != "0")) {
+    throw std::invalid_argument("Version 1 has no stash, but the list needs " +
+                                stash_blocks + " stashed blocks");
+  }
+
+  std::ofstream out(out_file);
+  if (unlikely(!out.is_open())) {
+    throw IOException(out_file, "open");
+  }
+  out << to_version << "\n" << total_blocks << "\n";
+  if (to_version >= 2) {
+    out << stash_entries << "\n" << stash_blocks << "\n";
+  }
+  for (const auto &command : commands) {
+    out << command << "\n";
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(out_file, "write");
+  }
+  std::cout << "Converted " << commands.size() << " commands from version "
+            << version << " to " << to_version << std::endl;
+}
+
+// Format ranges the way transfer lists store them: count, then the pairs.
+std::string formatRanges(const std::vector<FileSizeT> &ranges) {
+  std::string out = std::to_string(ranges.size());
+  for (const auto num : ranges) {
+    out += "," + std::to_string(num);
+  }
+  return out;
+}
+
+// Shrink a full OTA transfer list without changing what it does: merge
+// touching ranges, join consecutive commands of the same kind and drop the
+// empty ones. Order is kept, since the new data is consumed in list order.
+void minimizeTransferList(const std::filesystem::path &in_file,
+                          const std::filesystem::path &out_file) {
+  TextFile in(in_file);
+  int version = 0;
+  std::string stash_entries = "0", stash_blocks = "0", ignored;
+  if (unlikely(!in.takeOneLine(&version) || version < 1 || version > 4)) {
+    throw TextFileError::expected(in, "version 1, 2, 3 or 4");
+  }
+  if (unlikely(!in.takeOneLine(&ignored) ||
+               (version >= 2 && (!in.takeOneLine(&stash_entries) ||
+                                 !in.takeOneLine(&stash_blocks))))) {
+    throw TextFileError(in, "Truncated header");
+  }
+
+  std::vector<std::pair<std::string, std::vector<FileSizeT>>> commands;
+  size_t lines_before = 0, ranges_before = 0;
+  for (std::string line; in.takeOneLine(&line);) {
+    const auto split_line = split(line, ' ');
+    if (split_line.empty()) {
+      continue;
+    }
+    const std::string &name = split_line[0];
+    if (name != "erase" && name != "new" && name != "zero") {
+      throw TextFileError(in, "Only full OTA lists can be minimized, found " +
+                                  name);
+    }
+    const auto nums = parseCommandRanges(in, split_line);
+    ++lines_before;
+    ranges_before += nums.size() / 2;
+
+    if (commands.empty() || commands.back().first != name) {
+      commands.emplace_back(name, std::vector<FileSizeT>{});
+    }
+    for (size_t i = 0; i < nums.size(); i += 2) {
+      if (nums[i] == nums[i + 1]) {
+        continue;
+      }
+      // Ranges of a command are sorted, one going back starts another.
+      // They aren't reordered, as that would change which blocks of the
+      // dat go where.
+      if (!commands.back().second.empty() &&
+          nums[i] < commands.back().second.back()) {
+        commands.emplace_back(name, std::vector<FileSizeT>{});
+      }
+      auto &ranges = commands.back().second;
+      if (!ranges.empty() && ranges.back() == nums[i]) {
+        ranges.back() = nums[i + 1];
+      } else {
+        ranges.push_back(nums[i]);
+        ranges.push_back(nums[i + 1]);
+      }
+    }
+  }
+  commands.erase(std::remove_if(commands.begin(), commands.end(),
+                                [](const auto &c) { return c.second.empty(); }),
+                 commands.end());
+
+  FileSizeT total_blocks = 0;
+  size_t ranges_after = 0;
+  for (const auto &[name, ranges] : commands) {
+    ranges_after += ranges.size() / 2;
+    const auto command = TransferList::toOperations(name);
+    for (size_t i = 0; i < ranges.size(); i += 2) {
+      total_blocks = TransferList::addToTotal(total_blocks, command,
+                                              ranges[i + 1] - ranges[i]);
+    }
+  }
+
+  std::ofstream out(out_file);
+  if (unlikely(!out.is_open())) {
+    throw IOException(out_file, "open");
+  }
+  out << version << "\n" << total_blocks << "\n";
+  if (version >= 2) {
+    out << stash_entries << "\n" << stash_blocks << "\n";
+  }
+  for (const auto &[name, ranges] : commands) {
+    out << name << " " << formatRanges(ranges) << "\n";
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(out_file, "write");
+  }
+  out.close();
+
+  const auto size_before = std::filesystem::file_size(in_file);
+  const auto size_after = std::filesystem::file_size(out_file);
+  std::cout << "Commands: " << lines_before << " -> " << commands.size()
+            << std::endl;
+  std::cout << "Ranges: " << ranges_before << " -> " << ranges_after
+            << std::endl;
+  std::cout << "Size: " << size_before << " -> " << size_after << " bytes";
+  if (size_after < size_before) {
+    std::cout << " (saved " << size_before - size_after << " bytes)";
+  }
+  std::cout << std::endl;
+}
+
+// Write a transfer list from a document like export writes, for the given
+// version or the one of the document if 0. The ranges are written as they
+// are, so exporting and importing gives the same list back. Commands left
+// without ranges are dropped, since lists can't have those.
+void importTransferList(const std::filesystem::path &json_file,
+                        const std::filesystem::path &out_file,
+                        const int to_version) {
+  std::ifstream in(json_file, std::ios::binary);
+  if (unlikely(!in.is_open())) {
+    throw IOException(json_file, "open");
+  }
+  std::ostringstream text;
+  if (unlikely(in.peek() != EOF && !(text << in.rdbuf()))) {
+    throw IOException(json_file, "read");
+  }
+  Json document;
+  TransferList list;
+  try {
+    document = Json::parse(text.str());
+    // Checks the header, the commands and their ranges.
+    list = TransferList::fromJson(document);
+  } catch (const KindError &e) {
+    throw KindError(e.kind(), quotedPath(json_file) + ": " + e.what());
+  }
+  const int version =
+      to_version != 0
+          ? to_version
+          : document.at("version", Json::Type::Number).integer<int>();
+  if (unlikely(version < 1 || version > 4)) {
+    throw std::invalid_argument("Unsupported target version: " +
+                                std::to_string(version));
+  }
+  FileSizeT stash_entries = 0, stash_blocks = 0;
+  if (const auto *entries = document.find("stash_entries")) {
+    stash_entries = entries->integer<FileSizeT>();
+  }
+  if (const auto *blocks = document.find("stash_blocks")) {
+    stash_blocks = blocks->integer<FileSizeT>();
+  }
+  if (version == 1 && (stash_entries != 0 || stash_blocks != 0)) {
+    throw std::invalid_argument("Version 1 has no stash, but the list needs " +
+                                std::to_string(stash_blocks) +
+                                " stashed blocks");
+  }
+
+  std::ofstream out(out_file);
+  if (unlikely(!out.is_open())) {
+    throw IOException(out_file, "open");
+  }
+  out << version << "\n" << list.totalBlocks() << "\n";
+  if (version >= 2) {
+    out << stash_entries << "\n" << stash_blocks << "\n";
+  }
+  size_t written = 0, dropped = 0;
+  for (const auto &command : document.at("commands", Json::Type::Array).items) {
+    const auto &ranges = command.at("ranges", Json::Type::Array).items;
+    if (ranges.empty()) {
+      ++dropped;
+      continue;
+    }
+    std::vector<FileSizeT> nums;
+    for (const auto &range : ranges) {
+      nums.push_back(range.items[0].integer<FileSizeT>());
+      nums.push_back(range.items[1].integer<FileSizeT>());
+    }
+    out << command.at("command", Json::Type::String).text << " "
+        << formatRanges(nums) << "\n";
+    ++written;
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(out_file, "write");
+  }
+  std::cout << "Imported " << written << " commands into a version "
+            << version << " list";
+  if (dropped != 0) {
+    std::cout << ", dropped " << dropped << " without ranges";
+  }
+  std::cout << std::endl;
+}
+
+// Check that the ranges of every command are sorted and don't overlap, and
+// write a fixed list if asked to. Commands other than erase, new and zero
+// are copied over as they are. Returns the number of violations.
+size_t checkRangeOrder(const std::filesystem::path &in_file,
+                       const std::optional<std::filesystem::path> &out_file) {
+  TextFile in(in_file);
+  std::vector<std::string> out_lines;
+  std::string line;
+  int version = 0;
+  size_t violations = 0;
+
+  if (unlikely(!in.takeOneLine(&version) || version < 1 || version > 4)) {
+    throw TextFileError::expected(in, "version 1, 2, 3 or 4");
+  }
+  out_lines.emplace_back(std::to_string(version));
+  for (int i = version >= 2 ? 3 : 1; i > 0; --i) {
+    if (unlikely(!in.takeOneLine(&line))) {
+      throw TextFileError(in, "Truncated header");
+    }
+    out_lines.emplace_back(line);
+  }
+
+  while (in.takeOneLine(&line)) {
+    const auto split_line = split(line, ' ');
+    const std::string name = split_line.empty() ? "" : split_line[0];
+    if (name != "erase" && name != "new" && name != "zero") {
+      out_lines.emplace_back(line);
+      continue;
+    }
+    const auto nums = parseCommandRanges(in, split_line);
+    const auto found = findRangeOrderViolations(nums);
+    for (const auto &violation : found) {
+      std::cout << in.current() << ": " << name << " " << violation
+                << std::endl;
+    }
+    violations += found.size();
+    if (found.empty()) {
+      out_lines.emplace_back(line);
+      continue;
+    }
+    for (const auto &ranges :
+         fixRangeOrder(TransferList::toOperations(name), nums)) {
+      out_lines.emplace_back(name + " " + formatRanges(ranges));
+    }
+  }
+  std::cout << violations << " range order violations found" << std::endl;
+
+  if (out_file) {
+    std::ofstream out(*out_file);
+    for (const auto &out_line : out_lines) {
+      out << out_line << "\n";
+    }
+    if (unlikely(!out.flush())) {
+      throw IOException(*out_file, "write");
+    }
+    std::cout << "Wrote fixed list to " << *out_file << std::endl;
+  }
+  return violations;
+}
+
+// Shape of a generated test fixture.
+struct FixtureOptions {
+  size_t blocks = 64;
+  size_t ranges = 5;
+  uint64_t seed = 1;
+  bool brotli = false;
+};
+
+// Write a small, valid system.transfer.list and system.new.dat(.br) pair of
+// random data into a directory, along with the SHA-256 of the image they
+// convert to, for integration tests of this and other tools.
+void generateFixture(const std::filesystem::path &dir,
+                     const FixtureOptions &options) {
+  if (options.ranges == 0 || options.ranges * 2 > options.blocks) {
+    throw std::invalid_argument("Need at least two blocks per range");
+  }
+#ifndef HAVE_BROTLI_ENCODER
+  if (options.brotli) {
+    throw std::invalid_argument("Built without the brotli encoder");
+  }
+#endif
+  std::mt19937_64 random(options.seed);
+
+  // Distinct cut points make the new ranges, the gaps between get zeroed.
+  std::vector<size_t> cuts(options.blocks - 1);
+  std::iota(cuts.begin(), cuts.end(), 1);
+  std::shuffle(cuts.begin(), cuts.end(), random);
+  cuts.resize(options.ranges * 2);
+  std::sort(cuts.begin(), cuts.end());
+
+  std::vector<std::string> commands{"erase 2,0," +
+                                    std::to_string(options.blocks)};
+  std::string dat, image(options.blocks * BLOCK_SIZE, '\0');
+  // The header's total, counted like any list's.
+  FileSizeT total_blocks = 0;
+  const auto add = [&](const TransferList::Command command, const size_t begin,
+                       const size_t end) {
+    std::ostringstream line;
+    line << command << " "
+         << formatRanges({static_cast<FileSizeT>(begin),
+                          static_cast<FileSizeT>(end)});
+    commands.push_back(line.str());
+    total_blocks = TransferList::addToTotal(total_blocks, command, end - begin);
+  };
+  size_t zero_from = 0;
+  for (size_t i = 0; i < cuts.size(); i += 2) {
+    const size_t begin = cuts[i], end = cuts[i + 1];
+    if (zero_from < begin) {
+      add(TransferList::Command::Zero, zero_from, begin);
+    }
+    add(TransferList::Command::New, begin, end);
+    for (size_t n = (end - begin) * BLOCK_SIZE; n > 0; --n) {
+      dat += static_cast<char>(random());
+    }
+    std::copy(dat.end() - (end - begin) * BLOCK_SIZE, dat.end(),
+              image.begin() + begin * BLOCK_SIZE);
+    zero_from = end;
+  }
+  if (zero_from < options.blocks) {
+    add(TransferList::Command::Zero, zero_from, options.blocks);
+  }
+
+  std::filesystem::create_directories(dir);
+  const auto list_file = dir / "system.transfer.list";
+  std::ofstream list(list_file);
+  list << "4\n" << total_blocks << "\n0\n0\n";
+  for (const auto &command : commands) {
+    list << command << "\n";
+  }
+  if (unlikely(!list.flush())) {
+    throw IOException(list_file, "write");
+  }
+
+  auto dat_file = dir / "system.new.dat";
+#ifdef HAVE_BROTLI_ENCODER
+  if (options.brotli) {
+    std::string compressed(BrotliEncoderMaxCompressedSize(dat.size()), '\0');
+    size_t size = compressed.size();
+    if (!BrotliEncoderCompress(
+            BROTLI_DEFAULT_QUALITY, BROTLI_DEFAULT_WINDOW, BROTLI_MODE_GENERIC,
+            dat.size(), reinterpret_cast<const uint8_t *>(dat.data()), &size,
+            reinterpret_cast<uint8_t *>(compressed.data()))) {
+      throw std::runtime_error("Brotli compression failed");
+    }
+    compressed.resize(size);
+    dat.swap(compressed);
+    dat_file += ".br";
+  }
+#endif
+  std::ofstream(dat_file, std::ios::binary) << dat;
+
+  const auto digest = Sha256::of(image.data(), image.size());
+  const auto sum_file = dir / "system.img.sha256";
+  std::ofstream(sum_file) << toHex(digest.data(), digest.size())
+                          << "  system.img\n";
+  std::cout << "Wrote " << list_file << ", " << dat_file << " and "
+            << sum_file << std::endl;
+}
+
+// What the binary was built for, as <arch>-<os>.
+std::string buildTarget() {
+#if defined(__x86_64__) || defined(_M_X64)
+  std::string target = "x86_64";
+#elif defined(__i386__) || defined(_M_IX86)
+  std::string target = "i686";
+#elif defined(__aarch64__) || defined(_M_ARM64)
+  std::string target = "aarch64";
+#elif defined(__arm__) || defined(_M_ARM)
+  std::string target = "arm";
+#elif defined(__riscv) && __riscv_xlen == 64
+  std::string target = "riscv64";
+#else
+  std::string target = "unknown";
+#endif
+#if defined(__ANDROID__)
+  target += "-linux-android";
+#elif defined(__linux__)
+  target += "-linux";
+#elif defined(_WIN32)
+  target += "-windows";
+#elif defined(__APPLE__)
+  target += "-darwin";
+#elif defined(__FreeBSD__)
+  target += "-freebsd";
+#else
+  target += "-unknown";
+#endif
+  return target;
+}
+
+// The optional features this binary was built with.
+std::vector<std::string> buildFeatures() {
+  std::vector<std::string> features;
+#ifdef HAVE_BROTLI
+  features.emplace_back("brotli");
+#endif
+#ifdef HAVE_BROTLI_ENCODER
+  features.emplace_back("brotli-encoder");
+#endif
+#ifdef HAVE_BZIP2
+  features.emplace_back("bzip2");
+#endif
+#ifdef HAVE_ZLIB
+  features.emplace_back("zlib");
+#endif
+#ifdef HAS_SOCKETS
+  features.emplace_back("server");
+#endif
+#ifdef HAS_MMAP
+  features.emplace_back("mmap");
+#endif
+#ifdef FAST_IO
+  features.emplace_back("fast-io");
+#endif
+#ifdef HAVE_FUSE
+  features.emplace_back("fuse");
+#endif
+  return features;
+}
+
+// Which build this is, so that tools running several of them can record
+// which one wrote an image. As JSON, one object with the same fields.
+void printVersion(std::ostream &out, const bool json) {
+#if defined(__clang__)
+  const std::string compiler = "clang " __clang_version__;
+#elif defined(__GNUC__)
+  const std::string compiler = "gcc " __VERSION__;
+#elif defined(_MSC_VER)
+  const std::string compiler = "msvc " + std::to_string(_MSC_VER);
+#else
+  const std::string compiler = "unknown";
+#endif
+  const auto features = buildFeatures();
+  if (json) {
+    auto feature_list = Json::array();
+    for (const auto &feature : features) {
+      feature_list.push(Json::string(feature));
+    }
+    out << Json::object()
+               .set("version", Json::string(SDAT2IMG_VERSION))
+               .set("commit", Json::string(SDAT2IMG_GIT_COMMIT))
+               .set("build_date", Json::string(SDAT2IMG_BUILD_DATE))
+               .set("target", Json::string(buildTarget()))
+               .set("compiler", Json::string(compiler))
+               .set("features", std::move(feature_list))
+               .dump(true)
+        << std::endl;
+    return;
+  }
+  out << "sdat2img " << SDAT2IMG_VERSION << std::endl;
+  out << "commit: " << SDAT2IMG_GIT_COMMIT << std::endl;
+  out << "built: " << SDAT2IMG_BUILD_DATE << std::endl;
+  out << "target: " << buildTarget() << std::endl;
+  out << "compiler: " << compiler << std::endl;
+  out << "features:";
+  for (const auto &feature : features) {
+    out << " " << feature;
+  }
+  out << (features.empty() ? " none" : "") << std::endl;
+}
+
+[[noreturn]] void usage(const char *exe) {
+  std::cout << "Usage: " << exe
+            << " <transfer_list> <system_new_file> <system_img>" << std::endl;
+  std::cout << "    <transfer_list>: transfer list file" << std::endl;
+  std::cout << "    <system_new_file>: system new dat file ";
+#ifdef HAVE_BROTLI
+  std::cout << "(Can support brotli compressed)";
+#endif
+  std::cout << std::endl;
+  std::cout << "    <system_img>: output system image" << std::endl;
+  std::cout << "If you are lazy, then just provide directory and filename, I "
+               "will try to auto detect them."
+            << std::endl;
+  std::cout << "Or only the directory, to pick its partitions from a list."
+            << std::endl;
+  std::cout << "    --tui: show their progress on a dashboard" << std::endl;
+  std::cout << "Or the URL of an OTA zip and the partition, downloaded here:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " [--keep-download] <https://...zip> <partition> [system_img]"
+            << std::endl;
+  std::cout << "    --verify-ota, --ota-cert <pem>: check the zip's signature "
+               "first"
+            << std::endl;
+  std::cout << "Or name them, in any order:" << std::endl;
+  std::cout << "    " << exe
+            << " --transfer-list <transfer_list> --dat <system_new_file>"
+               " [--out <system_img>]"
+            << std::endl;
+  std::cout << "Options:" << std::endl;
+  std::cout << "    --strip-avb: cut the AVB hashtree and footer off the image"
+            << std::endl;
+  std::cout << "    --br-stats: report compression of each segment of a "
+               "brotli input"
+            << std::endl;
+  std::cout << "    --read-ahead <MiB>: read the dat in another thread, up to "
+               "MiB ahead"
+            << std::endl;
+  std::cout << "    --pad-to <bytes|Nblocks>: pad the image to the partition "
+               "size"
+            << std::endl;
+  std::cout << "    --resume: save checkpoints, and carry on from the last "
+               "one if interrupted"
+            << std::endl;
+  std::cout << "    --continue: without a checkpoint, only write the ranges "
+               "an existing image doesn't hold yet"
+            << std::endl;
+  std::cout << "    --checkpoint-seconds <N>, --checkpoint-mib <M>: how often "
+               "to save them (10s, 256MiB)"
+            << std::endl;
+  std::cout << "    --cache-dat: keep decompressed brotli inputs to reuse "
+               "them next time"
+            << std::endl;
+  std::cout << "    --stream-br: decode brotli inputs while writing, without "
+               "a decompressed file"
+            << std::endl;
+  std::cout << "    --buffer-size <size>: bytes copied at once, like 64K or "
+               "4M (default: 1M)"
+            << std::endl;
+  std::cout << "    --low-memory: cap buffers and don't map the dat, for "
+               "phones low on RAM"
+            << std::endl;
+  std::cout << "    --timings: print how long each phase took, and its "
+               "throughput"
+            << std::endl;
+  std::cout << "    --sidecar: record the inputs, their hashes, the options "
+               "and the image hash in <output>.json"
+            << std::endl;
+  std::cout << "    --output-format <raw|qcow2|vhd|vmdk|gpt|store|chunks>: "
+               "container to write the image in (default: raw)"
+            << std::endl;
+  std::cout << "    --chunk-size <size>: size of the chunks of the chunks "
+               "format (default: 64M)"
+            << std::endl;
+  std::cout << "    --split <size|fat32>: write the image as parts of at "
+               "most that size, like 2G"
+            << std::endl;
+  std::cout << "    --order <transfer|offset>: write the new data in list "
+               "order, or sorted by offset"
+            << std::endl;
+  std::cout << "    --store <dir>: chunk store of the store format (default: "
+               "store next to the output)"
+            << std::endl;
+  std::cout << "    --verify-after: read the new data back and compare it "
+               "with what was written"
+            << std::endl;
+  std::cout << "    --range-manifest <path>: save the hash of each range "
+               "of new data, for scrub"
+            << std::endl;
+  std::cout << "    --hash <blake3|sha256>: hash for the above and the audit "
+               "log (default: blake3)"
+            << std::endl;
+  std::cout << "    --sign-key <key_file>: sign the sidecar and manifests "
+               "with this Ed25519 key"
+            << std::endl;
+  std::cout << "    --secure-erase: zero the erased ranges, or discard them "
+               "securely on devices"
+            << std::endl;
+  std::cout << "    --block-map <path>: save where the image is on the disk, "
+               "for recovery to read"
+            << std::endl;
+  std::cout << "    --audit-log <path>: append the offset, length, source "
+               "and SHA-256 of every write"
+            << std::endl;
+  std::cout << "    --fsck: check the filesystem of the image with e2fsck "
+               "afterwards"
+            << std::endl;
+  std::cout << "    --e2fsck <path>: e2fsck to run (default: from PATH)"
+            << std::endl;
+  std::cout << "    --shrink: shrink the filesystem and image to the minimum "
+               "size with resize2fs -M"
+            << std::endl;
+  std::cout << "    --resize2fs <path>: resize2fs to run (default: from PATH)"
+            << std::endl;
+  std::cout << "    --mke2fs <path>: mke2fs for from-twrp to run (default: "
+               "from PATH)"
+            << std::endl;
+  std::cout << "    --threads <N>: use at most N threads (default: one per "
+               "CPU)"
+            << std::endl;
+  std::cout << "    --skip-unchanged: skip outputs converted from the same "
+               "inputs before"
+            << std::endl;
+  std::cout << "    --force: convert even if the output is up to date"
+            << std::endl;
+  std::cout << "    --really-force: write to devices even if they're "
+               "mounted"
+            << std::endl;
+  std::cout << "    --lenient: skip unknown transfer list commands with a "
+               "warning"
+            << std::endl;
+  std::cout << "    --python-compat: give the same image as sdat2img.py, "
+               "quirks included"
+            << std::endl;
+  std::cout << "    --source-img <image>: apply an incremental OTA to a copy "
+               "of this image or device"
+            << std::endl;
+  std::cout << "    --exec-before <command>, --exec-after <command>: run a "
+               "shell command before or after each conversion, e.g. "
+               "'sha256sum {output}'"
+            << std::endl;
+  std::cout << "    --error-format <text|json>: show the error which ends the "
+               "run as text, or as one JSON object"
+            << std::endl;
+  std::cout << "    --assume-no: never overwrite an existing output"
+            << std::endl;
+  std::cout << "    --out-template <template>: output names in directory and "
+               "watch mode, e.g. {partition}_{date}.img"
+            << std::endl;
+  std::cout << "    --prompt-timeout <N>: don't overwrite if nobody answers "
+               "within N seconds"
+            << std::endl;
+  std::cout << "Or watch a directory and convert whatever appears in it:"
+            << std::endl;
+  std::cout << "    " << exe << " watch <directory> [interval_seconds]"
+            << std::endl;
+  std::cout << "Or show the super partition layout of a Treble OTA:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " dynamic-partitions <dynamic_partitions_op_list>" << std::endl;
+  std::cout << "Or show the device and builds an OTA package is for:"
+            << std::endl;
+  std::cout << "    " << exe << " metadata <extracted_ota|metadata_file>"
+            << std::endl;
+  std::cout << "Or check an image against an A/B care map:" << std::endl;
+  std::cout << "    " << exe
+            << " care-map <care_map.pb|txt> <image> [partition]" << std::endl;
+  std::cout << "Or add an unsigned AVB hashtree footer to a modified image:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " add-hashtree-footer <image> <partition_size> <partition_name>"
+               " [salt_hex]"
+            << std::endl;
+  std::cout << "Or compute the dm-verity table of an image:" << std::endl;
+  std::cout << "    " << exe << " verity-table <image> [salt_hex]" << std::endl;
+  std::cout << "Or report duplicated blocks of the new data:" << std::endl;
+  std::cout << "    " << exe << " analyze <transfer_list> <system_new_file>"
+            << std::endl;
+  std::cout << "Or export a transfer list for other tools:" << std::endl;
+  std::cout << "    " << exe
+            << " export [--format <json|yaml>] <transfer_list>" << std::endl;
+  std::cout << "Or write a transfer list from an exported document:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " import [--to-version <1-4>] <json> <output_list>"
+            << std::endl;
+  std::cout << "Or rewrite a transfer list for another version:" << std::endl;
+  std::cout << "    " << exe
+            << " convert-list --to-version <1-4> <transfer_list> <output_list>"
+            << std::endl;
+  std::cout << "Or shrink a full OTA transfer list:" << std::endl;
+  std::cout << "    " << exe << " minimize-list <transfer_list> <output_list>"
+            << std::endl;
+  std::cout << "Or check that the ranges of a transfer list are sorted:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " check-list <transfer_list> [--fix-sort <output_list>]"
+            << std::endl;
+  std::cout << "Or check an extracted OTA or its zip before converting it:"
+            << std::endl;
+  std::cout << "    " << exe << " doctor <directory|zip> [partition]"
+            << std::endl;
+  std::cout << "Or check the signature of an OTA zip, and show its signer:"
+            << std::endl;
+  std::cout << "    " << exe << " verify-ota <zip> [certificate.pem]"
+            << std::endl;
+  std::cout << "Or rebuild an image from a chunk store manifest:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " materialize <manifest> <output_img> [--store <dir>]"
+            << std::endl;
+  std::cout << "Or join the parts of an image written with --split or as "
+               "chunks:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " join <image.join|chunk_index> <output_img> [--force]"
+            << std::endl;
+  std::cout << "Or make an image out of a TWRP backup:" << std::endl;
+  std::cout << "    " << exe
+            << " from-twrp <backup.win> <output_img> [--force] [--shrink] "
+               "[--fsck]"
+            << std::endl;
+  std::cout << "Or package an image as a TWRP backup, to restore in TWRP:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " to-twrp <image> <backup_dir> <partition> [--split <size>]"
+               " [--force]"
+            << std::endl;
+  std::cout << "Or show or merge a Virtual A/B COW file:" << std::endl;
+  std::cout << "    " << exe << " cow-info <file.cow>" << std::endl;
+  std::cout << "    " << exe
+            << " cow-merge <base_img> <file.cow> <output_img> [--force]"
+            << std::endl;
+  std::cout << "Or check an image against its range hashes, for bit rot:"
+            << std::endl;
+  std::cout << "    " << exe << " scrub <image> <range_manifest>" << std::endl;
+  std::cout << "Or list the CRC-32 of each block of an image as CSV:"
+            << std::endl;
+  std::cout << "    " << exe << " block-crc <image> [<report.csv>]"
+            << std::endl;
+  std::cout << "Or make a key to sign manifests with, and check them:"
+            << std::endl;
+  std::cout << "    " << exe << " keygen <key_file>" << std::endl;
+  std::cout << "    " << exe << " verify-manifest <manifest> <public_key>"
+            << std::endl;
+  std::cout << "Or restore an image onto a partition of this device:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " flash <transfer_list> <system_new_file> <partition>"
+               " [--slot <a|b>]"
+            << std::endl;
+  std::cout << "Or generate a small test fixture with known image hash:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " gen-fixture <directory> [--blocks N] [--ranges N] [--seed N]"
+#ifdef HAVE_BROTLI_ENCODER
+               " [--brotli]"
+#endif
+            << std::endl;
+#ifdef HAVE_FUSE
+  std::cout << "Or mount the image as a read-only file, without writing it:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " mount <transfer_list> <system_new_file> <mountpoint>"
+            << std::endl;
+#endif
+#ifdef HAS_SOCKETS
+  std::cout << "Or serve conversion jobs over HTTP:" << std::endl;
+  std::cout << "    " << exe << " serve [address:]port" << std::endl;
+  std::cout << "Or serve the image as a read-only network block device:"
+            << std::endl;
+  std::cout << "    " << exe
+            << " nbd [--listen address:port] <transfer_list> "
+               "<system_new_file>"
+            << std::endl;
+#endif
+  std::cout << "Or update this binary to the latest release:" << std::endl;
+  std::cout << "    " << exe << " self-update [--check] [--force]" << std::endl;
+  std::cout << "Or show which build this is:" << std::endl;
+  std::cout << "    " << exe << " --version [--format json]" << std::endl;
+  exit(EXIT_SUCCESS);
+}
+
+#ifdef HAVE_BROTLI
+
+class BrotliManager {
+public:
+  BrotliManager(const std::filesystem::path &input_file)
+      : file_path(input_file) {}
+
+  bool isValidBrotli() const {
+    // TODO: Check actual, for now we are doing the same as the brotli
+    // executable does Checking the br file extension.
+    return file_path.filename().extension() == ".br";
+  }
+  // Whether the first input_size bytes of the file decode, a quick check
+  // that it's brotli at all, without decompressing all of it.
+  bool decodesStart(const size_t input_size) const {
+    std::ifstream file(file_path, std::ios::binary);
+    std::vector<char> input(input_size);
+    file.read(input.data(), static_cast<std::streamsize>(input.size()));
+    size_t available_in = static_cast<size_t>(file.gcount());
+    if (available_in == 0) {
+      return false;
+    }
+    BrotliDecoderState *state =
+        BrotliDecoderCreateInstance(nullptr, nullptr, nullptr);
+    if (unlikely(!state)) {
+      return false;
+    }
+    const auto *next_in = reinterpret_cast<const uint8_t *>(input.data());
+    std::vector<uint8_t> output(size_t{1} << 16);
+    BrotliDecoderResult result = BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT;
+    while (result == BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT) {
+      size_t available_out = output.size();
+      uint8_t *next_out = output.data();
+      result = BrotliDecoderDecompressStream(state, &available_in, &next_in,
+                                             &available_out, &next_out,
+                                             nullptr);
+    }
+    BrotliDecoderDestroyInstance(state);
+    return result != BROTLI_DECODER_RESULT_ERROR;
+  }
+  bool decompress(const std::filesystem::path &output_file,
+                  DecodeCheckpoints *checkpoints = nullptr) const {
+    // Open the input file in binary mode
+    std::ifstream file(file_path, std::ios::binary | std::ios::ate);
+
+    if (unlikely(!file.is_open())) {
+      std::cerr << "Error opening input file: " << file_path << std::endl;
+      return false;
+    }
+
+    // Get the size of the file, the content is read a chunk at a time
+    const uint64_t file_size =
+        static_cast<uint64_t>(static_cast<std::streamoff>(file.tellg()));
+    file.seekg(0, std::ios::beg);
+
+    // Initialize the Brotli decoder
+    BrotliDecoderState *state =
+        BrotliDecoderCreateInstance(nullptr, nullptr, nullptr);
+    if (unlikely(!state)) {
+      std::cerr << "Error creating Brotli decoder state." << std::endl;
+      return false;
+    }
+
+    // Prepare output file
+    std::ofstream output(output_file, std::ios::binary);
+    if (unlikely(!output.is_open())) {
+      std::cerr << "Error opening output file: " << output_file << std::endl;
+      BrotliDecoderDestroyInstance(state);
+      return false;
+    }
+
+    // Decompression buffer
+    const size_t kBufferSize = 4096;
+    const size_t kInputChunkSize = 65536;
+    std::vector<uint8_t> output_buffer(kBufferSize);
+    std::vector<uint8_t> input_buffer(kInputChunkSize);
+    uint64_t buffered_pos = 0;
+    size_t buffered = 0;
+
+    uint64_t input_pos = 0;
+    uint64_t output_pos = 0;
+    size_t available_out = kBufferSize;
+    uint8_t *output_ptr = output_buffer.data();
+
+    // Decompress the data
+    BrotliDecoderResult result = BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT;
+
+    if (checkpoints) {
+      checkpoints->emplace_back(0, 0);
+    }
+    while (result != BROTLI_DECODER_RESULT_SUCCESS &&
+           result != BROTLI_DECODER_RESULT_ERROR) {
+      // Feed the input bit by bit, and only once the decoder is done with
+      // the previous bit, so that the checkpoints stay fine-grained.
+      const bool more_output = BrotliDecoderHasMoreOutput(state);
+      if (!more_output && input_pos == buffered_pos + buffered &&
+          input_pos < file_size) {
+        file.read(reinterpret_cast<char *>(input_buffer.data()),
+                  kInputChunkSize);
+        buffered_pos = input_pos;
+        buffered = static_cast<size_t>(file.gcount());
+        if (unlikely(buffered == 0)) {
+          std::cerr << "Error reading input file: " << file_path
+                    << std::endl;
+          BrotliDecoderDestroyInstance(state);
+          return false;
+        }
+      }
+      const size_t offset = static_cast<size_t>(input_pos - buffered_pos);
+      const size_t chunk = more_output ? 0 : buffered - offset;
+      size_t available_in = chunk;
+      const uint8_t *next_in = input_buffer.data() + offset;
+      result = BrotliDecoderDecompressStream(
+          state, &available_in, &next_in, &available_out, &output_ptr, nullptr);
+
+      // Write the decompressed data to the output file
+      if (output_ptr != output_buffer.data()) {
+        output.write(reinterpret_cast<char *>(output_buffer.data()),
+                     kBufferSize - available_out);
+        output_pos += kBufferSize - available_out;
+        available_out = kBufferSize;
+        output_ptr = output_buffer.data();
+      }
+
+      // Move the input position
+      input_pos += chunk - available_in;
+      if (checkpoints) {
+        checkpoints->emplace_back(output_pos, input_pos);
+      }
+      // A truncated stream would wait for more input forever.
+      if (result == BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT &&
+          input_pos == file_size &&
+          !BrotliDecoderHasMoreOutput(state)) {
+        std::cerr << "Brotli stream is truncated." << std::endl;
+        result = BROTLI_DECODER_RESULT_ERROR;
+      }
+    }
+
+    // Final check for success
+    if (result == BROTLI_DECODER_RESULT_SUCCESS) {
+      std::cout << "Decompression successful." << std::endl;
+    } else {
+      std::cerr << "Decompression failed with error code: "
+                << BrotliDecoderGetErrorCode(state) << std::endl;
+    }
+
+    // Clean up
+    BrotliDecoderDestroyInstance(state);
+    return result == BROTLI_DECODER_RESULT_SUCCESS;
+  }
+
+private:
+  std::filesystem::path file_path;
+};
+
+// Decodes a brotli file bit by bit as it's read, instead of all of it into
+// a file first.
+class BrotliStream {
+public:
+  explicit BrotliStream(const std::filesystem::path &path)
+      : file(path, std::ios::binary), path(path) {
+    if (unlikely(!file)) {
+      throw IOException(path, "open");
+    }
+    state = BrotliDecoderCreateInstance(nullptr, nullptr, nullptr);
+    if (unlikely(!state)) {
+      throw std::runtime_error("Error creating Brotli decoder state.");
+    }
+  }
+  ~BrotliStream() { BrotliDecoderDestroyInstance(state); }
+
+  // Decode up to size bytes, short only at the end of the stream.
+  size_t read(char *dest, const size_t size) {
+    size_t available_out = size;
+    auto *next_out = reinterpret_cast<uint8_t *>(dest);
+    while (available_out > 0 && result != BROTLI_DECODER_RESULT_SUCCESS) {
+      if (result == BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT &&
+          available_in == 0) {
+        file.read(reinterpret_cast<char *>(input.data()), input.size());
+        available_in = static_cast<size_t>(file.gcount());
+        next_in = input.data();
+        if (unlikely(available_in == 0)) {
+          throw std::runtime_error("Brotli stream is truncated: " +
+                                   path.string());
+        }
+      }
+      result = BrotliDecoderDecompressStream(
+          state, &available_in, &next_in, &available_out, &next_out, nullptr);
+      if (unlikely(result == BROTLI_DECODER_RESULT_ERROR)) {
+        throw std::runtime_error(
+            std::string("Decompression failed: ") +
+            BrotliDecoderErrorString(BrotliDecoderGetErrorCode(state)));
+      }
+    }
+    return size - available_out;
+  }
+
+  // Decode and drop the given number of bytes.
+  void skip(uint64_t size) {
+    std::vector<char> scratch(std::min<uint64_t>(size, 1 << 20));
+    while (size > 0) {
+      const size_t n = read(
+          scratch.data(),
+          static_cast<size_t>(std::min<uint64_t>(size, scratch.size())));
+      if (n == 0) {
+        break;
+      }
+      size -= n;
+    }
+  }
+
+  BrotliStream(const BrotliStream &) = delete;
+  BrotliStream &operator=(const BrotliStream &) = delete;
+
+private:
+  std::ifstream file;
+  std::filesystem::path path;
+  BrotliDecoderState *state = nullptr;
+  BrotliDecoderResult result = BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT;
+  std::array<uint8_t, 65536> input{};
+  size_t available_in = 0;
+  const uint8_t *next_in = nullptr;
+};
+
+#endif
+
+// Whether the name is one of the devices which Windows has in every
+// directory, like CON or NUL. These are reserved with any extension too, and
+// trailing dots and spaces are ignored, so "nul.img" and "aux .txt" can't be
+// created either.
+bool isReservedWindowsName(const std::filesystem::path &path) {
+  std::string name = path.filename().string();
+  name = name.substr(0, name.find('.'));
+  while (!name.empty() && (name.back() == ' ' || name.back() == '.')) {
+    name.pop_back();
+  }
+  std::transform(name.begin(), name.end(), name.begin(),
+                 [](const unsigned char c) { return std::toupper(c); });
+  if (name == "CON" || name == "PRN" || name == "AUX" || name == "NUL") {
+    return true;
+  }
+  return name.size() == 4 &&
+         (name.compare(0, 3, "COM") == 0 || name.compare(0, 3, "LPT") == 0) &&
+         std::isdigit(static_cast<unsigned char>(name[3]));
+}
+
+// The path with the \\?\ prefix on Windows once it gets near MAX_PATH, which
+// the file APIs refuse otherwise. Those paths are taken as they are, so this
+// makes them absolute and normalized first, and turns \\server\share into
+// \\?\UNC\server\share. Paths which already have a prefix are kept, and
+// other systems have no such limit.
+std::filesystem::path longPath(const std::filesystem::path &path) {
+#ifdef _WIN32
+  // Room for the suffixes of the files next to the output, like .inputs.tmp.
+  constexpr size_t kMaxPath = 260 - 32;
+  const std::wstring &native = path.native();
+  if (native.rfind(L"\\\\?\\", 0) == 0 || native.rfind(L"\\\\.\\", 0) == 0) {
+    return path;
+  }
+  std::error_code ec;
+  const std::wstring full =
+      std::filesystem::absolute(path, ec).lexically_normal().native();
+  if (ec || full.size() < kMaxPath) {
+    return path;
+  }
+  if (full.rfind(L"\\\\", 0) == 0) {
+    return L"\\\\?\\UNC\\" + full.substr(2);
+  }
+  return L"\\\\?\\" + full;
+#else
+  return path;
+#endif
+}
+
+// Find the new dat file for the given prefix, preferring the uncompressed one.
+std::filesystem::path findNewDat(const std::filesystem::path &dir,
+                                 const std::string &prefix) {
+  std::filesystem::path new_dat_file = dir / (prefix + ".new.dat");
+  if (!std::filesystem::exists(new_dat_file)) {
+    new_dat_file = dir / (prefix + ".new.dat.br");
+  }
+  return new_dat_file;
+}
+
+// Prefixes of the transfer lists in a directory, sorted.
+std::vector<std::string> transferListPrefixes(const std::filesystem::path &dir) {
+  constexpr std::string_view kSuffix = ".transfer.list";
+  std::vector<std::string> prefixes;
+  std::error_code ec;
+  for (const auto &entry : std::filesystem::directory_iterator(dir, ec)) {
+    const std::string name = entry.path().filename().string();
+    if (name.size() > kSuffix.size() &&
+        name.compare(name.size() - kSuffix.size(), kSuffix.size(),
+                     kSuffix) == 0) {
+      prefixes.push_back(name.substr(0, name.size() - kSuffix.size()));
+    }
+  }
+  std::sort(prefixes.begin(), prefixes.end());
+  return prefixes;
+}
+
+// The prefix closest to a mistyped one, ignoring case: one it starts with or
+// which starts with it, like system for system.new.dat, or else one a few
+// edits away.
+std::optional<std::string>
+closestPrefix(std::string wanted, const std::vector<std::string> &prefixes) {
+  const auto lower = [](std::string text) {
+    std::transform(text.begin(), text.end(), text.begin(),
+                   [](const unsigned char c) { return std::tolower(c); });
+    return text;
+  };
+  wanted = lower(wanted);
+  std::optional<std::string> best;
+  size_t best_distance = std::max<size_t>(2, wanted.size() / 3) + 1;
+  for (const auto &prefix : prefixes) {
+    const std::string candidate = lower(prefix);
+    if (wanted.rfind(candidate + '.', 0) == 0 ||
+        (!wanted.empty() && candidate.rfind(wanted, 0) == 0)) {
+      return prefix;
+    }
+    // Levenshtein distance, one row at a time.
+    std::vector<size_t> row(candidate.size() + 1);
+    std::iota(row.begin(), row.end(), 0);
+    for (size_t i = 1; i <= wanted.size(); ++i) {
+      size_t diagonal = row[0];
+      row[0] = i;
+      for (size_t j = 1; j <= candidate.size(); ++j) {
+        const size_t above = row[j];
+        row[j] = std::min({row[j] + 1, row[j - 1] + 1,
+                           diagonal + (wanted[i - 1] != candidate[j - 1])});
+        diagonal = above;
+      }
+    }
+    if (row.back() < best_distance) {
+      best_distance = row.back();
+      best = prefix;
+    }
+  }
+  return best;
+}
+
+// Ask which of the partitions in a directory to convert, showing the size of
+// their dats. Takes numbers and ranges like "1 3-5", or "a" for all of them,
+// which is also the default. Returns nothing if the user quits.
+std::vector<std::string> pickPartitions(const std::filesystem::path &dir,
+                                        const std::vector<std::string> &prefixes) {
+  std::cout << "Partitions in " << dir << ":" << std::endl;
+  for (size_t i = 0; i < prefixes.size(); ++i) {
+    std::error_code ec;
+    const auto size = std::filesystem::file_size(findNewDat(dir, prefixes[i]), ec);
+    std::cout << std::setw(4) << i + 1 << ") " << std::left << std::setw(20)
+              << prefixes[i] << std::right;
+    if (!ec) {
+      std::ostringstream mib;
+      mib << std::fixed << std::setprecision(1)
+          << static_cast<double>(size) / (1 << 20);
+      std::cout << " " << mib.str() << " MiB";
+    }
+    std::cout << std::endl;
+  }
+  for (;;) {
+    std::cout << "Convert which? Numbers or ranges like 1 3-5, a for all, q "
+                 "to quit [a]: "
+              << std::flush;
+    std::string line;
+    if (!std::getline(std::cin, line)) {
+      std::cout << std::endl;
+      return {};
+    }
+    std::replace(line.begin(), line.end(), ',', ' ');
+    std::istringstream words(line);
+    std::vector<bool> picked(prefixes.size());
+    bool valid = true, all = true;
+    for (std::string word; words >> word;) {
+      all = false;
+      if (word == "q") {
+        return {};
+      }
+      if (word == "a") {
+        picked.assign(prefixes.size(), true);
+        continue;
+      }
+      size_t first = 0, last = 0;
+      char dash = 0;
+      std::istringstream range(word);
+      range >> first;
+      last = first;
+      if (!range.eof() && range.peek() == '-') {
+        range >> dash >> last;
+      }
+      if (!range || !range.eof() || first < 1 || first > last ||
+          last > prefixes.size()) {
+        std::cout << "Not a partition number or range: " << word << std::endl;
+        valid = false;
+        break;
+      }
+      std::fill(picked.begin() + first - 1, picked.begin() + last, true);
+    }
+    if (!valid) {
+      continue;
+    }
+    std::vector<std::string> partitions;
+    for (size_t i = 0; i < prefixes.size(); ++i) {
+      if (all || picked[i]) {
+        partitions.push_back(prefixes[i]);
+      }
+    }
+    return partitions;
+  }
+}
+
+// What a file given on the command line looks like, by its name or else by
+// its first bytes.
+enum class FileKind { TransferList, NewDat, SparseImage, Image, Unknown };
+
+FileKind guessFileKind(const std::filesystem::path &path) {
+  const std::string name = path.filename().string();
+  const auto endsWith = [&name](const std::string_view suffix) {
+    return name.size() >= suffix.size() &&
+           name.compare(name.size() - suffix.size(), suffix.size(),
+                        suffix) == 0;
+  };
+  // Opening a pipe to peek into it would wait for a writer, or eat data.
+  std::error_code ec;
+  std::ifstream file;
+  if (std::filesystem::is_regular_file(path, ec)) {
+    file.open(path, std::ios::binary);
+  }
+  std::array<char, 4> magic{};
+  file.read(magic.data(), magic.size());
+  // Sparse images (from fastboot packages) start with 0xed26ff3a.
+  if (file && std::memcmp(magic.data(), "\x3a\xff\x26\xed", 4) == 0) {
+    return FileKind::SparseImage;
+  }
+  if (endsWith(".transfer.list")) {
+    return FileKind::TransferList;
+  }
+  if (endsWith(".dat") || endsWith(".dat.br")) {
+    return FileKind::NewDat;
+  }
+  if (endsWith(".img")) {
+    return FileKind::Image;
+  }
+  // Transfer lists start with the version and the number of blocks.
+  std::string version, blocks;
+  file.clear();
+  file.seekg(0);
+  if (std::getline(file, version) && std::getline(file, blocks) &&
+      version.size() == 1 && version[0] >= '1' && version[0] <= '4' &&
+      !blocks.empty() &&
+      std::all_of(blocks.begin(), blocks.end(),
+                  [](const char c) { return std::isdigit(c) || c == '\r'; })) {
+    return FileKind::TransferList;
+  }
+  return FileKind::Unknown;
+}
+
+// Catch inputs given in the wrong order before they are parsed as something
+// else. Swaps a new dat given before the transfer list, with a notice, and
+// returns false with a hint for the mistakes which can't be corrected.
+bool checkInputOrder(std::filesystem::path &transfer_list_file,
+                     std::filesystem::path &new_dat_file,
+                     const std::filesystem::path &output_img) {
+  const FileKind list_kind = guessFileKind(transfer_list_file);
+  const FileKind dat_kind = guessFileKind(new_dat_file);
+  if (list_kind == FileKind::SparseImage ||
+      dat_kind == FileKind::SparseImage) {
+    std::cerr << "Error: "
+              << (list_kind == FileKind::SparseImage ? transfer_list_file
+                                                     : new_dat_file)
+              << " is an Android sparse image, not a transfer list or new "
+                 "dat. Convert it with simg2img instead."
+              << std::endl;
+    return false;
+  }
+  if (list_kind != FileKind::TransferList &&
+      dat_kind == FileKind::TransferList) {
+    std::cout << "Note: " << new_dat_file << " is the transfer list, "
+              << "swapping the first two arguments" << std::endl;
+    std::swap(transfer_list_file, new_dat_file);
+  } else if (list_kind == FileKind::NewDat || list_kind == FileKind::Image) {
+    std::cerr << "Error: " << transfer_list_file << " looks like "
+              << (list_kind == FileKind::NewDat ? "a new dat" : "an image")
+              << ", but the transfer list comes first: <transfer_list> "
+                 "<system_new_file> [system_img]"
+              << std::endl;
+    return false;
+  }
+  // Overwriting an input would lose it.
+  std::error_code ec;
+  if (const FileKind out_kind = guessFileKind(output_img);
+      std::filesystem::is_regular_file(output_img, ec) &&
+      (out_kind == FileKind::TransferList || out_kind == FileKind::NewDat)) {
+    std::cerr << "Error: The output " << output_img << " looks like "
+              << (out_kind == FileKind::NewDat ? "a new dat" : "a transfer list")
+              << ", not overwriting it. The image comes last." << std::endl;
+    return false;
+  }
+  return true;
+}
+
+// Replace the {name} placeholders of a template with their values, throwing
+// an error for unknown ones.
+std::string
+expandTemplate(const std::string &text,
+               const std::map<std::string_view, std::string> &values) {
+  std::string out;
+  for (size_t pos = 0; pos < text.size();) {
+    const size_t open = text.find('{', pos);
+    out += text.substr(pos, open - pos);
+    if (open == std::string::npos) {
+      break;
+    }
+    const size_t close = text.find('}', open);
+    const auto it = close == std::string::npos
+                        ? values.end()
+                        : values.find(std::string_view(text).substr(
+                              open + 1, close - open - 1));
+    if (it == values.end()) {
+      throw std::invalid_argument("Unknown placeholder in template: " +
+                                  text.substr(open));
+    }
+    out += it->second;
+    pos = close + 1;
+  }
+  return out;
+}
+
+// Output path of a partition found in an input directory. The template may
+// use {partition} (or {prefix}, the same), {dir} for the name of the input
+// directory, {date} and {time}. Relative paths are inside the input
+// directory, like the default of {partition}.img.
+std::filesystem::path outputFor(const std::filesystem::path &dir,
+                                const std::string &partition,
+                                const std::string &out_template) {
+  const std::time_t now = std::time(nullptr);
+  std::array<char, 16> date{}, time{};
+  std::strftime(date.data(), date.size(), "%Y%m%d", std::localtime(&now));
+  std::strftime(time.data(), time.size(), "%H%M%S", std::localtime(&now));
+  // Without the trailing slash of "dir/" or ".", it has no name.
+  auto dir_path = std::filesystem::absolute(dir).lexically_normal();
+  if (!dir_path.has_filename()) {
+    dir_path = dir_path.parent_path();
+  }
+  // Drive roots like D:\ have only their letter, \\server\share its share.
+  std::string dir_name = dir_path.filename().string();
+  if (dir_name.empty()) {
+    dir_name = dir_path.root_name().string();
+    dir_name.erase(std::remove(dir_name.begin(), dir_name.end(), ':'),
+                   dir_name.end());
+  }
+  return dir / expandTemplate(out_template, {{"partition", partition},
+                                              {"prefix", partition},
+                                              {"dir", dir_name},
+                                              {"date", date.data()},
+                                              {"time", time.data()}});
+}
+
+// Directory for files kept between runs, empty if there is no home.
+std::filesystem::path cacheDir() {
+  if (const char *cache = std::getenv("XDG_CACHE_HOME"); cache && *cache) {
+    return std::filesystem::path(cache) / "sdat2img";
+  }
+#ifdef _WIN32
+  if (const char *local = std::getenv("LOCALAPPDATA"); local && *local) {
+    return std::filesystem::path(local) / "sdat2img";
+  }
+#endif
+  if (const char *home = std::getenv("HOME"); home && *home) {
+    return std::filesystem::path(home) / ".cache" / "sdat2img";
+  }
+  return {};
+}
+
+// Decompress the new dat file if it is brotli compressed, and point it to
+// the decompressed file. With use_cache, the decompressed data is kept in
+// the cache directory by the hash of the stream, and reused from there.
+bool decompressNewDat(
+    [[maybe_unused]] std::filesystem::path &new_dat_file,
+    [[maybe_unused]] DecodeCheckpoints *checkpoints = nullptr,
+    [[maybe_unused]] const bool use_cache = false) {
+#ifdef HAVE_BROTLI
+  BrotliManager brotli_manager(new_dat_file);
+  if (!brotli_manager.isValidBrotli()) {
+    std::cerr << "Warning: The input file " << new_dat_file
+              << " is not a valid Brotli-compressed file." << std::endl;
+    return true;
+  }
+
+  // The checkpoints only come out of decoding.
+  std::error_code ec;
+  if (use_cache && !checkpoints && !cacheDir().empty()) {
+    const auto cached =
+        cacheDir() / "dat" / (sha256File(new_dat_file) + ".dat");
+    if (std::filesystem::exists(cached, ec)) {
+      std::cout << "Using decompressed " << new_dat_file << " from "
+                << cached << std::endl;
+      new_dat_file = cached;
+      return true;
+    }
+    // Decode next to it first, so a failed run doesn't leave a bad entry.
+    const std::filesystem::path temp = cached.string() + ".tmp";
+    std::filesystem::create_directories(cached.parent_path(), ec);
+    std::cout << "Decompressing Brotli-compressed file to " << cached
+              << " ... ";
+    if (!brotli_manager.decompress(temp, checkpoints)) {
+      std::filesystem::remove(temp, ec);
+      return false;
+    }
+    std::filesystem::rename(temp, cached);
+    new_dat_file = cached;
+    return true;
+  }
+
+  std::cout << "Decompressing Brotli-compressed file to "
+            << new_dat_file.replace_extension() << " ... ";
+  // Remove the excepted .br suffix
+  if (!brotli_manager.decompress(new_dat_file, checkpoints)) {
+    return false;
+  }
+#else
+  // Copying the compressed data as it is would give a garbage image.
+  if (new_dat_file.extension() == ".br") {
+    std::cerr << "Error: " << new_dat_file << " is brotli compressed, but "
+              << "this build has no brotli support." << std::endl;
+    std::cerr << "Decompress it first with `brotli -d " << new_dat_file.string()
+              << "`, or install libbrotli-dev and rebuild with "
+              << "-DSDAT2IMG_BROTLI=ON." << std::endl;
+    return false;
+  }
+#endif
+  return true;
+}
+
+// Shannon entropy of a byte histogram, in bits per byte.
+double entropy(const std::array<uint64_t, 256> &histogram) {
+  uint64_t total = 0;
+  for (const auto count : histogram) {
+    total += count;
+  }
+  double bits = 0;
+  for (const auto count : histogram) {
+    if (count != 0) {
+      const double p = static_cast<double>(count) / total;
+      bits -= p * std::log2(p);
+    }
+  }
+  return bits;
+}
+
+// Report how well each new segment compressed, using the offsets recorded
+// while decoding the brotli stream, and flag segments which look like they
+// were compressed or encrypted before.
+void reportCompression(TransferList &tlist,
+                       const std::filesystem::path &new_dat_file,
+                       const DecodeCheckpoints &checkpoints) {
+  std::ifstream input_dat(new_dat_file, std::ios::binary);
+  if (checkpoints.empty() || !input_dat) {
+    return;
+  }
+  // Compressed offset at which the decoder had produced `offset` bytes.
+  const auto compressedAt = [&checkpoints](const uint64_t offset) {
+    const auto it = std::lower_bound(
+        checkpoints.begin(), checkpoints.end(), offset,
+        [](const auto &point, const uint64_t value) {
+          return point.first < value;
+        });
+    return it == checkpoints.end() ? checkpoints.back().second : it->second;
+  };
+
+  uint64_t offset = 0, flagged = 0, segments = 0;
+  std::array<char, BLOCK_SIZE> block{};
+  std::cout << "Compression per segment:" << std::endl;
+  tlist.forEachCommand([&](const TransferList::Command c,
+                           const TransferList::ByteSegments &seg) {
+    if (c != TransferList::Command::New) {
+      return;
+    }
+    std::array<uint64_t, 256> histogram{};
+    for (FileSizeT i = 0; i < seg.size() && input_dat; ++i) {
+      input_dat.read(block.data(), BLOCK_SIZE);
+      for (const char byte : block) {
+        ++histogram[static_cast<uint8_t>(byte)];
+      }
+    }
+    const uint64_t size = seg.size() * BLOCK_SIZE;
+    const uint64_t compressed =
+        compressedAt(offset + size) - compressedAt(offset);
+    const double ratio = static_cast<double>(compressed) / size;
+    const double bits = entropy(histogram);
+    // Random looking data which brotli couldn't shrink.
+    const bool incompressible = bits > 7.9 && ratio > 0.95;
+    flagged += incompressible;
+    ++segments;
+    std::cout << "    " << seg.begin() << "-" << seg.end() << ": " << size
+              << " -> " << compressed << " bytes (" << std::fixed
+              << std::setprecision(1) << ratio * 100 << "%), entropy "
+              << std::setprecision(2) << bits << " bits/byte"
+              << (incompressible ? ", looks compressed or encrypted" : "")
+              << std::defaultfloat << std::endl;
+    offset += size;
+  });
+  const uint64_t compressed = checkpoints.back().second;
+  std::cout << "Overall: " << offset << " -> " << compressed << " bytes ("
+            << std::fixed << std::setprecision(1)
+            << (offset ? compressed * 100.0 / offset : 0) << "%)"
+            << std::defaultfloat << ", " << flagged << " of " << segments
+            << " segments look compressed or encrypted already" << std::endl;
+}
+
+// Hash every block written by the new commands and report how many of them
+// are repeated, to see whether deduplication would be worth it.
+int analyzeDuplicates(const std::filesystem::path &transfer_list_file,
+                      std::filesystem::path new_dat_file) {
+  if (!decompressNewDat(new_dat_file)) {
+    return EXIT_FAILURE;
+  }
+  std::ifstream input_dat(new_dat_file, std::ios::binary);
+  if (unlikely(!input_dat)) {
+    std::cerr << "Error: Could not open file " << new_dat_file << std::endl;
+    return EXIT_FAILURE;
+  }
+
+  struct DigestHash {
+    size_t operator()(const Sha256::Digest &digest) const noexcept {
+      size_t value = 0;
+      std::memcpy(&value, digest.data(), sizeof(value));
+      return value;
+    }
+  };
+  std::unordered_map<Sha256::Digest, FileSizeT, DigestHash> counts;
+  const auto zero_digest = Sha256::of(std::array<char, BLOCK_SIZE>{}.data(),
+                                      BLOCK_SIZE);
+  FileSizeT total = 0;
+  std::array<char, BLOCK_SIZE> block{};
+
+  // The new data is in the dat in list order, so it's hashed while the
+  // list is read.
+  try {
+    TransferListReader reader(transfer_list_file);
+    while (const auto entry = reader.next()) {
+      if (entry->command != TransferList::Command::New) {
+        continue;
+      }
+      for (const auto &seg : entry->segments) {
+        for (FileSizeT i = 0; i < seg.size() && input_dat; ++i) {
+          input_dat.read(block.data(), BLOCK_SIZE);
+          ++counts[Sha256::of(block.data(), BLOCK_SIZE)];
+          ++total;
+        }
+      }
+    }
+  } catch (const std::exception &e) {
+    return reportError(e);
+  }
+  if (!input_dat) {
+    std::cerr << "Warning: The new dat file ended early, only " << total
+              << " blocks were analyzed" << std::endl;
+  }
+
+  std::vector<std::pair<FileSizeT, Sha256::Digest>> repeated;
+  for (const auto &[digest, count] : counts) {
+    if (count > 1) {
+      repeated.emplace_back(count, digest);
+    }
+  }
+  std::sort(repeated.rbegin(), repeated.rend());
+  const FileSizeT duplicates = total - static_cast<FileSizeT>(counts.size());
+  const auto zeros = counts.find(zero_digest);
+
+  std::cout << "New blocks: " << total << std::endl;
+  std::cout << "Unique blocks: " << counts.size() << std::endl;
+  std::cout << "Duplicate blocks: " << duplicates << " ("
+            << (total ? duplicates * 100 / total : 0) << "%), "
+            << "dedup would save " << duplicates * BLOCK_SIZE << " bytes"
+            << std::endl;
+  std::cout << "All-zero blocks stored as new: "
+            << (zeros == counts.end() ? 0 : zeros->second) << std::endl;
+  if (!repeated.empty()) {
+    std::cout << "Most repeated blocks:" << std::endl;
+  }
+  for (size_t i = 0; i < std::min<size_t>(repeated.size(), 5); ++i) {
+    const auto &[count, digest] = repeated[i];
+    std::cout << "    " << toHex(digest.data(), digest.size()) << " x "
+              << count << (digest == zero_digest ? " (zeros)" : "")
+              << std::endl;
+  }
+  return EXIT_SUCCESS;
+}
+
+#ifdef HAS_MMAP
+
+// Read-only memory mapping of a whole file. Copying segments out of it
+// saves a read() per block, which adds up with thousands of small segments.
+class MappedFile {
+public:
+  explicit MappedFile(const std::filesystem::path &path) {
+    fd = open(path.c_str(), O_RDONLY);
+    struct stat st {};
+    // A dat over 4 GiB can't be mapped on 32-bit hosts, it's read instead.
+    if (fd == -1 || fstat(fd, &st) != 0 || st.st_size <= 0 ||
+        static_cast<uintmax_t>(st.st_size) >
+            std::numeric_limits<size_t>::max()) {
+      return;
+    }
+    void *map = mmap(nullptr, static_cast<size_t>(st.st_size), PROT_READ,
+                     MAP_PRIVATE, fd, 0);
+    if (map == MAP_FAILED) {
+      return;
+    }
+    madvise(map, static_cast<size_t>(st.st_size), MADV_SEQUENTIAL);
+    map_data = static_cast<const char *>(map);
+    map_size = static_cast<size_t>(st.st_size);
+  }
+  ~MappedFile() {
+    if (map_data) {
+      munmap(const_cast<char *>(map_data), map_size);
+    }
+    if (fd != -1) {
+      close(fd);
+    }
+  }
+
+  [[nodiscard]] const char *data() const noexcept { return map_data; }
+  [[nodiscard]] size_t size() const noexcept { return map_size; }
+  explicit operator bool() const noexcept { return map_data != nullptr; }
+
+  MappedFile(const MappedFile &) = delete;
+  MappedFile &operator=(const MappedFile &) = delete;
+
+private:
+  int fd = -1;
+  const char *map_data = nullptr;
+  size_t map_size = 0;
+};
+
+#endif
+
+// "-" stands for stdin as an input and for stdout as the output. They are
+// opened by name like /dev/stdin and /dev/fd/N, so all of these work alike.
+std::filesystem::path stdioPath(const std::filesystem::path &path,
+                                [[maybe_unused]] const bool output) {
+#ifdef HAS_MMAP
+  if (path == "-") {
+    return output ? "/dev/stdout" : "/dev/stdin";
+  }
+#endif
+  return path;
+}
+
+// Whether the path is where stdout goes, also when that was redirected into
+// a file by the shell.
+bool isStdout([[maybe_unused]] const std::filesystem::path &path) {
+#ifdef HAS_MMAP
+  struct stat out {}, file {};
+  return fstat(STDOUT_FILENO, &out) == 0 && stat(path.c_str(), &file) == 0 &&
+         out.st_dev == file.st_dev && out.st_ino == file.st_ino;
+#else
+  return false;
+#endif
+}
+
+// Whether an output can only be written front to back, like a pipe or a
+// terminal. Stdout counts as one even if it is a file, as there's no place
+// for the files kept next to the output.
+bool isStreamOutput(const std::filesystem::path &output) {
+  std::error_code ec;
+  return std::filesystem::is_fifo(output, ec) ||
+         std::filesystem::is_character_file(output, ec) || isStdout(output);
+}
+
+// Whether an input can only be read once, like a pipe from process
+// substitution or stdin. Files redirected into stdin are read by name.
+bool isStreamInput(const std::filesystem::path &input) {
+  std::error_code ec;
+  return std::filesystem::is_fifo(input, ec) ||
+         std::filesystem::is_character_file(input, ec) ||
+         std::filesystem::is_socket(input, ec);
+}
+
+// The new dat is looked at more than once and read out of order, which a
+// pipe can't do.
+void checkSeekableDat(const std::filesystem::path &new_dat_file) {
+  if (isStreamInput(new_dat_file)) {
+    throw std::runtime_error(
+        "The new dat " + new_dat_file.string() +
+        " is a pipe, but it has to be read more than once. Save it into a "
+        "file first");
+  }
+}
+
+// A copy of an input which can only be read once, in the temporary
+// directory, for the transfer list which is parsed and hashed separately.
+// Other inputs are used as they are.
+class SpooledInput {
+public:
+  explicit SpooledInput(const std::filesystem::path &input) : path_(input) {
+    if (!isStreamInput(input)) {
+      return;
+    }
+    std::random_device random;
+    path_ = std::filesystem::temp_directory_path() /
+            ("sdat2img-" + std::to_string(random()) + "-" +
+             input.filename().string());
+    std::ifstream in(input, std::ios::binary);
+    std::ofstream out(path_, std::ios::binary | std::ios::trunc);
+    if (!in || !out || (in.peek() != EOF && !(out << in.rdbuf())) ||
+        !out.flush()) {
+      std::error_code ec;
+      std::filesystem::remove(path_, ec);
+      throw IOException(input, "copy");
+    }
+    spooled = true;
+  }
+  ~SpooledInput() {
+    if (spooled) {
+      std::error_code ec;
+      std::filesystem::remove(path_, ec);
+    }
+  }
+  SpooledInput(const SpooledInput &) = delete;
+  SpooledInput &operator=(const SpooledInput &) = delete;
+
+  const std::filesystem::path &path() const { return path_; }
+
+private:
+  std::filesystem::path path_;
+  bool spooled = false;
+};
+
+// Advisory lock keeping two conversions from writing the same image at once.
+// It's taken on <output>.lock, which holds the PID of the writer, so the
+// output itself can still be removed and created again while locked.
+class OutputLock {
+public:
+  explicit OutputLock(const std::filesystem::path &output)
+      : path(output.string() + ".lock") {
+#ifdef HAS_MMAP
+    // Devices are locked themselves, there's no place for a lock file next
+    // to them.
+    std::error_code ec;
+    // Nothing can mix writes into a pipe.
+    if (isStreamOutput(output)) {
+      return;
+    }
+    if (std::filesystem::is_block_file(output, ec)) {
+      device_fd = open(output.c_str(), O_RDONLY);
+      if (device_fd != -1 && flock(device_fd, LOCK_EX | LOCK_NB) != 0) {
+        close(device_fd);
+        device_fd = -1;
+        throw std::runtime_error("The device " + output.string() +
+                                 " is already being written");
+      }
+      return;
+    }
+    // Retry if the lock file was removed by its previous owner while we
+    // waited for it, locking a file nobody else sees anymore.
+    for (;;) {
+      fd = open(path.c_str(), O_RDWR | O_CREAT, 0644);
+      if (fd == -1) {
+        std::cerr << "Warning: Couldn't create " << path
+                  << ", not locking the output" << std::endl;
+        return;
+      }
+      if (flock(fd, LOCK_EX | LOCK_NB) != 0) {
+        std::array<char, 32> pid{};
+        const auto n = pread(fd, pid.data(), pid.size() - 1, 0);
+        close(fd);
+        fd = -1;
+        throw std::runtime_error(
+            "The output " + output.string() + " is already being written" +
+            (n > 0 ? " by PID " + std::to_string(std::atol(pid.data()))
+                   : ""));
+      }
+      struct stat locked {}, current {};
+      if (fstat(fd, &locked) == 0 && stat(path.c_str(), &current) == 0 &&
+          locked.st_ino == current.st_ino) {
+        break;
+      }
+      close(fd);
+    }
+    const auto pid = std::to_string(getpid()) + "\n";
+    if (ftruncate(fd, 0) != 0 ||
+        pwrite(fd, pid.data(), pid.size(), 0) < 0) {
+      std::cerr << "Warning: Couldn't write the PID into " << path
+                << std::endl;
+    }
+#else
+    // There's no flock, and a lock file alone would be left behind by a
+    // crash, keeping every later conversion out.
+    if (!isStreamOutput(output)) {
+      std::cerr << "Warning: Outputs can't be locked on this system, make "
+                   "sure nothing else writes "
+                << output << " meanwhile" << std::endl;
+    }
+#endif
+  }
+  ~OutputLock() {
+#ifdef HAS_MMAP
+    if (fd != -1) {
+      unlink(path.c_str());
+      close(fd);
+    }
+    if (device_fd != -1) {
+      close(device_fd);
+    }
+#endif
+  }
+
+  OutputLock(const OutputLock &) = delete;
+  OutputLock &operator=(const OutputLock &) = delete;
+
+private:
+  std::filesystem::path path;
+  [[maybe_unused]] int fd = -1;
+  [[maybe_unused]] int device_fd = -1;
+};
+
+#ifdef HAS_MMAP
+// Owns a raw file descriptor, for the pread and pwrite copies.
+class FileDescriptor {
+public:
+  FileDescriptor(const std::filesystem::path &path, const int flags,
+                 const mode_t mode = 0)
+      : fd(open(path.c_str(), flags, mode)) {}
+  ~FileDescriptor() {
+    if (fd != -1) {
+      close(fd);
+    }
+  }
+
+  [[nodiscard]] int get() const noexcept { return fd; }
+  explicit operator bool() const noexcept { return fd != -1; }
+
+  FileDescriptor(const FileDescriptor &) = delete;
+  FileDescriptor &operator=(const FileDescriptor &) = delete;
+
+private:
+  int fd;
+};
+#endif
+
+// Image formats the output can be written in, besides a raw image.
+enum class OutputFormat { Raw, Qcow2, Vhd, Vmdk, Gpt, Store, Chunks };
+
+// Writes an image in some container format through an ostream. Writes at
+// offsets of the image go to wherever the format keeps them. begin() is
+// called with the size of the image before the first write, and finish()
+// writes the metadata at the end.
+class ImageFormatBuf : public std::streambuf {
+public:
+  explicit ImageFormatBuf(const std::filesystem::path &path)
+      : file(path, std::ios::binary | std::ios::trunc), path(path) {
+    if (unlikely(!file)) {
+      throw IOException(path, "open");
+    }
+  }
+
+  virtual void begin(uint64_t /* image_size */) {}
+  virtual void finish(uint64_t image_size) = 0;
+
+protected:
+  std::ofstream file;
+  std::filesystem::path path;
+
+  // Write size bytes at the given offset into the image.
+  virtual void writeAt(uint64_t offset, const char *data, size_t size) = 0;
+
+  void writeHost(const uint64_t offset, const std::string &data) {
+    writeHost(offset, data.data(), data.size());
+  }
+  void writeHost(const uint64_t offset, const char *data, const size_t size) {
+    file.seekp(checkedCast<std::streamoff>(offset));
+    file.write(data, static_cast<std::streamsize>(size));
+  }
+
+  // Make the file at least size bytes long, flush and close it.
+  void close(const uint64_t size) {
+    file.seekp(0, std::ios::end);
+    if (static_cast<uint64_t>(file.tellp()) < size) {
+      writeHost(size - 1, std::string(1, '\0'));
+    }
+    if (unlikely(!file.flush())) {
+      throw IOException(path, "write");
+    }
+    file.close();
+  }
+
+  static bool allZeros(const char *data, const size_t size) {
+    return std::all_of(data, data + size, [](const char c) { return c == 0; });
+  }
+
+  std::streamsize xsputn(const char *data, const std::streamsize size) final {
+    writeAt(pos, data, static_cast<size_t>(size));
+    pos += static_cast<uint64_t>(size);
+    return size;
+  }
+  int_type overflow(const int_type c) final {
+    if (!traits_type::eq_int_type(c, traits_type::eof())) {
+      const char byte = traits_type::to_char_type(c);
+      xsputn(&byte, 1);
+    }
+    return traits_type::not_eof(c);
+  }
+  pos_type seekoff(const off_type off, const std::ios::seekdir dir,
+                   const std::ios::openmode) final {
+    if (dir == std::ios::beg) {
+      pos = static_cast<uint64_t>(off);
+    } else if (dir == std::ios::cur) {
+      pos += static_cast<uint64_t>(off);
+    } else {
+      return pos_type(off_type(-1));
+    }
+    return pos_type(static_cast<off_type>(pos));
+  }
+  pos_type seekpos(const pos_type target, const std::ios::openmode) final {
+    pos = static_cast<uint64_t>(static_cast<off_type>(target));
+    return target;
+  }
+
+private:
+  uint64_t pos = 0;
+};
+
+// QEMU copy-on-write image, version 3. Clusters are allocated as new data
+// comes in, the ones never written stay unallocated and read as zeros. The
+// tables go after the data, as their size is only known at the end.
+class Qcow2Buf final : public ImageFormatBuf {
+public:
+  using ImageFormatBuf::ImageFormatBuf;
+
+  void finish(const uint64_t image_size) override {
+    const uint64_t guest_clusters =
+        (image_size + kClusterSize - 1) / kClusterSize;
+    if (unlikely(guest_clusters < clusters.size())) {
+      throw std::logic_error("Data was written past the end of the image");
+    }
+    const uint64_t l1_size = (guest_clusters + kL2Entries - 1) / kL2Entries;
+    const auto clustersFor = [](const uint64_t bytes) {
+      return (bytes + kClusterSize - 1) / kClusterSize;
+    };
+
+    // Tables go after the data: L1, the L2 tables in use, the refcount
+    // table, then the refcount blocks covering everything including them.
+    uint64_t next = host_clusters;
+    const uint64_t l1_offset = next * kClusterSize;
+    next += std::max<uint64_t>(clustersFor(l1_size * 8), 1);
+    std::vector<uint64_t> l2_offsets(l1_size);
+    for (uint64_t i = 0; i < l1_size; ++i) {
+      if (std::any_of(clusters.begin() + std::min(i * kL2Entries,
+                                                  uint64_t{clusters.size()}),
+                      clusters.begin() + std::min((i + 1) * kL2Entries,
+                                                  uint64_t{clusters.size()}),
+                      [](const uint64_t c) { return c != 0; })) {
+        l2_offsets[i] = next++ * kClusterSize;
+      }
+    }
+    uint64_t refcount_blocks = 0, refcount_table_clusters = 0;
+    for (;;) {
+      const uint64_t total = next + refcount_table_clusters + refcount_blocks;
+      const uint64_t blocks = clustersFor(total * 2);
+      const uint64_t table_clusters = clustersFor(blocks * 8);
+      if (blocks == refcount_blocks &&
+          table_clusters == refcount_table_clusters) {
+        break;
+      }
+      refcount_blocks = blocks;
+      refcount_table_clusters = table_clusters;
+    }
+    const uint64_t refcount_table_offset = next * kClusterSize;
+    next += refcount_table_clusters;
+    const uint64_t first_refcount_block = next;
+    next += refcount_blocks;
+
+    std::string table;
+    for (const auto offset : l2_offsets) {
+      appendBigEndian<uint64_t>(table, offset ? offset | kCopied : 0);
+    }
+    writeHost(l1_offset, table);
+    for (uint64_t i = 0; i < l1_size; ++i) {
+      if (l2_offsets[i] == 0) {
+        continue;
+      }
+      table.clear();
+      for (uint64_t c = i * kL2Entries; c < (i + 1) * kL2Entries; ++c) {
+        const uint64_t host = c < clusters.size() ? clusters[c] : 0;
+        appendBigEndian<uint64_t>(table, host ? host | kCopied : 0);
+      }
+      writeHost(l2_offsets[i], table);
+    }
+    table.clear();
+    for (uint64_t i = 0; i < refcount_blocks; ++i) {
+      appendBigEndian<uint64_t>(table,
+                                (first_refcount_block + i) * kClusterSize);
+    }
+    writeHost(refcount_table_offset, table);
+    // Every cluster of the file is used once.
+    table.clear();
+    for (uint64_t i = 0; i < next; ++i) {
+      appendBigEndian<uint16_t>(table, 1);
+    }
+    writeHost(first_refcount_block * kClusterSize, table);
+
+    std::string header = "QFI\xfb";
+    appendBigEndian<uint32_t>(header, 3);
+    appendBigEndian<uint64_t>(header, 0);
+    appendBigEndian<uint32_t>(header, 0);
+    appendBigEndian<uint32_t>(header, kClusterBits);
+    appendBigEndian<uint64_t>(header, image_size);
+    appendBigEndian<uint32_t>(header, 0);
+    appendBigEndian<uint32_t>(header, checkedCast<uint32_t>(l1_size));
+    appendBigEndian<uint64_t>(header, l1_offset);
+    appendBigEndian<uint64_t>(header, refcount_table_offset);
+    appendBigEndian<uint32_t>(header,
+                              checkedCast<uint32_t>(refcount_table_clusters));
+    appendBigEndian<uint32_t>(header, 0);
+    appendBigEndian<uint64_t>(header, 0);
+    // Feature bits, then 16-bit refcounts and the header length.
+    appendBigEndian<uint64_t>(header, 0);
+    appendBigEndian<uint64_t>(header, 0);
+    appendBigEndian<uint64_t>(header, 0);
+    appendBigEndian<uint32_t>(header, 4);
+    appendBigEndian<uint32_t>(header, 104);
+    writeHost(0, header);
+    close(next * kClusterSize);
+  }
+
+protected:
+  void writeAt(uint64_t offset, const char *data, size_t size) override {
+    while (size > 0) {
+      const auto index = checkedCast<size_t>(offset / kClusterSize);
+      const uint64_t within = offset % kClusterSize;
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(size, kClusterSize - within));
+      if (index >= clusters.size()) {
+        clusters.resize(index + 1);
+      }
+      // Zeros need no cluster, an unallocated one reads as zeros.
+      if (clusters[index] == 0 && allZeros(data, n)) {
+        offset += n;
+        data += n;
+        size -= n;
+        continue;
+      }
+      if (clusters[index] == 0) {
+        clusters[index] = host_clusters++ * kClusterSize;
+      }
+      writeHost(clusters[index] + within, data, n);
+      offset += n;
+      data += n;
+      size -= n;
+    }
+  }
+
+private:
+  static constexpr uint32_t kClusterBits = 16;
+  static constexpr uint64_t kClusterSize = uint64_t{1} << kClusterBits;
+  static constexpr uint64_t kL2Entries = kClusterSize / 8;
+  // Refcount is exactly one, so the cluster may be written in place.
+  static constexpr uint64_t kCopied = uint64_t{1} << 63;
+
+  // Host offset of each guest cluster, 0 if unallocated.
+  std::vector<uint64_t> clusters;
+  // The header takes the first cluster.
+  uint64_t host_clusters = 1;
+};
+
+// Dynamic VHD, which Windows mounts in Disk Management and Hyper-V and
+// VirtualBox attach. Blocks of 2 MiB are allocated as data comes in, each
+// behind a bitmap telling its sectors are all there.
+class VhdBuf final : public ImageFormatBuf {
+public:
+  using ImageFormatBuf::ImageFormatBuf;
+
+  void begin(const uint64_t image_size) override {
+    if (image_size > kMaxSize) {
+      throw std::invalid_argument("VHD images can hold at most 2040 GiB");
+    }
+    blocks.assign((image_size + kBlockSize - 1) / kBlockSize, kUnused);
+    next_sector = (kTableOffset + blocks.size() * 4 + 511) / 512;
+  }
+
+  void finish(const uint64_t image_size) override {
+    const std::string footer = makeFooter(image_size);
+    writeHost(0, footer);
+
+    // Dynamic disk header, pointing at the block allocation table.
+    std::string header = "cxsparse";
+    appendBigEndian<uint64_t>(header, ~uint64_t{0});
+    appendBigEndian<uint64_t>(header, kTableOffset);
+    appendBigEndian<uint32_t>(header, 0x00010000);
+    appendBigEndian<uint32_t>(header, checkedCast<uint32_t>(blocks.size()));
+    appendBigEndian<uint32_t>(header, kBlockSize);
+    header.resize(1024, '\0');
+    writeChecksum(header, 36);
+    writeHost(512, header);
+
+    std::string table;
+    for (const auto sector : blocks) {
+      appendBigEndian<uint32_t>(table, sector);
+    }
+    writeHost(kTableOffset, table);
+    writeHost(uint64_t{next_sector} * 512, footer);
+    close(uint64_t{next_sector} * 512 + footer.size());
+  }
+
+protected:
+  void writeAt(uint64_t offset, const char *data, size_t size) override {
+    while (size > 0) {
+      const auto index = checkedCast<size_t>(offset / kBlockSize);
+      const uint64_t within = offset % kBlockSize;
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(size, kBlockSize - within));
+      if (unlikely(index >= blocks.size())) {
+        throw std::logic_error("Data was written past the end of the image");
+      }
+      if (blocks[index] == kUnused && !allZeros(data, n)) {
+        blocks[index] = next_sector;
+        writeHost(uint64_t{next_sector} * 512, std::string(512, '\xff'));
+        next_sector += 1 + kBlockSize / 512;
+      }
+      if (blocks[index] != kUnused) {
+        writeHost((uint64_t{blocks[index]} + 1) * 512 + within, data, n);
+      }
+      offset += n;
+      data += n;
+      size -= n;
+    }
+  }
+
+private:
+  static constexpr uint32_t kBlockSize = 2 << 20;
+  static constexpr uint32_t kUnused = 0xffffffff;
+  static constexpr uint64_t kTableOffset = 1536;
+  static constexpr uint64_t kMaxSize = uint64_t{2040} << 30;
+
+  // Sector of each block, kUnused if it was never written.
+  std::vector<uint32_t> blocks;
+  uint32_t next_sector = 0;
+
+  // One's complement of the sum of all bytes, at the given offset.
+  static void writeChecksum(std::string &data, const size_t offset) {
+    uint32_t sum = 0;
+    for (const char c : data) {
+      sum += static_cast<uint8_t>(c);
+    }
+    std::string checksum;
+    appendBigEndian<uint32_t>(checksum, ~sum);
+    data.replace(offset, 4, checksum);
+  }
+
+  static std::string makeFooter(const uint64_t image_size) {
+    // Cylinders, heads and sectors per track, as the VHD spec computes them.
+    uint64_t sectors = std::min<uint64_t>(image_size / 512, 65535 * 16 * 255);
+    uint64_t spt = 0, heads = 0, cylinder_heads = 0;
+    if (sectors >= 65535 * 16 * 63) {
+      spt = 255;
+      heads = 16;
+      cylinder_heads = sectors / spt;
+    } else {
+      spt = 17;
+      cylinder_heads = sectors / spt;
+      heads = std::max<uint64_t>((cylinder_heads + 1023) / 1024, 4);
+      if (cylinder_heads >= heads * 1024 || heads > 16) {
+        spt = 31;
+        heads = 16;
+        cylinder_heads = sectors / spt;
+      }
+      if (cylinder_heads >= heads * 1024) {
+        spt = 63;
+        heads = 16;
+        cylinder_heads = sectors / spt;
+      }
+    }
+    const uint64_t cylinders = cylinder_heads / heads;
+
+    std::string footer = "conectix";
+    appendBigEndian<uint32_t>(footer, 2);
+    appendBigEndian<uint32_t>(footer, 0x00010000);
+    appendBigEndian<uint64_t>(footer, 512);
+    // Seconds since 2000-01-01.
+    appendBigEndian<uint32_t>(
+        footer, static_cast<uint32_t>(std::time(nullptr) - 946684800));
+    footer += "s2i ";
+    appendBigEndian<uint32_t>(footer, 0x00010000);
+    footer += "Wi2k";
+    appendBigEndian<uint64_t>(footer, image_size);
+    appendBigEndian<uint64_t>(footer, image_size);
+    appendBigEndian<uint16_t>(footer, static_cast<uint16_t>(cylinders));
+    footer += static_cast<char>(heads);
+    footer += static_cast<char>(spt);
+    appendBigEndian<uint32_t>(footer, 3);
+    appendBigEndian<uint32_t>(footer, 0);
+    std::random_device random;
+    for (int i = 0; i < 16; ++i) {
+      footer += static_cast<char>(random());
+    }
+    footer.resize(512, '\0');
+    writeChecksum(footer, 64);
+    return footer;
+  }
+};
+
+// Monolithic sparse VMDK, for VMware and VirtualBox. The descriptor and
+// all grain tables go in front, the 64 KiB grains are appended as data
+// comes in.
+class VmdkBuf final : public ImageFormatBuf {
+public:
+  using ImageFormatBuf::ImageFormatBuf;
+
+  void begin(const uint64_t image_size) override {
+    capacity = (image_size + 511) / 512;
+    const uint64_t tables = (capacity + kTableSectors - 1) / kTableSectors;
+    // Grain directory, then the grain tables, padded to a grain.
+    const uint64_t directory_sectors = (tables * 4 + 511) / 512;
+    overhead = (kDirectoryOffset + directory_sectors + tables * 4 +
+                kGrainSectors - 1) /
+               kGrainSectors * kGrainSectors;
+    if (overhead + capacity / kGrainSectors * kGrainSectors + kGrainSectors >
+        std::numeric_limits<uint32_t>::max()) {
+      throw std::invalid_argument("VMDK images can hold at most 2 TiB");
+    }
+    grains.assign(tables * kTableEntries, 0);
+    next_sector = overhead;
+  }
+
+  void finish(const uint64_t /* image_size */) override {
+    const uint64_t tables = grains.size() / kTableEntries;
+    const uint64_t first_table =
+        kDirectoryOffset + (tables * 4 + 511) / 512;
+
+    std::string header = "KDMV";
+    appendLittleEndian<uint32_t>(header, 1);
+    // Valid newline detection.
+    appendLittleEndian<uint32_t>(header, 1);
+    appendLittleEndian<uint64_t>(header, capacity);
+    appendLittleEndian<uint64_t>(header, kGrainSectors);
+    appendLittleEndian<uint64_t>(header, kDescriptorOffset);
+    appendLittleEndian<uint64_t>(header, kDescriptorSectors);
+    appendLittleEndian<uint32_t>(header, kTableEntries);
+    appendLittleEndian<uint64_t>(header, 0);
+    appendLittleEndian<uint64_t>(header, kDirectoryOffset);
+    appendLittleEndian<uint64_t>(header, overhead);
+    header += std::string("\0\n \r\n", 5);
+    appendLittleEndian<uint16_t>(header, 0);
+    header.resize(512, '\0');
+    writeHost(0, header);
+
+    std::random_device random;
+    std::ostringstream descriptor;
+    descriptor << "# Disk DescriptorFile\n"
+               << "version=1\n"
+               << "CID=" << std::hex << std::setw(8) << std::setfill('0')
+               << random() << std::dec << "\n"
+               << "parentCID=ffffffff\n"
+               << "createType=\"monolithicSparse\"\n\n"
+               << "# Extent description\n"
+               << "RW " << capacity << " SPARSE \""
+               << path.filename().string() << "\"\n\n"
+               << "# The Disk Data Base\n"
+               << "#DDB\n\n"
+               << "ddb.virtualHWVersion = \"4\"\n"
+               << "ddb.geometry.cylinders = \""
+               << std::min<uint64_t>(capacity / (16 * 63), 16383) << "\"\n"
+               << "ddb.geometry.heads = \"16\"\n"
+               << "ddb.geometry.sectors = \"63\"\n"
+               << "ddb.adapterType = \"ide\"\n";
+    writeHost(kDescriptorOffset * 512, descriptor.str());
+
+    std::string table;
+    for (uint64_t i = 0; i < tables; ++i) {
+      appendLittleEndian<uint32_t>(
+          table, static_cast<uint32_t>(first_table + i * 4));
+    }
+    writeHost(kDirectoryOffset * 512, table);
+    table.clear();
+    for (const auto sector : grains) {
+      appendLittleEndian<uint32_t>(table, sector);
+    }
+    writeHost(first_table * 512, table);
+    close(next_sector * 512);
+  }
+
+protected:
+  void writeAt(uint64_t offset, const char *data, size_t size) override {
+    constexpr uint64_t kGrainSize = kGrainSectors * 512;
+    while (size > 0) {
+      const auto index = checkedCast<size_t>(offset / kGrainSize);
+      const uint64_t within = offset % kGrainSize;
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(size, kGrainSize - within));
+      if (unlikely(index >= grains.size())) {
+        throw std::logic_error("Data was written past the end of the image");
+      }
+      if (grains[index] == 0 && !allZeros(data, n)) {
+        grains[index] = static_cast<uint32_t>(next_sector);
+        next_sector += kGrainSectors;
+      }
+      if (grains[index] != 0) {
+        writeHost(uint64_t{grains[index]} * 512 + within, data, n);
+      }
+      offset += n;
+      data += n;
+      size -= n;
+    }
+  }
+
+private:
+  static constexpr uint64_t kGrainSectors = 128;
+  static constexpr uint32_t kTableEntries = 512;
+  static constexpr uint64_t kTableSectors = kGrainSectors * kTableEntries;
+  static constexpr uint64_t kDescriptorOffset = 1;
+  static constexpr uint64_t kDescriptorSectors = 20;
+  static constexpr uint64_t kDirectoryOffset =
+      kDescriptorOffset + kDescriptorSectors;
+
+  uint64_t capacity = 0;
+  uint64_t overhead = 0;
+  uint64_t next_sector = 0;
+  // Sector of each grain, 0 if it was never written.
+  std::vector<uint32_t> grains;
+};
+
+// Raw disk image with a GPT holding the image as its only partition, for
+// tools which only take whole disks. The partition starts at 1 MiB and is
+// named after the output file, with the Linux filesystem type.
+class GptBuf final : public ImageFormatBuf {
+public:
+  using ImageFormatBuf::ImageFormatBuf;
+
+  void finish(const uint64_t image_size) override {
+    const uint64_t first = kDataOffset / 512;
+    const uint64_t last = first + (image_size + 511) / 512 - 1;
+    // Room for the backup table behind the partition, aligned to 1 MiB.
+    const uint64_t disk_sectors =
+        (last + 1 + kEntriesSectors + 1 + 2047) / 2048 * 2048;
+    const uint64_t backup = disk_sectors - 1;
+
+    std::string mbr(446, '\0');
+    mbr += std::string("\x00\x00\x02\x00\xee\xff\xff\xff", 8);
+    appendLittleEndian<uint32_t>(mbr, 1);
+    appendLittleEndian<uint32_t>(
+        mbr, static_cast<uint32_t>(std::min<uint64_t>(disk_sectors - 1,
+                                                      0xffffffff)));
+    mbr.resize(510, '\0');
+    mbr += "\x55\xaa";
+    writeHost(0, mbr);
+
+    // Linux filesystem data, 0FC63DAF-8483-4772-8E79-3D69D8477DE4.
+    std::string entries =
+        "\xaf\x3d\xc6\x0f\x83\x84\x72\x47\x8e\x79\x3d\x69\xd8\x47\x7d\xe4";
+    entries += randomGuid();
+    appendLittleEndian<uint64_t>(entries, first);
+    appendLittleEndian<uint64_t>(entries, last);
+    appendLittleEndian<uint64_t>(entries, 0);
+    const std::string name = path.stem().string().substr(0, 36);
+    for (const char c : name) {
+      appendLittleEndian<uint16_t>(entries, static_cast<uint8_t>(c));
+    }
+    entries.resize(kEntries * kEntrySize, '\0');
+    const std::string disk_guid = randomGuid();
+
+    const auto header = [&](const uint64_t current, const uint64_t other,
+                            const uint64_t entries_lba) {
+      std::string out = "EFI PART";
+      appendLittleEndian<uint32_t>(out, 0x00010000);
+      appendLittleEndian<uint32_t>(out, 92);
+      appendLittleEndian<uint32_t>(out, 0);
+      appendLittleEndian<uint32_t>(out, 0);
+      appendLittleEndian<uint64_t>(out, current);
+      appendLittleEndian<uint64_t>(out, other);
+      appendLittleEndian<uint64_t>(out, 2 + kEntriesSectors);
+      appendLittleEndian<uint64_t>(out, backup - kEntriesSectors - 1);
+      out += disk_guid;
+      appendLittleEndian<uint64_t>(out, entries_lba);
+      appendLittleEndian<uint32_t>(out, kEntries);
+      appendLittleEndian<uint32_t>(out, kEntrySize);
+      appendLittleEndian<uint32_t>(out, crc32(entries.data(), entries.size()));
+      std::string crc;
+      appendLittleEndian<uint32_t>(crc, crc32(out.data(), out.size()));
+      out.replace(16, 4, crc);
+      out.resize(512, '\0');
+      return out;
+    };
+    writeHost(512, header(1, backup, 2));
+    writeHost(1024, entries);
+    writeHost((backup - kEntriesSectors) * 512, entries);
+    writeHost(backup * 512,
+              header(backup, 1, backup - kEntriesSectors));
+    close(disk_sectors * 512);
+  }
+
+protected:
+  void writeAt(const uint64_t offset, const char *data,
+               const size_t size) override {
+    // The file is sparse, zeros don't need writing.
+    if (!allZeros(data, size)) {
+      writeHost(kDataOffset + offset, data, size);
+    }
+  }
+
+private:
+  static constexpr uint64_t kDataOffset = 1 << 20;
+  static constexpr uint32_t kEntries = 128;
+  static constexpr uint32_t kEntrySize = 128;
+  static constexpr uint64_t kEntriesSectors = kEntries * kEntrySize / 512;
+
+  // Version 4 GUID, in the mixed-endian layout of GPT.
+  static std::string randomGuid() {
+    std::random_device random;
+    std::string guid;
+    for (int i = 0; i < 16; ++i) {
+      guid += static_cast<char>(random());
+    }
+    guid[7] = static_cast<char>((guid[7] & 0x0f) | 0x40);
+    guid[8] = static_cast<char>((guid[8] & 0x3f) | 0x80);
+    return guid;
+  }
+};
+
+// Writer of the image in the given format, nothing for raw images.
+// Deduplicating store of image chunks, named after their SHA-256, which
+// any number of images share. The output is a manifest listing the chunks
+// of the image, which materializeImage() puts back together. Chunks of only
+// zeros aren't stored.
+class StoreBuf final : public ImageFormatBuf {
+public:
+  static constexpr size_t kChunkSize = size_t{64} << 10;
+  static constexpr std::string_view kMagic = "sdat2img-store 1";
+
+  StoreBuf(const std::filesystem::path &path, std::filesystem::path store)
+      : ImageFormatBuf(path), store(std::move(store)),
+        scratch_path(path.string() + ".part"),
+        scratch(scratch_path, std::ios::binary | std::ios::in |
+                                  std::ios::out | std::ios::trunc) {
+    if (unlikely(!scratch)) {
+      throw IOException(scratch_path, "create");
+    }
+  }
+
+  // The store given, or the one next to the manifest.
+  static std::filesystem::path storeFor(const std::filesystem::path &manifest,
+                                        const std::filesystem::path &store) {
+    return store.empty() ? manifest.parent_path() / "store" : store;
+  }
+
+  static std::filesystem::path chunkPath(const std::filesystem::path &store,
+                                         const std::string &hash) {
+    return store / "chunks" / hash.substr(0, 2) / hash;
+  }
+
+  static std::string readChunk(const std::filesystem::path &store,
+                               const std::string &hash) {
+    const auto chunk_path = chunkPath(store, hash);
+    std::ifstream in(chunk_path, std::ios::binary);
+    std::string data(kChunkSize, '\0');
+    if (unlikely(!in.read(data.data(), kChunkSize))) {
+      throw IOException(chunk_path, "read");
+    }
+    return data;
+  }
+
+  void finish(const uint64_t image_size) override {
+    // Chunks which were only partly written are zeros in the rest.
+    while (!pending.empty()) {
+      storePending(pending.begin()->first);
+    }
+    scratch.close();
+    std::filesystem::remove(scratch_path);
+
+    std::string manifest = std::string(kMagic) + "\n" +
+                           std::to_string(image_size) + " " +
+                           std::to_string(kChunkSize) + "\n";
+    for (const auto &[index, hash] : chunks) {
+      manifest += std::to_string(index) + " " + hash + "\n";
+    }
+    writeHost(0, manifest);
+    close(0);
+    std::cout << "Stored " << chunks.size() << " chunks in " << store << ", "
+              << new_chunks << " of them new (" << new_chunks * kChunkSize
+              << " bytes)" << std::endl;
+  }
+
+protected:
+  void writeAt(uint64_t offset, const char *data, size_t size) override {
+    // The image is only ever written in whole blocks.
+    if (unlikely(offset % BLOCK_SIZE != 0 || size % BLOCK_SIZE != 0)) {
+      throw std::logic_error("Unaligned write into the store");
+    }
+    while (size > 0) {
+      const uint64_t index = offset / kChunkSize;
+      const auto in_chunk = static_cast<size_t>(offset % kChunkSize);
+      const size_t n = std::min(size, kChunkSize - in_chunk);
+      if (n == kChunkSize) {
+        pending.erase(index);
+        storeChunk(index, data);
+      } else {
+        // Parts of chunks wait in the scratch file for the rest, which
+        // starts out as whatever the chunk held so far.
+        auto [it, added] = pending.try_emplace(index, 0);
+        if (added) {
+          const auto found = chunks.find(index);
+          const auto old = found != chunks.end()
+                               ? readChunk(store, found->second)
+                               : std::string(kChunkSize, '\0');
+          scratchWrite(index * kChunkSize, old.data(), old.size());
+          it->second = found != chunks.end() ? kAllBlocks : 0;
+        }
+        scratchWrite(offset, data, n);
+        for (size_t block = in_chunk / BLOCK_SIZE;
+             block < (in_chunk + n) / BLOCK_SIZE; ++block) {
+          it->second |= uint16_t(1U << block);
+        }
+        if (it->second == kAllBlocks) {
+          storePending(index);
+        }
+      }
+      offset += n;
+      data += n;
+      size -= n;
+    }
+  }
+
+private:
+  // Blocks written of a pending chunk, one bit each.
+  static constexpr uint16_t kAllBlocks = 0xFFFF;
+  static_assert(kChunkSize / BLOCK_SIZE == 16, "A bit for every block");
+
+  std::filesystem::path store;
+  std::filesystem::path scratch_path;
+  std::fstream scratch;
+  std::map<uint64_t, uint16_t> pending;
+  // Hash of each chunk of the image which isn't all zeros, by index.
+  std::map<uint64_t, std::string> chunks;
+  uint64_t new_chunks = 0;
+
+  void scratchWrite(const uint64_t offset, const char *data,
+                    const size_t size) {
+    scratch.seekp(checkedCast<std::streamoff>(offset));
+    if (unlikely(!scratch.write(data, static_cast<std::streamsize>(size)))) {
+      throw IOException(scratch_path, "write");
+    }
+  }
+
+  void storePending(const uint64_t index) {
+    std::string data(kChunkSize, '\0');
+    scratch.seekg(checkedCast<std::streamoff>(index * kChunkSize));
+    if (unlikely(!scratch.read(data.data(), kChunkSize))) {
+      throw IOException(scratch_path, "read");
+    }
+    pending.erase(index);
+    storeChunk(index, data.data());
+  }
+
+  void storeChunk(const uint64_t index, const char *data) {
+    if (allZeros(data, kChunkSize)) {
+      chunks.erase(index);
+      return;
+    }
+    const auto digest = Sha256::of(data, kChunkSize);
+    auto hash = toHex(digest.data(), digest.size());
+    const auto target = chunkPath(store, hash);
+    std::error_code ec;
+    if (!std::filesystem::exists(target, ec)) {
+      // Written under another name first, so that a crash never leaves a
+      // bad chunk behind.
+      std::filesystem::create_directories(target.parent_path());
+      const std::filesystem::path temp = target.string() + ".tmp";
+      std::ofstream out(temp, std::ios::binary);
+      if (unlikely(!out.write(data, kChunkSize) || !out.flush())) {
+        throw IOException(temp, "write");
+      }
+      out.close();
+      std::filesystem::rename(temp, target);
+      ++new_chunks;
+    }
+    chunks[index] = std::move(hash);
+  }
+};
+
+// Put an image back together from a store manifest and its chunks,
+// checking each chunk against its hash.
+void materializeImage(const std::filesystem::path &manifest_file,
+                      const std::filesystem::path &store,
+                      const std::filesystem::path &output) {
+  TextFile manifest(manifest_file);
+  std::string line;
+  if (!manifest.takeOneLine(&line) || line != StoreBuf::kMagic) {
+    throw TextFileError::expected(manifest,
+                                  "'" + std::string(StoreBuf::kMagic) + "'");
+  }
+  uint64_t image_size = 0, chunk_size = 0;
+  if (!manifest.takeOneLine(&line) ||
+      !(std::stringstream(line) >> image_size >> chunk_size) ||
+      chunk_size != StoreBuf::kChunkSize) {
+    throw TextFileError::expected(manifest, "'<image_size> " +
+                                                std::to_string(
+                                                    StoreBuf::kChunkSize) +
+                                                "'");
+  }
+
+  std::ofstream out(output, std::ios::binary | std::ios::trunc);
+  if (unlikely(!out)) {
+    throw IOException(output, "open");
+  }
+  uint64_t count = 0;
+  while (manifest.takeOneLine(&line)) {
+    uint64_t index = 0;
+    std::string hash;
+    if (!(std::stringstream(line) >> index >> hash) || hash.size() != 64 ||
+        index >= (image_size + chunk_size - 1) / chunk_size) {
+      throw TextFileError::expected(manifest, "'<chunk_index> <sha256>'");
+    }
+    const auto data = StoreBuf::readChunk(store, hash);
+    const auto digest = Sha256::of(data.data(), data.size());
+    if (toHex(digest.data(), digest.size()) != hash) {
+      throw KindError(ErrorKind::Verification,
+                      "The chunk " +
+                          StoreBuf::chunkPath(store, hash).string() +
+                          " is corrupted");
+    }
+    out.seekp(checkedCast<std::streamoff>(index * chunk_size));
+    out.write(data.data(), static_cast<std::streamsize>(data.size()));
+    ++count;
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(output, "write");
+  }
+  out.close();
+  // The last chunk may go past the end, and zeros at the end aren't listed.
+  std::filesystem::resize_file(output, image_size);
+  std::cout << "Materialized " << count << " chunks from " << store
+            << " into " << output << " (" << image_size << " bytes)"
+            << std::endl;
+}
+
+// Writes a raw image as parts of at most a fixed size, <image>.000, .001
+// and so on, for FAT32 SD cards and recoveries which can't hold files of
+// 4 GiB or more. Joined with cat, the parts are the image. The manifest,
+// <image>.join, lists them for join.
+class SplitBuf final : public ImageFormatBuf {
+public:
+  static constexpr std::string_view kMagic = "sdat2img-split 1";
+
+  SplitBuf(const std::filesystem::path &path, const uint64_t part_size)
+      : ImageFormatBuf(manifestFor(path)), image(path), part_size(part_size) {}
+
+  static std::filesystem::path manifestFor(const std::filesystem::path &path) {
+    return path.string() + ".join";
+  }
+
+  static std::filesystem::path partPath(const std::filesystem::path &image,
+                                        const size_t index) {
+    std::ostringstream name;
+    name << image.string() << "." << std::setw(3) << std::setfill('0')
+         << index;
+    return name.str();
+  }
+
+  void begin(const uint64_t image_size) override {
+    const auto count =
+        std::max<uint64_t>(1, (image_size + part_size - 1) / part_size);
+    for (size_t i = 0; i < count; ++i) {
+      auto &part = parts.emplace_back(partPath(image, i),
+                                      std::ios::binary | std::ios::trunc);
+      if (unlikely(!part)) {
+        throw IOException(partPath(image, i), "open");
+      }
+    }
+  }
+
+  void finish(const uint64_t image_size) override {
+    std::string manifest = std::string(kMagic) + "\n" +
+                           std::to_string(image_size) + " " +
+                           std::to_string(part_size) + "\n";
+    for (size_t i = 0; i < parts.size(); ++i) {
+      if (unlikely(!parts[i].flush())) {
+        throw IOException(partPath(image, i), "write");
+      }
+      parts[i].close();
+      // Parts which end in blocks without new data are shorter so far.
+      const uint64_t begin = i * part_size;
+      std::filesystem::resize_file(
+          partPath(image, i),
+          std::min(part_size, image_size - std::min(begin, image_size)));
+      manifest += partPath(image, i).filename().string() + "\n";
+    }
+    writeHost(0, manifest);
+    close(0);
+    std::cout << "Split the image into " << parts.size() << " parts of up to "
+              << part_size << " bytes, listed in " << path << std::endl;
+  }
+
+protected:
+  void writeAt(uint64_t offset, const char *data, size_t size) override {
+    while (size > 0) {
+      const auto index = static_cast<size_t>(offset / part_size);
+      if (unlikely(index >= parts.size())) {
+        throw std::logic_error("Write past the end of the split image");
+      }
+      const uint64_t in_part = offset % part_size;
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(size, part_size - in_part));
+      parts[index].seekp(checkedCast<std::streamoff>(in_part));
+      if (unlikely(!parts[index].write(data,
+                                       static_cast<std::streamsize>(n)))) {
+        throw IOException(partPath(image, index), "write");
+      }
+      offset += n;
+      data += n;
+      size -= n;
+    }
+  }
+
+private:
+  std::filesystem::path image;
+  uint64_t part_size;
+  std::vector<std::ofstream> parts;
+};
+
+// Writes the image as numbered chunks of a fixed size, <output>.00000 and
+// up, for multipart uploads to object storage, and the output as an index
+// of them: "sdat2img-chunks 1", "<image_size> <chunk_size>", then
+// "<offset> <size> <sha256> <file>" for each chunk. Only the chunk being
+// written to is open, so there can be any number of them.
+class ChunkBuf final : public ImageFormatBuf {
+public:
+  static constexpr std::string_view kMagic = "sdat2img-chunks 1";
+  static constexpr uint64_t kDefaultSize = uint64_t{64} << 20;
+
+  ChunkBuf(const std::filesystem::path &path, const uint64_t chunk_size)
+      : ImageFormatBuf(path), chunk_size(chunk_size) {}
+
+  static std::filesystem::path chunkPath(const std::filesystem::path &index,
+                                         const size_t number) {
+    std::ostringstream name;
+    name << index.string() << "." << std::setw(5) << std::setfill('0')
+         << number;
+    return name.str();
+  }
+
+  void begin(const uint64_t image_size) override {
+    count = static_cast<size_t>(
+        std::max<uint64_t>(1, (image_size + chunk_size - 1) / chunk_size));
+    for (size_t i = 0; i < count; ++i) {
+      std::ofstream chunk(chunkPath(path, i),
+                          std::ios::binary | std::ios::trunc);
+      if (unlikely(!chunk)) {
+        throw IOException(chunkPath(path, i), "open");
+      }
+    }
+  }
+
+  void finish(const uint64_t image_size) override {
+    if (current.is_open()) {
+      current.close();
+      if (unlikely(current.fail())) {
+        throw IOException(chunkPath(path, open_chunk), "write");
+      }
+    }
+    std::string index = std::string(kMagic) + "\n" +
+                        std::to_string(image_size) + " " +
+                        std::to_string(chunk_size) + "\n";
+    for (size_t i = 0; i < count; ++i) {
+      // Chunks which end in blocks without new data are shorter so far.
+      const uint64_t offset = i * chunk_size;
+      const uint64_t size =
+          std::min(chunk_size, image_size - std::min(offset, image_size));
+      std::filesystem::resize_file(chunkPath(path, i), size);
+      index += std::to_string(offset) + " " + std::to_string(size) + " " +
+               sha256File(chunkPath(path, i)) + " " +
+               chunkPath(path, i).filename().string() + "\n";
+    }
+    writeHost(0, index);
+    close(0);
+    std::cout << "Wrote the image as " << count << " chunks of up to "
+              << chunk_size << " bytes, indexed in " << path << std::endl;
+  }
+
+protected:
+  void writeAt(uint64_t offset, const char *data, size_t size) override {
+    while (size > 0) {
+      const auto number = static_cast<size_t>(offset / chunk_size);
+      if (unlikely(number >= count)) {
+        throw std::logic_error("Write past the end of the chunked image");
+      }
+      if (!current.is_open() || number != open_chunk) {
+        current.close();
+        current.open(chunkPath(path, number),
+                     std::ios::binary | std::ios::in | std::ios::out);
+        open_chunk = number;
+      }
+      const uint64_t in_chunk = offset % chunk_size;
+      const auto n = static_cast<size_t>(
+          std::min<uint64_t>(size, chunk_size - in_chunk));
+      current.seekp(checkedCast<std::streamoff>(in_chunk));
+      if (unlikely(!current.write(data, static_cast<std::streamsize>(n)))) {
+        throw IOException(chunkPath(path, number), "write");
+      }
+      offset += n;
+      data += n;
+      size -= n;
+    }
+  }
+
+private:
+  uint64_t chunk_size;
+  size_t count = 0;
+  std::fstream current;
+  size_t open_chunk = 0;
+};
+
+// Join the parts listed in a split manifest or chunk index back into one
+// image, checking that each is there in full, and for chunks that it has
+// the hash the index gives.
+void joinImage(const std::filesystem::path &manifest_file,
+               const std::filesystem::path &output, const size_t buffer_size) {
+  TextFile manifest(manifest_file);
+  std::string line;
+  const bool chunks = manifest.takeOneLine(&line) && line == ChunkBuf::kMagic;
+  if (!chunks && line != SplitBuf::kMagic) {
+    throw TextFileError::expected(manifest,
+                                  "'" + std::string(SplitBuf::kMagic) +
+                                      "' or '" +
+                                      std::string(ChunkBuf::kMagic) + "'");
+  }
+  uint64_t image_size = 0, part_size = 0;
+  if (!manifest.takeOneLine(&line) ||
+      !(std::stringstream(line) >> image_size >> part_size) ||
+      part_size == 0) {
+    throw TextFileError::expected(manifest, "'<image_size> <part_size>'");
+  }
+  std::vector<std::filesystem::path> parts;
+  std::vector<std::string> hashes;
+  while (manifest.takeOneLine(&line)) {
+    if (line.empty()) {
+      continue;
+    }
+    if (!chunks) {
+      parts.push_back(manifest_file.parent_path() / line);
+      continue;
+    }
+    uint64_t offset = 0, size = 0;
+    std::string hash, name;
+    if (!(std::stringstream(line) >> offset >> size >> hash >> name) ||
+        offset != parts.size() * part_size) {
+      throw TextFileError::expected(manifest,
+                                    "'<offset> <size> <sha256> <file>' "
+                                    "for the next chunk");
+    }
+    parts.push_back(manifest_file.parent_path() / name);
+    hashes.push_back(hash);
+  }
+  if (unlikely(parts.size() !=
+               std::max<uint64_t>(1, (image_size + part_size - 1) /
+                                         part_size))) {
+    throw KindError(ErrorKind::Parse,
+                    quotedPath(manifest_file) + " lists " +
+                        std::to_string(parts.size()) + " parts, but " +
+                        std::to_string(image_size) +
+                        " bytes take a different number");
+  }
+  // All of them are checked before writing anything.
+  std::error_code ec;
+  for (size_t i = 0; i < parts.size(); ++i) {
+    const uint64_t expected =
+        std::min(part_size, image_size - std::min(i * part_size, image_size));
+    const auto size = std::filesystem::file_size(parts[i], ec);
+    if (unlikely(ec || size != expected)) {
+      throw KindError(ErrorKind::Verification,
+                      "The part " + quotedPath(parts[i]) +
+                          (ec ? " is missing"
+                              : " has " + std::to_string(size) +
+                                    " bytes instead of " +
+                                    std::to_string(expected)));
+    }
+    if (chunks && sha256File(parts[i]) != hashes[i]) {
+      throw KindError(ErrorKind::Verification,
+                      "The chunk " + quotedPath(parts[i]) +
+                          " doesn't have the SHA-256 of the index");
+    }
+  }
+
+  std::ofstream out(output, std::ios::binary | std::ios::trunc);
+  if (unlikely(!out)) {
+    throw IOException(output, "open");
+  }
+  std::vector<char> buffer(buffer_size);
+  for (const auto &part : parts) {
+    std::ifstream in(part, std::ios::binary);
+    while (in) {
+      in.read(buffer.data(), static_cast<std::streamsize>(buffer.size()));
+      out.write(buffer.data(), in.gcount());
+    }
+    if (unlikely(in.bad())) {
+      throw IOException(part, "read");
+    }
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(output, "write");
+  }
+  std::cout << "Joined " << parts.size() << " parts into " << output << " ("
+            << image_size << " bytes)" << std::endl;
+}
+
+std::unique_ptr<ImageFormatBuf>
+makeImageFormatBuf(const OutputFormat format,
+                   const std::filesystem::path &path,
+                   const std::filesystem::path &store = {},
+                   const uint64_t chunk_size = ChunkBuf::kDefaultSize) {
+  switch (format) {
+  case OutputFormat::Store:
+    return std::make_unique<StoreBuf>(path, StoreBuf::storeFor(path, store));
+  case OutputFormat::Chunks:
+    return std::make_unique<ChunkBuf>(path, chunk_size);
+  case OutputFormat::Qcow2:
+    return std::make_unique<Qcow2Buf>(path);
+  case OutputFormat::Vhd:
+    return std::make_unique<VhdBuf>(path);
+  case OutputFormat::Vmdk:
+    return std::make_unique<VmdkBuf>(path);
+  case OutputFormat::Gpt:
+    return std::make_unique<GptBuf>(path);
+  case OutputFormat::Raw:
+    break;
+  }
+  return nullptr;
+}
+
+// A reader thread keeping up to a fixed amount of the dat buffered ahead of
+// the writer, so that a slow input and a slow output don't stall each other.
+class ReadAheadChannel {
+public:
+  // Where the data comes from: fills up to size bytes, short at the end.
+  using Source = std::function<size_t(char *, size_t)>;
+
+  ReadAheadChannel(Source source, const size_t capacity, const uint64_t total)
+      : capacity(std::max(capacity, kChunkSize)),
+        reader([this, source = std::move(source), total] {
+          fill(source, total);
+        }) {}
+
+  ReadAheadChannel(std::istream &in, const size_t capacity,
+                   const uint64_t total)
+      : ReadAheadChannel(
+            [&in](char *dest, const size_t size) {
+              in.read(dest, static_cast<std::streamsize>(size));
+              return static_cast<size_t>(in.gcount());
+            },
+            capacity, total) {}
+
+  ~ReadAheadChannel() {
+    {
+      std::lock_guard<std::mutex> guard(lock);
+      stopped = true;
+    }
+    changed.notify_all();
+    reader.join();
+  }
+
+  // Read up to size bytes, short only at the end of the dat. Errors of the
+  // source are thrown here, once everything before them was read.
+  size_t read(char *dest, size_t size) {
+    size_t done = 0;
+    std::unique_lock<std::mutex> guard(lock);
+    while (done < size) {
+      changed.wait(guard, [this] { return !chunks.empty() || finished; });
+      if (chunks.empty()) {
+        if (error) {
+          std::rethrow_exception(error);
+        }
+        break;
+      }
+      auto &chunk = chunks.front();
+      const size_t n = std::min(size - done, chunk.size() - front_offset);
+      std::memcpy(dest + done, chunk.data() + front_offset, n);
+      done += n;
+      front_offset += n;
+      if (front_offset == chunk.size()) {
+        buffered -= chunk.size();
+        spare.push_back(std::move(chunk));
+        chunks.pop_front();
+        front_offset = 0;
+        changed.notify_all();
+      }
+    }
+    return done;
+  }
+
+  ReadAheadChannel(const ReadAheadChannel &) = delete;
+  ReadAheadChannel &operator=(const ReadAheadChannel &) = delete;
+
+private:
+  static constexpr size_t kChunkSize = 1 << 20;
+
+  const size_t capacity;
+  std::mutex lock;
+  std::condition_variable changed;
+  std::deque<std::vector<char>> chunks;
+  // Chunks which were read, to fill again instead of allocating new ones.
+  std::vector<std::vector<char>> spare;
+  size_t front_offset = 0;
+  size_t buffered = 0;
+  bool finished = false;
+  bool stopped = false;
+  std::exception_ptr error;
+  std::thread reader;
+
+  void fill(const Source &source, uint64_t left) {
+    while (left > 0) {
+      std::vector<char> chunk;
+      {
+        std::lock_guard<std::mutex> guard(lock);
+        if (!spare.empty()) {
+          chunk = std::move(spare.back());
+          spare.pop_back();
+        }
+      }
+      chunk.resize(std::min<uint64_t>(left, kChunkSize));
+      try {
+        chunk.resize(source(chunk.data(), chunk.size()));
+      } catch (...) {
+        std::lock_guard<std::mutex> guard(lock);
+        error = std::current_exception();
+        break;
+      }
+      left = chunk.empty() ? 0 : left - chunk.size();
+
+      std::unique_lock<std::mutex> guard(lock);
+      changed.wait(guard, [this] {
+        return stopped || buffered + kChunkSize <= capacity;
+      });
+      if (stopped) {
+        return;
+      }
+      buffered += chunk.size();
+      if (!chunk.empty()) {
+        chunks.push_back(std::move(chunk));
+      }
+      changed.notify_all();
+    }
+    std::lock_guard<std::mutex> guard(lock);
+    finished = true;
+    changed.notify_all();
+  }
+};
+
+// Run a program with the given arguments, found through PATH, and wait for
+// it. Returns its exit code, or -1 if it couldn't be run.
+int runProgram(const std::vector<std::string> &args) {
+#ifdef HAS_MMAP
+  std::vector<char *> argv;
+  for (const auto &arg : args) {
+    argv.push_back(const_cast<char *>(arg.c_str()));
+  }
+  argv.push_back(nullptr);
+  std::cout << std::flush;
+  const pid_t pid = fork();
+  if (pid == 0) {
+    execvp(argv[0], argv.data());
+    _exit(127);
+  }
+  int status = 0;
+  if (pid == -1 || waitpid(pid, &status, 0) == -1 || !WIFEXITED(status) ||
+      WEXITSTATUS(status) == 127) {
+    return -1;
+  }
+  return WEXITSTATUS(status);
+#else
+  std::string command;
+  for (const auto &arg : args) {
+    command += (command.empty() ? "\"" : " \"") + arg + "\"";
+  }
+  const int rc = std::system(command.c_str());
+  return rc < 0 ? -1 : rc;
+#endif
+}
+
+// Quote a value for the shell, so it is taken as one word whatever it holds.
+std::string shellQuote(const std::string &value) {
+#ifdef _WIN32
+  return "\"" + value + "\"";
+#else
+  std::string quoted = "'";
+  for (const char c : value) {
+    quoted += c == '\'' ? std::string("'\\''") : std::string(1, c);
+  }
+  return quoted + "'";
+#endif
+}
+
+// Run a command line through the shell and wait for it. Returns its exit
+// code, or -1 if it couldn't be run.
+int runShell(const std::string &command) {
+#ifdef HAS_MMAP
+  return runProgram({"/bin/sh", "-c", command});
+#else
+  std::cout << std::flush;
+  const int rc = std::system(command.c_str());
+  return rc < 0 ? -1 : rc;
+#endif
+}
+
+// Download a URL into a file with curl, which about every system has,
+// instead of linking a TLS library. With resume, a partial file is carried
+// on from where it stopped. Only the bytes in range are asked for if it's
+// given, like "0-99", or "-100" for the last 100, and the response headers
+// are saved into headers if that's given. Throws if it fails.
+void downloadFile(const std::string &url, const std::filesystem::path &file,
+                  const bool resume = false, const std::string &range = {},
+                  const std::filesystem::path &headers = {}) {
+  std::vector<std::string> args{"curl", "--fail", "--location", "--silent",
+                                "--show-error", "--retry", "3", "--output",
+                                file.string()};
+  if (resume) {
+    args.insert(args.end(), {"--continue-at", "-"});
+  }
+  if (!range.empty()) {
+    args.insert(args.end(), {"--range", range});
+  }
+  if (!headers.empty()) {
+    args.insert(args.end(), {"--dump-header", headers.string()});
+  }
+  args.push_back(url);
+  const int rc = runProgram(args);
+  if (rc == -1) {
+    throw KindError(ErrorKind::Unsupported,
+                    "Downloading " + url + " needs curl, which couldn't be "
+                                           "run");
+  }
+  if (rc != 0) {
+    throw KindError(ErrorKind::Io, "Downloading " + url +
+                                       " failed, curl exited with " +
+                                       std::to_string(rc));
+  }
+}
+
+// Whether an argument is a URL to download rather than a path.
+bool isUrl(const std::string_view arg) {
+  return arg.substr(0, 8) == "https://" || arg.substr(0, 7) == "http://";
+}
+
+// An entry in the central directory of a zip.
+struct ZipEntry {
+  std::string name;
+  uint16_t flags = 0;
+  uint16_t method = 0;
+  uint32_t crc = 0;
+  uint64_t compressed_size = 0;
+  uint64_t size = 0;
+  uint64_t header_offset = 0;
+};
+
+// Where an entry of a zip goes when extracted into dir. The names come from
+// the zip, so those which would land outside of dir are refused: absolute
+// ones, ones with a drive on Windows and ones with .. in them.
+std::filesystem::path zipEntryPath(const std::filesystem::path &dir,
+                                   const std::string &name) {
+  const std::filesystem::path path(name);
+  bool outside = name.empty() || path.has_root_path();
+  for (const auto &part : path) {
+    outside = outside || part == "..";
+  }
+  if (outside) {
+    throw KindError(ErrorKind::Parse,
+                    "The zip has an entry outside of it: " + name);
+  }
+  return dir / path;
+}
+
+// Parse the central directory of a zip, given its last bytes, which are at
+// tail_offset in it. read(offset, size) gets any other bytes it needs.
+// Zip64, which zips over 4 GiB need, is understood too.
+std::vector<ZipEntry>
+readZipDirectory(const std::string &tail, const uint64_t tail_offset,
+                 const std::function<std::string(uint64_t, uint64_t)> &read) {
+  const auto bytesAt = [&](const uint64_t offset, const uint64_t size) {
+    return offset >= tail_offset &&
+                   offset - tail_offset + size <= tail.size()
+               ? tail.substr(static_cast<size_t>(offset - tail_offset),
+                             static_cast<size_t>(size))
+               : read(offset, size);
+  };
+  // The end of central directory record, 22 bytes and a comment.
+  const auto eocd = tail.rfind(std::string("PK\5\6", 4));
+  if (eocd == std::string::npos || tail.size() - eocd < 22) {
+    throw KindError(ErrorKind::Parse,
+                    "Not a zip, it has no end of central directory");
+  }
+  const char *end = tail.data() + eocd;
+  uint64_t entries = readLittleEndian<uint16_t>(end + 10);
+  uint64_t dir_size = readLittleEndian<uint32_t>(end + 12);
+  uint64_t dir_offset = readLittleEndian<uint32_t>(end + 16);
+  if (eocd >= 20 && tail.compare(eocd - 20, 4, "PK\6\7") == 0) {
+    const auto record =
+        bytesAt(readLittleEndian<uint64_t>(end - 20 + 8), 56);
+    if (record.size() < 56 || record.compare(0, 4, "PK\6\6") != 0) {
+      throw KindError(ErrorKind::Parse,
+                      "The zip64 end of central directory is broken");
+    }
+    entries = readLittleEndian<uint64_t>(record.data() + 32);
+    dir_size = readLittleEndian<uint64_t>(record.data() + 40);
+    dir_offset = readLittleEndian<uint64_t>(record.data() + 48);
+  }
+  const auto dir = bytesAt(dir_offset, dir_size);
+  const auto broken = [] {
+    return KindError(ErrorKind::Parse,
+                     "The central directory of the zip is broken");
+  };
+  std::vector<ZipEntry> ret;
+  size_t at = 0;
+  for (uint64_t i = 0; i < entries; ++i) {
+    if (dir.size() - at < 46 || dir.compare(at, 4, "PK\1\2") != 0) {
+      throw broken();
+    }
+    const char *header = dir.data() + at;
+    const size_t name_size = readLittleEndian<uint16_t>(header + 28);
+    const size_t extra_size = readLittleEndian<uint16_t>(header + 30);
+    const size_t comment_size = readLittleEndian<uint16_t>(header + 32);
+    if (dir.size() - at - 46 < name_size + extra_size + comment_size) {
+      throw broken();
+    }
+    ZipEntry entry;
+    entry.name = dir.substr(at + 46, name_size);
+    entry.flags = readLittleEndian<uint16_t>(header + 8);
+    entry.method = readLittleEndian<uint16_t>(header + 10);
+    entry.crc = readLittleEndian<uint32_t>(header + 16);
+    entry.compressed_size = readLittleEndian<uint32_t>(header + 20);
+    entry.size = readLittleEndian<uint32_t>(header + 24);
+    entry.header_offset = readLittleEndian<uint32_t>(header + 42);
+    // Whichever of them don't fit are in the zip64 extra field, in this
+    // order.
+    const size_t extra_end = at + 46 + name_size + extra_size;
+    for (size_t x = at + 46 + name_size; x + 4 <= extra_end;) {
+      const uint16_t id = readLittleEndian<uint16_t>(dir.data() + x);
+      const size_t size = readLittleEndian<uint16_t>(dir.data() + x + 2);
+      if (id == 1) {
+        size_t field = x + 4;
+        for (uint64_t *value :
+             {&entry.size, &entry.compressed_size, &entry.header_offset}) {
+          if (*value == 0xffffffff && field + 8 <= x + 4 + size) {
+            *value = readLittleEndian<uint64_t>(dir.data() + field);
+            field += 8;
+          }
+        }
+      }
+      x += 4 + size;
+    }
+    at = extra_end + comment_size;
+    ret.push_back(std::move(entry));
+  }
+  return ret;
+}
+
+#ifdef HAVE_ZLIB
+// Inflate the raw deflate stream of a zip entry from one file into another.
+// Returns the size and CRC-32 of what came out.
+std::pair<uint64_t, uint32_t> inflateFile(const std::filesystem::path &from,
+                                          const std::filesystem::path &to) {
+  std::ifstream in(from, std::ios::binary);
+  std::ofstream out(to, std::ios::binary | std::ios::trunc);
+  if (unlikely(!in || !out)) {
+    throw IOException(!in ? from : to, "open");
+  }
+  z_stream stream{};
+  if (unlikely(inflateInit2(&stream, -15) != Z_OK)) {
+    throw std::runtime_error("Error creating zlib inflate state.");
+  }
+  std::vector<char> input(size_t{1} << 16), output(size_t{1} << 16);
+  uint64_t size = 0;
+  uint32_t crc = 0;
+  int rc = Z_OK;
+  while (rc != Z_STREAM_END) {
+    if (stream.avail_in == 0) {
+      in.read(input.data(), static_cast<std::streamsize>(input.size()));
+      stream.next_in = reinterpret_cast<Bytef *>(input.data());
+      stream.avail_in = static_cast<uInt>(in.gcount());
+      if (stream.avail_in == 0) {
+        break;
+      }
+    }
+    stream.next_out = reinterpret_cast<Bytef *>(output.data());
+    stream.avail_out = static_cast<uInt>(output.size());
+    rc = inflate(&stream, Z_NO_FLUSH);
+    if (rc != Z_OK && rc != Z_STREAM_END) {
+      break;
+    }
+    const size_t produced = output.size() - stream.avail_out;
+    out.write(output.data(), static_cast<std::streamsize>(produced));
+    crc = crc32(output.data(), produced, crc);
+    size += produced;
+  }
+  inflateEnd(&stream);
+  if (unlikely(rc != Z_STREAM_END)) {
+    throw KindError(ErrorKind::Parse,
+                    "The deflated data in " + quotedPath(from) +
+                        " is corrupt or cut short");
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(to, "write");
+  }
+  return {size, crc};
+}
+#endif
+
+// Throw if an entry of a zip can't be extracted by extractZipEntry.
+void checkZipMethod(const ZipEntry &entry) {
+  if (entry.flags & 1) {
+    throw KindError(ErrorKind::Unsupported,
+                    entry.name + " is encrypted in the zip");
+  }
+  if (entry.method != 0 && entry.method != 8) {
+    throw KindError(ErrorKind::Unsupported,
+                    entry.name + " is compressed with zip method " +
+                        std::to_string(entry.method) +
+                        ", only stored and deflated are supported");
+  }
+#ifndef HAVE_ZLIB
+  if (entry.method == 8) {
+    throw KindError(ErrorKind::Unsupported,
+                    entry.name + " is deflated, and this build has no zlib");
+  }
+#endif
+}
+
+// Extract an entry of a zip into dir and check its size and CRC-32.
+// read(offset, size) gets bytes of the zip, and copy(offset, size, to)
+// writes some of them into a file, so that the data goes to the disk
+// without being held in memory. Deflated data is copied to scratch and
+// inflated from there. Returns where the entry went.
+std::filesystem::path extractZipEntry(
+    const ZipEntry &entry, const std::filesystem::path &dir,
+    const std::filesystem::path &scratch,
+    const std::function<std::string(uint64_t, uint64_t)> &read,
+    const std::function<void(uint64_t, uint64_t,
+                             const std::filesystem::path &)> &copy) {
+  checkZipMethod(entry);
+  const auto header = read(entry.header_offset, 30);
+  if (header.size() < 30 || header.compare(0, 4, "PK\3\4") != 0) {
+    throw KindError(ErrorKind::Parse,
+                    "The local header of " + entry.name +
+                        " in the zip is broken");
+  }
+  const uint64_t data_offset =
+      entry.header_offset + 30 +
+      readLittleEndian<uint16_t>(header.data() + 26) +
+      readLittleEndian<uint16_t>(header.data() + 28);
+  const auto path = zipEntryPath(dir, entry.name);
+  std::filesystem::create_directories(path.parent_path());
+  if (entry.compressed_size == 0) {
+    std::ofstream(path, std::ios::trunc);
+  } else {
+    copy(data_offset, entry.compressed_size,
+         entry.method == 0 ? path : scratch);
+  }
+  uint64_t size = 0;
+  uint32_t crc = 0;
+#ifdef HAVE_ZLIB
+  if (entry.method == 8 && entry.compressed_size != 0) {
+    std::tie(size, crc) = inflateFile(scratch, path);
+    std::error_code ec;
+    std::filesystem::remove(scratch, ec);
+  }
+#endif
+  if (entry.method == 0) {
+    std::ifstream in(path, std::ios::binary);
+    std::vector<char> buffer(size_t{1} << 16);
+    while (in) {
+      in.read(buffer.data(), static_cast<std::streamsize>(buffer.size()));
+      const auto n = static_cast<size_t>(in.gcount());
+      crc = crc32(buffer.data(), n, crc);
+      size += n;
+    }
+  }
+  if (unlikely(size != entry.size || crc != entry.crc)) {
+    throw KindError(ErrorKind::Verification,
+                    entry.name + " from the zip doesn't have the size "
+                                 "and CRC-32 its directory gives");
+  }
+  return path;
+}
+
+// Download only the entries of a zip at a URL which wanted() picks, into
+// dir, with range requests for the central directory and their data.
+// Returns false if that can't be done, and the whole zip has to be
+// downloaded instead; if the server sent all of it anyway, it's in zip.
+bool fetchZipEntries(const std::string &url, const std::filesystem::path &zip,
+                     const std::function<bool(const std::string &)> &wanted,
+                     const std::filesystem::path &dir) {
+  // The end of central directory record is within the last 64 KiB and 22
+  // bytes, its comment being at most that long.
+  constexpr uint64_t kTailSize = (uint64_t{1} << 16) + 22;
+  const std::filesystem::path scratch = zip.string() + ".range";
+  const std::filesystem::path headers = zip.string() + ".headers";
+  std::error_code ec;
+  downloadFile(url, scratch, false, "-" + std::to_string(kTailSize),
+               headers);
+  // A partial response tells the size of the zip in its Content-Range, as
+  // bytes <first>-<last>/<size>, where the size may be * for unknown. Only
+  // the last response counts, after any redirects.
+  bool partial = false;
+  uint64_t zip_size = 0;
+  {
+    std::ifstream in(headers);
+    std::string line;
+    while (std::getline(in, line)) {
+      std::transform(line.begin(), line.end(), line.begin(),
+                     [](const unsigned char c) { return std::tolower(c); });
+      if (line.rfind("http/", 0) == 0) {
+        partial = false;
+        zip_size = 0;
+      } else if (line.rfind("content-range:", 0) == 0) {
+        partial = true;
+        zip_size = 0;
+        if (const auto slash = line.find('/'); slash != std::string::npos) {
+          std::string size = line.substr(slash + 1);
+          size.erase(size.find_last_not_of(" \t\r") + 1);
+          const char *end = size.data() + size.size();
+          if (std::from_chars(size.data(), end, zip_size).ptr != end) {
+            zip_size = 0;
+          }
+        }
+      }
+    }
+  }
+  std::filesystem::remove(headers, ec);
+  if (!partial) {
+    std::cout << "The server doesn't send parts of files, so all of it was "
+                 "downloaded"
+              << std::endl;
+    std::filesystem::rename(scratch, zip);
+    return false;
+  }
+  // Then there's only a part of it, which is no use without the size.
+  if (zip_size < std::filesystem::file_size(scratch) || zip_size == 0) {
+    std::cout << "The server doesn't tell the size of the zip, so all of it "
+                 "is downloaded"
+              << std::endl;
+    std::filesystem::remove(scratch, ec);
+    return false;
+  }
+  uint64_t fetched = std::filesystem::file_size(scratch);
+  const auto read = [&](const uint64_t offset, const uint64_t size) {
+    if (size == 0) {
+      return std::string();
+    }
+    downloadFile(url, scratch, false,
+                 std::to_string(offset) + "-" +
+                     std::to_string(offset + size - 1));
+    std::ifstream in(scratch, std::ios::binary);
+    std::string bytes((std::istreambuf_iterator<char>(in)),
+                      std::istreambuf_iterator<char>());
+    if (unlikely(bytes.size() != size)) {
+      throw KindError(ErrorKind::Io,
+                      "The server sent " + std::to_string(bytes.size()) +
+                          " bytes of " + url + " instead of " +
+                          std::to_string(size));
+    }
+    fetched += size;
+    return bytes;
+  };
+  std::string tail;
+  {
+    std::ifstream in(scratch, std::ios::binary);
+    tail.assign(std::istreambuf_iterator<char>(in),
+                std::istreambuf_iterator<char>());
+  }
+  std::vector<ZipEntry> entries;
+  try {
+    entries = readZipDirectory(tail, zip_size - tail.size(), read);
+  } catch (...) {
+    std::filesystem::remove(scratch, ec);
+    throw;
+  }
+  std::vector<ZipEntry> picked;
+  for (const auto &entry : entries) {
+    if (wanted(entry.name)) {
+      zipEntryPath(dir, entry.name);
+#ifndef HAVE_ZLIB
+      // unzip can still inflate it.
+      if (entry.method == 8 && !(entry.flags & 1)) {
+        std::filesystem::remove(scratch, ec);
+        return false;
+      }
+#endif
+      checkZipMethod(entry);
+      picked.push_back(entry);
+    }
+  }
+
+  const auto copy = [&](const uint64_t offset, const uint64_t size,
+                        const std::filesystem::path &to) {
+    downloadFile(url, to, false,
+                 std::to_string(offset) + "-" +
+                     std::to_string(offset + size - 1));
+    fetched += size;
+  };
+  for (const auto &entry : picked) {
+    std::cout << "Fetching " << entry.name << " (" << entry.compressed_size
+              << " bytes)..." << std::endl;
+    extractZipEntry(entry, dir, scratch, read, copy);
+  }
+  std::filesystem::remove(scratch, ec);
+  std::cout << "Downloaded " << fetched << " bytes of the " << zip_size
+            << " byte zip" << std::endl;
+  return true;
+}
+
+// Check the whole-file signature of an OTA zip with openssl, and show who
+// signed it. signapk puts a detached PKCS#7 signature into the comment of
+// the zip, over everything before the comment size, and ends the comment
+// with 6 bytes: where the signature starts, counted from the end of the
+// file, 0xffff and the comment size again. With a certificate, the signer
+// has to be that one, otherwise any signer is shown. Throws if the zip
+// isn't signed or the signature doesn't match.
+void verifyOtaSignature(const std::filesystem::path &zip,
+                        const std::filesystem::path &cert) {
+  std::ifstream in(zip, std::ios::binary);
+  if (unlikely(!in)) {
+    throw IOException(zip, "open");
+  }
+  const uint64_t zip_size = std::filesystem::file_size(zip);
+  std::array<char, 6> footer{};
+  if (zip_size >= 22 + footer.size()) {
+    in.seekg(checkedCast<std::streamoff>(zip_size - footer.size()));
+    in.read(footer.data(), footer.size());
+  }
+  const uint64_t sig_start = readLittleEndian<uint16_t>(footer.data());
+  const uint64_t comment_size = readLittleEndian<uint16_t>(footer.data() + 4);
+  std::array<char, 22> eocd{};
+  if (readLittleEndian<uint16_t>(footer.data() + 2) == 0xffff &&
+      comment_size + eocd.size() <= zip_size) {
+    in.seekg(checkedCast<std::streamoff>(zip_size - comment_size -
+                                         eocd.size()));
+    in.read(eocd.data(), eocd.size());
+  }
+  if (!in || std::memcmp(eocd.data(), "PK\5\6", 4) != 0 ||
+      readLittleEndian<uint16_t>(eocd.data() + 20) != comment_size ||
+      sig_start < footer.size() || sig_start > comment_size) {
+    throw KindError(ErrorKind::Verification,
+                    quotedPath(zip) + " has no whole-file signature");
+  }
+  std::string signature(static_cast<size_t>(sig_start - footer.size()),
+                        '\0');
+  in.seekg(checkedCast<std::streamoff>(zip_size - sig_start));
+  in.read(signature.data(), static_cast<std::streamsize>(signature.size()));
+  if (unlikely(!in)) {
+    throw IOException(zip, "read");
+  }
+  in.close();
+
+  const auto work = std::filesystem::temp_directory_path() /
+                    ("sdat2img-ota-" + std::to_string(std::random_device{}()));
+  std::filesystem::create_directories(work);
+  int rc = 0;
+  try {
+    std::ofstream(work / "signature.p7", std::ios::binary) << signature;
+    std::filesystem::copy_file(zip, work / "signed");
+    std::filesystem::resize_file(work / "signed", zip_size - comment_size - 2);
+    std::vector<std::string> args{
+        "openssl", "cms", "-verify", "-binary", "-inform", "DER", "-in",
+        (work / "signature.p7").string(), "-content",
+        (work / "signed").string(),
+        "-signer", (work / "signer.pem").string(), "-purpose", "any", "-out",
+#ifdef _WIN32
+        "NUL"};
+#else
+        "/dev/null"};
+#endif
+    if (cert.empty()) {
+      args.emplace_back("-noverify");
+    } else {
+      args.insert(args.end(), {"-CAfile", cert.string(), "-partial_chain"});
+    }
+    rc = runProgram(args);
+    if (rc == 0) {
+      std::cout << "Signed by:" << std::endl;
+      runProgram({"openssl", "x509", "-in", (work / "signer.pem").string(),
+                  "-noout", "-subject", "-fingerprint", "-sha256"});
+    }
+  } catch (...) {
+    std::error_code ec;
+    std::filesystem::remove_all(work, ec);
+    throw;
+  }
+  std::error_code ec;
+  std::filesystem::remove_all(work, ec);
+  if (rc == -1) {
+    throw KindError(ErrorKind::Unsupported,
+                    "Checking the signature needs openssl, which couldn't "
+                    "be run");
+  } else if (rc != 0) {
+    throw KindError(ErrorKind::Verification,
+                    "The signature of " + quotedPath(zip) +
+                        (cert.empty() ? " doesn't match it"
+                                      : " doesn't match it, or isn't by " +
+                                            quotedPath(cert)));
+  }
+  std::cout << "The signature of " << zip << " matches" << std::endl;
+}
+
+// Get what converting the partition needs out of an OTA zip into dir. All
+// transfer lists come along, so that a wrong partition name can be told
+// apart from a missing one. Unless whole is set, only those entries are
+// downloaded where the server allows. Otherwise the zip is downloaded into
+// the current directory, carrying on with the partial download of an
+// earlier run, and returned. With verify, which takes whole, its signature
+// is checked before anything is extracted.
+std::filesystem::path fetchOta(const std::string &url,
+                               const std::string &partition,
+                               const std::filesystem::path &dir,
+                               const bool whole, const bool verify = false,
+                               const std::filesystem::path &cert = {}) {
+  std::string name = url.substr(0, url.find_first_of("?#"));
+  name.erase(0, name.find_last_of('/') + 1);
+  const std::filesystem::path zip = name.empty() ? "ota.zip" : name;
+  const auto wanted = [&partition](const std::string &entry) {
+    constexpr std::string_view kList = ".transfer.list";
+    return (entry.size() > kList.size() &&
+            entry.compare(entry.size() - kList.size(), kList.size(),
+                          kList) == 0) ||
+           entry == partition + ".new.dat" ||
+           entry == partition + ".new.dat.br" ||
+           entry == "dynamic_partitions_op_list" ||
+           entry == "META-INF/com/android/metadata";
+  };
+  std::error_code ec;
+  if (std::filesystem::exists(zip, ec)) {
+    std::cout << "Using " << zip << ", downloaded before" << std::endl;
+  } else if (!whole && fetchZipEntries(url, zip, wanted, dir)) {
+    return {};
+  } else if (!std::filesystem::exists(zip, ec)) {
+    const std::filesystem::path part = zip.string() + ".part";
+    std::cout << "Downloading " << url << " to " << zip;
+    if (const auto done = std::filesystem::file_size(part, ec); !ec) {
+      std::cout << ", carrying on after " << done << " bytes";
+    }
+    std::cout << "..." << std::endl;
+    downloadFile(url, part, true);
+    std::filesystem::rename(part, zip);
+  }
+  if (verify) {
+    verifyOtaSignature(zip, cert);
+  }
+  const int rc = runProgram(
+      {"unzip", "-o", "-q", zip.string(), "*.transfer.list",
+       partition + ".new.dat", partition + ".new.dat.br",
+       "dynamic_partitions_op_list", "META-INF/com/android/metadata", "-d",
+       dir.string()});
+  if (rc == -1) {
+    throw KindError(ErrorKind::Unsupported,
+                    "Extracting " + quotedPath(zip) +
+                        " needs unzip, which couldn't be run");
+  }
+  // 11 is for names without a match, and not every OTA has all of them.
+  if (rc != 0 && rc != 11) {
+    throw KindError(ErrorKind::Io, "Extracting " + quotedPath(zip) +
+                                       " failed, unzip exited with " +
+                                       std::to_string(rc));
+  }
+  return zip;
+}
+
+// Compare dotted version numbers like 1.10.2, nothing if either isn't one.
+std::optional<int> compareVersions(const std::string &a, const std::string &b) {
+  const auto numbers = [](const std::string &version) {
+    std::optional<std::vector<unsigned long>> parts{std::in_place};
+    for (const auto &part : split(version, '.')) {
+      if (part.empty() ||
+          !std::all_of(part.begin(), part.end(), [](const char c) {
+            return std::isdigit(static_cast<unsigned char>(c));
+          })) {
+        return decltype(parts){};
+      }
+      parts->push_back(std::stoul(part));
+    }
+    return parts;
+  };
+  const auto left = numbers(a), right = numbers(b);
+  if (!left || !right || left->empty() || right->empty()) {
+    return std::nullopt;
+  }
+  return *left < *right ? -1 : *left > *right ? 1 : 0;
+}
+
+// The file of the running binary.
+std::filesystem::path runningBinary(const char *argv0) {
+  std::error_code ec;
+  if (auto self = std::filesystem::read_symlink("/proc/self/exe", ec); !ec) {
+    return self;
+  }
+  std::filesystem::path binary = argv0;
+  if (!binary.has_parent_path()) {
+    // Run through PATH.
+    const char *path = std::getenv("PATH");
+#ifdef _WIN32
+    const char separator = ';';
+#else
+    const char separator = ':';
+#endif
+    for (const auto &dir : split(path ? path : "", separator)) {
+      if (!dir.empty() && std::filesystem::exists(dir / binary, ec)) {
+        return std::filesystem::weakly_canonical(dir / binary, ec);
+      }
+    }
+  }
+  return std::filesystem::weakly_canonical(binary, ec);
+}
+
+// Replace the running binary with the one of the latest GitHub release if
+// that's newer, or any other version with force. Release binaries are
+// named sdat2img-<target> (.exe on Windows), and SHA256SUMS lists their
+// hashes the way sha256sum does. A download which doesn't match its hash is
+// never installed. Returns the exit code.
+int selfUpdate(const char *argv0, const bool check_only, const bool force) {
+  const std::string current = SDAT2IMG_VERSION;
+  const std::string api = "https://api.github.com/repos/" SDAT2IMG_RELEASES_REPO
+                          "/releases/latest";
+  std::random_device random;
+  const auto temp = std::filesystem::temp_directory_path() /
+                    ("sdat2img-" + std::to_string(random()) + "-release.json");
+  std::error_code ec;
+  Json release;
+  try {
+    downloadFile(api, temp);
+    std::ifstream in(temp, std::ios::binary);
+    std::ostringstream text;
+    text << in.rdbuf();
+    release = Json::parse(text.str());
+  } catch (...) {
+    std::filesystem::remove(temp, ec);
+    throw;
+  }
+  std::filesystem::remove(temp, ec);
+
+  std::string latest = release.at("tag_name", Json::Type::String).text;
+  if (!latest.empty() && latest[0] == 'v') {
+    latest.erase(0, 1);
+  }
+  const auto order = compareVersions(current, latest);
+  std::cout << "This is " << current << ", the latest release is " << latest
+            << std::endl;
+  if (check_only) {
+    return EXIT_SUCCESS;
+  }
+  if (!force && order && *order >= 0) {
+    std::cout << "Already up to date." << std::endl;
+    return EXIT_SUCCESS;
+  }
+  if (!force && !order) {
+    return reportError(ErrorKind::Unsupported,
+                       "Can't tell whether " + latest + " is newer than " +
+                           current + ", use --force to install it anyway");
+  }
+
+#ifdef _WIN32
+  const std::string wanted = "sdat2img-" + buildTarget() + ".exe";
+#else
+  const std::string wanted = "sdat2img-" + buildTarget();
+#endif
+  std::string binary_url, sums_url;
+  for (const auto &asset : release.at("assets", Json::Type::Array).items) {
+    const auto &name = asset.at("name", Json::Type::String).text;
+    const auto &url = asset.at("browser_download_url", Json::Type::String).text;
+    if (name == wanted) {
+      binary_url = url;
+    } else if (name == "SHA256SUMS") {
+      sums_url = url;
+    }
+  }
+  if (binary_url.empty()) {
+    return reportError(ErrorKind::Unsupported, "Release " + latest +
+                                                   " has no " + wanted +
+                                                   " for this system");
+  }
+  if (sums_url.empty()) {
+    return reportError(ErrorKind::Verification,
+                       "Release " + latest +
+                           " has no SHA256SUMS to check the download against");
+  }
+
+  // Next to the binary, so that it can be renamed over it.
+  const auto binary = runningBinary(argv0);
+  const std::filesystem::path update = binary.string() + ".new";
+  const std::filesystem::path sums = binary.string() + ".sha256sums";
+  std::string expected;
+  try {
+    downloadFile(sums_url, sums);
+    TextFile sums_file(sums);
+    for (std::string line; sums_file.takeOneLine(&line);) {
+      std::string hex, name;
+      if (std::istringstream(line) >> hex >> name &&
+          (name == wanted || name == "*" + wanted)) {
+        expected = hex;
+      }
+    }
+    std::filesystem::remove(sums, ec);
+    std::cout << "Downloading " << binary_url << "..." << std::endl;
+    downloadFile(binary_url, update);
+  } catch (...) {
+    std::filesystem::remove(sums, ec);
+    std::filesystem::remove(update, ec);
+    throw;
+  }
+  if (expected.empty() || sha256File(update) != expected) {
+    std::filesystem::remove(update, ec);
+    return reportError(ErrorKind::Verification,
+                       expected.empty()
+                           ? "SHA256SUMS of release " + latest +
+                                 " has no hash of " + wanted
+                           : "The download of " + wanted +
+                                 " doesn't match its hash in SHA256SUMS",
+                       binary_url);
+  }
+  std::filesystem::permissions(
+      update, std::filesystem::status(binary).permissions(), ec);
+#ifdef _WIN32
+  // A running binary can't be replaced, but it can be moved aside.
+  const std::filesystem::path old = binary.string() + ".old";
+  std::filesystem::remove(old, ec);
+  std::filesystem::rename(binary, old);
+#endif
+  std::filesystem::rename(update, binary);
+  std::cout << "Updated " << binary << " from " << current << " to " << latest
+            << std::endl;
+  return EXIT_SUCCESS;
+}
+
+// Run the command of --exec-before or --exec-after for a conversion, with
+// its {output}, {transfer_list}, {dat}, {partition} and {size} filled in.
+// Returns whether it succeeded.
+bool runHook(const std::string &command, const std::string_view option,
+             const std::filesystem::path &transfer_list_file,
+             const std::filesystem::path &new_dat_file,
+             const std::filesystem::path &output_img) {
+  // Named after the transfer list like in directory mode, or the output.
+  std::string partition = transfer_list_file.filename().string();
+  if (const auto suffix = partition.rfind(".transfer.list");
+      suffix != std::string::npos && suffix != 0) {
+    partition.resize(suffix);
+  } else {
+    partition = output_img.stem().string();
+  }
+  std::error_code ec;
+  const auto size = std::filesystem::file_size(output_img, ec);
+  const std::string expanded =
+      expandTemplate(command, {{"output", shellQuote(output_img.string())},
+                               {"transfer_list",
+                                shellQuote(transfer_list_file.string())},
+                               {"dat", shellQuote(new_dat_file.string())},
+                               {"partition", shellQuote(partition)},
+                               {"size", std::to_string(ec ? 0 : size)}});
+  std::cout << "Running " << option << ": " << expanded << std::endl;
+  if (const int rc = runShell(expanded); rc != 0) {
+    reportError(ErrorKind::Other,
+                std::string(option) +
+                    (rc < 0 ? " couldn't be run"
+                            : " failed with exit code " + std::to_string(rc)));
+    return false;
+  }
+  return true;
+}
+
+// Size in bytes of the ext2, ext3 or ext4 filesystem in an image, from its
+// superblock, or nothing if it holds none. Newer Android builds use EROFS
+// for read-only partitions.
+std::optional<uint64_t> extFilesystemSize(const std::filesystem::path &image) {
+  std::ifstream file(image, std::ios::binary);
+  std::array<char, 1024> super{};
+  if (!file.seekg(1024).read(super.data(), super.size()) ||
+      readLittleEndian<uint16_t>(&super[0x38]) != 0xEF53) {
+    return std::nullopt;
+  }
+  uint64_t blocks = readLittleEndian<uint32_t>(&super[0x04]);
+  // The high half of the block count is only there with the 64bit feature.
+  if (readLittleEndian<uint32_t>(&super[0x60]) & 0x80) {
+    blocks |= uint64_t{readLittleEndian<uint32_t>(&super[0x150])} << 32;
+  }
+  const auto log_block_size = readLittleEndian<uint32_t>(&super[0x18]);
+  if (log_block_size > 6) {
+    return std::nullopt;
+  }
+  return checkedMul<uint64_t>(blocks, uint64_t{1024} << log_block_size);
+}
+
+// Check the filesystem of an image with e2fsck, without changing anything.
+// Returns false if it found problems or couldn't run.
+bool checkFilesystem(const std::string &e2fsck,
+                     const std::filesystem::path &image) {
+  if (!extFilesystemSize(image)) {
+    std::cout << "Filesystem check: " << image << " isn't ext2/3/4, skipped"
+              << std::endl;
+    return true;
+  }
+  std::cout << "Checking the filesystem with " << e2fsck << "..."
+            << std::endl;
+  const int rc = runProgram({e2fsck, "-f", "-n", image.string()});
+  if (rc == -1) {
+    std::cerr << "Error: Couldn't run " << e2fsck << ", point --e2fsck at it"
+              << std::endl;
+    return false;
+  }
+  // With -n nothing gets fixed, so anything but 0 means errors are left.
+  std::cout << "Filesystem check: "
+            << (rc == 0 ? "clean"
+                        : "FAILED (e2fsck exit code " + std::to_string(rc) +
+                              ")")
+            << std::endl;
+  return rc == 0;
+}
+
+// Shrink the ext2/3/4 filesystem of an image to its minimum size with
+// resize2fs, and cut the file down to it. Returns false if that failed.
+bool shrinkFilesystem(const std::string &resize2fs,
+                      const std::string &e2fsck,
+                      const std::filesystem::path &image) {
+  if (!extFilesystemSize(image)) {
+    std::cout << "Shrinking: " << image << " isn't ext2/3/4, skipped"
+              << std::endl;
+    return true;
+  }
+  // resize2fs wants a freshly checked filesystem, and -f skips asking for
+  // that, so check it here instead, without changing anything.
+  if (!checkFilesystem(e2fsck, image)) {
+    std::cerr << "Error: Not shrinking a filesystem with errors" << std::endl;
+    return false;
+  }
+  const auto before = std::filesystem::file_size(image);
+  std::cout << "Shrinking the filesystem with " << resize2fs << "..."
+            << std::endl;
+  const int rc = runProgram({resize2fs, "-f", "-M", image.string()});
+  if (rc != 0) {
+    std::cerr << "Error: "
+              << (rc == -1 ? "Couldn't run " + resize2fs +
+                                 ", point --resize2fs at it"
+                           : resize2fs + " failed with exit code " +
+                                 std::to_string(rc))
+              << std::endl;
+    return false;
+  }
+  // resize2fs truncates regular files itself, but anything after the
+  // filesystem, like an AVB footer, would be left otherwise.
+  const auto size = extFilesystemSize(image).value_or(before);
+  if (size < std::filesystem::file_size(image)) {
+    std::filesystem::resize_file(image, size);
+  }
+  std::cout << "Shrunk the image from " << before << " to " << size
+            << " bytes" << std::endl;
+  return true;
+}
+
+// A TWRP backup of a partition, "<name>.<filesystem>.win". Partitions
+// backed up as images, like "boot.emmc.win", hold the raw image. The others
+// are tar archives of the files under the mount point, gzipped if
+// compression was on, and split into .win000, .win001 and so on when
+// large. Each file has its SHA-256 in a ".sha2" file next to it, as a
+// sha256sum line.
+struct TwrpBackup {
+  // Data in each archive TWRP writes, at most.
+  static constexpr uint64_t kPartSize = uint64_t{3} << 29;
+  // Parts are numbered with three digits.
+  static constexpr size_t kMaxParts = 1000;
+
+  // The name of part i of a backup split into several, base + "000" on.
+  static std::string partName(const std::string &base, const size_t i) {
+    const auto number = std::to_string(i);
+    return base + std::string(number.size() < 3 ? 3 - number.size() : 0, '0') +
+           number;
+  }
+
+  std::string name;
+  std::string filesystem;
+  std::vector<std::filesystem::path> parts;
+
+  // The backup at path, which may also be its first part.
+  static TwrpBackup find(const std::filesystem::path &path) {
+    std::string base = path.string();
+    const auto endsWith = [&base](const std::string_view suffix) {
+      return base.size() >= suffix.size() &&
+             base.compare(base.size() - suffix.size(), suffix.size(),
+                          suffix) == 0;
+    };
+    if (base.size() > 7 && base.compare(base.size() - 7, 4, ".win") == 0 &&
+        std::all_of(base.end() - 3, base.end(),
+                    [](const char c) { return std::isdigit(c); })) {
+      base.resize(base.size() - 3);
+    }
+    if (!endsWith(".win")) {
+      throw KindError(ErrorKind::Parse,
+                      quotedPath(path) +
+                          " isn't a TWRP backup, their names end in .win");
+    }
+    TwrpBackup backup;
+    std::error_code ec;
+    if (std::filesystem::is_regular_file(base, ec)) {
+      backup.parts.push_back(base);
+    } else {
+      for (size_t i = 0; i < kMaxParts; ++i) {
+        const std::filesystem::path part = partName(base, i);
+        if (!std::filesystem::is_regular_file(part, ec)) {
+          break;
+        }
+        backup.parts.push_back(part);
+      }
+    }
+    if (backup.parts.empty()) {
+      throw KindError(ErrorKind::Io, "No TWRP backup at " + quotedPath(path));
+    }
+    const auto stem = std::filesystem::path(base).stem().string();
+    const auto dot = stem.rfind('.');
+    backup.name = stem.substr(0, dot);
+    backup.filesystem = dot == std::string::npos ? "" : stem.substr(dot + 1);
+    return backup;
+  }
+
+  // Check each part against its .sha2. Older TWRP versions wrote MD5s
+  // instead, which aren't checked.
+  void verify() const {
+    size_t checked = 0;
+    for (const auto &part : parts) {
+      const auto digest_file = part.string() + ".sha2";
+      std::ifstream in(digest_file);
+      std::string expected;
+      if (!(in >> expected)) {
+        std::cerr << "Warning: " << part << " has no .sha2, not checked"
+                  << std::endl;
+        continue;
+      }
+      std::transform(expected.begin(), expected.end(), expected.begin(),
+                     [](const unsigned char c) { return std::tolower(c); });
+      if (sha256File(part) != expected) {
+        throw KindError(ErrorKind::Verification,
+                        quotedPath(part) + " doesn't match the SHA-256 of " +
+                            quotedPath(digest_file));
+      }
+      ++checked;
+    }
+    std::cout << "Checked the SHA-256 of " << checked << " of "
+              << parts.size() << " parts" << std::endl;
+  }
+
+  // Whether the backup is of the files rather than an image.
+  bool isArchive() const {
+    std::array<char, 512> header{};
+    std::ifstream in(parts.front(), std::ios::binary);
+    in.read(header.data(), header.size());
+    if (std::string_view(header.data(), 4) == "OAES") {
+      throw KindError(ErrorKind::Unsupported,
+                      quotedPath(parts.front()) +
+                          " is encrypted, restore it with TWRP instead");
+    }
+    return (header[0] == '\x1f' && header[1] == '\x8b') ||
+           std::string_view(header.data() + 257, 5) == "ustar";
+  }
+};
+
+// Make a raw image out of a TWRP backup. Images are joined from their
+// parts. Archives are unpacked next to the output with tar, keeping owners,
+// modes and SELinux labels, which takes root, and made into a filesystem
+// with mke2fs. It's image_size bytes, or if 0 as large as the files need
+// with some room to spare, for --shrink to trim.
+void restoreTwrpBackup(const std::filesystem::path &path,
+                       const std::filesystem::path &output,
+                       const size_t buffer_size, const std::string &mke2fs,
+                       const uint64_t image_size) {
+  const auto backup = TwrpBackup::find(path);
+  std::cout << "TWRP backup of " << backup.name << " ("
+            << (backup.filesystem.empty() ? "unknown" : backup.filesystem)
+            << "), " << backup.parts.size() << " parts" << std::endl;
+  backup.verify();
+  if (!backup.isArchive()) {
+    std::ofstream out(output, std::ios::binary | std::ios::trunc);
+    if (unlikely(!out)) {
+      throw IOException(output, "open");
+    }
+    std::vector<char> buffer(buffer_size);
+    for (const auto &part : backup.parts) {
+      std::ifstream in(part, std::ios::binary);
+      while (in) {
+        in.read(buffer.data(), static_cast<std::streamsize>(buffer.size()));
+        out.write(buffer.data(), in.gcount());
+      }
+      if (unlikely(in.bad())) {
+        throw IOException(part, "read");
+      }
+    }
+    if (unlikely(!out.flush())) {
+      throw IOException(output, "write");
+    }
+    std::cout << "Wrote the image of " << backup.name << " into " << output
+              << std::endl;
+    return;
+  }
+
+  if (backup.filesystem != "ext2" && backup.filesystem != "ext3" &&
+      backup.filesystem != "ext4") {
+    throw KindError(ErrorKind::Unsupported,
+                    "Only ext2/3/4 backups of files can be made into an "
+                    "image, not " +
+                        (backup.filesystem.empty() ? std::string("unknown")
+                                                   : backup.filesystem));
+  }
+  const std::filesystem::path files = output.string() + ".files";
+  std::error_code ec;
+  if (std::filesystem::exists(files, ec)) {
+    throw KindError(ErrorKind::Other,
+                    quotedPath(files) + " is in the way, move it first");
+  }
+  std::filesystem::create_directory(files);
+  try {
+    for (const auto &part : backup.parts) {
+      std::cout << "Unpacking " << part << "..." << std::endl;
+      const int rc =
+          runProgram({"tar", "-x", "-p", "--numeric-owner", "--xattrs",
+                      "--xattrs-include=*", "--selinux", "-f", part.string(),
+                      "-C", files.string()});
+      if (rc != 0) {
+        throw KindError(ErrorKind::Other,
+                        rc == -1 ? std::string("Couldn't run tar")
+                                 : "tar failed with exit code " +
+                                       std::to_string(rc) + " on " +
+                                       quotedPath(part));
+      }
+    }
+    // The archives hold the files under the mount point, like
+    // system/build.prop.
+    auto root = files;
+    if (const auto entries = std::distance(
+            std::filesystem::directory_iterator(files),
+            std::filesystem::directory_iterator());
+        entries == 1) {
+      const auto only = *std::filesystem::directory_iterator(files);
+      if (only.is_directory() && !only.is_symlink()) {
+        root = only.path();
+      }
+    }
+    uint64_t size = image_size;
+    if (size == 0) {
+      // The data in whole blocks, an inode's worth for each file, and a
+      // tenth more plus the journal.
+      uint64_t used = 0;
+      for (const auto &entry :
+           std::filesystem::recursive_directory_iterator(root)) {
+        used += BLOCK_SIZE;
+        if (entry.is_regular_file() && !entry.is_symlink()) {
+          used += (entry.file_size() + BLOCK_SIZE - 1) / BLOCK_SIZE *
+                  BLOCK_SIZE;
+        }
+      }
+      size = used + used / 10 + (uint64_t{64} << 20);
+    }
+    const uint64_t blocks = size / BLOCK_SIZE;
+    std::filesystem::remove(output, ec);
+    std::cout << "Making the " << backup.filesystem << " filesystem ("
+              << blocks * BLOCK_SIZE << " bytes) with " << mke2fs << "..."
+              << std::endl;
+    const int rc = runProgram({mke2fs, "-q", "-t", backup.filesystem, "-b",
+                               std::to_string(BLOCK_SIZE), "-d",
+                               root.string(), output.string(),
+                               std::to_string(blocks)});
+    if (rc != 0) {
+      throw KindError(ErrorKind::Other,
+                      rc == -1 ? "Couldn't run " + mke2fs +
+                                     ", point --mke2fs at it"
+                               : mke2fs + " failed with exit code " +
+                                     std::to_string(rc));
+    }
+  } catch (...) {
+    std::filesystem::remove_all(files, ec);
+    throw;
+  }
+  std::filesystem::remove_all(files, ec);
+  std::cout << "Made the image of " << backup.name << " into " << output
+            << std::endl;
+}
+
+// Write the .sha2 of a file of a TWRP backup next to it.
+void writeTwrpDigest(const std::filesystem::path &file) {
+  const auto digest_file = file.string() + ".sha2";
+  std::ofstream out(digest_file, std::ios::trunc);
+  out << sha256File(file) << "  " << file.filename().string() << "\n";
+  if (unlikely(!out.flush())) {
+    throw IOException(digest_file, "write");
+  }
+}
+
+// Package an image as a TWRP backup of a partition in dir, for TWRP to
+// restore. ext2/3/4 images are backed up as their files, like TWRP does:
+// the image is mounted read-only, which takes root, and its files packed
+// into tar archives of at most part_size bytes of data each, with their
+// owners, modes, SELinux labels and capabilities. Other images, and
+// partitions named like TWRP's image backups ("system_image"), are backed
+// up as the image itself, which TWRP doesn't split.
+void makeTwrpBackup(const std::filesystem::path &image,
+                    const std::filesystem::path &dir,
+                    const std::string &partition, const uint64_t part_size) {
+  std::filesystem::create_directories(dir);
+  // What's left of an earlier backup of the partition would be restored
+  // along with this one.
+  for (const auto &entry : std::filesystem::directory_iterator(dir)) {
+    const auto name = entry.path().filename().string();
+    if (name.rfind(partition + ".", 0) == 0 &&
+        name.find(".win") != std::string::npos) {
+      std::filesystem::remove(entry.path());
+    }
+  }
+  const bool as_image =
+      !extFilesystemSize(image) ||
+      (partition.size() > 6 &&
+       partition.compare(partition.size() - 6, 6, "_image") == 0);
+  std::vector<std::filesystem::path> files;
+  uint64_t backup_size = 0;
+  if (as_image) {
+    files.push_back(dir / (partition + ".emmc.win"));
+    std::cout << "Copying " << image << " into " << files.back() << "..."
+              << std::endl;
+    std::filesystem::copy_file(
+        image, files.back(),
+        std::filesystem::copy_options::overwrite_existing);
+    backup_size = std::filesystem::file_size(files.back());
+  } else {
+    const auto mounts = std::filesystem::temp_directory_path() /
+                        ("sdat2img-twrp-" +
+                         std::to_string(std::random_device{}()));
+    const auto mount_point = mounts / partition;
+    std::filesystem::create_directories(mount_point);
+    std::error_code ec;
+    const int rc =
+        runProgram({"mount", "-t", "ext4", "-o", "ro,loop,noload",
+                    image.string(), mount_point.string()});
+    if (rc != 0) {
+      std::filesystem::remove_all(mounts, ec);
+      throw KindError(ErrorKind::Other,
+                      "Couldn't mount " + quotedPath(image) +
+                          " to back up its files, which takes root");
+    }
+    try {
+      // The directories go into the first archive, the other files into
+      // as many as it takes, TWRP's names being relative to the root.
+      std::vector<std::string> lists(1, partition + '\0');
+      std::vector<uint64_t> sizes(1);
+      for (auto it = std::filesystem::recursive_directory_iterator(
+               mount_point);
+           it != std::filesystem::recursive_directory_iterator(); ++it) {
+        const auto status = it->symlink_status();
+        const auto name =
+            (std::filesystem::path(partition) /
+             it->path().lexically_relative(mount_point))
+                .string();
+        if (std::filesystem::is_directory(status)) {
+          if (it.depth() == 0 && it->path().filename() == "lost+found") {
+            it.disable_recursion_pending();
+          } else {
+            lists.front() += name + '\0';
+          }
+          continue;
+        }
+        const uint64_t size =
+            std::filesystem::is_regular_file(status) ? it->file_size() : 0;
+        if (sizes.back() != 0 && sizes.back() + size > part_size) {
+          lists.emplace_back();
+          sizes.push_back(0);
+        }
+        lists.back() += name + '\0';
+        sizes.back() += size;
+        backup_size += size;
+      }
+      // TWRP names one archive .win, and several .win000, .win001 and so
+      // on.
+      const auto base = (dir / (partition + ".ext4.win")).string();
+      const auto list_file = mounts / "files";
+      if (lists.size() > TwrpBackup::kMaxParts) {
+        throw KindError(ErrorKind::Other,
+                        "The files need more than " +
+                            std::to_string(TwrpBackup::kMaxParts) +
+                            " archives, use a larger --split");
+      }
+      for (size_t i = 0; i < lists.size(); ++i) {
+        files.push_back(lists.size() == 1 ? base
+                                          : TwrpBackup::partName(base, i));
+        std::ofstream(list_file, std::ios::binary | std::ios::trunc)
+            << lists[i];
+        std::cout << "Packing " << sizes[i] << " bytes into " << files.back()
+                  << "..." << std::endl;
+        const int tar_rc = runProgram(
+            {"tar", "-c", "-f", files.back().string(), "--format=posix",
+             "--numeric-owner", "--selinux", "--xattrs",
+             "--xattrs-include=security.capability", "--no-recursion",
+             "-C", mounts.string(), "--null", "-T", list_file.string()});
+        if (tar_rc != 0) {
+          throw KindError(ErrorKind::Other,
+                          tar_rc == -1 ? std::string("Couldn't run tar")
+                                       : "tar failed with exit code " +
+                                             std::to_string(tar_rc));
+        }
+      }
+    } catch (...) {
+      runProgram({"umount", mount_point.string()});
+      std::filesystem::remove_all(mounts, ec);
+      throw;
+    }
+    runProgram({"umount", mount_point.string()});
+    std::filesystem::remove_all(mounts, ec);
+  }
+  for (const auto &file : files) {
+    writeTwrpDigest(file);
+  }
+  // TWRP's settings format, which it reads the size from to show the
+  // progress: a version of 0 as an int, then each key and value as a
+  // 16-bit length, which counts the NUL ending it, and the string.
+  const auto info_file = dir / (partition + ".info");
+  std::string info;
+  appendLittleEndian<uint32_t>(info, 0);
+  const std::map<std::string, std::string> values{
+      {"backup_display_name", partition},
+      {"backup_size", std::to_string(backup_size)}};
+  for (const auto &[key, value] : values) {
+    for (const auto &text : {key, value}) {
+      appendLittleEndian<uint16_t>(info,
+                                   checkedCast<uint16_t>(text.size() + 1));
+      info.append(text.c_str(), text.size() + 1);
+    }
+  }
+  std::ofstream out(info_file, std::ios::binary | std::ios::trunc);
+  if (unlikely(!out.write(info.data(),
+                          static_cast<std::streamsize>(info.size())) ||
+               !out.flush())) {
+    throw IOException(info_file, "write");
+  }
+  if (as_image) {
+    std::cout << "Backed up the image of " << partition << " into " << dir
+              << std::endl;
+  } else {
+    std::cout << "Backed up the files of " << partition << " into " << dir
+              << ", " << files.size()
+              << (files.size() == 1 ? " archive" : " archives") << std::endl;
+  }
+}
+
+// Make sure the data written to a file is on the disk.
+bool syncFile([[maybe_unused]] const std::filesystem::path &path) {
+#ifdef HAS_MMAP
+  const int fd = open(path.c_str(), O_RDONLY);
+  if (fd == -1) {
+    return false;
+  }
+  const bool ok = fsync(fd) == 0;
+  close(fd);
+  return ok;
+#else
+  return true;
+#endif
+}
+
+// A range of blocks of the image, and the hash of what was written into it.
+struct RangeHash {
+  FileSizeT begin;
+  FileSizeT end;
+  Sha256::Digest digest;
+};
+
+// Which of the ranges, in the order they were written, were written over
+// in part by a later one, so that they don't hold their data anymore.
+std::vector<bool> overwrittenRanges(const std::vector<RangeHash> &ranges) {
+  std::vector<size_t> by_begin(ranges.size());
+  std::iota(by_begin.begin(), by_begin.end(), size_t{0});
+  std::sort(by_begin.begin(), by_begin.end(), [&ranges](auto a, auto b) {
+    return ranges[a].begin < ranges[b].begin;
+  });
+  std::vector<bool> overwritten(ranges.size());
+  std::vector<size_t> active;
+  for (const auto i : by_begin) {
+    active.erase(std::remove_if(active.begin(), active.end(),
+                                [&](const size_t j) {
+                                  return ranges[j].end <= ranges[i].begin;
+                                }),
+                 active.end());
+    for (const auto j : active) {
+      overwritten[std::min(i, j)] = true;
+    }
+    active.push_back(i);
+  }
+  return overwritten;
+}
+
+// Hash blocks begin to end of an image, or nothing if they can't be read.
+std::optional<Sha256::Digest> hashRange(std::istream &in,
+                                        const FileSizeT begin,
+                                        const FileSizeT end,
+                                        std::vector<char> &buffer,
+                                        const HashAlgorithm algorithm,
+                                        const unsigned threads = 1) {
+  DataHash hash(algorithm, threads);
+  in.clear();
+  in.seekg(checkedCast<std::streamoff>(begin * BLOCK_SIZE));
+  for (auto left = static_cast<uint64_t>(end - begin) * BLOCK_SIZE;
+       left > 0;) {
+    const auto n = static_cast<size_t>(std::min<uint64_t>(left, buffer.size()));
+    if (!in.read(buffer.data(), static_cast<std::streamsize>(n))) {
+      return std::nullopt;
+    }
+    hash.update(buffer.data(), n);
+    left -= n;
+  }
+  return hash.digest();
+}
+
+// Hashes of the ranges of an image, saved next to it to check it against
+// later. A line of "<begin> <end> <hash>" for each range, in blocks like
+// the transfer list. The hashes are SHA-256, or BLAKE3 if the first line
+// ends in " blake3".
+struct RangeManifest {
+  static constexpr std::string_view kMagic = "sdat2img-ranges 1";
+
+  static std::vector<RangeHash> load(const std::filesystem::path &path,
+                                     HashAlgorithm *algorithm) {
+    TextFile file(path);
+    std::string line;
+    const std::string blake3_magic = std::string(kMagic) + " blake3";
+    if (!file.takeOneLine(&line) || (line != kMagic && line != blake3_magic)) {
+      throw TextFileError::expected(file, "'" + std::string(kMagic) + "'");
+    }
+    *algorithm =
+        line == kMagic ? HashAlgorithm::Sha256 : HashAlgorithm::Blake3;
+    std::vector<RangeHash> ranges;
+    while (file.takeOneLine(&line)) {
+      RangeHash range{};
+      std::string hex;
+      if (!(std::stringstream(line) >> range.begin >> range.end >> hex) ||
+          range.begin < 0 || range.begin >= range.end ||
+          range.end > std::numeric_limits<FileSizeT>::max() / BLOCK_SIZE ||
+          hex.size() != 2 * range.digest.size() ||
+          !std::all_of(hex.begin(), hex.end(),
+                       [](const char c) { return std::isxdigit(c); })) {
+        throw TextFileError::expected(file, "'<begin> <end> <hash>'");
+      }
+      const auto digest = fromHex(hex);
+      std::copy(digest.begin(), digest.end(), range.digest.begin());
+      ranges.push_back(range);
+    }
+    return ranges;
+  }
+
+  // Save the ranges which still hold what was written into them, and
+  // aren't past the end of the image.
+  static void save(const std::filesystem::path &path,
+                   const std::vector<RangeHash> &ranges,
+                   const uint64_t image_size,
+                   const HashAlgorithm algorithm) {
+    const auto overwritten = overwrittenRanges(ranges);
+    std::ofstream file(path);
+    file << kMagic;
+    if (algorithm != HashAlgorithm::Sha256) {
+      file << " " << hashName(algorithm);
+    }
+    file << "\n";
+    for (size_t i = 0; i < ranges.size(); ++i) {
+      const auto &range = ranges[i];
+      if (!overwritten[i] &&
+          static_cast<uint64_t>(range.end) * BLOCK_SIZE <= image_size) {
+        file << range.begin << " " << range.end << " "
+             << toHex(range.digest.data(), range.digest.size()) << "\n";
+      }
+    }
+    if (unlikely(!file.flush())) {
+      throw IOException(path, "write");
+    }
+  }
+};
+
+// Append-only record of what a conversion wrote into its output, so that
+// what was done to a device can be proven afterwards. After a
+// "sdat2img-audit 1" line at the top, each run adds a "run <utc_time>
+// <version>" line, "output" and "input" lines with the paths, a "write
+// <offset> <length> <dat_offset|zero> <sha256>" line for each write, in
+// bytes of the image and the dat, and a "done <utc_time> <writes> <bytes>"
+// line once the output was closed. Runs which failed have no "done". Runs
+// hashing with BLAKE3 instead say so with a "hash blake3" line after the
+// input.
+class AuditLog {
+public:
+  static constexpr std::string_view kMagic = "sdat2img-audit 1";
+
+  AuditLog(const std::filesystem::path &path,
+           const std::filesystem::path &output,
+           const std::filesystem::path &input,
+           const HashAlgorithm algorithm)
+      : path(path), algorithm(algorithm) {
+    std::error_code ec;
+    const bool empty = std::filesystem::file_size(path, ec) == 0 || ec;
+    file.open(path, std::ios::app);
+    if (unlikely(!file)) {
+      throw IOException(path, "open");
+    }
+    if (empty) {
+      file << kMagic << "\n";
+    }
+    file << "run " << utcNow() << " " << SDAT2IMG_VERSION << "\n"
+         << "output " << std::filesystem::absolute(output, ec).string()
+         << "\n"
+         << "input " << std::filesystem::absolute(input, ec).string()
+         << "\n";
+    if (algorithm != HashAlgorithm::Sha256) {
+      file << "hash " << hashName(algorithm) << "\n";
+    }
+    flush();
+  }
+
+  // Each line is flushed, so the log is complete up to a crash.
+  void record(const uint64_t offset, const uint64_t length,
+              const std::optional<uint64_t> dat_offset,
+              const Sha256::Digest &digest) {
+    file << "write " << offset << " " << length << " ";
+    if (dat_offset) {
+      file << *dat_offset;
+    } else {
+      file << "zero";
+    }
+    file << " " << toHex(digest.data(), digest.size()) << "\n";
+    flush();
+    ++writes;
+    bytes += length;
+  }
+
+  void recordZeros(const uint64_t offset, const uint64_t length) {
+    const std::vector<char> zeros(size_t{1} << 20);
+    DataHash hash(algorithm);
+    for (uint64_t left = length; left > 0;) {
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(left, zeros.size()));
+      hash.update(zeros.data(), n);
+      left -= n;
+    }
+    record(offset, length, std::nullopt, hash.digest());
+  }
+
+  void finish() {
+    file << "done " << utcNow() << " " << writes << " " << bytes << "\n";
+    flush();
+  }
+
+private:
+  std::filesystem::path path;
+  HashAlgorithm algorithm;
+  std::ofstream file;
+  uint64_t writes = 0;
+  uint64_t bytes = 0;
+
+  void flush() {
+    if (unlikely(!file.flush())) {
+      throw IOException(path, "write");
+    }
+  }
+
+  static std::string utcNow() {
+    const std::time_t now = std::time(nullptr);
+    std::array<char, 32> date{};
+    std::strftime(date.data(), date.size(), "%Y-%m-%dT%H:%M:%SZ",
+                  std::gmtime(&now));
+    return date.data();
+  }
+};
+
+// Read the ranges back from an image, from the disk rather than the page
+// cache where the system allows, and return the ones which don't match
+// their hash. Data written more than once is only checked where it was
+// written last. BLAKE3 hashes each buffer with all the threads, so it
+// reads that many buffers at once.
+std::vector<RangeHash> verifyRanges(const std::filesystem::path &output,
+                                    const std::vector<RangeHash> &ranges,
+                                    const size_t buffer_size,
+                                    const HashAlgorithm algorithm,
+                                    const unsigned threads = 1) {
+  const auto overwritten = overwrittenRanges(ranges);
+  syncFile(output);
+#ifdef HAS_FADVISE
+  if (const int fd = open(output.c_str(), O_RDONLY); fd != -1) {
+    posix_fadvise(fd, 0, 0, POSIX_FADV_DONTNEED);
+    close(fd);
+  }
+#endif
+  std::ifstream in(output, std::ios::binary);
+  if (unlikely(!in)) {
+    throw IOException(output, "open");
+  }
+  std::vector<RangeHash> bad;
+  std::vector<char> buffer(algorithm == HashAlgorithm::Blake3
+                               ? buffer_size * std::max(threads, 1U)
+                               : buffer_size);
+  uint64_t checked = 0, bytes = 0;
+  for (size_t i = 0; i < ranges.size(); ++i) {
+    if (overwritten[i]) {
+      continue;
+    }
+    const auto &range = ranges[i];
+    const auto digest =
+        hashRange(in, range.begin, range.end, buffer, algorithm, threads);
+    if (!digest) {
+      std::cerr << "Error: Blocks " << range.begin << "-" << range.end
+                << " of " << output << " can't be read" << std::endl;
+      bad.push_back(range);
+    } else if (*digest != range.digest) {
+      std::cerr << "Error: Blocks " << range.begin << "-" << range.end
+                << " of " << output << " don't match their hash"
+                << std::endl;
+      bad.push_back(range);
+    }
+    bytes += static_cast<uint64_t>(range.end - range.begin) * BLOCK_SIZE;
+    ++checked;
+  }
+  std::cout << "Verified " << checked << " ranges (" << bytes << " bytes) of "
+            << output << ", " << bad.size() << " bad";
+  if (checked != ranges.size()) {
+    std::cout << ", " << ranges.size() - checked
+              << " skipped as they were overwritten";
+  }
+  std::cout << std::endl;
+  return bad;
+}
+
+// Write the CRC-32 of each block of the image as CSV, one line per block,
+// so that images from different devices or OTAs can be compared block by
+// block with diff or a spreadsheet. A partial block at the end is hashed as
+// far as it goes.
+void writeBlockCrcs(const std::filesystem::path &image, std::ostream &out,
+                    const size_t buffer_size) {
+  std::ifstream in(image, std::ios::binary);
+  if (unlikely(!in)) {
+    throw IOException(image, "open");
+  }
+  std::vector<char> buffer(buffer_size);
+  uint64_t block = 0;
+  out << "block,crc32" << std::endl;
+  for (;;) {
+    in.read(buffer.data(), static_cast<std::streamsize>(buffer.size()));
+    const auto got = static_cast<size_t>(in.gcount());
+    for (size_t at = 0; at < got; at += BLOCK_SIZE) {
+      const auto crc =
+          crc32(buffer.data() + at, std::min<size_t>(BLOCK_SIZE, got - at));
+      out << block++ << "," << std::hex << std::setw(8) << std::setfill('0')
+          << crc << std::dec << std::setfill(' ') << "\n";
+    }
+    if (got < buffer.size()) {
+      break;
+    }
+  }
+  if (unlikely(in.bad())) {
+    throw IOException(image, "read");
+  }
+  out.flush();
+  std::cerr << "Hashed " << block << " blocks of " << image << std::endl;
+}
+
+// Progress saved next to the output image, so that --resume can carry on
+// after a crash or power loss. It's only written once the output data it
+// covers was synced, and replaced atomically.
+struct Checkpoint {
+  // Sizes of the inputs, to not resume with different ones.
+  std::uintmax_t list_size{};
+  std::uintmax_t dat_size{};
+  // Their inodes and modification times, as the dat of another build may
+  // well have the same size.
+  std::string list_id;
+  std::string dat_id;
+  // New segments fully written, and where the next one starts in the dat.
+  uint64_t segments{};
+  uint64_t dat_offset{};
+  // Whether they were written in offset order, where dat_offset only
+  // counts the bytes written.
+  bool by_offset{};
+
+  static std::filesystem::path pathFor(const std::filesystem::path &output) {
+    return output.string() + ".ckpt";
+  }
+
+  // "<inode>:<mtime>" of a file, or only the time where there are no
+  // inodes.
+  static std::string idOf(const std::filesystem::path &file) {
+    std::error_code ec;
+    std::string id = std::to_string(
+        std::filesystem::last_write_time(file, ec).time_since_epoch().count());
+#ifdef HAS_MMAP
+    struct stat st {};
+    if (stat(file.c_str(), &st) == 0) {
+      id.insert(0, std::to_string(st.st_ino) + ":");
+    }
+#endif
+    return id;
+  }
+
+  static std::optional<Checkpoint> load(const std::filesystem::path &output) {
+    std::ifstream file(pathFor(output));
+    std::string magic;
+    Checkpoint ret;
+    if (!(file >> magic)) {
+      return std::nullopt;
+    }
+    // Version 1 has no ids, so it matches no inputs.
+    if (magic == "sdat2img-checkpoint-2" &&
+        (file >> ret.list_size >> ret.list_id >> ret.dat_size >>
+         ret.dat_id >> ret.segments >> ret.dat_offset >> ret.by_offset)) {
+      return ret;
+    }
+    if (magic == "sdat2img-checkpoint-1" &&
+        (file >> ret.list_size >> ret.dat_size >> ret.segments >>
+         ret.dat_offset)) {
+      return ret;
+    }
+    return std::nullopt;
+  }
+
+  // Whether it's for the same inputs, written in the same order.
+  [[nodiscard]] bool sameInputs(const Checkpoint &other) const {
+    return std::tie(list_size, list_id, dat_size, dat_id, by_offset) ==
+           std::tie(other.list_size, other.list_id, other.dat_size,
+                    other.dat_id, other.by_offset);
+  }
+
+  void save(const std::filesystem::path &output) const {
+    const auto path = pathFor(output);
+    const std::filesystem::path temp = path.string() + ".tmp";
+    {
+      std::ofstream file(temp);
+      file << "sdat2img-checkpoint-2\n"
+           << list_size << " " << list_id << "\n"
+           << dat_size << " " << dat_id << "\n"
+           << segments << " " << dat_offset << " " << by_offset << "\n";
+      if (unlikely(!file.flush())) {
+        throw IOException(temp, "write");
+      }
+    }
+    syncFile(temp);
+    std::filesystem::rename(temp, path);
+    // The rename only lasts once the directory is synced too.
+    syncFile(path.has_parent_path() ? path.parent_path() : ".");
+  }
+};
+
+// Conversion speed of earlier runs on this machine, to estimate how long a
+// run takes right from the start.
+struct ThroughputHistory {
+  static std::filesystem::path path() {
+    const auto dir = cacheDir();
+    return dir.empty() ? dir : dir / "throughput";
+  }
+
+  // Bytes per second, if known.
+  static std::optional<double> load() {
+    std::ifstream file(path());
+    double rate = 0;
+    if (!(file >> rate) || rate <= 0) {
+      return std::nullopt;
+    }
+    return rate;
+  }
+
+  // Blend a new measurement in, so a single odd run doesn't throw it off.
+  static void record(double rate) {
+    const auto file_path = path();
+    if (file_path.empty()) {
+      return;
+    }
+    if (const auto old = load()) {
+      rate = 0.7 * *old + 0.3 * rate;
+    }
+    std::error_code ec;
+    std::filesystem::create_directories(file_path.parent_path(), ec);
+    std::ofstream(file_path) << rate << std::endl;
+  }
+};
+
+// Saved next to the output image with --skip-unchanged: what it was
+// converted from, and how it looked afterwards, so that converting the same
+// inputs again can be skipped. The sizes and times of the inputs tell
+// cheaply that they're the same, their hashes when they were only touched.
+struct InputRecord {
+  std::string list_hash;
+  std::string dat_hash;
+  std::uintmax_t list_size{}, dat_size{};
+  long long list_time{}, dat_time{};
+  // Options changing the image.
+  FileSizeT partition_size{};
+  bool strip_avb{};
+  bool shrink{};
+  bool lenient{};
+  OutputFormat output_format = OutputFormat::Raw;
+  // The output as it was written.
+  std::uintmax_t output_size{};
+  long long output_time{};
+
+  static std::filesystem::path pathFor(const std::filesystem::path &output) {
+    return output.string() + ".inputs";
+  }
+
+  static long long timeOf(const std::filesystem::path &file) {
+    return static_cast<long long>(
+        std::filesystem::last_write_time(file).time_since_epoch().count());
+  }
+
+  // Take the current sizes and times of the inputs, which leaves them to
+  // be hashed.
+  void setInputs(const std::filesystem::path &list,
+                 const std::filesystem::path &dat) {
+    list_hash.clear();
+    dat_hash.clear();
+    list_size = std::filesystem::file_size(list);
+    dat_size = std::filesystem::file_size(dat);
+    list_time = timeOf(list);
+    dat_time = timeOf(dat);
+  }
+
+  // Take the current size and time of the output.
+  void setOutput(const std::filesystem::path &output) {
+    output_size = std::filesystem::file_size(output);
+    output_time = timeOf(output);
+  }
+
+  // Whether the inputs may be the same, and the output and the options are.
+  [[nodiscard]] bool maybeSame(const InputRecord &other) const {
+    return std::tie(list_size, dat_size, partition_size, strip_avb, shrink,
+                    lenient, output_format, output_size, output_time) ==
+           std::tie(other.list_size, other.dat_size, other.partition_size,
+                    other.strip_avb, other.shrink, other.lenient,
+                    other.output_format, other.output_size,
+                    other.output_time);
+  }
+
+  // Whether the inputs weren't touched either, so that there's no need to
+  // hash them.
+  [[nodiscard]] bool sameTimes(const InputRecord &other) const {
+    return list_time == other.list_time && dat_time == other.dat_time;
+  }
+
+  static std::optional<InputRecord> load(const std::filesystem::path &output) {
+    std::ifstream file(pathFor(output));
+    InputRecord ret;
+    int format{};
+    if (!(file >> ret.list_hash >> ret.dat_hash >> ret.list_size >>
+          ret.list_time >> ret.dat_size >> ret.dat_time >>
+          ret.partition_size >> ret.strip_avb >> ret.shrink >>
+          ret.lenient >> format >> ret.output_size >> ret.output_time) ||
+        format < 0 || format > static_cast<int>(OutputFormat::Chunks)) {
+      return std::nullopt;
+    }
+    ret.output_format = static_cast<OutputFormat>(format);
+    return ret;
+  }
+
+  void save(const std::filesystem::path &output) const {
+    std::ofstream file(pathFor(output));
+    file << list_hash << "\n"
+         << dat_hash << "\n"
+         << list_size << " " << list_time << "\n"
+         << dat_size << " " << dat_time << "\n"
+         << partition_size << " " << strip_avb << " " << shrink << " "
+         << lenient << " " << static_cast<int>(output_format) << "\n"
+         << output_size << " " << output_time << "\n";
+    if (unlikely(!file.flush())) {
+      throw IOException(pathFor(output), "write");
+    }
+  }
+};
+
+// Counters of a conversion, updated while it runs and readable from other
+// threads.
+struct ConversionStats {
+  // New data read from the dat and written into the image.
+  std::atomic<uint64_t> bytes_read{0};
+  std::atomic<uint64_t> bytes_written{0};
+  // New data segments written, and erase or zero blocks left untouched.
+  std::atomic<uint64_t> segments{0};
+  std::atomic<uint64_t> skipped_blocks{0};
+  // Reads and writes which were interrupted and done again.
+  std::atomic<uint64_t> retries{0};
+  // Seconds spent in each phase, set once it's over.
+  std::atomic<double> parse_seconds{0};
+  std::atomic<double> decompress_seconds{0};
+  std::atomic<double> write_seconds{0};
+  std::atomic<double> sync_seconds{0};
+};
+
+// Progress of a running conversion, readable from other threads.
+struct ConversionProgress {
+  std::atomic<FileSizeT> total_blocks{0};
+  std::atomic<FileSizeT> written_blocks{0};
+  ConversionStats stats;
+};
+
+// How long each phase of a conversion took and how much data it handled,
+// to pinpoint bottlenecks with --timings.
+class PhaseTimings {
+public:
+  using Clock = std::chrono::steady_clock;
+
+  // Add the time since start to a phase, along with the bytes it handled.
+  // Returns the time added.
+  double add(const std::string &name, const Clock::time_point start,
+             const uint64_t bytes = 0) {
+    return add(name,
+               std::chrono::duration<double>(Clock::now() - start).count(),
+               bytes);
+  }
+
+  double add(const std::string &name, const double seconds,
+             const uint64_t bytes = 0) {
+    const auto it = std::find_if(
+        phases.begin(), phases.end(),
+        [&name](const Phase &phase) { return phase.name == name; });
+    auto &phase = it != phases.end() ? *it : phases.emplace_back(Phase{name});
+    phase.seconds += seconds;
+    phase.bytes += bytes;
+    return seconds;
+  }
+
+  // One line per phase, in the order they first ran.
+  void print(std::ostream &out) const {
+    out << "Timings:" << std::endl;
+    for (const auto &phase : phases) {
+      const double mib = static_cast<double>(phase.bytes) / (1 << 20);
+      out << "  " << std::left << std::setw(14) << phase.name << std::right
+          << std::fixed << std::setprecision(3) << std::setw(9)
+          << phase.seconds << "s" << std::setprecision(1) << std::setw(10)
+          << mib << " MiB";
+      if (phase.bytes != 0 && phase.seconds > 0) {
+        out << std::setw(10) << mib / phase.seconds << " MiB/s";
+      }
+      out << std::endl;
+    }
+    out << std::defaultfloat << std::setprecision(6);
+  }
+
+private:
+  struct Phase {
+    std::string name;
+    double seconds = 0;
+    uint64_t bytes = 0;
+  };
+  std::vector<Phase> phases;
+};
+
+// Order to write the new data in.
+enum class SegmentOrder {
+  // As the transfer list has it, like the updater does.
+  Transfer,
+  // Sorted by the place in the image, for sequential writes.
+  Offset
+};
+
+// Knobs for a single conversion.
+struct ConvertOptions {
+  // Size of the target partition in bytes, 0 if unknown. The image is padded
+  // up to it, even if the list ends with erase or zero commands.
+  FileSizeT partition_size = 0;
+  // Cut the AVB hashtree and footer off the image, leaving the filesystem.
+  bool strip_avb = false;
+  // Report how well each segment of a brotli input compressed.
+  bool br_stats = false;
+  // Bytes of the dat to read ahead in another thread, 0 to not do that.
+  size_t read_ahead = 0;
+  // Save checkpoints while writing, and carry on from an existing one.
+  bool resume = false;
+  // Write only the ranges an existing image doesn't hold yet, when there's
+  // no checkpoint to resume from.
+  bool fill_in = false;
+  // Sync and save a checkpoint after this many seconds or bytes.
+  std::chrono::seconds checkpoint_interval{10};
+  uint64_t checkpoint_bytes = uint64_t{256} << 20;
+  // Never overwrite an existing output, instead of asking.
+  bool assume_no = false;
+  // Give up asking after this long, 0 to wait for an answer forever.
+  std::chrono::seconds prompt_timeout{0};
+  // Skip converting into an output converted from the same inputs before,
+  // keeping a record of them next to it.
+  bool skip_unchanged = false;
+  // Convert even if the output is up to date.
+  bool force = false;
+  // Write to devices even if they're mounted.
+  bool really_force = false;
+  // Skip commands the parser doesn't know, with a warning, instead of
+  // failing on them.
+  bool lenient = false;
+  // Give the same image as sdat2img.py, quirks included: read the list like
+  // it does, size the image by the ranges alone, copy the dat as it is and
+  // overwrite the output without asking.
+  bool python_compat = false;
+  // The image an incremental OTA applies to, empty for full OTAs.
+  std::filesystem::path source_img;
+  // Shell commands to run before and after converting, empty for none.
+  std::string exec_before, exec_after;
+  // Keep decompressed brotli inputs in the cache directory for next time.
+  bool cache_dat = false;
+  // Decode brotli inputs in another thread while writing, instead of into
+  // a file first.
+  bool stream_br = false;
+  // Threads to use at most, 1 to not start any besides the main one.
+  unsigned threads = 1;
+  // Bytes copied at once, and the size of the file stream buffers. A
+  // multiple of the block size.
+  size_t buffer_size = size_t{1} << 20;
+  // Measure each phase, and sync the output at the end to include the time
+  // to get it onto the disk.
+  bool timings = false;
+  // Container to write the image in.
+  OutputFormat output_format = OutputFormat::Raw;
+  // Bytes per part of a raw image split into several files, 0 for one.
+  uint64_t split_size = 0;
+  // Size of the chunks of OutputFormat::Chunks.
+  uint64_t chunk_size = ChunkBuf::kDefaultSize;
+  // Keep buffers small and the dat unmapped, for phones low on memory.
+  bool low_memory = false;
+  static constexpr size_t kLowMemoryBuffer = size_t{256} << 10;
+  static constexpr size_t kLowMemoryReadAhead = size_t{4} << 20;
+  // Write <output>.json with the provenance of the image, and the command
+  // line to record in it.
+  bool sidecar = false;
+  std::vector<std::string> command_line;
+  SegmentOrder order = SegmentOrder::Transfer;
+  // Chunk store of OutputFormat::Store, next to the manifest if empty.
+  std::filesystem::path store;
+  // Check the filesystem of the image afterwards, with this e2fsck.
+  bool fsck = false;
+  std::string e2fsck = "e2fsck";
+  // Read the new data back after writing it, and compare it with what was
+  // written.
+  bool verify_after = false;
+  // Save the hash of each range of new data here, if not empty.
+  std::filesystem::path range_manifest;
+  // Append a line for every write to the output here, if not empty.
+  std::filesystem::path audit_log;
+  // Hash for the range manifest, reading back and the audit log. Unset is
+  // BLAKE3, or SHA-256 with an audit log, which other tools may check.
+  std::optional<HashAlgorithm> hash;
+  // Save where the image is on the disk here for recovery, if not empty.
+  std::filesystem::path block_map;
+  // Sign the sidecar, the range manifest and store manifests with this
+  // Ed25519 key, if not empty.
+  std::filesystem::path sign_key;
+  // Zero erased ranges, or discard them securely on devices, instead of
+  // skipping them.
+  bool secure_erase = false;
+  // Shrink the filesystem to its minimum size afterwards, with this
+  // resize2fs.
+  bool shrink = false;
+  std::string resize2fs = "resize2fs";
+  // Make the filesystems of TWRP backups of files with this mke2fs.
+  std::string mke2fs = "mke2fs";
+};
+
+// Read an answer from stdin, or nothing if none came within the timeout.
+std::optional<std::string>
+readAnswer([[maybe_unused]] const std::chrono::seconds timeout) {
+#ifdef HAS_MMAP
+  if (timeout.count() > 0) {
+    pollfd in{STDIN_FILENO, POLLIN, 0};
+    if (poll(&in, 1, static_cast<int>(timeout.count() * 1000)) <= 0) {
+      return std::nullopt;
+    }
+  }
+#endif
+  std::string answer;
+  std::cin >> answer;
+  return answer;
+}
+
+// Size in bytes of a block device, 0 if unknown.
+uint64_t blockDeviceSize([[maybe_unused]] const std::filesystem::path &path) {
+#ifdef HAS_MMAP
+  const int fd = open(path.c_str(), O_RDONLY);
+  if (fd == -1) {
+    return 0;
+  }
+  const off_t size = lseek(fd, 0, SEEK_END);
+  close(fd);
+  return size > 0 ? static_cast<uint64_t>(size) : 0;
+#else
+  return 0;
+#endif
+}
+
+// Name of a block device in sysfs, like sda1 or dm-3, and of the whole disk
+// a partition is on, or empty for whole disks.
+std::string blockName(const std::filesystem::path &device) {
+  std::error_code ec;
+  const auto real = std::filesystem::canonical(device, ec);
+  return (ec ? device : real).filename().string();
+}
+std::string parentDisk(const std::string &name) {
+  std::error_code ec;
+  const std::filesystem::path sys = "/sys/class/block/" + name;
+  if (!std::filesystem::exists(sys / "partition", ec)) {
+    return "";
+  }
+  return std::filesystem::canonical(sys, ec).parent_path().filename().string();
+}
+
+// Mounts which would be corrupted by writing to a device, as "source on
+// target": those of the device itself, of its partitions, of the disk it's
+// a partition of, and of the device mapper targets built on top of it.
+// Linux and Android only, elsewhere nothing is found.
+std::vector<std::string> mountsOf(const std::filesystem::path &device) {
+  const std::string name = blockName(device);
+  const std::string parent = parentDisk(name);
+  std::error_code ec;
+  const auto holdsIt = [&](const std::string &source) {
+    for (const auto &holder : std::filesystem::directory_iterator(
+             "/sys/class/block/" + source + "/slaves", ec)) {
+      const std::string lower = holder.path().filename().string();
+      if (lower == name || parentDisk(lower) == name) {
+        return true;
+      }
+    }
+    return false;
+  };
+  std::vector<std::string> found;
+  std::ifstream mounts("/proc/mounts");
+  for (std::string source, target, rest;
+       mounts >> source >> target && std::getline(mounts, rest);) {
+    if (source.rfind("/dev/", 0) != 0) {
+      continue;
+    }
+    // Whole names are compared, /dev/loop1 isn't mounted when /dev/loop10
+    // is.
+    const std::string source_name = blockName(source);
+    if (source_name == name || source_name == parent ||
+        parentDisk(source_name) == name || holdsIt(source_name)) {
+      found.push_back(source + " on " + target);
+    }
+  }
+  return found;
+}
+
+// Writing to a mounted filesystem corrupts it, and the system with it if
+// it's the running one, so that takes more than --force.
+void checkNotMounted(const std::filesystem::path &output,
+                     const bool really_force) {
+  std::error_code ec;
+  if (!std::filesystem::is_block_file(output, ec)) {
+    return;
+  }
+  const auto mounted = mountsOf(output);
+  if (mounted.empty()) {
+    return;
+  }
+  for (const auto &mount : mounted) {
+    std::cerr << "    Mounted: " << mount << std::endl;
+  }
+  if (really_force) {
+    std::cerr << "Warning: Writing to the mounted " << output
+              << " anyway, as asked" << std::endl;
+    return;
+  }
+  throw KindError(ErrorKind::Aborted,
+                  quotedPath(output) + " or a disk it's on is mounted, "
+                                       "unmount it first, or use "
+                                       "--really-force");
+}
+
+#ifdef HAS_LINUX_FS
+// The block device a file is on, as recovery opens it.
+std::string blockDeviceOf(const struct stat &st) {
+  std::ifstream uevent("/sys/dev/block/" + std::to_string(major(st.st_dev)) +
+                       ":" + std::to_string(minor(st.st_dev)) + "/uevent");
+  for (std::string line; std::getline(uevent, line);) {
+    if (line.rfind("DEVNAME=", 0) == 0) {
+      const std::string name = line.substr(8);
+      std::error_code ec;
+      return std::filesystem::exists("/dev/block/" + name, ec)
+                 ? "/dev/block/" + name
+                 : "/dev/" + name;
+    }
+  }
+  throw KindError(ErrorKind::Unsupported,
+                  "The file isn't on a block device");
+}
+
+// Write zeros into the holes of a sparse file, so that all of it is on the
+// disk.
+void fillHoles(const std::filesystem::path &path, const int fd,
+               const off_t size) {
+  const std::vector<char> zeros(size_t{1} << 20);
+  for (off_t pos = 0; pos < size;) {
+    const off_t hole = lseek(fd, pos, SEEK_HOLE);
+    if (hole < 0 || hole >= size) {
+      break;
+    }
+    // There's no data after the last hole.
+    const off_t data = lseek(fd, hole, SEEK_DATA);
+    const off_t end = data < 0 ? size : std::min(data, size);
+    for (off_t at = hole; at < end;) {
+      const auto n = static_cast<size_t>(
+          std::min<off_t>(end - at, static_cast<off_t>(zeros.size())));
+      if (unlikely(pwrite(fd, zeros.data(), n, at) !=
+                   static_cast<ssize_t>(n))) {
+        throw IOException(path, "write");
+      }
+      at += static_cast<off_t>(n);
+    }
+    pos = end;
+  }
+}
+#endif
+
+// Discard a range of a device so that its data can't be recovered, even
+// from the spare blocks of the flash. False if the device can't.
+bool secureDiscard([[maybe_unused]] const std::filesystem::path &device,
+                   [[maybe_unused]] const uint64_t offset,
+                   [[maybe_unused]] const uint64_t length) {
+#ifdef HAS_LINUX_FS
+  const FileDescriptor fd(device, O_WRONLY);
+  uint64_t range[2] = {offset, length};
+  return fd && ioctl(fd.get(), BLKSECDISCARD, &range) == 0;
+#else
+  return false;
+#endif
+}
+
+// Write the block map of an image, the format uncrypt gives recovery for
+// an OTA package on /data: the block device holding it, its size and the
+// block size, the number of ranges, then "<begin> <end>" for each range of
+// device blocks the image is in, the end exclusive. Holes of image files
+// are filled first, as recovery reads the blocks right from the device.
+// On devices, the image takes up the first image_size bytes.
+void writeBlockMap(const std::filesystem::path &image,
+                   const std::filesystem::path &map_file,
+                   const uint64_t image_size) {
+#ifdef HAS_LINUX_FS
+  std::error_code ec;
+  std::string device;
+  uint64_t size = 0, block_size = BLOCK_SIZE;
+  std::vector<std::pair<uint64_t, uint64_t>> ranges;
+  if (std::filesystem::is_block_file(image, ec)) {
+    device = std::filesystem::absolute(image, ec).string();
+    size = image_size;
+    ranges.emplace_back(0, (size + block_size - 1) / block_size);
+  } else {
+    const FileDescriptor fd(image, O_RDWR);
+    struct stat st {};
+    if (unlikely(!fd || fstat(fd.get(), &st) != 0)) {
+      throw IOException(image, "open");
+    }
+    device = blockDeviceOf(st);
+    size = static_cast<uint64_t>(st.st_size);
+    block_size = static_cast<uint64_t>(st.st_blksize);
+    fillHoles(image, fd.get(), st.st_size);
+
+    // The extents on the disk, in pieces of a few at a time.
+    constexpr uint32_t kExtents = 256;
+    std::vector<uint8_t> buffer(sizeof(fiemap) +
+                                kExtents * sizeof(fiemap_extent));
+    auto *map = reinterpret_cast<fiemap *>(buffer.data());
+    constexpr uint32_t kUnreadable =
+        FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DELALLOC |
+        FIEMAP_EXTENT_ENCODED | FIEMAP_EXTENT_DATA_ENCRYPTED |
+        FIEMAP_EXTENT_NOT_ALIGNED | FIEMAP_EXTENT_DATA_INLINE |
+        FIEMAP_EXTENT_DATA_TAIL | FIEMAP_EXTENT_UNWRITTEN;
+    uint64_t mapped = 0;
+    for (bool last = false; !last && mapped < size;) {
+      std::fill(buffer.begin(), buffer.end(), 0);
+      map->fm_start = mapped;
+      map->fm_length = FIEMAP_MAX_OFFSET;
+      map->fm_flags = FIEMAP_FLAG_SYNC;
+      map->fm_extent_count = kExtents;
+      if (ioctl(fd.get(), FS_IOC_FIEMAP, map) != 0) {
+        throw KindError(ErrorKind::Unsupported,
+                        "The filesystem of " + quotedPath(image) +
+                            " doesn't tell where its blocks are");
+      }
+      if (map->fm_mapped_extents == 0) {
+        break;
+      }
+      for (uint32_t i = 0; i < map->fm_mapped_extents; ++i) {
+        const auto &extent = map->fm_extents[i];
+        if (extent.fe_flags & kUnreadable) {
+          throw KindError(ErrorKind::Unsupported,
+                          "Blocks of " + quotedPath(image) +
+                              " aren't stored as they are, like in an "
+                              "encrypted or compressed directory");
+        }
+        if (extent.fe_logical != mapped ||
+            extent.fe_physical % block_size != 0) {
+          throw KindError(ErrorKind::Other,
+                          quotedPath(image) + " has blocks which aren't "
+                                              "on the disk");
+        }
+        const uint64_t begin = extent.fe_physical / block_size;
+        const uint64_t end =
+            begin + (extent.fe_length + block_size - 1) / block_size;
+        if (!ranges.empty() && ranges.back().second == begin) {
+          ranges.back().second = end;
+        } else {
+          ranges.emplace_back(begin, end);
+        }
+        mapped += extent.fe_length;
+        last = extent.fe_flags & FIEMAP_EXTENT_LAST;
+      }
+    }
+    if (mapped < size) {
+      throw KindError(ErrorKind::Other,
+                      quotedPath(image) + " has blocks which aren't on the "
+                                          "disk");
+    }
+  }
+
+  std::ofstream out(map_file);
+  out << device << "\n" << size << " " << block_size << "\n"
+      << ranges.size() << "\n";
+  for (const auto &[begin, end] : ranges) {
+    out << begin << " " << end << "\n";
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(map_file, "write");
+  }
+#else
+  (void)image;
+  (void)map_file;
+  (void)image_size;
+  throw KindError(ErrorKind::Unsupported,
+                  "Block maps can only be written on Linux and Android");
+#endif
+}
+
+// Show what is about to be overwritten, like mkfs does, and have the user
+// type the name of the device to go on.
+bool confirmDeviceOverwrite(const std::filesystem::path &device,
+                            const uint64_t size, const bool assume_no,
+                            const std::chrono::seconds timeout) {
+  const std::string name = blockName(device);
+
+  std::cerr << "Warning: " << device << " is a block device";
+  if (size != 0) {
+    std::cerr << " of " << size << " bytes (" << size / (1 << 20) << " MiB)";
+  }
+  std::cerr << "." << std::endl;
+  // Linux tells the model of whole disks in sysfs.
+  if (std::ifstream model_file("/sys/class/block/" + name + "/device/model");
+      model_file) {
+    std::string model;
+    std::getline(model_file, model);
+    std::cerr << "    Model: " << model << std::endl;
+  }
+  if (assume_no) {
+    std::cerr << "Not overwriting it, as asked." << std::endl;
+    return false;
+  }
+
+  std::cout << "All data on it will be lost. Type " << name
+            << " to overwrite it: " << std::flush;
+  const auto answer = readAnswer(timeout);
+  if (!answer) {
+    std::cerr << std::endl
+              << "No answer within " << timeout.count() << " seconds."
+              << std::endl;
+  }
+  return answer == name || answer == device.string();
+}
+
+// A device keeps its old data wherever the image has no new data, so zero
+// those blocks, up to the size of the image.
+void zeroOutsideNewData(
+    TransferList &tlist, const FileSizeT size, std::ostream &out,
+    const std::function<void(FileSizeT, FileSizeT)> &zeroed = {}) {
+  std::vector<std::pair<FileSizeT, FileSizeT>> written;
+  tlist.forEachSegmentOf(TransferList::Command::New,
+                         [&written](const TransferList::ByteSegments &seg) {
+                           written.emplace_back(seg.begin() * BLOCK_SIZE,
+                                                seg.end() * BLOCK_SIZE);
+                         });
+  std::sort(written.begin(), written.end());
+  written.emplace_back(size, size);
+
+  const std::vector<char> zeros(size_t{1} << 20);
+  FileSizeT pos = 0;
+  for (const auto &[begin, end] : written) {
+    if (pos < begin) {
+      out.seekp(pos);
+      for (FileSizeT left = begin - pos; left > 0;) {
+        const auto n = std::min<FileSizeT>(left, zeros.size());
+        out.write(zeros.data(), n);
+        left -= n;
+      }
+      if (zeroed) {
+        zeroed(pos, begin);
+      }
+    }
+    pos = std::max(pos, end);
+  }
+}
+
+#ifdef HAS_MMAP
+// The image a transfer list and dat convert to, read on demand from the dat
+// instead of being written out. Reads from several threads at once are
+// fine.
+class VirtualImage {
+public:
+  VirtualImage(TransferList &tlist, const std::filesystem::path &dat,
+               const FileSizeT partition_size = 0)
+      : dat_fd(dat, O_RDONLY) {
+    image_size = static_cast<uint64_t>(
+        std::max(checkedMul<FileSizeT>(tlist.max(), BLOCK_SIZE),
+                 partition_size));
+    uint64_t dat_offset = 0;
+    tlist.forEachCommand([&](const TransferList::Command c,
+                             const TransferList::ByteSegments &seg) {
+      if (c != TransferList::Command::New) {
+        return;
+      }
+      const auto begin = static_cast<uint64_t>(seg.begin()) * BLOCK_SIZE;
+      const auto end = static_cast<uint64_t>(seg.end()) * BLOCK_SIZE;
+      // Later data overwrites earlier data of the same blocks, so cut
+      // that out of the extents first.
+      auto it = extents.lower_bound(begin);
+      if (it != extents.begin() && std::prev(it)->second.end > begin) {
+        --it;
+      }
+      while (it != extents.end() && it->first < end) {
+        const auto [old_begin, old] = *it;
+        it = extents.erase(it);
+        if (old_begin < begin) {
+          extents.emplace(old_begin, Extent{begin, old.dat_offset});
+        }
+        if (old.end > end) {
+          extents.emplace(end,
+                          Extent{old.end, old.dat_offset + (end - old_begin)});
+        }
+      }
+      if (begin < end) {
+        extents.emplace(begin, Extent{end, dat_offset});
+      }
+      dat_offset += end - begin;
+    });
+    if (!extents.empty() && !dat_fd) {
+      throw IOException(dat, "open");
+    }
+  }
+
+  [[nodiscard]] uint64_t size() const noexcept { return image_size; }
+
+  // Read up to size bytes at offset, fewer only at the end of the image.
+  // Returns how many were read.
+  size_t read(char *dest, size_t size, const uint64_t offset) const {
+    if (offset >= image_size) {
+      return 0;
+    }
+    size = static_cast<size_t>(std::min<uint64_t>(size, image_size - offset));
+    // Erased, zeroed and unlisted blocks read as zeros.
+    std::memset(dest, 0, size);
+    auto it = extents.upper_bound(offset);
+    if (it != extents.begin()) {
+      --it;
+    }
+    for (; it != extents.end() && it->first < offset + size; ++it) {
+      const auto begin = std::max(it->first, offset);
+      const auto end = std::min(it->second.end, offset + size);
+      if (begin < end) {
+        readDat(dest + (begin - offset), static_cast<size_t>(end - begin),
+                it->second.dat_offset + (begin - it->first));
+      }
+    }
+    return size;
+  }
+
+private:
+  // Image bytes from the key up to end come from the dat at dat_offset.
+  struct Extent {
+    uint64_t end;
+    uint64_t dat_offset;
+  };
+
+  // Past the end of the dat, reading it gives zeros too.
+  void readDat(char *dest, const size_t size, const uint64_t offset) const {
+    for (size_t got = 0; got < size;) {
+      const ssize_t rc = pread(dat_fd.get(), dest + got, size - got,
+                               checkedCast<off_t>(offset + got));
+      if (rc < 0 && errno == EINTR) {
+        continue;
+      }
+      if (unlikely(rc < 0)) {
+        throw std::system_error(errno, std::generic_category(), "pread");
+      }
+      if (rc == 0) {
+        break;
+      }
+      got += static_cast<size_t>(rc);
+    }
+  }
+
+  FileDescriptor dat_fd;
+  uint64_t image_size = 0;
+  std::map<uint64_t, Extent> extents;
+};
+
+// Parse a transfer list into a virtual image. Brotli can't be read at
+// random offsets, so a compressed dat is decompressed first.
+std::unique_ptr<VirtualImage>
+openVirtualImage(const std::filesystem::path &transfer_list_file,
+                 std::filesystem::path new_dat_file,
+                 const ConvertOptions &options) {
+  TransferList tlist;
+  if (options.lenient) {
+    tlist.setFallbackHandler(skipUnknownCommand);
+  }
+  tlist.parse(stdioPath(transfer_list_file, false));
+  new_dat_file = stdioPath(new_dat_file, false);
+  checkSeekableDat(new_dat_file);
+  if (!decompressNewDat(new_dat_file, nullptr, options.cache_dat)) {
+    throw std::runtime_error("Couldn't decompress " + new_dat_file.string());
+  }
+  return std::make_unique<VirtualImage>(tlist, new_dat_file,
+                                        options.partition_size);
+}
+
+// Write a whole image front to back, for outputs which can't seek. The new
+// data comes out sorted by its place in the image, whatever the order of
+// the list, and the gaps in between are filled with zeros.
+void streamImage(const VirtualImage &image, std::ostream &out,
+                 const size_t buffer_size, ConversionStats &stats,
+                 ConversionProgress *progress) {
+  std::vector<char> buffer(buffer_size);
+  for (uint64_t offset = 0; offset < image.size();) {
+    const size_t n = image.read(buffer.data(), buffer.size(), offset);
+    if (unlikely(!out.write(buffer.data(), static_cast<std::streamsize>(n)))) {
+      throw std::runtime_error("Couldn't write the image");
+    }
+    offset += n;
+    stats.bytes_written += n;
+    if (progress) {
+      progress->written_blocks += n / BLOCK_SIZE;
+    }
+  }
+  if (unlikely(!out.flush())) {
+    throw std::runtime_error("Couldn't write the image");
+  }
+}
+#endif
+
+#ifdef HAVE_FUSE
+// What the FUSE callbacks serve: a directory holding only the image.
+struct FuseImage {
+  std::unique_ptr<VirtualImage> image;
+  // Path of the image in the mount, like /system.img.
+  std::string path;
+
+  static const FuseImage &get() {
+    return *static_cast<const FuseImage *>(fuse_get_context()->private_data);
+  }
+};
+
+int fuseGetattr(const char *path, struct stat *st, fuse_file_info *) {
+  const auto &mounted = FuseImage::get();
+  *st = {};
+  if (std::strcmp(path, "/") == 0) {
+    st->st_mode = S_IFDIR | 0555;
+    st->st_nlink = 2;
+  } else if (path == mounted.path) {
+    st->st_mode = S_IFREG | 0444;
+    st->st_nlink = 1;
+    st->st_size = checkedCast<off_t>(mounted.image->size());
+  } else {
+    return -ENOENT;
+  }
+  return 0;
+}
+
+int fuseReaddir(const char *path, void *buf, fuse_fill_dir_t filler, off_t,
+                fuse_file_info *, fuse_readdir_flags) {
+  if (std::strcmp(path, "/") != 0) {
+    return -ENOENT;
+  }
+  filler(buf, ".", nullptr, 0, fuse_fill_dir_flags{});
+  filler(buf, "..", nullptr, 0, fuse_fill_dir_flags{});
+  filler(buf, FuseImage::get().path.c_str() + 1, nullptr, 0,
+         fuse_fill_dir_flags{});
+  return 0;
+}
+
+int fuseOpen(const char *path, fuse_file_info *fi) {
+  if (path != FuseImage::get().path) {
+    return -ENOENT;
+  }
+  return (fi->flags & O_ACCMODE) == O_RDONLY ? 0 : -EROFS;
+}
+
+int fuseRead(const char *, char *buf, size_t size, off_t offset,
+             fuse_file_info *) {
+  try {
+    return static_cast<int>(FuseImage::get().image->read(
+        buf, size, static_cast<uint64_t>(offset)));
+  } catch (const std::exception &e) {
+    std::cerr << "Error: " << e.what() << std::endl;
+    return -EIO;
+  }
+}
+
+// Show the image as a read-only file in mountpoint, until it's unmounted.
+int mountImage(const std::filesystem::path &transfer_list_file,
+               const std::filesystem::path &new_dat_file,
+               const std::filesystem::path &mountpoint,
+               const std::string &name, const ConvertOptions &options) {
+  FuseImage mounted;
+  try {
+    mounted.image =
+        openVirtualImage(transfer_list_file, new_dat_file, options);
+  } catch (const std::exception &e) {
+    return reportError(e);
+  }
+  mounted.path = "/" + name;
+
+  fuse_operations operations{};
+  operations.getattr = fuseGetattr;
+  operations.readdir = fuseReaddir;
+  operations.open = fuseOpen;
+  operations.read = fuseRead;
+  // In the foreground, so Ctrl+C unmounts it as well as fusermount -u.
+  std::vector<std::string> args{"sdat2img", mountpoint.string(), "-f", "-o",
+                                "ro,fsname=sdat2img"};
+  std::vector<char *> argv;
+  for (auto &arg : args) {
+    argv.push_back(arg.data());
+  }
+  std::cout << "Serving " << mounted.image->size() << " bytes as "
+            << (mountpoint / name) << ", unmount it to stop" << std::endl;
+  return fuse_main(static_cast<int>(argv.size()), argv.data(), &operations,
+                   &mounted) == 0
+             ? EXIT_SUCCESS
+             : EXIT_FAILURE;
+}
+#endif
+
+// Convert a transfer list and new dat pair into an output image. Errors
+// past the parsing throw, convert() below reports them.
+int convertOrThrow(const std::filesystem::path &transfer_list_file,
+                   std::filesystem::path new_dat_file,
+                   const std::filesystem::path &output_img,
+                   const ConvertOptions &options,
+                   ConversionProgress *progress) {
+  std::error_code ec;
+  // Fail right away if another conversion is writing the same output.
+  const OutputLock lock(output_img);
+
+  // Counters go into the progress, if anybody is looking.
+  ConversionStats local_stats;
+  auto &stats = progress ? progress->stats : local_stats;
+  PhaseTimings timings;
+  auto phase_start = PhaseTimings::Clock::now();
+  TransferList tlist;
+  if (options.lenient) {
+    tlist.setFallbackHandler(skipUnknownCommand);
+  }
+  tlist.setPythonQuirks(options.python_compat);
+
+  try {
+    tlist.parse(transfer_list_file);
+  } catch (const std::exception &e) {
+    return reportError(e);
+  }
+  stats.parse_seconds = timings.add("parsing", phase_start,
+              std::filesystem::file_size(transfer_list_file, ec));
+
+  // Pipes get the image front to back, so the data must be at hand in any
+  // order, and nothing can be read back.
+  const bool to_stream = isStreamOutput(output_img);
+  if (to_stream && (options.resume || options.fill_in || options.strip_avb ||
+                    options.shrink ||
+                    options.fsck || options.verify_after ||
+                    !options.range_manifest.empty() ||
+                    options.output_format != OutputFormat::Raw ||
+                    options.split_size != 0)) {
+    return reportError(ErrorKind::Unsupported,
+                       "Pipes only take raw images, which can't be resumed, "
+                       "stripped, shrunk or checked");
+  }
+#ifdef HAS_MMAP
+  if (to_stream && isStdout(output_img) && isatty(STDOUT_FILENO)) {
+    return reportError(ErrorKind::Unsupported,
+                       "Not writing an image to the terminal, redirect stdout "
+                       "into a file or pipe");
+  }
+#else
+  if (to_stream) {
+    return reportError(ErrorKind::Unsupported,
+                       "Writing into pipes isn't supported on this system");
+  }
+#endif
+
+  // With --skip-unchanged, nothing to do if the output was converted from
+  // the same inputs, and wasn't touched since. The inputs are only hashed
+  // if they have the same sizes but not the same times as then.
+  std::optional<InputRecord> record;
+  const std::filesystem::path recorded_dat = new_dat_file;
+  const auto hashInputs = [&] {
+    phase_start = PhaseTimings::Clock::now();
+    record->list_hash = sha256File(transfer_list_file);
+    record->dat_hash = sha256File(recorded_dat);
+    timings.add("hashing", phase_start,
+                record->list_size + record->dat_size);
+  };
+  if (options.skip_unchanged &&
+      !std::filesystem::is_block_file(output_img, ec) && !to_stream &&
+      options.split_size == 0 &&
+      std::filesystem::is_regular_file(transfer_list_file, ec) &&
+      std::filesystem::exists(new_dat_file, ec)) {
+    record = InputRecord{};
+    record->setInputs(transfer_list_file, new_dat_file);
+    record->partition_size = options.partition_size;
+    record->strip_avb = options.strip_avb;
+    record->shrink = options.shrink;
+    record->lenient = options.lenient;
+    record->output_format = options.output_format;
+    if (const auto saved = InputRecord::load(output_img);
+        saved && !options.force && !options.python_compat &&
+        std::filesystem::exists(output_img, ec)) {
+      record->setOutput(output_img);
+      bool same = false;
+      if (saved->maybeSame(*record) && saved->sameTimes(*record)) {
+        record->list_hash = saved->list_hash;
+        record->dat_hash = saved->dat_hash;
+        same = true;
+      } else if (saved->maybeSame(*record)) {
+        hashInputs();
+        same = record->list_hash == saved->list_hash &&
+               record->dat_hash == saved->dat_hash;
+        if (same) {
+          record->save(output_img);
+        }
+      }
+      if (same) {
+        std::cout << output_img << " is up to date, skipping it (use "
+                  << "--force to convert anyway)." << std::endl;
+        return EXIT_SUCCESS;
+      }
+    }
+  }
+
+  // A list of only erase and zero commands doesn't need the dat at all.
+  bool has_new_data = false;
+  FileSizeT new_blocks = 0;
+  tlist.forEachCommand([&](const TransferList::Command c,
+                           const TransferList::ByteSegments &seg) {
+    if (c == TransferList::Command::New) {
+      has_new_data = true;
+      new_blocks = checkedAdd(new_blocks, seg.size());
+    }
+  });
+  if (!has_new_data) {
+    std::cout << "Note: The list has no new data, the image will be all "
+                 "zeros."
+              << std::endl;
+  }
+
+  // Offset order reads the dat wherever the data is. Blocks written more
+  // than once must end up with the data written last, so such lists keep
+  // their order.
+  bool by_offset = options.order == SegmentOrder::Offset && !to_stream;
+  std::vector<std::pair<TransferList::ByteSegments, uint64_t>> sorted_segments;
+  if (by_offset) {
+    uint64_t offset = 0;
+    tlist.forEachCommand([&](const TransferList::Command c,
+                             const TransferList::ByteSegments &seg) {
+      if (c == TransferList::Command::New) {
+        sorted_segments.emplace_back(seg, offset);
+        offset += static_cast<uint64_t>(seg.size()) * BLOCK_SIZE;
+      }
+    });
+    std::stable_sort(sorted_segments.begin(), sorted_segments.end(),
+                     [](const auto &a, const auto &b) {
+                       return a.first.begin() < b.first.begin();
+                     });
+    FileSizeT end = 0;
+    for (const auto &[seg, offset] : sorted_segments) {
+      if (seg.begin() < end) {
+        std::cerr << "Warning: The list writes some blocks more than once, "
+                  << "keeping its order" << std::endl;
+        by_offset = false;
+        sorted_segments.clear();
+        break;
+      }
+      end = std::max(end, seg.end());
+    }
+  }
+
+  typedef const int cint;
+#ifdef HAS_FADVISE
+  cint fd = has_new_data ? open(new_dat_file.c_str(), O_RDONLY) : -1;
+  if (fd != -1) {
+    cint rc =
+        posix_fadvise(fd, 0, 0, POSIX_FADV_SEQUENTIAL | POSIX_FADV_WILLNEED);
+    if (rc != 0) {
+      std::cerr << "Warning: Failed to set file advise: " << strerror(errno)
+                << std::endl;
+    }
+    close(fd);
+  }
+#endif
+
+  // The stats and the cache need the whole stream decoded first.
+  bool stream_br = false;
+#ifdef HAVE_BROTLI
+  if (options.stream_br && options.threads == 1) {
+    std::cerr << "Warning: --stream-br needs a second thread, decompressing "
+              << "first" << std::endl;
+  } else if (options.stream_br && (options.br_stats || options.cache_dat)) {
+    std::cerr << "Warning: --stream-br doesn't go with --br-stats or "
+              << "--cache-dat, decompressing first" << std::endl;
+  } else if (options.stream_br && options.fill_in) {
+    std::cerr << "Warning: --stream-br can't skip what --continue keeps, "
+              << "decompressing first" << std::endl;
+  } else if (options.stream_br && options.low_memory) {
+    std::cerr << "Warning: --stream-br buffers 64 MiB, more than "
+              << "--low-memory allows, decompressing first" << std::endl;
+  } else if (options.stream_br && by_offset) {
+    std::cerr << "Warning: --stream-br decodes in list order, which "
+              << "--order offset doesn't write in, decompressing first"
+              << std::endl;
+  } else {
+    stream_br = options.stream_br && has_new_data && !to_stream &&
+                !options.python_compat &&
+                BrotliManager(new_dat_file).isValidBrotli();
+  }
+#endif
+
+  DecodeCheckpoints checkpoints;
+  phase_start = PhaseTimings::Clock::now();
+  const auto compressed_dat = new_dat_file;
+  if (options.python_compat && new_dat_file.extension() == ".br") {
+    std::cerr << "Warning: Like sdat2img.py, copying " << new_dat_file
+              << " as it is, without decompressing it" << std::endl;
+  }
+  if (has_new_data && !stream_br && !options.python_compat &&
+      !decompressNewDat(new_dat_file,
+                        options.br_stats ? &checkpoints : nullptr,
+                        options.cache_dat)) {
+    return EXIT_FAILURE;
+  }
+  if (new_dat_file != compressed_dat) {
+    stats.decompress_seconds =
+        timings.add("decompression", phase_start,
+                    std::filesystem::file_size(new_dat_file, ec));
+  }
+  // A dat cut short would leave zeros where the data should be.
+  if (has_new_data && !stream_br && !options.python_compat) {
+    const auto needed = static_cast<uint64_t>(
+        checkedMul<FileSizeT>(new_blocks, BLOCK_SIZE));
+    const auto dat_size = std::filesystem::file_size(new_dat_file, ec);
+    if (!ec && dat_size < needed) {
+      const std::string message =
+          quotedPath(compressed_dat) + " has " + std::to_string(dat_size) +
+          " bytes of new data, but the transfer list writes " +
+          std::to_string(needed);
+      if (!options.lenient) {
+        return reportError(ErrorKind::Parse,
+                           message + ". It was cut short, extract it again, "
+                                     "or convert it with --lenient to get "
+                                     "zeros for the rest",
+                           compressed_dat);
+      }
+      std::cerr << "Warning: " << message << ", the rest is zeros"
+                << std::endl;
+    }
+  }
+
+  if (options.br_stats) {
+#ifdef HAVE_BROTLI
+    if (checkpoints.empty()) {
+      std::cout << "The input isn't brotli compressed, no compression stats."
+                << std::endl;
+    }
+    reportCompression(tlist, new_dat_file, checkpoints);
+#else
+    std::cerr << "Warning: Built without brotli, no compression stats."
+              << std::endl;
+#endif
+  }
+
+#ifdef HAS_MMAP
+  if (to_stream) {
+    const VirtualImage image(tlist, new_dat_file, options.partition_size);
+    std::ofstream output(output_img, std::ios::binary);
+    if (unlikely(!output)) {
+      return reportError(ErrorKind::Io,
+                         "Could not open file " + quotedPath(output_img),
+                         output_img);
+    }
+    std::cout << "Streaming " << image.size() << " bytes into " << output_img
+              << " in order of offset" << std::endl;
+    if (progress) {
+      progress->total_blocks += image.size() / BLOCK_SIZE;
+    }
+    phase_start = PhaseTimings::Clock::now();
+    streamImage(image, output, options.buffer_size, stats, progress);
+    stats.write_seconds =
+        timings.add("writing", phase_start, stats.bytes_written);
+    if (options.timings) {
+      timings.print(std::cout);
+    }
+    std::cout << "Done! Output image: " << output_img << std::endl;
+    return EXIT_SUCCESS;
+  }
+#endif
+
+  // Carry on with a previous conversion of the same inputs, if asked to.
+  // The dat given, rather than its decompressed copy, which is new each
+  // time.
+  Checkpoint checkpoint{std::filesystem::file_size(transfer_list_file, ec),
+                        std::filesystem::file_size(new_dat_file, ec),
+                        Checkpoint::idOf(transfer_list_file),
+                        Checkpoint::idOf(compressed_dat)};
+  checkpoint.by_offset = by_offset;
+  bool resuming = false;
+  if (options.resume && std::filesystem::exists(output_img, ec)) {
+    if (const auto saved = Checkpoint::load(output_img)) {
+      resuming = saved->sameInputs(checkpoint);
+      if (resuming) {
+        checkpoint = *saved;
+        std::cout << "Resuming after " << checkpoint.segments
+                  << " segments" << std::endl;
+      } else {
+        std::cerr << "Warning: The checkpoint is for other inputs, starting "
+                  << "over" << std::endl;
+      }
+    }
+  }
+
+  // Without a checkpoint, an existing image is compared range by range, and
+  // only written where it doesn't hold the data yet.
+  const bool filling_in = options.fill_in && !resuming &&
+                          std::filesystem::exists(output_img, ec);
+  if (filling_in) {
+    std::cout << "Continuing " << output_img << ", writing only what it "
+              << "doesn't hold yet" << std::endl;
+  }
+
+  // Devices always exist, and can't be removed to start over.
+  const bool to_device = std::filesystem::is_block_file(output_img, ec);
+  const uint64_t device_size = to_device ? blockDeviceSize(output_img) : 0;
+  // Other formats are written from scratch, with their tables at the end.
+  // So are split images, whose parts are only sized at the end.
+  const bool raw =
+      options.output_format == OutputFormat::Raw && options.split_size == 0;
+  if (options.split_size != 0 && options.output_format != OutputFormat::Raw) {
+    return reportError(ErrorKind::Unsupported,
+                       "Only raw images can be split with --split");
+  }
+  if (!raw && (to_device || options.resume || options.fill_in ||
+               options.strip_avb)) {
+    return reportError(ErrorKind::Unsupported,
+                       "Only raw images can be written to devices, resumed, "
+                       "continued or stripped of their AVB footer");
+  }
+  if (options.shrink && (!raw || to_device)) {
+    return reportError(ErrorKind::Unsupported,
+                       "Only raw image files can be shrunk");
+  }
+  if (options.shrink && !options.range_manifest.empty()) {
+    return reportError(ErrorKind::Unsupported,
+                       "--shrink moves the blocks listed in the range "
+                       "manifest");
+  }
+  if (options.verify_after && !raw) {
+    return reportError(ErrorKind::Unsupported,
+                       "Only raw images can be read back with --verify-after");
+  }
+  if (options.secure_erase &&
+      (options.output_format != OutputFormat::Raw ||
+       isStreamOutput(output_img))) {
+    return reportError(ErrorKind::Unsupported,
+                       "--secure-erase only erases image files and devices");
+  }
+  if (!options.block_map.empty() && (!raw || isStreamOutput(output_img))) {
+    return reportError(ErrorKind::Unsupported,
+                       "Block maps are only written for raw images");
+  }
+  if (to_device) {
+    checkNotMounted(output_img, options.really_force);
+  }
+  if (to_device && !resuming && !filling_in &&
+      !confirmDeviceOverwrite(output_img, device_size, options.assume_no,
+                              options.prompt_timeout)) {
+    return reportError(ErrorKind::Aborted, "Not overwriting " +
+                                               quotedPath(output_img),
+                       output_img);
+  }
+
+  // sdat2img.py truncates it without asking. Split images are there if
+  // their manifest is.
+  const auto existing_output = options.split_size != 0
+                                   ? SplitBuf::manifestFor(output_img)
+                                   : output_img;
+  if (!to_device && !resuming && !filling_in && !options.python_compat &&
+      std::filesystem::exists(existing_output, ec)) {
+    std::cerr << "The output file " << existing_output << " already exists."
+              << std::endl;
+
+    if (options.assume_no) {
+      return reportError(ErrorKind::Aborted,
+                         "Not overwriting " + quotedPath(existing_output) +
+                             ", as asked",
+                         existing_output);
+    }
+    std::cout << "Do you want to overwrite it? (y/N): " << std::flush;
+    const auto answer = readAnswer(options.prompt_timeout);
+    if (!answer) {
+      std::cerr << std::endl
+                << "No answer within " << options.prompt_timeout.count()
+                << " seconds." << std::endl;
+    }
+    if (answer != "y" && answer != "Y") {
+      return reportError(ErrorKind::Aborted,
+                         "Not overwriting " + quotedPath(existing_output),
+                         existing_output);
+    } else {
+      std::filesystem::remove(existing_output, ec);
+      if (ec) {
+        return reportError(ErrorKind::Io,
+                           "Could not remove file " + quotedPath(existing_output) +
+                               ": " + ec.message(),
+                           existing_output);
+      }
+    }
+  }
+
+  // The stream buffers must be set before opening the files.
+  std::vector<char> output_buffer(options.buffer_size);
+  std::vector<char> input_buffer(options.buffer_size);
+  std::ofstream output;
+  const auto format_buf =
+      options.split_size != 0
+          ? std::make_unique<SplitBuf>(output_img, options.split_size)
+          : makeImageFormatBuf(options.output_format, output_img,
+                               options.store, options.chunk_size);
+  std::optional<std::ostream> formatted;
+  if (format_buf) {
+    formatted.emplace(format_buf.get());
+  } else {
+    output.rdbuf()->pubsetbuf(
+        output_buffer.data(),
+        static_cast<std::streamsize>(output_buffer.size()));
+    // Don't truncate what was written before when resuming.
+    output.open(output_img, resuming || filling_in || to_device
+                                ? std::ios::binary | std::ios::in
+                                : std::ios::binary);
+    if (unlikely(!output)) {
+      return reportError(ErrorKind::Io,
+                         "Could not open file " + quotedPath(output_img),
+                         output_img);
+    }
+  }
+  std::ostream &out = formatted ? *formatted : output;
+
+  std::ifstream input_dat;
+  input_dat.rdbuf()->pubsetbuf(
+      input_buffer.data(), static_cast<std::streamsize>(input_buffer.size()));
+  if (has_new_data) {
+    input_dat.open(new_dat_file, std::ios::binary);
+    if (unlikely(!input_dat)) {
+      return reportError(ErrorKind::Io,
+                         "Could not open file " + quotedPath(new_dat_file),
+                         new_dat_file);
+    }
+    input_dat.seekg(checkedCast<std::streamoff>(checkpoint.dat_offset));
+  }
+
+  // Calculate total number of blocks
+  FileSizeT max_file_size = checkedMul<FileSizeT>(tlist.max(), BLOCK_SIZE);
+  if (options.partition_size > max_file_size) {
+    max_file_size = options.partition_size;
+  } else if (options.partition_size != 0 &&
+             options.partition_size < max_file_size) {
+    std::cerr << "Warning: The transfer list needs " << max_file_size
+              << " bytes, but the partition is only "
+              << options.partition_size << " bytes." << std::endl;
+  }
+  std::cout << "New file size: " << max_file_size << " bytes" << std::endl;
+  if (to_device && device_size != 0 &&
+      static_cast<uint64_t>(max_file_size) > device_size) {
+    return reportError(ErrorKind::Other,
+                       "The image doesn't fit on " + quotedPath(output_img) +
+                           " (" + std::to_string(device_size) + " bytes)",
+                       output_img);
+  }
+  if (format_buf) {
+    format_buf->begin(static_cast<uint64_t>(max_file_size));
+  }
+
+  if (progress) {
+    tlist.forEachCommand([&](const TransferList::Command c,
+                             const TransferList::ByteSegments &seg) {
+      if (c == TransferList::Command::New) {
+        progress->total_blocks += seg.size();
+      }
+    });
+  }
+
+  uint64_t total = 0;
+  tlist.forEachSegmentOf(
+      TransferList::Command::New,
+      [&total](const TransferList::ByteSegments &seg) {
+        total = checkedAdd<uint64_t>(
+            total, static_cast<uint64_t>(seg.size()) * BLOCK_SIZE);
+      });
+  const uint64_t to_write = total - checkpoint.dat_offset;
+
+  // Estimate from earlier runs at first, and trust the measured speed more
+  // as the run goes on.
+  const auto history = ThroughputHistory::load();
+  if (history && to_write != 0) {
+    std::cout << "Estimated time: "
+              << static_cast<long long>(to_write / *history) << "s"
+              << std::endl;
+  }
+  const auto start = std::chrono::steady_clock::now();
+  auto last_eta = start;
+
+  std::optional<ReadAheadChannel> read_ahead;
+  if (stream_br) {
+#ifdef HAVE_BROTLI
+    std::cout << "Decoding " << new_dat_file << " while writing" << std::endl;
+    auto stream = std::make_shared<BrotliStream>(new_dat_file);
+    stream->skip(checkpoint.dat_offset);
+    read_ahead.emplace(
+        [stream](char *dest, const size_t size) {
+          return stream->read(dest, size);
+        },
+        std::max<size_t>(options.read_ahead, size_t{64} << 20), to_write);
+#endif
+  } else if (options.read_ahead != 0 && options.threads == 1) {
+    std::cerr << "Warning: --read-ahead needs a second thread, ignoring it"
+              << std::endl;
+  } else if (options.read_ahead != 0 && options.fill_in) {
+    std::cerr << "Warning: --read-ahead can't skip what --continue keeps, "
+              << "ignoring it" << std::endl;
+  } else if (options.read_ahead != 0 && by_offset) {
+    std::cerr << "Warning: --read-ahead reads in list order, which --order "
+              << "offset doesn't write in, ignoring it" << std::endl;
+  } else if (options.read_ahead != 0) {
+    read_ahead.emplace(input_dat, options.read_ahead, to_write);
+  }
+#ifdef FAST_IO
+  // The stream only creates and sizes the output then.
+  const FileDescriptor dat_fd(new_dat_file, O_RDONLY);
+  const FileDescriptor output_fd(output_img, O_WRONLY);
+  [[maybe_unused]] const bool fast_io =
+      raw && !read_ahead && dat_fd && output_fd;
+#else
+  [[maybe_unused]] const bool fast_io = false;
+#endif
+#ifdef HAS_MMAP
+  // Reading ahead already takes care of the input. Mapped dats take up
+  // memory as they're read, which low memory systems may not have to spare.
+  std::optional<MappedFile> mapped_dat;
+  if (!read_ahead && !fast_io && !options.low_memory) {
+    mapped_dat.emplace(new_dat_file);
+  }
+#endif
+  // In offset order, this only counts the bytes written.
+  uint64_t dat_offset = checkpoint.dat_offset;
+  uint64_t segment_index = 0, unsynced_bytes = 0;
+  auto last_sync = std::chrono::steady_clock::now();
+  // Time spent syncing checkpoints, which isn't part of the writing.
+  double sync_seconds = 0;
+  phase_start = PhaseTimings::Clock::now();
+
+  const auto algorithm = options.hash.value_or(
+      options.audit_log.empty() ? HashAlgorithm::Blake3
+                                : HashAlgorithm::Sha256);
+  std::optional<AuditLog> audit;
+  if (!options.audit_log.empty()) {
+    audit.emplace(options.audit_log, output_img, new_dat_file, algorithm);
+  }
+  // Hashes of what was written, to read it back against, to save and to
+  // log.
+  const bool hash_ranges =
+      options.verify_after || !options.range_manifest.empty() || audit;
+  std::vector<RangeHash> written_ranges;
+  // Ranges which are hashed from the output at the end instead.
+  std::vector<TransferList::ByteSegments> resumed_ranges;
+  std::optional<DataHash> hasher;
+  // What an image being continued holds already, and the dat to compare it
+  // with.
+  std::ifstream existing, compared_dat;
+  std::vector<char> compare_buffer;
+  FileSizeT kept_blocks = 0;
+  if (filling_in) {
+    existing.open(output_img, std::ios::binary);
+    compared_dat.open(new_dat_file, std::ios::binary);
+    compare_buffer.resize(options.buffer_size);
+  }
+  // Hash of the data of a segment at seg_dat_offset in the dat. Past the
+  // end of the dat, it's zeros.
+  const auto hashDat = [&](const TransferList::ByteSegments &seg,
+                           const uint64_t seg_dat_offset) {
+    DataHash hash(algorithm, options.threads);
+    compared_dat.clear();
+    compared_dat.seekg(checkedCast<std::streamoff>(seg_dat_offset));
+    for (auto left = static_cast<uint64_t>(seg.size()) * BLOCK_SIZE;
+         left > 0;) {
+      const auto n = static_cast<size_t>(
+          std::min<uint64_t>(left, compare_buffer.size()));
+      std::fill_n(compare_buffer.begin(), n, 0);
+      compared_dat.read(compare_buffer.data(),
+                        static_cast<std::streamsize>(n));
+      hash.update(compare_buffer.data(), n);
+      left -= n;
+    }
+    return hash.digest();
+  };
+  // Write a segment whose data is at seg_dat_offset in the dat.
+  const auto writeSegment = [&](const TransferList::ByteSegments &seg,
+                                const uint64_t seg_dat_offset) {
+    // Already written before the checkpoint.
+    if (segment_index < checkpoint.segments) {
+      if (hash_ranges) {
+        resumed_ranges.push_back(seg);
+      }
+      ++segment_index;
+      if (progress) {
+        progress->written_blocks += seg.size();
+      }
+      return;
+    }
+    // Already in the image being continued. Earlier segments of this run
+    // may have written over it, so those are flushed first.
+    if (filling_in) {
+      const auto wanted = hashDat(seg, seg_dat_offset);
+      out.flush();
+      if (hashRange(existing, seg.begin(), seg.end(), compare_buffer,
+                    algorithm, options.threads) == wanted) {
+        if (hash_ranges) {
+          written_ranges.push_back({seg.begin(), seg.end(), wanted});
+        }
+        const auto bytes = static_cast<uint64_t>(seg.size()) * BLOCK_SIZE;
+        dat_offset += bytes;
+        stats.bytes_read += bytes;
+        kept_blocks += seg.size();
+        ++segment_index;
+        if (progress) {
+          progress->written_blocks += seg.size();
+        }
+        return;
+      }
+    }
+    DataHash *hash =
+        hash_ranges ? &hasher.emplace(algorithm, options.threads) : nullptr;
+    // sdat2img.py writes nothing past the end of the dat, which leaves
+    // whatever earlier ranges wrote there.
+    if (options.python_compat) {
+      std::cout << "Copying " << seg.size() << " blocks into position "
+                << seg.begin() << "..." << std::endl;
+      input_dat.clear();
+      input_dat.seekg(checkedCast<std::streamoff>(seg_dat_offset));
+      out.seekp(seg.begin() * BLOCK_SIZE, std::ios::beg);
+      std::vector<char> buffer(options.buffer_size);
+      DataHash copied_hash(algorithm);
+      uint64_t copied = 0;
+      for (auto left = static_cast<uint64_t>(seg.size()) * BLOCK_SIZE;
+           left > 0 && input_dat;) {
+        const auto n =
+            static_cast<size_t>(std::min<uint64_t>(left, buffer.size()));
+        input_dat.read(buffer.data(), static_cast<std::streamsize>(n));
+        out.write(buffer.data(), input_dat.gcount());
+        copied_hash.update(buffer.data(),
+                           static_cast<size_t>(input_dat.gcount()));
+        copied += static_cast<uint64_t>(input_dat.gcount());
+        left -= n;
+      }
+      if (audit && copied != 0) {
+        audit->record(static_cast<uint64_t>(seg.begin()) * BLOCK_SIZE,
+                      copied, seg_dat_offset, copied_hash.digest());
+      }
+      if (hash) {
+        resumed_ranges.push_back(seg);
+        hash = nullptr;
+      }
+    } else if (read_ahead) {
+      seg.writeToFile(*read_ahead, out, options.buffer_size, hash);
+    }
+#ifdef FAST_IO
+    else if (fast_io) {
+      seg.writeToFile(dat_fd.get(), seg_dat_offset, output_fd.get(),
+                      options.buffer_size, &stats.retries, hash);
+    }
+#endif
+#ifdef HAS_MMAP
+    else if (mapped_dat && *mapped_dat) {
+      seg.writeToFile(mapped_dat->data(), mapped_dat->size(),
+                      seg_dat_offset, out, hash);
+    }
+#endif
+    else {
+      if (by_offset || filling_in) {
+        input_dat.clear();
+        input_dat.seekg(checkedCast<std::streamoff>(seg_dat_offset));
+      }
+      seg.writeToFile(input_dat, out, options.buffer_size, hash);
+    }
+    const auto bytes = static_cast<uint64_t>(seg.size()) * BLOCK_SIZE;
+    if (hash) {
+      written_ranges.push_back({seg.begin(), seg.end(), hash->digest()});
+      if (audit) {
+        audit->record(static_cast<uint64_t>(seg.begin()) * BLOCK_SIZE, bytes,
+                      seg_dat_offset, written_ranges.back().digest);
+      }
+    }
+    dat_offset += bytes;
+    stats.bytes_read += bytes;
+    stats.bytes_written += bytes;
+    ++stats.segments;
+    if (progress) {
+      progress->written_blocks += seg.size();
+    }
+    ++segment_index;
+    unsynced_bytes += bytes;
+    if (options.resume &&
+        (unsynced_bytes >= options.checkpoint_bytes ||
+         std::chrono::steady_clock::now() - last_sync >=
+             options.checkpoint_interval)) {
+      output.flush();
+      const auto sync_start = PhaseTimings::Clock::now();
+      const bool synced = syncFile(output_img);
+      sync_seconds += std::chrono::duration<double>(
+                          PhaseTimings::Clock::now() - sync_start)
+                          .count();
+      if (synced) {
+        checkpoint.segments = segment_index;
+        checkpoint.dat_offset = dat_offset;
+        checkpoint.save(output_img);
+      }
+      unsynced_bytes = 0;
+      last_sync = std::chrono::steady_clock::now();
+    }
+    const auto now = std::chrono::steady_clock::now();
+    if (now - last_eta >= std::chrono::seconds(5)) {
+      const double elapsed = std::chrono::duration<double>(now - start).count();
+      const uint64_t written = dat_offset - checkpoint.dat_offset;
+      const double weight = std::min(1.0, elapsed / 60);
+      const double rate =
+          history ? (1 - weight) * *history + weight * written / elapsed
+                  : written / elapsed;
+      std::cout << "ETA: "
+                << static_cast<long long>((to_write - written) / rate)
+                << "s" << std::endl;
+      last_eta = now;
+    }
+  };
+
+  // Devices which can't discard securely get zeros instead, like files.
+  bool can_discard = to_device;
+  FileSizeT erased_blocks = 0;
+  const auto eraseSegment = [&](const TransferList::ByteSegments &seg) {
+    std::cout << "Erasing " << seg.size() << " blocks at position "
+              << seg.begin() << "..." << std::endl;
+    const auto offset = static_cast<uint64_t>(seg.begin()) * BLOCK_SIZE;
+    const auto bytes = static_cast<uint64_t>(seg.size()) * BLOCK_SIZE;
+    // Earlier writes into the range go first.
+    out.flush();
+    // The zeroing of devices at the end writes zeros there afterwards.
+    if (can_discard && secureDiscard(output_img, offset, bytes)) {
+      erased_blocks += seg.size();
+      return;
+    }
+    if (can_discard) {
+      std::cerr << "Warning: " << output_img << " can't discard securely, "
+                << "writing zeros instead" << std::endl;
+      can_discard = false;
+    }
+    const std::vector<char> zeros(options.buffer_size);
+    out.seekp(checkedCast<std::streamoff>(offset), std::ios::beg);
+    for (uint64_t left = bytes; left > 0;) {
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(left, zeros.size()));
+      out.write(zeros.data(), static_cast<std::streamsize>(n));
+      left -= n;
+    }
+    if (unlikely(!out.flush())) {
+      throw IOException(output_img, "write");
+    }
+    if (audit) {
+      audit->recordZeros(offset, bytes);
+    }
+    erased_blocks += seg.size();
+  };
+
+  tlist.forEachCommand([&](const TransferList::Command c,
+                           const TransferList::ByteSegments &seg) {
+    if (c == TransferList::Command::Erase && options.secure_erase) {
+      eraseSegment(seg);
+    } else if (c != TransferList::Command::New) {
+      std::cout << "Skipping command " << c << "..." << std::endl;
+      stats.skipped_blocks += seg.size();
+    } else if (!by_offset) {
+      writeSegment(seg, dat_offset);
+    }
+  });
+  for (const auto &[seg, seg_dat_offset] : sorted_segments) {
+    writeSegment(seg, seg_dat_offset);
+  }
+
+  if (filling_in) {
+    std::cout << "Kept " << kept_blocks << " blocks which " << output_img
+              << " held already" << std::endl;
+  }
+  if (options.secure_erase) {
+    std::cout << "Erased " << erased_blocks << " blocks" << std::endl;
+  }
+  if (to_device) {
+    std::cout << "Zeroing the rest of " << output_img << "..." << std::endl;
+    zeroOutsideNewData(
+        tlist, max_file_size, output,
+        [&audit](const FileSizeT begin, const FileSizeT end) {
+          if (audit) {
+            audit->recordZeros(static_cast<uint64_t>(begin),
+                               static_cast<uint64_t>(end - begin));
+          }
+        });
+  }
+  if (unlikely(!out.flush())) {
+    throw IOException(output_img, "write");
+  }
+  if (format_buf) {
+    format_buf->finish(max_file_size);
+  } else {
+    output.close();
+  }
+  if (audit) {
+    audit->finish();
+  }
+  input_dat.close();
+  const double write_seconds =
+      std::chrono::duration<double>(PhaseTimings::Clock::now() - phase_start)
+          .count() -
+      sync_seconds;
+  stats.write_seconds = timings.add(
+      stream_br ? "decoding+writing" : "writing", write_seconds, to_write);
+  if (sync_seconds > 0) {
+    timings.add("fsync", sync_seconds);
+  }
+  stats.sync_seconds = sync_seconds;
+
+  // Very short runs say more about the start up than the speed.
+  const double elapsed =
+      std::chrono::duration<double>(std::chrono::steady_clock::now() - start)
+          .count();
+  if (elapsed >= 1) {
+    ThroughputHistory::record(to_write / elapsed);
+  }
+
+  if (raw && !to_device) {
+    std::filesystem::resize_file(output_img, max_file_size);
+  }
+  if (options.resume) {
+    std::filesystem::remove(Checkpoint::pathFor(output_img), ec);
+  }
+  // Before anything is cut off the image.
+  if (options.verify_after) {
+    if (resuming) {
+      std::cout << "Note: Only the data written since resuming is verified"
+                << std::endl;
+    }
+    phase_start = PhaseTimings::Clock::now();
+    const auto bad = verifyRanges(output_img, written_ranges,
+                                  options.buffer_size, algorithm,
+                                  options.threads);
+    timings.add("verifying", phase_start, to_write);
+    if (!bad.empty()) {
+      return reportError(ErrorKind::Verification,
+                         quotedPath(output_img) +
+                             " doesn't hold what was written, the storage "
+                             "may be failing",
+                         output_img);
+    }
+  }
+
+  uint64_t image_size = static_cast<uint64_t>(max_file_size);
+  if (const auto footer = raw ? AvbFooter::read(output_img) : std::nullopt) {
+    footer->print(std::cout, max_file_size);
+    if (options.strip_avb && to_device) {
+      std::cerr << "Warning: Can't strip the AVB metadata off a device"
+                << std::endl;
+    } else if (options.strip_avb) {
+      std::filesystem::resize_file(output_img, footer->original_image_size);
+      image_size = footer->original_image_size;
+      std::cout << "Stripped AVB metadata, the image is now "
+                << footer->original_image_size << " bytes" << std::endl;
+    }
+  } else if (options.strip_avb) {
+    std::cout << "No AVB footer found, nothing to strip." << std::endl;
+  }
+  if (!options.range_manifest.empty()) {
+    // Data written before resuming is hashed from the output.
+    std::vector<RangeHash> ranges;
+    std::ifstream in(output_img, std::ios::binary);
+    std::vector<char> buffer(options.buffer_size);
+    for (const auto &seg : resumed_ranges) {
+      if (const auto digest = hashRange(in, seg.begin(), seg.end(), buffer,
+                                        algorithm, options.threads)) {
+        ranges.push_back({seg.begin(), seg.end(), *digest});
+      }
+    }
+    ranges.insert(ranges.end(), written_ranges.begin(), written_ranges.end());
+    RangeManifest::save(options.range_manifest, ranges, image_size,
+                        algorithm);
+    std::cout << "Saved the range hashes to " << options.range_manifest
+              << std::endl;
+  }
+  if (!options.block_map.empty()) {
+    writeBlockMap(output_img, options.block_map, image_size);
+    std::cout << "Saved the block map to " << options.block_map
+              << std::endl;
+  }
+  if (options.timings) {
+    phase_start = PhaseTimings::Clock::now();
+    syncFile(output_img);
+    stats.sync_seconds = stats.sync_seconds + timings.add("fsync", phase_start);
+  }
+  // A broken image isn't up to date, so it isn't recorded.
+  bool fsck_ok = true;
+  if (options.shrink) {
+    phase_start = PhaseTimings::Clock::now();
+    fsck_ok = shrinkFilesystem(options.resize2fs, options.e2fsck, output_img);
+    timings.add("shrinking", phase_start);
+  }
+  if (options.fsck && fsck_ok && !raw) {
+    std::cerr << "Warning: e2fsck only checks raw images, skipping it"
+              << std::endl;
+  } else if (options.fsck && fsck_ok) {
+    phase_start = PhaseTimings::Clock::now();
+    fsck_ok = checkFilesystem(options.e2fsck, output_img);
+    timings.add("fsck", phase_start);
+  }
+  if (record && fsck_ok) {
+    if (record->list_hash.empty()) {
+      hashInputs();
+    }
+    record->setOutput(output_img);
+    record->save(output_img);
+  }
+  if (options.timings) {
+    timings.print(std::cout);
+  }
+  if (options.python_compat) {
+    // The full path, without quotes.
+    std::cout << "Done! Output image: "
+              << std::filesystem::weakly_canonical(output_img, ec).string()
+              << std::endl;
+  } else {
+    std::cout << "Done! Output image: " << output_img << std::endl;
+  }
+  if (!fsck_ok) {
+    return reportError(ErrorKind::Verification,
+                       std::string("The filesystem ") +
+                           (options.shrink ? "check or shrinking" : "check") +
+                           " of " + quotedPath(output_img) + " failed",
+                       output_img);
+  }
+
+  return EXIT_SUCCESS;
+}
+
+// One of the three streams of a bsdiff patch: a part of the patch file,
+// stored or compressed. Decoded bit by bit as it's read, so patches of any
+// size take little memory.
+class PatchStream {
+public:
+  enum class Compression { None, Bzip2, Brotli };
+
+  PatchStream(std::istream &patch, const uint64_t offset, const uint64_t size,
+              const Compression compression)
+      : patch(patch), offset(offset), left(size), compression(compression) {
+    if (compression == Compression::Bzip2) {
+#ifdef HAVE_BZIP2
+      if (unlikely(BZ2_bzDecompressInit(&bzip2, 0, 0) != BZ_OK)) {
+        throw std::runtime_error("Error creating bzip2 decoder state.");
+      }
+#else
+      throw KindError(ErrorKind::Unsupported,
+                      "This build has no bzip2 support, which the patch "
+                      "needs. Build it with libbz2");
+#endif
+    } else if (compression == Compression::Brotli) {
+#ifdef HAVE_BROTLI
+      brotli = BrotliDecoderCreateInstance(nullptr, nullptr, nullptr);
+      if (unlikely(!brotli)) {
+        throw std::runtime_error("Error creating Brotli decoder state.");
+      }
+#else
+      throw KindError(ErrorKind::Unsupported,
+                      "This build has no brotli support, which the patch "
+                      "needs. Build it with libbrotli");
+#endif
+    }
+  }
+
+  ~PatchStream() {
+#ifdef HAVE_BZIP2
+    if (compression == Compression::Bzip2) {
+      BZ2_bzDecompressEnd(&bzip2);
+    }
+#endif
+#ifdef HAVE_BROTLI
+    if (brotli) {
+      BrotliDecoderDestroyInstance(brotli);
+    }
+#endif
+  }
+
+  PatchStream(const PatchStream &) = delete;
+  PatchStream &operator=(const PatchStream &) = delete;
+
+  // Read exactly size bytes, the patch is corrupt if there aren't as many.
+  void read(char *dest, const size_t size) {
+    if (compression == Compression::None) {
+      if (unlikely(size > left)) {
+        throw truncated();
+      }
+      fill(dest, size);
+      return;
+    }
+    size_t done = 0;
+    while (done < size) {
+      if (available == 0 && left > 0) {
+        available = static_cast<size_t>(std::min<uint64_t>(left, input.size()));
+        fill(input.data(), available);
+        next = input.data();
+      }
+      const size_t before = available;
+      const size_t n = decode(dest + done, size - done);
+      done += n;
+      if (unlikely(n == 0 && available == before && left == 0)) {
+        throw truncated();
+      }
+    }
+  }
+
+  // A sign and magnitude number of the control stream.
+  int64_t readNumber() {
+    std::array<uint8_t, 8> bytes{};
+    read(reinterpret_cast<char *>(bytes.data()), bytes.size());
+    return decodeNumber(bytes.data());
+  }
+
+  static int64_t decodeNumber(const uint8_t *bytes) {
+    uint64_t value = bytes[7] & 0x7f;
+    for (int i = 6; i >= 0; --i) {
+      value = value << 8 | bytes[i];
+    }
+    if (unlikely(value > static_cast<uint64_t>(
+                             std::numeric_limits<int64_t>::max()))) {
+      throw KindError(ErrorKind::Parse, "The patch is corrupt");
+    }
+    const auto number = static_cast<int64_t>(value);
+    return bytes[7] & 0x80 ? -number : number;
+  }
+
+private:
+  std::istream &patch;
+  uint64_t offset;
+  uint64_t left;
+  Compression compression;
+  std::array<char, 65536> input{};
+  char *next = nullptr;
+  size_t available = 0;
+#ifdef HAVE_BZIP2
+  bz_stream bzip2{};
+#endif
+#ifdef HAVE_BROTLI
+  BrotliDecoderState *brotli = nullptr;
+#endif
+
+  static KindError truncated() {
+    return KindError(ErrorKind::Parse, "The patch is truncated");
+  }
+
+  // Read the next bytes of the stream from the patch file.
+  void fill(char *dest, const size_t size) {
+    patch.clear();
+    patch.seekg(checkedCast<std::streamoff>(offset));
+    if (unlikely(!patch.read(dest, static_cast<std::streamsize>(size)))) {
+      throw truncated();
+    }
+    offset += size;
+    left -= size;
+  }
+
+  // Decode what the input buffer holds into dest, up to size bytes.
+  size_t decode([[maybe_unused]] char *dest, [[maybe_unused]] size_t size) {
+#ifdef HAVE_BZIP2
+    if (compression == Compression::Bzip2) {
+      bzip2.next_in = next;
+      bzip2.avail_in = static_cast<unsigned>(available);
+      bzip2.next_out = dest;
+      bzip2.avail_out = static_cast<unsigned>(
+          std::min<size_t>(size, std::numeric_limits<unsigned>::max()));
+      const auto out_before = bzip2.avail_out;
+      const int result = BZ2_bzDecompress(&bzip2);
+      if (unlikely(result != BZ_OK && result != BZ_STREAM_END)) {
+        throw KindError(ErrorKind::Parse,
+                        "The patch is corrupt, bzip2 error " +
+                            std::to_string(result));
+      }
+      next = bzip2.next_in;
+      available = bzip2.avail_in;
+      const size_t written = out_before - bzip2.avail_out;
+      if (unlikely(result == BZ_STREAM_END && written < size)) {
+        throw truncated();
+      }
+      return written;
+    }
+#endif
+#ifdef HAVE_BROTLI
+    if (compression == Compression::Brotli) {
+      size_t available_out = size;
+      auto *next_out = reinterpret_cast<uint8_t *>(dest);
+      const auto *next_in = reinterpret_cast<const uint8_t *>(next);
+      const auto result = BrotliDecoderDecompressStream(
+          brotli, &available, &next_in, &available_out, &next_out, nullptr);
+      next = const_cast<char *>(reinterpret_cast<const char *>(next_in));
+      if (unlikely(result == BROTLI_DECODER_RESULT_ERROR ||
+                   (result == BROTLI_DECODER_RESULT_SUCCESS &&
+                    available_out > 0))) {
+        throw KindError(ErrorKind::Parse, "The patch is corrupt");
+      }
+      return size - available_out;
+    }
+#endif
+    return 0;
+  }
+};
+
+// Apply a bsdiff patch, the part of patch from offset on, to old, handing
+// the new data to sink bit by bit. Takes the BSDIFF40 format of
+// applypatch, with bzip2 streams, and the BSDF2 one of update_engine,
+// whose streams may be stored, bzip2 or brotli. Returns the size of the
+// new data.
+uint64_t bspatch(const char *old, const size_t old_size, std::istream &patch,
+                 const uint64_t offset, const uint64_t size,
+                 const std::function<void(const char *, size_t)> &sink) {
+  constexpr size_t kHeaderSize = 32;
+  if (unlikely(size < kHeaderSize)) {
+    throw KindError(ErrorKind::Parse, "The patch is truncated");
+  }
+  std::array<uint8_t, kHeaderSize> header{};
+  patch.clear();
+  patch.seekg(checkedCast<std::streamoff>(offset));
+  if (unlikely(!patch.read(reinterpret_cast<char *>(header.data()),
+                           header.size()))) {
+    throw KindError(ErrorKind::Parse, "The patch is truncated");
+  }
+  using Compression = PatchStream::Compression;
+  std::array<Compression, 3> compression{
+      Compression::Bzip2, Compression::Bzip2, Compression::Bzip2};
+  if (std::memcmp(header.data(), "BSDF2", 5) == 0) {
+    for (size_t i = 0; i < compression.size(); ++i) {
+      if (unlikely(header[5 + i] > 2)) {
+        throw KindError(ErrorKind::Unsupported,
+                        "Unknown compression " +
+                            std::to_string(header[5 + i]) +
+                            " in the patch");
+      }
+      compression[i] = static_cast<Compression>(header[5 + i]);
+    }
+  } else if (unlikely(std::memcmp(header.data(), "BSDIFF40", 8) != 0)) {
+    throw KindError(ErrorKind::Parse, "Not a bsdiff patch");
+  }
+  const int64_t ctrl_size = PatchStream::decodeNumber(header.data() + 8);
+  const int64_t diff_size = PatchStream::decodeNumber(header.data() + 16);
+  const int64_t new_size = PatchStream::decodeNumber(header.data() + 24);
+  if (unlikely(ctrl_size < 0 || diff_size < 0 || new_size < 0 ||
+               static_cast<uint64_t>(ctrl_size) + diff_size >
+                   size - kHeaderSize)) {
+    throw KindError(ErrorKind::Parse, "The patch is corrupt");
+  }
+  const uint64_t ctrl_at = offset + kHeaderSize;
+  const uint64_t diff_at = ctrl_at + ctrl_size;
+  const uint64_t extra_at = diff_at + diff_size;
+  PatchStream ctrl(patch, ctrl_at, ctrl_size, compression[0]);
+  PatchStream diff(patch, diff_at, diff_size, compression[1]);
+  PatchStream extra(patch, extra_at, offset + size - extra_at,
+                    compression[2]);
+
+  // The new data is the old one plus the diff bytes where the control
+  // stream says so, and extra bytes in between.
+  std::vector<char> buffer(1 << 16);
+  int64_t old_pos = 0;
+  int64_t new_pos = 0;
+  while (new_pos < new_size) {
+    const int64_t add = ctrl.readNumber();
+    const int64_t copy = ctrl.readNumber();
+    const int64_t seek = ctrl.readNumber();
+    if (unlikely(add < 0 || copy < 0 || add > new_size - new_pos ||
+                 copy > new_size - new_pos - add)) {
+      throw KindError(ErrorKind::Parse, "The patch is corrupt");
+    }
+    for (int64_t left = add; left > 0;) {
+      const auto n = static_cast<size_t>(
+          std::min<int64_t>(left, static_cast<int64_t>(buffer.size())));
+      diff.read(buffer.data(), n);
+      for (size_t i = 0; i < n; ++i) {
+        const int64_t at = old_pos + static_cast<int64_t>(i);
+        if (at >= 0 && at < static_cast<int64_t>(old_size)) {
+          buffer[i] = static_cast<char>(buffer[i] + old[at]);
+        }
+      }
+      sink(buffer.data(), n);
+      old_pos += static_cast<int64_t>(n);
+      left -= static_cast<int64_t>(n);
+    }
+    for (int64_t left = copy; left > 0;) {
+      const auto n = static_cast<size_t>(
+          std::min<int64_t>(left, static_cast<int64_t>(buffer.size())));
+      extra.read(buffer.data(), n);
+      sink(buffer.data(), n);
+      left -= static_cast<int64_t>(n);
+    }
+    new_pos += add + copy;
+    old_pos = checkedAdd(old_pos, seek);
+  }
+  return static_cast<uint64_t>(new_size);
+}
+
+// Apply an imgdiff patch of applypatch, the part of patch from offset on,
+// to old, handing the new data to sink. Such patches split the data into
+// chunks, and patch the deflate streams in it, like the files of an APK,
+// decompressed, so that small changes to them give small patches. Those
+// are compressed again with the same settings, which gives the same bytes
+// with the zlib the recovery uses. Returns the size of the new data.
+uint64_t imgpatch(const char *old, const size_t old_size, std::istream &patch,
+                  const uint64_t offset, const uint64_t size,
+                  const std::function<void(const char *, size_t)> &sink) {
+  const auto corrupt = [] {
+    return KindError(ErrorKind::Parse, "The imgdiff patch is corrupt");
+  };
+  uint64_t at = offset;
+  const auto read = [&](const size_t n) {
+    if (unlikely(at + n > offset + size)) {
+      throw KindError(ErrorKind::Parse, "The imgdiff patch is truncated");
+    }
+    std::vector<char> data(n);
+    patch.clear();
+    patch.seekg(checkedCast<std::streamoff>(at));
+    if (unlikely(!patch.read(data.data(), static_cast<std::streamsize>(n)))) {
+      throw KindError(ErrorKind::Parse, "The imgdiff patch is truncated");
+    }
+    at += n;
+    return data;
+  };
+  // The old data of a chunk, and the patch of it, which runs to the end.
+  const auto sourceOf = [&](const std::vector<char> &header) {
+    const auto src_start = readLittleEndian<uint64_t>(header.data());
+    const auto src_len = readLittleEndian<uint64_t>(header.data() + 8);
+    const auto patch_offset = readLittleEndian<uint64_t>(header.data() + 16);
+    if (unlikely(src_start > old_size || src_len > old_size - src_start ||
+                 patch_offset > size)) {
+      throw corrupt();
+    }
+    return std::make_tuple(old + src_start, static_cast<size_t>(src_len),
+                           patch_offset);
+  };
+
+  const auto header = read(12);
+  if (unlikely(std::memcmp(header.data(), "IMGDIFF2", 8) != 0)) {
+    throw KindError(ErrorKind::Parse, "Not an imgdiff patch");
+  }
+  const auto chunks = readLittleEndian<uint32_t>(header.data() + 8);
+  uint64_t written = 0;
+  const auto count = [&](const char *data, size_t n) {
+    written += n;
+    sink(data, n);
+  };
+  for (uint32_t chunk = 0; chunk < chunks; ++chunk) {
+    const auto type = readLittleEndian<uint32_t>(read(4).data());
+    if (type == 0) {
+      // Normal: a bsdiff patch of a part of the old data.
+      const auto [src, src_len, patch_offset] = sourceOf(read(24));
+      bspatch(src, src_len, patch, offset + patch_offset, size - patch_offset,
+              count);
+    } else if (type == 2) {
+      // Deflate: a bsdiff patch of the decompressed deflate stream.
+      const auto chunk_header = read(60);
+      const auto [src, src_len, patch_offset] = sourceOf(chunk_header);
+      const auto expanded_len =
+          readLittleEndian<uint64_t>(chunk_header.data() + 24);
+      const auto target_len =
+          readLittleEndian<uint64_t>(chunk_header.data() + 32);
+      std::array<int, 5> settings{};
+      for (size_t i = 0; i < settings.size(); ++i) {
+        settings[i] = static_cast<int32_t>(
+            readLittleEndian<uint32_t>(chunk_header.data() + 40 + 4 * i));
+      }
+#ifdef HAVE_ZLIB
+      // Only chunks are held in memory, as large as the files they hold.
+      std::vector<char> expanded(checkedCast<size_t>(expanded_len));
+      z_stream inflater{};
+      if (unlikely(inflateInit2(&inflater, -15) != Z_OK)) {
+        throw std::runtime_error("Error creating zlib inflate state.");
+      }
+      inflater.next_in =
+          reinterpret_cast<Bytef *>(const_cast<char *>(src));
+      inflater.avail_in = checkedCast<uInt>(src_len);
+      inflater.next_out = reinterpret_cast<Bytef *>(expanded.data());
+      inflater.avail_out = checkedCast<uInt>(expanded.size());
+      const int inflated = inflate(&inflater, Z_FINISH);
+      const bool complete = inflated == Z_STREAM_END ||
+                            (inflated == Z_BUF_ERROR && inflater.avail_out == 0);
+      inflateEnd(&inflater);
+      if (unlikely(!complete || inflater.avail_out != 0)) {
+        throw KindError(ErrorKind::Parse,
+                        "The old deflate data of chunk " +
+                            std::to_string(chunk) + " doesn't expand to " +
+                            std::to_string(expanded_len) + " bytes");
+      }
+
+      z_stream deflater{};
+      if (unlikely(deflateInit2(&deflater, settings[0], settings[1],
+                                settings[2], settings[3],
+                                settings[4]) != Z_OK)) {
+        throw KindError(ErrorKind::Parse, "The imgdiff patch has unknown "
+                                          "deflate settings");
+      }
+      std::vector<char> compressed(1 << 16);
+      uint64_t patched = 0;
+      const auto compress = [&](const char *data, size_t n, const int flush) {
+        deflater.next_in = reinterpret_cast<Bytef *>(const_cast<char *>(data));
+        deflater.avail_in = static_cast<uInt>(n);
+        int result = Z_OK;
+        do {
+          deflater.next_out = reinterpret_cast<Bytef *>(compressed.data());
+          deflater.avail_out = static_cast<uInt>(compressed.size());
+          result = deflate(&deflater, flush);
+          if (unlikely(result == Z_STREAM_ERROR)) {
+            throw corrupt();
+          }
+          count(compressed.data(), compressed.size() - deflater.avail_out);
+        } while (deflater.avail_out == 0 ||
+                 (flush == Z_FINISH && result != Z_STREAM_END));
+      };
+      try {
+        bspatch(expanded.data(), expanded.size(), patch, offset + patch_offset,
+                size - patch_offset, [&](const char *data, size_t n) {
+                  patched += n;
+                  compress(data, n, Z_NO_FLUSH);
+                });
+        compress(nullptr, 0, Z_FINISH);
+      } catch (...) {
+        deflateEnd(&deflater);
+        throw;
+      }
+      deflateEnd(&deflater);
+      if (unlikely(patched != target_len)) {
+        throw corrupt();
+      }
+#else
+      (void)src;
+      (void)patch_offset;
+      (void)expanded_len;
+      (void)target_len;
+      throw KindError(ErrorKind::Unsupported,
+                      "This build has no zlib support, which imgdiff "
+                      "patches of deflate data need. Build it with zlib");
+#endif
+    } else if (type == 3) {
+      // Raw: the new data itself.
+      const auto len = readLittleEndian<uint32_t>(read(4).data());
+      for (uint32_t left = len; left > 0;) {
+        const auto n = std::min<uint32_t>(left, 1 << 16);
+        const auto data = read(n);
+        count(data.data(), data.size());
+        left -= n;
+      }
+    } else {
+      throw KindError(ErrorKind::Unsupported,
+                      "Unknown imgdiff chunk type " + std::to_string(type));
+    }
+  }
+  return written;
+}
+
+// A source image or device which an incremental OTA is applied to. It's
+// only opened for reading, and read at block offsets.
+class SourceImage {
+public:
+  using Ranges = std::vector<TransferList::ByteSegments>;
+
+  explicit SourceImage(const std::filesystem::path &path)
+      : file_path(path), file(path, std::ios::binary) {
+    if (unlikely(!file.is_open())) {
+      throw IOException(path, "open");
+    }
+    // Devices have no file size, their end is found by seeking there.
+    file.seekg(0, std::ios::end);
+    const auto end = file.tellg();
+    if (unlikely(end < 0)) {
+      throw IOException(path, "seek");
+    }
+    file_size = static_cast<uint64_t>(end);
+  }
+
+  [[nodiscard]] const std::filesystem::path &path() const noexcept {
+    return file_path;
+  }
+  [[nodiscard]] uint64_t size() const noexcept { return file_size; }
+  [[nodiscard]] FileSizeT blocks() const noexcept {
+    return static_cast<FileSizeT>(file_size / BLOCK_SIZE);
+  }
+
+  // The bytes at offset, which need not be at a block.
+  std::vector<char> readBytes(const uint64_t offset, const size_t size) {
+    std::vector<char> data(size);
+    file.clear();
+    file.seekg(checkedCast<std::streamoff>(offset));
+    if (unlikely(!file.read(data.data(), static_cast<std::streamsize>(size)))) {
+      throw IOException(file_path, "read");
+    }
+    return data;
+  }
+
+  // The blocks of the ranges, one after another.
+  std::vector<char> read(const Ranges &ranges) {
+    FileSizeT count = 0;
+    for (const auto &range : ranges) {
+      count = checkedAdd(count, range.size());
+    }
+    std::vector<char> data(checkedCast<size_t>(count) * BLOCK_SIZE);
+    char *at = data.data();
+    for (const auto &range : ranges) {
+      const auto bytes = static_cast<size_t>(range.size()) * BLOCK_SIZE;
+      file.clear();
+      file.seekg(checkedCast<std::streamoff>(range.begin() * BLOCK_SIZE));
+      if (unlikely(!file.read(at, static_cast<std::streamsize>(bytes)))) {
+        throw IOException(file_path, "read");
+      }
+      at += bytes;
+    }
+    return data;
+  }
+
+  // Copy the whole source into out, at its start.
+  void copyTo(std::ostream &out, const size_t buffer_size) {
+    std::vector<char> buffer(buffer_size);
+    file.clear();
+    file.seekg(0);
+    out.seekp(0);
+    for (uint64_t left = file_size; left > 0;) {
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(left, buffer.size()));
+      if (unlikely(!file.read(buffer.data(), static_cast<std::streamsize>(n)))) {
+        throw IOException(file_path, "read");
+      }
+      out.write(buffer.data(), static_cast<std::streamsize>(n));
+      left -= n;
+    }
+  }
+
+private:
+  std::filesystem::path file_path;
+  std::ifstream file;
+  uint64_t file_size = 0;
+};
+
+// The patch dat next to a new dat, system.patch.dat for
+// system.new.dat(.br).
+std::filesystem::path patchDatFor(const std::filesystem::path &new_dat_file) {
+  std::string name = new_dat_file.filename().string();
+  if (std::filesystem::path(name).extension() == ".br") {
+    name = std::filesystem::path(name).stem().string();
+  }
+  constexpr std::string_view kSuffix = ".new.dat";
+  if (name.size() > kSuffix.size() &&
+      name.compare(name.size() - kSuffix.size(), kSuffix.size(), kSuffix) ==
+          0) {
+    name.replace(name.size() - kSuffix.size(), kSuffix.size(), ".patch.dat");
+  } else {
+    name += ".patch.dat";
+  }
+  return new_dat_file.parent_path() / name;
+}
+
+// Applies an incremental OTA, whose transfer list also moves, stashes and
+// patches blocks of the source image, the way the recovery applies it to
+// the partition. The output starts as a copy of the source, which is never
+// written, and the commands go into it.
+class IncrementalUpdate {
+public:
+  using Ranges = SourceImage::Ranges;
+
+  // A command of the list, with its words taken apart.
+  struct Transfer {
+    std::string command;
+    int line = 0;
+    // The line as written, for errors.
+    std::string text;
+    Ranges target;
+    // The source blocks the command starts from: src_blocks of them, from
+    // the source ranges (at src_locs in the buffer, if given) and from the
+    // stashes at their ranges in the buffer.
+    FileSizeT src_blocks = 0;
+    Ranges src;
+    std::optional<Ranges> src_locs;
+    std::vector<std::pair<std::string, Ranges>> stashes;
+    // Version 3 and up: SHA-1 of the source and the target blocks.
+    std::string src_hash, tgt_hash;
+    // Where the patch of bsdiff and imgdiff is in the patch dat.
+    uint64_t patch_offset = 0, patch_size = 0;
+    // The stash of stash and free.
+    std::string stash_id;
+  };
+
+  IncrementalUpdate(const std::filesystem::path &transfer_list_file,
+                    SourceImage &source)
+      : source(source) {
+    parse(transfer_list_file);
+  }
+
+  [[nodiscard]] int version() const noexcept { return list_version; }
+  [[nodiscard]] const std::vector<Transfer> &transfers() const noexcept {
+    return list;
+  }
+  // The end of the target, in blocks.
+  [[nodiscard]] FileSizeT targetBlocks() const noexcept { return max_target; }
+
+  // Write the target into out, which holds a copy of the source. New data
+  // comes from new_dat, patches from patch_dat. Version 3 and up have the
+  // hashes of the blocks which are moved and patched, which are checked
+  // before writing them, and read back after. The blocks written go into
+  // the progress, if given.
+  void apply(std::iostream &out, std::istream *new_dat,
+             const std::filesystem::path &patch_dat,
+             ConversionProgress *progress = nullptr) {
+    // Erase writes nothing, stash and free have no target.
+    const auto writes = [](const Transfer &transfer) {
+      return transfer.command == "erase" ? 0 : count(transfer.target);
+    };
+    if (progress) {
+      for (const auto &transfer : list) {
+        progress->total_blocks += writes(transfer);
+      }
+    }
+    for (const auto &transfer : list) {
+      try {
+        applyOne(transfer, out, new_dat, patch_dat);
+      } catch (const KindError &e) {
+        throw KindError(e.kind(),
+                        "Line " + std::to_string(transfer.line) + " (" +
+                            transfer.command + "): " + e.what(),
+                        list_path, transfer.line, transfer.text);
+      }
+      if (unlikely(!out)) {
+        throw std::system_error(errno, std::generic_category(), "write");
+      }
+      if (progress) {
+        progress->written_blocks += writes(transfer);
+        progress->stats.bytes_written += writes(transfer) * BLOCK_SIZE;
+      }
+    }
+  }
+
+private:
+  SourceImage &source;
+  std::filesystem::path list_path;
+  int list_version = 0;
+  std::vector<Transfer> list;
+  FileSizeT max_target = 0;
+  std::map<std::string, std::vector<char>> stash;
+  std::ifstream patch_file;
+
+  static FileSizeT count(const Ranges &ranges) {
+    FileSizeT blocks = 0;
+    for (const auto &range : ranges) {
+      blocks = checkedAdd(blocks, range.size());
+    }
+    return blocks;
+  }
+
+  void parse(const std::filesystem::path &transfer_list_file) {
+    list_path = transfer_list_file;
+    TextFile file(transfer_list_file);
+    if (unlikely(!file.takeOneLine(&list_version) || list_version < 1 ||
+                 list_version > 4)) {
+      throw TextFileError::expected(file, "version 1, 2, 3 or 4");
+    }
+    // The total, and the stash sizes of version 2 and up.
+    file.ignoreLine<1>();
+    if (list_version >= 2) {
+      file.ignoreLine<2>();
+    }
+    const auto ranges = [&](const std::string &word) {
+      std::vector<FileSizeT> nums;
+      try {
+        nums = parseRanges(word);
+      } catch (const std::invalid_argument &) {
+      }
+      if (unlikely(nums.empty())) {
+        throw TextFileError::expected(file, "a range set, not '" + word + "'");
+      }
+      Ranges segments;
+      for (size_t i = 0; i < nums.size(); i += 2) {
+        if (unlikely(nums[i] > nums[i + 1])) {
+          throw TextFileError::expected(file, "ranges with <begin> <= <end>");
+        }
+        segments.emplace_back(nums[i], nums[i + 1]);
+      }
+      return segments;
+    };
+    const auto number = [&](const std::string &word) {
+      uint64_t value = 0;
+      const auto [ptr, ec] =
+          std::from_chars(word.data(), word.data() + word.size(), value);
+      if (unlikely(ec != std::errc() || ptr != word.data() + word.size())) {
+        throw TextFileError::expected(file, "a number, not '" + word + "'");
+      }
+      return value;
+    };
+
+    for (std::string line; file.takeOneLine(&line);) {
+      const auto words = split(line, ' ');
+      if (words.empty()) {
+        continue;
+      }
+      Transfer transfer;
+      transfer.command = words[0];
+      transfer.line = file.lineNumber();
+      transfer.text = file.wholeLastLine();
+      size_t pos = 1;
+      const auto next = [&]() -> const std::string & {
+        if (unlikely(pos >= words.size())) {
+          throw TextFileError::expected(file, "more words for " +
+                                                  transfer.command);
+        }
+        return words[pos++];
+      };
+      // Version 2 and up: <count> <range or -> [<locs>] [<id>:<range>...]
+      const auto sourceSpec = [&] {
+        transfer.src_blocks = static_cast<FileSizeT>(number(next()));
+        if (const auto &src = next(); src != "-") {
+          transfer.src = ranges(src);
+          if (pos < words.size()) {
+            transfer.src_locs = ranges(next());
+          }
+        }
+        while (pos < words.size()) {
+          const auto &spec = next();
+          const auto colon = spec.find(':');
+          if (unlikely(colon == std::string::npos)) {
+            throw TextFileError::expected(file, "<stash_id>:<range>");
+          }
+          transfer.stashes.emplace_back(spec.substr(0, colon),
+                                        ranges(spec.substr(colon + 1)));
+        }
+      };
+
+      const bool diff =
+          transfer.command == "bsdiff" || transfer.command == "imgdiff";
+      if (transfer.command == "erase" || transfer.command == "new" ||
+          transfer.command == "zero") {
+        transfer.target = ranges(next());
+      } else if (transfer.command == "move" || diff) {
+        if (diff) {
+          transfer.patch_offset = number(next());
+          transfer.patch_size = number(next());
+        }
+        if (list_version == 1) {
+          transfer.src = ranges(next());
+          transfer.src_blocks = count(transfer.src);
+          transfer.target = ranges(next());
+        } else {
+          if (list_version >= 3) {
+            transfer.src_hash = next();
+            // A move gives the target the same hash.
+            transfer.tgt_hash = diff ? next() : transfer.src_hash;
+          }
+          transfer.target = ranges(next());
+          sourceSpec();
+        }
+      } else if (list_version >= 2 && transfer.command == "stash") {
+        transfer.stash_id = next();
+        transfer.src = ranges(next());
+        transfer.src_blocks = count(transfer.src);
+      } else if (list_version >= 2 && transfer.command == "free") {
+        transfer.stash_id = next();
+      } else {
+        throw TextFileError::expected(
+            file, "a command of a version " + std::to_string(list_version) +
+                      " transfer list");
+      }
+
+      // Fail before writing anything if it's the wrong source.
+      for (const auto &range : transfer.src) {
+        if (unlikely(range.end() > source.blocks())) {
+          throw TextFileError(
+              file, "The source " + quotedPath(source.path()) + " has " +
+                        std::to_string(source.blocks()) +
+                        " blocks, but the list reads up to block " +
+                        std::to_string(range.end()) +
+                        ", wrong source image");
+        }
+      }
+      if ((transfer.command == "move" || diff) && list_version >= 2 &&
+          unlikely(transfer.src_blocks < count(transfer.src))) {
+        throw TextFileError::expected(file, "a source block count which "
+                                            "covers the source ranges");
+      }
+      if (transfer.command == "move" &&
+          unlikely(transfer.src_blocks != count(transfer.target))) {
+        throw TextFileError::expected(file,
+                                      "as many target as source blocks");
+      }
+      for (const auto &range : transfer.target) {
+        max_target = std::max(max_target, range.end());
+      }
+      if (list_version >= 3) {
+        // Hashes are compared as lowercase hex.
+        for (auto *hash :
+             {&transfer.src_hash, &transfer.tgt_hash, &transfer.stash_id}) {
+          std::transform(hash->begin(), hash->end(), hash->begin(),
+                         [](const unsigned char c) { return std::tolower(c); });
+        }
+      }
+      list.push_back(std::move(transfer));
+    }
+  }
+
+  // Copy the blocks of from, in order, into the ranges of into, which are
+  // in blocks of the buffer.
+  static void moveRange(std::vector<char> &into, const Ranges &locs,
+                        const std::vector<char> &from) {
+    size_t at = 0;
+    for (const auto &range : locs) {
+      const auto bytes = static_cast<size_t>(range.size()) * BLOCK_SIZE;
+      const auto offset = static_cast<size_t>(range.begin()) * BLOCK_SIZE;
+      if (unlikely(offset + bytes > into.size() || at + bytes > from.size())) {
+        throw KindError(ErrorKind::Parse,
+                        "A range is outside of the source blocks");
+      }
+      std::copy_n(from.begin() + static_cast<std::ptrdiff_t>(at), bytes,
+                  into.begin() + static_cast<std::ptrdiff_t>(offset));
+      at += bytes;
+    }
+  }
+
+  // The source blocks of a move or a patch, put together.
+  std::vector<char> loadSource(const Transfer &transfer) {
+    std::vector<char> buffer(checkedCast<size_t>(transfer.src_blocks) *
+                             BLOCK_SIZE);
+    if (!transfer.src.empty()) {
+      auto data = source.read(transfer.src);
+      if (transfer.src_locs) {
+        moveRange(buffer, *transfer.src_locs, data);
+      } else {
+        std::copy(data.begin(), data.end(), buffer.begin());
+      }
+    }
+    for (const auto &[id, locs] : transfer.stashes) {
+      const auto stashed = stash.find(id);
+      if (unlikely(stashed == stash.end())) {
+        throw KindError(ErrorKind::Parse, "No stash " + id);
+      }
+      moveRange(buffer, locs, stashed->second);
+    }
+    return buffer;
+  }
+
+  // Write data into the ranges, one after another.
+  static void writeRanges(std::ostream &out, const Ranges &ranges,
+                          const char *data) {
+    for (const auto &range : ranges) {
+      const auto bytes = static_cast<std::streamsize>(range.size()) * BLOCK_SIZE;
+      out.seekp(range.begin() * BLOCK_SIZE);
+      out.write(data, bytes);
+      data += bytes;
+    }
+  }
+
+  // The SHA-1 of the blocks of the ranges in the output.
+  static std::string hashRanges(std::iostream &out, const Ranges &ranges) {
+    Sha1 hash;
+    std::vector<char> buffer(BLOCK_SIZE);
+    for (const auto &range : ranges) {
+      out.seekg(range.begin() * BLOCK_SIZE);
+      for (auto block = range.begin(); block < range.end(); ++block) {
+        if (unlikely(!out.read(buffer.data(), BLOCK_SIZE))) {
+          throw std::system_error(errno, std::generic_category(), "read");
+        }
+        hash.update(buffer.data(), buffer.size());
+      }
+    }
+    const auto digest = hash.digest();
+    return toHex(digest.data(), digest.size());
+  }
+
+  static std::string hashData(const std::vector<char> &data) {
+    const auto digest = Sha1::of(data.data(), data.size());
+    return toHex(digest.data(), digest.size());
+  }
+
+  // Whether the source blocks are the ones the list expects. If not, the
+  // target may hold what the command would write already, like the
+  // recovery finds it when it carries on after a reboot, and it's skipped.
+  bool checkSource(const Transfer &transfer, const std::vector<char> &data,
+                   std::iostream &out) {
+    if (transfer.src_hash.empty()) {
+      return true;
+    }
+    const auto actual = hashData(data);
+    if (actual == transfer.src_hash) {
+      return true;
+    }
+    if (hashRanges(out, transfer.target) == transfer.tgt_hash) {
+      std::cout << "Line " << transfer.line << " (" << transfer.command
+                << "): the target holds its blocks already, skipping it"
+                << std::endl;
+      return false;
+    }
+    throw KindError(ErrorKind::Verification,
+                    "The source blocks have SHA-1 " + actual + ", expected " +
+                        transfer.src_hash + ", wrong source image");
+  }
+
+  // Whether the blocks written are the ones the list expects.
+  static void checkTarget(const Transfer &transfer, std::iostream &out) {
+    if (transfer.tgt_hash.empty()) {
+      return;
+    }
+    out.flush();
+    const auto actual = hashRanges(out, transfer.target);
+    if (unlikely(actual != transfer.tgt_hash)) {
+      throw KindError(ErrorKind::Verification,
+                      "The target blocks have SHA-1 " + actual +
+                          " after writing them, expected " +
+                          transfer.tgt_hash);
+    }
+  }
+
+  void applyOne(const Transfer &transfer, std::iostream &out,
+                std::istream *new_dat,
+                [[maybe_unused]] const std::filesystem::path &patch_dat) {
+    const auto &command = transfer.command;
+    if (command == "erase") {
+      // Whatever is there may stay.
+    } else if (command == "zero" || command == "new") {
+      std::vector<char> data(checkedCast<size_t>(count(transfer.target)) *
+                             BLOCK_SIZE);
+      // Past the end of the dat, reading it gives zeros too.
+      if (command == "new" && new_dat) {
+        new_dat->read(data.data(), static_cast<std::streamsize>(data.size()));
+        new_dat->clear();
+      }
+      writeRanges(out, transfer.target, data.data());
+    } else if (command == "move") {
+      const auto buffer = loadSource(transfer);
+      if (checkSource(transfer, buffer, out)) {
+        writeRanges(out, transfer.target, buffer.data());
+        checkTarget(transfer, out);
+      }
+    } else if (command == "stash") {
+      auto data = source.read(transfer.src);
+      // From version 3 on, stashes are named after the SHA-1 of the blocks.
+      if (list_version >= 3 &&
+          unlikely(hashData(data) != transfer.stash_id)) {
+        throw KindError(ErrorKind::Verification,
+                        "The blocks to stash have SHA-1 " + hashData(data) +
+                            ", expected " + transfer.stash_id +
+                            ", wrong source image");
+      }
+      stash[transfer.stash_id] = std::move(data);
+    } else if (command == "free") {
+      stash.erase(transfer.stash_id);
+    } else if (command == "bsdiff" || command == "imgdiff") {
+      const auto buffer = loadSource(transfer);
+      if (checkSource(transfer, buffer, out)) {
+        const auto size = count(transfer.target) * BLOCK_SIZE;
+        RangeWriter writer(out, transfer.target);
+        const auto written = (command == "bsdiff" ? bspatch : imgpatch)(
+            buffer.data(), buffer.size(), patchFile(patch_dat),
+            transfer.patch_offset, transfer.patch_size,
+            [&](const char *data, size_t n) { writer.write(data, n); });
+        if (unlikely(written != static_cast<uint64_t>(size))) {
+          throw KindError(ErrorKind::Parse,
+                          "The patch gives " + std::to_string(written) +
+                              " bytes for " + std::to_string(size) +
+                              " bytes of target blocks");
+        }
+        checkTarget(transfer, out);
+      }
+    }
+  }
+
+  // The patch dat, opened once a command needs it.
+  std::istream &patchFile(const std::filesystem::path &patch_dat) {
+    if (!patch_file.is_open()) {
+      patch_file.open(patch_dat, std::ios::binary);
+      if (unlikely(!patch_file.is_open())) {
+        throw IOException(patch_dat, "open");
+      }
+    }
+    return patch_file;
+  }
+
+  // Writes data into the ranges one after another, as it comes.
+  class RangeWriter {
+  public:
+    RangeWriter(std::ostream &out, const Ranges &ranges)
+        : out(out), ranges(ranges) {}
+
+    void write(const char *data, size_t size) {
+      while (size > 0) {
+        if (unlikely(range == ranges.size())) {
+          throw KindError(ErrorKind::Parse,
+                          "The patch gives more data than the target holds");
+        }
+        const auto &current = ranges[range];
+        const auto bytes = static_cast<uint64_t>(current.size()) * BLOCK_SIZE;
+        const auto n =
+            static_cast<size_t>(std::min<uint64_t>(size, bytes - at));
+        out.seekp(checkedCast<std::streamoff>(current.begin() * BLOCK_SIZE +
+                                              at));
+        out.write(data, static_cast<std::streamsize>(n));
+        data += n;
+        size -= n;
+        at += n;
+        if (at == bytes) {
+          ++range;
+          at = 0;
+        }
+      }
+    }
+
+  private:
+    std::ostream &out;
+    const Ranges &ranges;
+    size_t range = 0;
+    uint64_t at = 0;
+  };
+};
+
+// Apply an incremental OTA to a copy of options.source_img. The list may
+// have any commands of its version, the new dat only has the data of the
+// new commands and the patch dat next to it the patches.
+int applyIncrementalOrThrow(const std::filesystem::path &transfer_list_file,
+                            std::filesystem::path new_dat_file,
+                            const std::filesystem::path &output_img,
+                            const ConvertOptions &options,
+                            ConversionProgress *progress = nullptr) {
+  std::error_code ec;
+  const OutputLock lock(output_img);
+  if (isStreamOutput(output_img) ||
+      std::filesystem::is_block_file(output_img, ec) ||
+      options.output_format != OutputFormat::Raw) {
+    return reportError(ErrorKind::Unsupported,
+                       "Incremental OTAs are only applied into raw image "
+                       "files");
+  }
+  if (!options.audit_log.empty()) {
+    return reportError(ErrorKind::Unsupported,
+                       "--audit-log only records full OTAs");
+  }
+  if (std::filesystem::equivalent(options.source_img, output_img, ec)) {
+    return reportError(ErrorKind::Unsupported,
+                       "The source image is only read, write the output "
+                       "into another file",
+                       output_img);
+  }
+  if (std::filesystem::exists(output_img, ec) && !options.force) {
+    return reportError(ErrorKind::Aborted,
+                       quotedPath(output_img) +
+                           " already exists, use --force to overwrite it",
+                       output_img);
+  }
+
+  SourceImage source(options.source_img);
+  if (source.size() % BLOCK_SIZE != 0) {
+    std::cerr << "Warning: The source " << source.path() << " isn't a "
+              << "whole number of blocks, ignoring the last "
+              << source.size() % BLOCK_SIZE << " bytes" << std::endl;
+  }
+  IncrementalUpdate update(transfer_list_file, source);
+  std::cout << "Applying " << update.transfers().size()
+            << " commands of a version " << update.version()
+            << " transfer list to " << source.path() << " ("
+            << source.blocks() << " blocks)" << std::endl;
+
+  const auto patch_dat = patchDatFor(new_dat_file);
+  std::ifstream new_dat;
+  if (std::filesystem::exists(new_dat_file, ec)) {
+    if (!decompressNewDat(new_dat_file)) {
+      return EXIT_FAILURE;
+    }
+    new_dat.open(new_dat_file, std::ios::binary);
+  }
+
+  // Applied into a file next to the output, which only takes its place
+  // once all the hashes check out. A wrong source image then leaves
+  // nothing behind that would need --force to try again.
+  const std::filesystem::path temp = output_img.string() + ".tmp";
+  // Read back too, to check the blocks written.
+  std::fstream output(temp, std::ios::in | std::ios::out | std::ios::trunc |
+                                std::ios::binary);
+  if (unlikely(!output)) {
+    return reportError(ErrorKind::Io,
+                       "Could not open file " + quotedPath(temp), temp);
+  }
+  try {
+    source.copyTo(output, options.buffer_size);
+    update.apply(output, new_dat.is_open() ? &new_dat : nullptr, patch_dat,
+                 progress);
+    if (unlikely(!output.flush())) {
+      throw IOException(temp, "write");
+    }
+    output.close();
+
+    // The target may be larger than the source, or the partition larger
+    // yet.
+    const auto size = std::max<uint64_t>(
+        {source.size(),
+         static_cast<uint64_t>(
+             checkedMul<FileSizeT>(update.targetBlocks(), BLOCK_SIZE)),
+         static_cast<uint64_t>(options.partition_size)});
+    std::filesystem::resize_file(temp, size);
+    std::filesystem::rename(temp, output_img);
+  } catch (...) {
+    output.close();
+    std::filesystem::remove(temp, ec);
+    throw;
+  }
+  std::cout << "Done! Output image: " << output_img << std::endl;
+  return EXIT_SUCCESS;
+}
+
+// A COW file of Virtual A/B (Android 12 and up), as snapshotctl and the
+// update_engine write them: the changes to a partition as operations, each
+// writing one block of the new partition from a block of the old one, from
+// data of the file, XORed with old data, or with zeros. This reads the
+// version 2 format of Android 12 to 14.
+class CowFile {
+public:
+  enum class OpType : uint8_t {
+    Copy = 1,
+    Replace = 2,
+    Zero = 3,
+    Label = 4,
+    Cluster = 5,
+    Xor = 6,
+    Sequence = 7,
+    Footer = 0xff,
+  };
+
+  struct Operation {
+    OpType type;
+    uint8_t compression;
+    uint16_t data_length;
+    uint64_t new_block;
+    // The old block of copies, the byte offset in the old partition of XORs.
+    uint64_t source;
+    // Where the data of replaces and XORs is in the file.
+    uint64_t data_offset;
+  };
+
+  explicit CowFile(const std::filesystem::path &path)
+      : file_path(path), file(path, std::ios::binary) {
+    if (unlikely(!file.is_open())) {
+      throw IOException(path, "open");
+    }
+    std::array<char, 34> header{};
+    file.read(header.data(), header.size());
+    const auto got = static_cast<size_t>(file.gcount());
+    if (unlikely(got < 22 || readLittleEndian<uint64_t>(header.data()) !=
+                                 0x436f77634f572121ULL)) {
+      throw KindError(ErrorKind::Parse,
+                      quotedPath(path) + " isn't a Virtual A/B COW file");
+    }
+    major = readLittleEndian<uint16_t>(header.data() + 8);
+    minor = readLittleEndian<uint16_t>(header.data() + 10);
+    const auto header_size = readLittleEndian<uint16_t>(header.data() + 12);
+    const auto op_size = readLittleEndian<uint16_t>(header.data() + 16);
+    block_size = readLittleEndian<uint32_t>(header.data() + 18);
+    if (unlikely(major != 1 && major != 2)) {
+      throw KindError(ErrorKind::Unsupported,
+                      "COW version " + std::to_string(major) +
+                          " isn't supported, only 1 and 2 are");
+    }
+    if (unlikely(op_size != kOpSize || header_size < 22 ||
+                 block_size == 0 || block_size > (1 << 20))) {
+      throw KindError(ErrorKind::Parse,
+                      "The COW header of " + quotedPath(path) +
+                          " is corrupt");
+    }
+    // Newer ones have room for the merge after the header.
+    uint32_t buffer_size = 0;
+    if (header_size >= 34 && got >= 34) {
+      buffer_size = readLittleEndian<uint32_t>(header.data() + 30);
+    }
+
+    // Each operation is followed by its data, if it has some.
+    uint64_t pos = header_size + static_cast<uint64_t>(buffer_size);
+    std::array<char, kOpSize> op{};
+    for (;;) {
+      file.clear();
+      file.seekg(checkedCast<std::streamoff>(pos));
+      // The footer is shorter than the operations.
+      file.read(op.data(), op.size());
+      const auto type = static_cast<OpType>(op[0]);
+      if (file.gcount() > 0 && type == OpType::Footer) {
+        complete = true;
+        break;
+      }
+      if (static_cast<size_t>(file.gcount()) < op.size()) {
+        break;
+      }
+      Operation operation{type,
+                          static_cast<uint8_t>(op[1]),
+                          readLittleEndian<uint16_t>(op.data() + 2),
+                          readLittleEndian<uint64_t>(op.data() + 4),
+                          readLittleEndian<uint64_t>(op.data() + 12),
+                          pos + kOpSize};
+      if (unlikely(static_cast<uint8_t>(type) < 1 ||
+                   static_cast<uint8_t>(type) > 7)) {
+        throw KindError(ErrorKind::Parse,
+                        "Unknown COW operation " +
+                            std::to_string(static_cast<int>(op[0])) +
+                            " at offset " + std::to_string(pos));
+      }
+      pos += kOpSize;
+      if (type == OpType::Replace || type == OpType::Xor ||
+          type == OpType::Sequence) {
+        pos += operation.data_length;
+      }
+      operations.push_back(operation);
+    }
+  }
+
+  [[nodiscard]] const std::vector<Operation> &ops() const noexcept {
+    return operations;
+  }
+  [[nodiscard]] uint32_t blockSize() const noexcept { return block_size; }
+  [[nodiscard]] std::string version() const {
+    return std::to_string(major) + "." + std::to_string(minor);
+  }
+  // Whether the footer is there, which it isn't while it's being written.
+  [[nodiscard]] bool isComplete() const noexcept { return complete; }
+
+  static std::string compressionName(const uint8_t compression) {
+    static const std::array<const char *, 5> kNames{"none", "gz", "brotli",
+                                                    "lz4", "zstd"};
+    return compression < kNames.size() ? kNames[compression]
+                                       : std::to_string(compression);
+  }
+
+  // The block of data of a replace or XOR.
+  std::vector<char> data(const Operation &op) {
+    std::vector<char> stored(op.data_length);
+    file.clear();
+    file.seekg(checkedCast<std::streamoff>(op.data_offset));
+    if (unlikely(!file.read(stored.data(),
+                            static_cast<std::streamsize>(stored.size())))) {
+      throw KindError(ErrorKind::Parse,
+                      "The COW file " + quotedPath(file_path) +
+                          " is truncated");
+    }
+    // Blocks which don't get smaller are stored as they are.
+    if (op.compression == 0 || stored.size() == block_size) {
+      if (unlikely(stored.size() != block_size)) {
+        throw corrupt(op);
+      }
+      return stored;
+    }
+    std::vector<char> block(block_size);
+    if (op.compression == 1) {
+#ifdef HAVE_ZLIB
+      auto size = static_cast<uLongf>(block.size());
+      if (unlikely(uncompress(reinterpret_cast<Bytef *>(block.data()), &size,
+                              reinterpret_cast<const Bytef *>(stored.data()),
+                              static_cast<uLong>(stored.size())) != Z_OK ||
+                   size != block.size())) {
+        throw corrupt(op);
+      }
+      return block;
+#endif
+    } else if (op.compression == 2) {
+#ifdef HAVE_BROTLI
+      size_t size = block.size();
+      if (unlikely(BrotliDecoderDecompress(
+                       stored.size(),
+                       reinterpret_cast<const uint8_t *>(stored.data()),
+                       &size, reinterpret_cast<uint8_t *>(block.data())) !=
+                       BROTLI_DECODER_RESULT_SUCCESS ||
+                   size != block.size())) {
+        throw corrupt(op);
+      }
+      return block;
+#endif
+    }
+    throw KindError(ErrorKind::Unsupported,
+                    compressionName(op.compression) +
+                        " compressed COW data isn't supported by this build");
+  }
+
+private:
+  static constexpr size_t kOpSize = 20;
+
+  std::filesystem::path file_path;
+  std::ifstream file;
+  uint16_t major = 0, minor = 0;
+  uint32_t block_size = 0;
+  bool complete = false;
+  std::vector<Operation> operations;
+
+  KindError corrupt(const Operation &op) const {
+    return KindError(ErrorKind::Parse,
+                     "The data of block " + std::to_string(op.new_block) +
+                         " in " + quotedPath(file_path) + " is corrupt");
+  }
+};
+
+// Show what a COW file holds: its version, how many operations of each
+// kind and compression it has, and how far into the partition they write.
+void printCowInfo(const std::filesystem::path &path) {
+  CowFile cow(path);
+  std::map<std::string, size_t> counts;
+  uint64_t end = 0;
+  for (const auto &op : cow.ops()) {
+    std::string name;
+    switch (op.type) {
+    case CowFile::OpType::Copy:
+      name = "copy";
+      break;
+    case CowFile::OpType::Replace:
+      name = "replace (" + CowFile::compressionName(op.compression) + ")";
+      break;
+    case CowFile::OpType::Zero:
+      name = "zero";
+      break;
+    case CowFile::OpType::Xor:
+      name = "xor (" + CowFile::compressionName(op.compression) + ")";
+      break;
+    case CowFile::OpType::Label:
+      name = "label";
+      break;
+    case CowFile::OpType::Cluster:
+      name = "cluster";
+      break;
+    default:
+      name = "sequence";
+      break;
+    }
+    ++counts[name];
+    if (op.type == CowFile::OpType::Copy ||
+        op.type == CowFile::OpType::Replace ||
+        op.type == CowFile::OpType::Zero || op.type == CowFile::OpType::Xor) {
+      end = std::max<uint64_t>(end, op.new_block + 1);
+    }
+  }
+  std::cout << "COW version " << cow.version() << ", block size "
+            << cow.blockSize() << ", "
+            << (cow.isComplete() ? "complete" : "incomplete (no footer)")
+            << std::endl;
+  std::cout << cow.ops().size() << " operations" << std::endl;
+  for (const auto &[name, count] : counts) {
+    std::cout << "  " << name << ": " << count << std::endl;
+  }
+  std::cout << "Writes up to block " << end << " (" << std::fixed
+            << std::setprecision(1)
+            << static_cast<double>(end * cow.blockSize()) / (1 << 20)
+            << " MiB)" << std::endl;
+}
+
+// Merge a COW file into a copy of the partition it was made against, which
+// gives the new partition, like the device does after the reboot.
+void mergeCow(const std::filesystem::path &base_img,
+              const std::filesystem::path &cow_file,
+              const std::filesystem::path &output_img,
+              const size_t buffer_size) {
+  CowFile cow(cow_file);
+  if (!cow.isComplete()) {
+    std::cerr << "Warning: " << cow_file << " has no footer, it may be "
+              << "incomplete" << std::endl;
+  }
+  SourceImage base(base_img);
+  const uint64_t block_size = cow.blockSize();
+  uint64_t end = base.size();
+  for (const auto &op : cow.ops()) {
+    if (op.type == CowFile::OpType::Copy &&
+        unlikely((op.source + 1) * block_size > base.size())) {
+      throw KindError(ErrorKind::Parse,
+                      "The COW copies block " + std::to_string(op.source) +
+                          ", past the end of " + quotedPath(base_img) +
+                          ", wrong base image");
+    }
+    if (op.type == CowFile::OpType::Xor &&
+        unlikely(op.source + block_size > base.size())) {
+      throw KindError(ErrorKind::Parse,
+                      "The COW reads past the end of " + quotedPath(base_img) +
+                          ", wrong base image");
+    }
+    end = std::max(end, checkedMul<uint64_t>(op.new_block + 1, block_size));
+  }
+
+  const OutputLock lock(output_img);
+  std::ofstream output(output_img, std::ios::binary);
+  if (unlikely(!output)) {
+    throw IOException(output_img, "open");
+  }
+  base.copyTo(output, buffer_size);
+  size_t merged = 0;
+  std::vector<char> block(block_size);
+  for (const auto &op : cow.ops()) {
+    switch (op.type) {
+    case CowFile::OpType::Copy:
+      block = base.readBytes(op.source * block_size, block_size);
+      break;
+    case CowFile::OpType::Replace:
+      block = cow.data(op);
+      break;
+    case CowFile::OpType::Zero:
+      block.assign(block_size, 0);
+      break;
+    case CowFile::OpType::Xor: {
+      block = cow.data(op);
+      const auto old = base.readBytes(op.source, block_size);
+      for (size_t i = 0; i < block.size(); ++i) {
+        block[i] ^= old[i];
+      }
+      break;
+    }
+    default:
+      continue;
+    }
+    output.seekp(checkedCast<std::streamoff>(op.new_block * block_size));
+    output.write(block.data(), static_cast<std::streamsize>(block.size()));
+    ++merged;
+  }
+  if (unlikely(!output.flush())) {
+    throw IOException(output_img, "write");
+  }
+  output.close();
+  std::filesystem::resize_file(output_img, end);
+  std::cout << "Merged " << merged << " blocks into " << output_img
+            << std::endl;
+}
+
+// Keys for signing manifests are text files with the hex of an Ed25519
+// key: the 32 byte seed for private keys, the 32 byte key for public ones.
+// Public keys may also be given as the hex itself.
+template <typename Key> Key loadKey(const std::string &key_or_file) {
+  std::error_code ec;
+  std::string hex = key_or_file;
+  if (std::filesystem::is_regular_file(key_or_file, ec)) {
+    TextFile file(key_or_file);
+    if (!file.takeOneLine(&hex)) {
+      throw TextFileError::expected(file, "a key in hex");
+    }
+  }
+  Key key{};
+  try {
+    const auto bytes = fromHex(hex);
+    if (bytes.size() != key.size()) {
+      throw std::invalid_argument("Wrong key size");
+    }
+    std::copy(bytes.begin(), bytes.end(), key.begin());
+  } catch (const std::invalid_argument &) {
+    throw KindError(ErrorKind::Parse,
+                    quotedPath(key_or_file) + " isn't a key of " +
+                        std::to_string(2 * key.size()) + " hex digits");
+  }
+  return key;
+}
+
+// Write a key file which mustn't exist yet. It's created with the key's
+// permissions in the same step, so it's never there to be replaced or read
+// by others in between.
+void writeKeyFile(const std::filesystem::path &path, const std::string &hex,
+                  const bool private_key) {
+  const std::string text = hex + "\n";
+#ifdef HAS_MMAP
+  const FileDescriptor fd(path, O_WRONLY | O_CREAT | O_EXCL | O_CLOEXEC,
+                          private_key ? 0600 : 0644);
+  const bool exists = !fd && errno == EEXIST;
+  bool written = false;
+  if (fd) {
+    size_t done = 0;
+    while (done < text.size()) {
+      const ssize_t n = write(fd.get(), text.data() + done, text.size() - done);
+      if (n < 0 && errno == EINTR) {
+        continue;
+      } else if (n <= 0) {
+        break;
+      }
+      done += static_cast<size_t>(n);
+    }
+    written = done == text.size();
+  }
+#else
+  // "x" fails if the file is there, as O_EXCL does. Windows has no mode
+  // for private files.
+  (void)private_key;
+  std::FILE *file = std::fopen(path.string().c_str(), "wx");
+  std::error_code exists_ec;
+  const bool exists = !file && std::filesystem::exists(path, exists_ec);
+  bool written = false;
+  if (file) {
+    written = std::fwrite(text.data(), 1, text.size(), file) == text.size();
+    written = std::fclose(file) == 0 && written;
+  }
+#endif
+  if (exists) {
+    throw KindError(ErrorKind::Aborted, quotedPath(path) +
+                                            " already exists, not "
+                                            "overwriting a key");
+  } else if (unlikely(!written)) {
+    std::error_code ec;
+    std::filesystem::remove(path, ec);
+    throw IOException(path, "write");
+  }
+}
+
+// Write a new private key, readable by the owner only, and its public key
+// into <key_file>.pub.
+void generateSigningKey(const std::filesystem::path &key_file) {
+  const std::filesystem::path pub_file = key_file.string() + ".pub";
+  // Taken from the system's random source on the platforms this builds on.
+  std::random_device random;
+  Ed25519::Seed seed{};
+  for (auto &byte : seed) {
+    byte = static_cast<uint8_t>(random());
+  }
+  const auto key = Ed25519::publicKey(seed);
+  writeKeyFile(key_file, toHex(seed.data(), seed.size()), true);
+  try {
+    writeKeyFile(pub_file, toHex(key.data(), key.size()), false);
+  } catch (...) {
+    // Without its public key, the private one is of no use.
+    std::error_code ec;
+    std::filesystem::remove(key_file, ec);
+    throw;
+  }
+  std::cout << "Public key: " << toHex(key.data(), key.size()) << std::endl;
+  std::cout << "Saved the keys to " << key_file << " and " << pub_file
+            << std::endl;
+}
+
+// Signatures are detached, in <file>.sig: "ed25519 <public_key>
+// <signature>", both in hex, over the bytes of the file.
+std::filesystem::path signatureFor(const std::filesystem::path &file) {
+  return file.string() + ".sig";
+}
+
+std::string readWholeFile(const std::filesystem::path &path) {
+  std::ifstream in(path, std::ios::binary);
+  if (unlikely(!in)) {
+    throw IOException(path, "open");
+  }
+  return std::string(std::istreambuf_iterator<char>(in),
+                     std::istreambuf_iterator<char>());
+}
+
+void signFile(const std::filesystem::path &file, const Ed25519::Seed &seed) {
+  const std::string data = readWholeFile(file);
+  const auto key = Ed25519::publicKey(seed);
+  const auto signature = Ed25519::sign(seed, data.data(), data.size());
+  const auto sig_file = signatureFor(file);
+  std::ofstream out(sig_file);
+  out << "ed25519 " << toHex(key.data(), key.size()) << " "
+      << toHex(signature.data(), signature.size()) << "\n";
+  if (unlikely(!out.flush())) {
+    throw IOException(sig_file, "write");
+  }
+  std::cout << "Signed " << file << std::endl;
+}
+
+// Check the signature of a file against the key it's trusted from.
+void verifyFileSignature(const std::filesystem::path &file,
+                         const Ed25519::PublicKey &trusted) {
+  TextFile sig_file(signatureFor(file));
+  std::string line, algorithm, key_hex, signature_hex;
+  Ed25519::PublicKey key{};
+  Ed25519::Signature signature{};
+  const auto parse = [](const std::string &hex, auto &out) {
+    if (hex.size() != 2 * out.size() ||
+        !std::all_of(hex.begin(), hex.end(),
+                     [](const char c) { return std::isxdigit(c); })) {
+      return false;
+    }
+    const auto bytes = fromHex(hex);
+    std::copy(bytes.begin(), bytes.end(), out.begin());
+    return true;
+  };
+  if (!sig_file.takeOneLine(&line) ||
+      !(std::stringstream(line) >> algorithm >> key_hex >> signature_hex) ||
+      algorithm != "ed25519" || !parse(key_hex, key) ||
+      !parse(signature_hex, signature)) {
+    throw TextFileError::expected(sig_file,
+                                  "'ed25519 <public_key> <signature>'");
+  }
+  if (key != trusted) {
+    throw KindError(ErrorKind::Verification,
+                    quotedPath(file) + " is signed by another key, " +
+                        key_hex);
+  }
+  const std::string data = readWholeFile(file);
+  if (!Ed25519::verify(key, data.data(), data.size(), signature)) {
+    throw KindError(ErrorKind::Verification,
+                    "The signature of " + quotedPath(file) +
+                        " doesn't match, it was changed since");
+  }
+  std::cout << "Good signature of " << file << " by " << key_hex
+            << std::endl;
+}
+
+// Write <output>.json, recording where an image came from: the inputs and
+// their hashes, this build, the command line, how long it took and the
+// hash of the image, for ROM archives and CI pipelines to keep with it.
+// Returns where it went.
+std::filesystem::path writeSidecar(const std::filesystem::path &transfer_list_file,
+                  const std::filesystem::path &list_copy,
+                  const std::filesystem::path &new_dat_file,
+                  const std::filesystem::path &output_img,
+                  const ConvertOptions &options,
+                  const std::chrono::system_clock::time_point started,
+                  const std::chrono::steady_clock::duration elapsed) {
+  std::error_code ec;
+  const auto absolute = [&](const std::filesystem::path &path) {
+    const auto full = std::filesystem::absolute(path, ec);
+    return Json::string((ec ? path : full).string());
+  };
+  const auto file = [&](const std::filesystem::path &path,
+                        const std::filesystem::path &contents) {
+    auto json = Json::object().set("path", absolute(path));
+    if (std::filesystem::exists(contents, ec)) {
+      json.set("sha256", Json::string(sha256File(contents)));
+    }
+    return json;
+  };
+
+  auto list = file(transfer_list_file, list_copy);
+  TextFile header(list_copy);
+  if (int version = 0; header.takeOneLine(&version)) {
+    list.set("version", Json::number(version));
+  }
+
+  // Split images are hashed as the image their parts make up.
+  std::vector<std::filesystem::path> parts{output_img};
+  if (options.split_size != 0) {
+    parts.clear();
+    TextFile manifest(SplitBuf::manifestFor(output_img));
+    manifest.ignoreLine<2>();
+    for (std::string line; manifest.takeOneLine(&line);) {
+      parts.push_back(output_img.parent_path() / line);
+    }
+  }
+  Sha256 image_hash;
+  uint64_t image_size = 0;
+  std::vector<char> buffer(options.buffer_size);
+  for (const auto &part : parts) {
+    std::ifstream in(part, std::ios::binary);
+    if (unlikely(!in)) {
+      throw IOException(part, "open");
+    }
+    while (in.read(buffer.data(), static_cast<std::streamsize>(buffer.size())) ||
+           in.gcount() > 0) {
+      image_hash.update(buffer.data(), static_cast<size_t>(in.gcount()));
+      image_size += static_cast<uint64_t>(in.gcount());
+    }
+  }
+  const auto digest = image_hash.digest();
+
+  auto arguments = Json::array();
+  for (const auto &arg : options.command_line) {
+    arguments.push(Json::string(arg));
+  }
+  const std::time_t start_time = std::chrono::system_clock::to_time_t(started);
+  std::array<char, 32> date{};
+  std::strftime(date.data(), date.size(), "%Y-%m-%dT%H:%M:%SZ",
+                std::gmtime(&start_time));
+
+  auto sidecar =
+      Json::object()
+          .set("tool", Json::object()
+                           .set("name", Json::string("sdat2img"))
+                           .set("version", Json::string(SDAT2IMG_VERSION))
+                           .set("commit", Json::string(SDAT2IMG_GIT_COMMIT)))
+          .set("arguments", std::move(arguments))
+          .set("transfer_list", std::move(list))
+          .set("new_dat", file(new_dat_file, new_dat_file));
+  if (!options.source_img.empty()) {
+    sidecar.set("source_img", file(options.source_img, options.source_img));
+  }
+  sidecar
+      .set("output", Json::object()
+                         .set("path", absolute(output_img))
+                         .set("size", Json::number(checkedCast<intmax_t>(
+                                          image_size)))
+                         .set("sha256", Json::string(toHex(digest.data(),
+                                                           digest.size()))))
+      .set("started", Json::string(date.data()))
+      .set("duration_ms",
+           Json::number(std::chrono::duration_cast<std::chrono::milliseconds>(
+                            elapsed)
+                            .count()));
+
+  const std::filesystem::path path = output_img.string() + ".json";
+  std::ofstream out(path);
+  out << sidecar.dump(true) << std::endl;
+  if (unlikely(!out)) {
+    throw IOException(path, "write");
+  }
+  std::cout << "Saved the provenance of the image to " << path << std::endl;
+  return path;
+}
+
+// Same, but never throwing, as it also runs in the watch and serve loops.
+int convert(const std::filesystem::path &transfer_list_file,
+            const std::filesystem::path &new_dat_file,
+            const std::filesystem::path &output_img,
+            const ConvertOptions &options = {},
+            ConversionProgress *progress = nullptr) {
+#ifdef _WIN32
+  if (isReservedWindowsName(output_img)) {
+    return reportError(ErrorKind::Unsupported,
+                       quotedPath(output_img.filename()) +
+                           " is a reserved device name on Windows, pick "
+                           "another name for the output",
+                       output_img);
+  }
+#endif
+  try {
+    if (!options.exec_before.empty() &&
+        !runHook(options.exec_before, "--exec-before", transfer_list_file,
+                 new_dat_file, output_img)) {
+      return EXIT_FAILURE;
+    }
+    const auto new_dat = longPath(stdioPath(new_dat_file, false));
+    checkSeekableDat(new_dat);
+    const SpooledInput transfer_list(
+        longPath(stdioPath(transfer_list_file, false)));
+    // A bad key fails before the conversion, not after.
+    std::optional<Ed25519::Seed> signing_key;
+    if (!options.sign_key.empty()) {
+      signing_key = loadKey<Ed25519::Seed>(options.sign_key.string());
+    }
+    const auto started = std::chrono::system_clock::now();
+    const auto start = std::chrono::steady_clock::now();
+    const int result =
+        options.source_img.empty()
+            ? convertOrThrow(transfer_list.path(), new_dat,
+                             longPath(stdioPath(output_img, true)), options,
+                             progress)
+            : applyIncrementalOrThrow(transfer_list.path(), new_dat,
+                                      longPath(output_img), options,
+                                      progress);
+    // Before the hook, which may want to upload it too.
+    std::vector<std::filesystem::path> manifests;
+    if (result == EXIT_SUCCESS && options.sidecar) {
+      if (isStreamOutput(output_img)) {
+        std::cerr << "Warning: Images written into pipes have no sidecar"
+                  << std::endl;
+      } else {
+        manifests.push_back(writeSidecar(
+            transfer_list_file, transfer_list.path(), new_dat_file,
+            output_img, options, started,
+            std::chrono::steady_clock::now() - start));
+      }
+    }
+    if (result == EXIT_SUCCESS && signing_key) {
+      if (!options.range_manifest.empty()) {
+        manifests.push_back(options.range_manifest);
+      }
+      if (options.output_format == OutputFormat::Store ||
+          options.output_format == OutputFormat::Chunks) {
+        manifests.push_back(output_img);
+      }
+      if (manifests.empty()) {
+        std::cerr << "Warning: Nothing to sign, --sign-key signs what "
+                  << "--sidecar and --range-manifest write" << std::endl;
+      }
+      for (const auto &manifest : manifests) {
+        signFile(manifest, *signing_key);
+      }
+    }
+    if (result == EXIT_SUCCESS && !options.exec_after.empty() &&
+        !runHook(options.exec_after, "--exec-after", transfer_list_file,
+                 new_dat_file, output_img)) {
+      return EXIT_FAILURE;
+    }
+    return result;
+  } catch (const std::exception &e) {
+    return reportError(e);
+  }
+}
+
+// What --tui shows while the partitions picked from a directory convert one
+// after another: a row for each with a progress bar and a graph of its
+// throughput over the last 10 seconds, and the latest warnings, redrawn in
+// place with ANSI escapes. The lines the conversions print are kept off the
+// screen, those with warnings and errors are shown, all of them at the end
+// along with a summary.
+class Dashboard : public std::streambuf {
+public:
+  explicit Dashboard(const std::vector<std::string> &partitions) {
+    for (const auto &name : partitions) {
+      rows.emplace_back().name = name;
+    }
+  }
+
+  // Run convert on each partition in turn with its progress, and show it
+  // until all are done. Returns whether they all converted.
+  bool run(const std::function<int(const std::string &,
+                                   ConversionProgress *)> &convert) {
+    std::ostream screen(std::cout.rdbuf());
+    std::streambuf *const err = std::cerr.rdbuf(this);
+    std::cout.rdbuf(this);
+    std::atomic<bool> finished{false};
+    std::thread worker([this, &convert, &finished] {
+      for (size_t i = 0; i < rows.size(); ++i) {
+        auto &row = rows[i];
+        {
+          std::lock_guard<std::mutex> guard(lock);
+          current = i;
+        }
+        row.start = std::chrono::steady_clock::now();
+        row.state = State::Running;
+        int rc = EXIT_FAILURE;
+        try {
+          rc = convert(row.name, &row.progress);
+        } catch (const std::exception &e) {
+          std::cerr << "Error: " << e.what() << std::endl;
+        }
+        row.seconds = std::chrono::duration<double>(
+                          std::chrono::steady_clock::now() - row.start)
+                          .count();
+        row.state = rc == EXIT_SUCCESS ? State::Done : State::Failed;
+      }
+      finished = true;
+    });
+    size_t drawn = 0;
+    for (unsigned tick = 0; !finished; ++tick) {
+      if (tick % 2 == 0) {
+        sample();
+      }
+      drawn = draw(screen, drawn, false);
+      std::this_thread::sleep_for(std::chrono::milliseconds(250));
+    }
+    worker.join();
+    std::cout.rdbuf(screen.rdbuf());
+    std::cerr.rdbuf(err);
+    draw(screen, drawn, true);
+    return std::all_of(rows.begin(), rows.end(), [](const Row &row) {
+      return row.state == State::Done;
+    });
+  }
+
+protected:
+  int overflow(const int c) override {
+    if (c != traits_type::eof()) {
+      const char ch = static_cast<char>(c);
+      xsputn(&ch, 1);
+    }
+    return traits_type::not_eof(c);
+  }
+
+  std::streamsize xsputn(const char *text, const std::streamsize n) override {
+    std::lock_guard<std::mutex> guard(lock);
+    for (std::streamsize i = 0; i < n; ++i) {
+      if (text[i] != '\n') {
+        line += text[i];
+        continue;
+      }
+      if (line.rfind("Warning", 0) == 0 || line.rfind("Error", 0) == 0) {
+        warnings.push_back(rows[current].name + ": " + line);
+      }
+      line.clear();
+    }
+    return n;
+  }
+
+private:
+  enum class State { Waiting, Running, Done, Failed };
+
+  struct Row {
+    std::string name;
+    ConversionProgress progress;
+    std::atomic<State> state{State::Waiting};
+    // Set by the worker before the state changes.
+    std::chrono::steady_clock::time_point start;
+    double seconds = 0;
+    // Only touched by the thread drawing.
+    std::deque<double> rates;
+    uint64_t sampled_bytes = 0;
+  };
+
+  static constexpr size_t kGraphWidth = 20;
+  static constexpr size_t kShownWarnings = 5;
+
+  static std::string mib(const double bytes) {
+    std::ostringstream text;
+    text << std::fixed << std::setprecision(1) << bytes / (1 << 20);
+    return text.str();
+  }
+
+  // Add the throughput of the running partition since the last sample, taken
+  // every half a second, to its graph.
+  void sample() {
+    for (auto &row : rows) {
+      if (row.state != State::Running) {
+        continue;
+      }
+      const uint64_t bytes = row.progress.stats.bytes_written;
+      row.rates.push_back(static_cast<double>(bytes - row.sampled_bytes) * 2);
+      row.sampled_bytes = bytes;
+      if (row.rates.size() > kGraphWidth) {
+        row.rates.pop_front();
+      }
+    }
+  }
+
+  // Draw over the lines drawn last time. The final view has all warnings
+  // and the summary instead of the latest ones. Returns the lines drawn.
+  size_t draw(std::ostream &screen, const size_t drawn, const bool final) {
+    static const std::array<const char *, 8> kBars = {"▁", "▂", "▃", "▄",
+                                                      "▅", "▆", "▇", "█"};
+    std::ostringstream frame;
+    for (const auto &row : rows) {
+      frame << "  " << std::left << std::setw(14)
+            << row.name.substr(0, 13) << std::right;
+      const FileSizeT total = row.progress.total_blocks;
+      const FileSizeT written = row.progress.written_blocks;
+      const auto bytes =
+          static_cast<double>(row.progress.stats.bytes_written);
+      switch (row.state.load()) {
+      case State::Waiting:
+        frame << "waiting";
+        break;
+      case State::Running: {
+        const size_t percent =
+            total == 0 ? 0 : static_cast<size_t>(std::min(written, total) *
+                                                 100 / total);
+        frame << '[' << std::string(percent / 5, '#')
+              << std::string(20 - percent / 5, '.') << "] " << std::setw(3)
+              << percent << "% " << std::setw(7)
+              << mib(row.rates.empty() ? 0 : row.rates.back()) << " MiB/s ";
+        const double top =
+            row.rates.empty()
+                ? 0
+                : *std::max_element(row.rates.begin(), row.rates.end());
+        for (const double rate : row.rates) {
+          frame << kBars[top == 0 ? 0 : static_cast<size_t>(rate / top * 7)];
+        }
+        break;
+      }
+      case State::Done:
+        frame << "done, " << mib(bytes) << " MiB in " << std::fixed
+              << std::setprecision(1) << row.seconds << " s ("
+              << mib(row.seconds == 0 ? 0 : bytes / row.seconds)
+              << " MiB/s)";
+        break;
+      case State::Failed:
+        frame << "failed after " << std::fixed << std::setprecision(1)
+              << row.seconds << " s";
+        break;
+      }
+      frame << '\n';
+    }
+    std::vector<std::string> shown;
+    {
+      std::lock_guard<std::mutex> guard(lock);
+      shown = warnings;
+    }
+    if (!final && shown.size() > kShownWarnings) {
+      shown.erase(shown.begin(), shown.end() - kShownWarnings);
+    }
+    if (!shown.empty()) {
+      frame << (final ? "Warnings and errors:\n" : "Latest warnings:\n");
+    }
+    for (const auto &warning : shown) {
+      frame << "  " << (final ? warning : warning.substr(0, 76)) << '\n';
+    }
+    if (final) {
+      size_t converted = 0;
+      double seconds = 0;
+      for (const auto &row : rows) {
+        converted += row.state == State::Done ? 1 : 0;
+        seconds += row.seconds;
+      }
+      frame << "Converted " << converted << " of " << rows.size()
+            << (rows.size() == 1 ? " partition" : " partitions") << " in "
+            << std::fixed << std::setprecision(1) << seconds << " s, "
+            << rows.size() - converted << " failed, " << shown.size()
+            << (shown.size() == 1 ? " warning or error" : " warnings or errors")
+            << '\n';
+    }
+    // Up to the first line drawn, and clear from there.
+    if (drawn != 0) {
+      screen << "\033[" << drawn << "F";
+    }
+    screen << "\033[J" << frame.str() << std::flush;
+    const std::string text = frame.str();
+    return static_cast<size_t>(std::count(text.begin(), text.end(), '\n'));
+  }
+
+  std::deque<Row> rows;
+  std::mutex lock;
+  // The partition converting, and the line it's printing.
+  size_t current = 0;
+  std::string line;
+  std::vector<std::string> warnings;
+};
+
+// The slot suffix the device booted from, like "_a", or empty without A/B.
+std::string activeSlotSuffix() {
+  // Newer kernels get it in the bootconfig, older ones on the command line.
+  for (const char *file : {"/proc/bootconfig", "/proc/cmdline"}) {
+    std::ifstream in(file);
+    const std::string text((std::istreambuf_iterator<char>(in)),
+                           std::istreambuf_iterator<char>());
+    for (const std::string key :
+         {"androidboot.slot_suffix", "androidboot.slot"}) {
+      for (auto pos = text.find(key); pos != std::string::npos;
+           pos = text.find(key, pos + 1)) {
+        auto at = text.find_first_not_of(" ", pos + key.size());
+        if (at == std::string::npos || text[at] != '=') {
+          continue;
+        }
+        // A key at the very end has no value.
+        at = text.find_first_not_of(" \"", at + 1);
+        if (at == std::string::npos) {
+          continue;
+        }
+        const auto end = text.find_first_of(" \"\n", at);
+        std::string slot = text.substr(at, end - at);
+        if (!slot.empty() && slot[0] != '_') {
+          slot.insert(0, "_");
+        }
+        return slot;
+      }
+    }
+  }
+  return "";
+}
+
+// Where Android links the partitions by name: the generic directory, the
+// one of the boot device, the one of older platform layouts and the
+// device mapper for logical partitions in super.
+std::optional<std::filesystem::path> findByName(const std::string &name) {
+  std::error_code ec;
+  std::vector<std::filesystem::path> dirs{"/dev/block/by-name",
+                                          "/dev/block/bootdevice/by-name"};
+  for (const auto &platform :
+       std::filesystem::directory_iterator("/dev/block/platform", ec)) {
+    dirs.push_back(platform.path() / "by-name");
+    for (const auto &soc :
+         std::filesystem::directory_iterator(platform.path(), ec)) {
+      dirs.push_back(soc.path() / "by-name");
+    }
+  }
+  dirs.emplace_back("/dev/block/mapper");
+  for (const auto &dir : dirs) {
+    if (std::filesystem::exists(dir / name, ec)) {
+      return dir / name;
+    }
+  }
+  return std::nullopt;
+}
+
+// Restore an image onto a partition of the device this runs on, named like
+// system or system_b, or given as the path of its by-name link. A/B
+// partitions need the slot spelled out, and the image must be as large as
+// the partition, so that a mistyped name can't overwrite the wrong one.
+int flashPartition(const std::filesystem::path &transfer_list_file,
+                   const std::filesystem::path &new_dat_file,
+                   const std::string &partition,
+                   const std::optional<std::string> &slot,
+                   const ConvertOptions &options) {
+  std::error_code ec;
+  const bool is_path = partition.find('/') != std::string::npos;
+  const auto find = [&](const std::string &name) {
+    return is_path ? std::filesystem::exists(name, ec)
+                         ? std::optional<std::filesystem::path>(name)
+                         : std::nullopt
+                   : findByName(name);
+  };
+  const std::string active = activeSlotSuffix();
+  std::string name = partition;
+  if (slot) {
+    const std::string suffix = (*slot)[0] == '_' ? *slot : "_" + *slot;
+    if (suffix != "_a" && suffix != "_b") {
+      return reportError(ErrorKind::Other,
+                         "The slot must be a or b, not " + *slot);
+    }
+    const std::string current =
+        name.size() > 2 ? name.substr(name.size() - 2) : "";
+    if (current != "_a" && current != "_b") {
+      name += suffix;
+    } else if (current != suffix) {
+      return reportError(ErrorKind::Other,
+                         partition + " is not on slot " + suffix);
+    }
+  }
+  auto link = find(name);
+  if (!link && !slot && find(name + "_a")) {
+    return reportError(ErrorKind::Other,
+                       partition + " has a slot _a and _b, pick one with "
+                                   "--slot a or --slot b" +
+                           (active.empty() ? std::string()
+                                           : " (booted from " + active + ")"));
+  }
+  if (!link) {
+    return reportError(ErrorKind::Io,
+                       is_path ? "No partition at " + quotedPath(name)
+                               : "No partition named " + name +
+                                     " under /dev/block");
+  }
+  const auto device = std::filesystem::canonical(*link, ec);
+  if (ec || !std::filesystem::is_block_file(device, ec)) {
+    return reportError(ErrorKind::Other,
+                       quotedPath(*link) + " isn't a block device");
+  }
+  std::cout << "Flashing " << name << " (" << device.string() << ")";
+  if (!active.empty()) {
+    std::cout << ", booted from slot " << active;
+  }
+  std::cout << std::endl;
+
+  TransferList tlist;
+  try {
+    tlist.parse(transfer_list_file);
+  } catch (const std::exception &e) {
+    return reportError(e);
+  }
+  // Padded with --pad-to, if that's larger.
+  const uint64_t image_size = std::max<uint64_t>(
+      checkedMul<uint64_t>(static_cast<uint64_t>(tlist.max()), BLOCK_SIZE),
+      options.partition_size);
+  const uint64_t device_size = blockDeviceSize(device);
+  if (device_size == 0) {
+    return reportError(ErrorKind::Io,
+                       "Couldn't get the size of " + quotedPath(device),
+                       device);
+  }
+  // Filesystems of OTAs fill their partition, anything else is suspect.
+  // A larger image wouldn't fit at all.
+  if (image_size > device_size) {
+    return reportError(ErrorKind::Other,
+                       "The image is " + std::to_string(image_size) +
+                           " bytes, but " + name + " is only " +
+                           std::to_string(device_size) + " bytes");
+  }
+  if (image_size != device_size && !options.force) {
+    return reportError(ErrorKind::Other,
+                       "The image is " + std::to_string(image_size) +
+                           " bytes, but " + name + " is " +
+                           std::to_string(device_size) +
+                           " bytes, use --force if that's intended");
+  }
+
+  // Written through to the flash before saying it's done.
+  const int result = convert(transfer_list_file, new_dat_file, device,
+                             options);
+  if (result != EXIT_SUCCESS) {
+    return result;
+  }
+  if (!syncFile(device)) {
+    return reportError(ErrorKind::Io, "Couldn't sync " + quotedPath(device),
+                       device);
+  }
+  std::cout << "Flashed and synced " << name << std::endl;
+  return EXIT_SUCCESS;
+}
+
+// The checks of doctor, each passing, failing or only warning, with a hint
+// on what to do about the latter two.
+class DoctorReport {
+public:
+  explicit DoctorReport(std::ostream &out) : out(out) {}
+
+  void pass(const std::string &what) {
+    out << "PASS  " << what << std::endl;
+    ++passed;
+  }
+  void warn(const std::string &what, const std::string &hint) {
+    out << "WARN  " << what << std::endl << "      " << hint << std::endl;
+    ++warnings;
+  }
+  void fail(const std::string &what, const std::string &hint) {
+    out << "FAIL  " << what << std::endl << "      " << hint << std::endl;
+    ++failed;
+  }
+  [[nodiscard]] bool ok() const noexcept { return failed == 0; }
+  void summary() const {
+    out << passed << " passed, " << warnings
+        << (warnings == 1 ? " warning, " : " warnings, ") << failed
+        << " failed" << std::endl;
+  }
+
+private:
+  std::ostream &out;
+  size_t passed = 0, warnings = 0, failed = 0;
+};
+
+// Keeps what is written into a stream from showing while it lives.
+class Silenced {
+public:
+  explicit Silenced(std::ostream &stream)
+      : stream(stream), buffer(stream.rdbuf(nullptr)) {}
+  ~Silenced() { stream.rdbuf(buffer); }
+  Silenced(const Silenced &) = delete;
+  Silenced &operator=(const Silenced &) = delete;
+
+private:
+  std::ostream &stream;
+  std::streambuf *buffer;
+};
+
+// Sizes in the report, like "812.3 MiB".
+std::string doctorSize(const uint64_t bytes) {
+  std::ostringstream mib;
+  mib << std::fixed << std::setprecision(1)
+      << static_cast<double>(bytes) / (1 << 20) << " MiB";
+  return mib.str();
+}
+
+// Check one partition for doctor: its transfer list, a file, and its new
+// dat, which is a file too unless it's in a zip, where only its size is
+// known. No size means it's missing. Returns the bytes of the image, 0 if
+// it can't be converted.
+uint64_t doctorPartition(DoctorReport &report,
+                         const std::filesystem::path &list_file,
+                         const std::string &dat_name,
+                         const std::filesystem::path &dat_file,
+                         const std::optional<uint64_t> &dat_size,
+                         FileSizeT partition_size,
+                         const ConvertOptions &options) {
+  const std::string list_name = list_file.filename().string();
+  std::set<std::string> unknown;
+  int version = 0;
+  FileSizeT end = 0, new_blocks = 0;
+  size_t violations = 0;
+  try {
+    const Silenced quiet_out(std::cout), quiet_err(std::cerr);
+    TransferListReader reader(
+        list_file, {},
+        [&unknown](const std::vector<std::string> &words)
+            -> std::optional<TransferList::Command> {
+          unknown.insert(words.empty() ? "" : words[0]);
+          return std::nullopt;
+        });
+    version = reader.version();
+    while (const auto entry = reader.next()) {
+      for (const auto &segment : entry->segments) {
+        end = std::max(end, segment.end());
+        if (entry->command == TransferList::Command::New) {
+          new_blocks += segment.size();
+        }
+      }
+    }
+    violations = checkRangeOrder(list_file, std::nullopt);
+  } catch (const std::exception &e) {
+    report.fail(list_name + " can't be read: " + e.what(),
+                "Extract it again, it may be cut short, or it's not the "
+                "transfer list of a block based OTA");
+    return 0;
+  }
+  static const std::array<const char *, 5> kAndroid = {
+      "", "Android 5.0", "Android 5.1", "Android 6.x", "Android 7.x or above"};
+  report.pass(list_name + " is a version " + std::to_string(version) +
+              " list (" + kAndroid[static_cast<size_t>(version)] + ") of " +
+              std::to_string(end) + (end == 1 ? " block" : " blocks"));
+  if (violations != 0) {
+    report.warn(list_name + " has " + std::to_string(violations) +
+                    (violations == 1 ? " range" : " ranges") +
+                    " out of order, which AOSP doesn't expect",
+                "It still converts, check-list --fix-sort writes a sorted "
+                "copy");
+  }
+
+  // Incremental OTAs move, patch and stash blocks of the image they apply
+  // to.
+  static const std::set<std::string> kIncremental = {
+      "move", "bsdiff", "imgdiff", "stash", "free"};
+  const bool incremental =
+      std::any_of(unknown.begin(), unknown.end(),
+                  [](const auto &name) { return kIncremental.count(name); });
+  std::string others;
+  for (const auto &name : unknown) {
+    if (!kIncremental.count(name)) {
+      others += (others.empty() ? "" : ", ") + name;
+    }
+  }
+  std::error_code ec;
+  if (incremental && options.source_img.empty()) {
+    report.fail(list_name + " is of an incremental OTA, which patches the "
+                            "image of the build before",
+                "Give that image with --source-img, or get the full OTA");
+  } else if (incremental &&
+             !std::filesystem::exists(options.source_img, ec)) {
+    report.fail("The source image " + quotedPath(options.source_img) +
+                    " isn't there",
+                "Give the image the incremental OTA applies to with "
+                "--source-img");
+  } else if (incremental) {
+    report.pass(list_name + " is of an incremental OTA, applied to " +
+                quotedPath(options.source_img));
+  }
+  if (!others.empty() && options.lenient) {
+    report.warn(list_name + " has unknown commands: " + others,
+                "--lenient skips them, the image may miss what they write");
+  } else if (!others.empty()) {
+    report.fail(list_name + " has unknown commands: " + others,
+                "Skip them with --lenient if the image can do without "
+                "what they write");
+  }
+
+  const uint64_t new_bytes = static_cast<uint64_t>(new_blocks) * BLOCK_SIZE;
+  const bool brotli = dat_name.size() > 3 &&
+                      dat_name.compare(dat_name.size() - 3, 3, ".br") == 0;
+  if (!dat_size && new_blocks == 0) {
+    report.pass(list_name + " writes no new data, so it needs no new dat");
+  } else if (!dat_size) {
+    report.fail(dat_name + "(.br) is missing",
+                "Extract the new dat of the partition from the OTA along "
+                "with its transfer list");
+    return 0;
+  } else if (brotli) {
+#ifdef HAVE_BROTLI
+    if (!dat_file.empty() && !BrotliManager(dat_file).decodesStart(1 << 20)) {
+      report.fail(dat_name + " isn't brotli compressed, or it's broken",
+                  "Extract or download it again");
+      return 0;
+    }
+    report.pass(dat_name + " is brotli compressed" +
+                (dat_file.empty() ? "" : " and starts out right"));
+#else
+    report.fail(dat_name + " is brotli compressed, but this build has no "
+                           "brotli",
+                "Decompress it with brotli -d first, or use a build with "
+                "brotli");
+    return 0;
+#endif
+  } else {
+    // Other compressions, which the file may have been packed with on the
+    // way.
+    static const std::array<std::pair<std::string_view, const char *>, 5>
+        kMagic = {{{std::string_view("\x1f\x8b", 2), "gzip"},
+                   {std::string_view("\xfd" "7zXZ", 5), "xz"},
+                   {std::string_view("\x28\xb5\x2f\xfd", 4), "zstd"},
+                   {std::string_view("\x04\x22\x4d\x18", 4), "lz4"},
+                   {std::string_view("BZh", 3), "bzip2"}}};
+    std::string magic(8, '\0');
+    if (!dat_file.empty()) {
+      std::ifstream in(dat_file, std::ios::binary);
+      in.read(magic.data(), static_cast<std::streamsize>(magic.size()));
+    }
+    if (!dat_file.empty() &&
+        guessFileKind(dat_file) == FileKind::SparseImage) {
+      report.fail(dat_name + " is an Android sparse image, not a new dat",
+                  "Convert it with simg2img instead");
+      return 0;
+    }
+    for (const auto &[bytes, format] : kMagic) {
+      if (magic.compare(0, bytes.size(), bytes) == 0) {
+        report.fail(dat_name + " is " + format + " compressed",
+                    std::string("Decompress it with ") + format +
+                        " -d first");
+        return 0;
+      }
+    }
+    if (*dat_size < new_bytes) {
+      report.fail(dat_name + " is " + std::to_string(new_bytes - *dat_size) +
+                      " bytes shorter than the " + std::to_string(new_bytes) +
+                      " the list writes",
+                  "It was cut short, extract or download it again");
+      return 0;
+    }
+    if (*dat_size > new_bytes) {
+      report.warn(dat_name + " is " + std::to_string(*dat_size - new_bytes) +
+                      " bytes longer than the " + std::to_string(new_bytes) +
+                      " the list writes",
+                  "The rest is left out, make sure both files are of the "
+                  "same OTA");
+    } else {
+      report.pass(dat_name + " has the " + std::to_string(new_bytes) +
+                  " bytes the list writes");
+    }
+  }
+
+  uint64_t image_size = static_cast<uint64_t>(end) * BLOCK_SIZE;
+  if (options.partition_size != 0) {
+    partition_size = options.partition_size;
+  }
+  if (partition_size != 0 &&
+      static_cast<uint64_t>(partition_size) < image_size) {
+    report.warn(list_name + " needs " + std::to_string(image_size) +
+                    " bytes, but the partition is only " +
+                    std::to_string(partition_size),
+                "The image still gets the size the list needs, check the "
+                "partition size");
+  }
+  image_size = std::max(image_size, static_cast<uint64_t>(partition_size));
+  // The brotli compressed dat is decompressed into a file first.
+  return image_size + (brotli && !options.stream_br ? new_bytes : 0);
+}
+
+// Check an extracted OTA in a directory, or an OTA zip, before converting
+// it: that the transfer lists and new dats are there, readable, of a
+// supported version and compression and of sizes which fit together, and
+// that there's space for the images. Only the named partition is checked,
+// if any. Prints a report, and returns whether nothing failed.
+bool doctor(const std::filesystem::path &input, const std::string &partition,
+            const std::string &out_template, const ConvertOptions &options) {
+  DoctorReport report(std::cout);
+  std::error_code ec;
+  const bool is_zip = std::filesystem::is_regular_file(input, ec);
+  if (!is_zip && !std::filesystem::is_directory(input, ec)) {
+    throw KindError(ErrorKind::Io, quotedPath(input) + " isn't there");
+  }
+  // Where images and extracted files go.
+  std::filesystem::path out_dir = input;
+  std::vector<std::string> prefixes;
+  // The entries of a zip by name, and where its lists and op list are
+  // extracted to.
+  std::map<std::string, ZipEntry> entries;
+  std::ifstream zip;
+  std::filesystem::path dir = input;
+  const auto read = [&zip](const uint64_t offset, const uint64_t size) {
+    std::string bytes(static_cast<size_t>(size), '\0');
+    zip.clear();
+    zip.seekg(checkedCast<std::streamoff>(offset));
+    zip.read(bytes.data(), static_cast<std::streamsize>(size));
+    bytes.resize(static_cast<size_t>(zip.gcount()));
+    return bytes;
+  };
+  if (is_zip) {
+    zip.open(input, std::ios::binary);
+    const uint64_t zip_size = std::filesystem::file_size(input);
+    const uint64_t tail_size =
+        std::min<uint64_t>(zip_size, (uint64_t{1} << 16) + 22);
+    try {
+      for (auto &entry :
+           readZipDirectory(read(zip_size - tail_size, tail_size),
+                            zip_size - tail_size, read)) {
+        entries.emplace(entry.name, std::move(entry));
+      }
+    } catch (const std::exception &e) {
+      report.fail(quotedPath(input) + " can't be read as a zip: " + e.what(),
+                  "Download it again, it may be cut short");
+      report.summary();
+      return false;
+    }
+    report.pass(quotedPath(input) + " is a zip of " +
+                std::to_string(entries.size()) +
+                (entries.size() == 1 ? " file" : " files"));
+    constexpr std::string_view kSuffix = ".transfer.list";
+    for (const auto &[name, entry] : entries) {
+      if (name.size() > kSuffix.size() && name.find('/') == std::string::npos &&
+          name.compare(name.size() - kSuffix.size(), kSuffix.size(),
+                       kSuffix) == 0) {
+        prefixes.push_back(name.substr(0, name.size() - kSuffix.size()));
+      }
+    }
+    out_dir = std::filesystem::absolute(input).parent_path();
+    dir = std::filesystem::temp_directory_path() /
+          ("sdat2img-doctor-" + std::to_string(std::random_device{}()));
+    std::filesystem::create_directories(dir);
+  } else {
+    prefixes = transferListPrefixes(input);
+    if (!prefixes.empty()) {
+      out_dir = outputFor(input, prefixes[0], out_template).parent_path();
+    }
+  }
+
+  // Extract an entry of the zip into dir, throwing if it can't be.
+  const auto copy = [&zip](const uint64_t offset, uint64_t size,
+                           const std::filesystem::path &to) {
+    std::ofstream out(to, std::ios::binary | std::ios::trunc);
+    std::vector<char> buffer(size_t{1} << 16);
+    zip.clear();
+    zip.seekg(checkedCast<std::streamoff>(offset));
+    while (size != 0 && zip) {
+      const auto n = std::min<uint64_t>(size, buffer.size());
+      zip.read(buffer.data(), static_cast<std::streamsize>(n));
+      out.write(buffer.data(), zip.gcount());
+      size -= static_cast<uint64_t>(zip.gcount());
+    }
+    if (unlikely(size != 0)) {
+      throw KindError(ErrorKind::Parse, "The zip is cut short");
+    } else if (unlikely(!out.flush())) {
+      throw IOException(to, "write");
+    }
+  };
+  const auto extract = [&](const ZipEntry &entry) {
+    return extractZipEntry(entry, dir, dir / (entry.name + ".deflated"), read,
+                           copy);
+  };
+
+  if (prefixes.empty()) {
+    if (entries.count("payload.bin")) {
+      report.fail("There are no transfer lists, the zip has a payload.bin",
+                  "It's an A/B OTA, extract its images with a payload "
+                  "dumper instead");
+    } else if (is_zip) {
+      report.fail("There are no transfer lists in the zip",
+                  "Make sure it's the OTA of a block based update");
+    } else {
+      report.fail("There are no transfer lists in " + quotedPath(input),
+                  "Extract the *.transfer.list and *.new.dat.br files of the "
+                  "OTA there, or give its zip");
+    }
+  } else if (!partition.empty() &&
+             std::find(prefixes.begin(), prefixes.end(), partition) ==
+                 prefixes.end()) {
+    const auto closest = closestPrefix(partition, prefixes);
+    report.fail("There is no transfer list of " + partition,
+                closest ? "Did you mean '" + *closest + "'?"
+                        : "Check the name of the partition");
+    prefixes.clear();
+  } else {
+    std::string found;
+    for (const auto &prefix : prefixes) {
+      found += (found.empty() ? "" : " ") + prefix;
+    }
+    report.pass("Transfer lists found: " + found);
+    if (!partition.empty()) {
+      prefixes = {partition};
+    }
+  }
+
+  uint64_t needed = 0;
+  bool convertible = !prefixes.empty();
+  // The extracted lists go in the end, whatever happens.
+  try {
+    // Full Treble OTAs tell the size of each partition after the update.
+    DynamicPartitions dynamic_partitions;
+    try {
+      if (is_zip && entries.count("dynamic_partitions_op_list")) {
+        dynamic_partitions.parse(
+            extract(entries.at("dynamic_partitions_op_list")));
+      } else if (!is_zip && std::filesystem::exists(
+                                input / "dynamic_partitions_op_list", ec)) {
+        dynamic_partitions.parse(input / "dynamic_partitions_op_list");
+      }
+    } catch (const std::exception &e) {
+      report.warn(std::string("dynamic_partitions_op_list can't be read: ") +
+                      e.what(),
+                  "The images are only as large as their lists say");
+    }
+
+    for (const auto &prefix : prefixes) {
+      const std::string list_name = prefix + ".transfer.list";
+      std::filesystem::path list_file = input / list_name;
+      std::filesystem::path dat_file;
+      std::string dat_name = prefix + ".new.dat";
+      std::optional<uint64_t> dat_size;
+      if (is_zip) {
+        try {
+          list_file = extract(entries.at(list_name));
+        } catch (const std::exception &e) {
+          report.fail(list_name + " can't be extracted from the zip: " +
+                          e.what(),
+                      "Extract the zip with another tool, and check that "
+                      "directory");
+          convertible = false;
+          continue;
+        }
+        if (!entries.count(dat_name) && entries.count(dat_name + ".br")) {
+          dat_name += ".br";
+        }
+        if (const auto entry = entries.find(dat_name);
+            entry != entries.end()) {
+          dat_size = entry->second.size;
+          needed += entry->second.size;
+          if (entry->second.flags & 1) {
+            report.fail(dat_name + " is encrypted in the zip",
+                        "Get an OTA which isn't");
+            convertible = false;
+          } else if (entry->second.method != 0 && entry->second.method != 8) {
+            report.fail(dat_name + " is compressed with zip method " +
+                            std::to_string(entry->second.method),
+                        "Extract the zip with a tool which knows it");
+            convertible = false;
+          }
+        }
+      } else {
+        dat_file = findNewDat(input, prefix);
+        dat_name = dat_file.filename().string();
+        if (const auto size = std::filesystem::file_size(dat_file, ec); !ec) {
+          dat_size = size;
+        } else {
+          dat_name = prefix + ".new.dat";
+          dat_file.clear();
+        }
+      }
+      const uint64_t image_size =
+          doctorPartition(report, list_file, dat_name, dat_file, dat_size,
+                          dynamic_partitions.sizeOf(prefix), options);
+      needed += image_size;
+      convertible = convertible && image_size != 0;
+    }
+  } catch (...) {
+    if (is_zip) {
+      std::filesystem::remove_all(dir, ec);
+    }
+    throw;
+  }
+  if (is_zip) {
+    std::filesystem::remove_all(dir, ec);
+  }
+
+  if (convertible) {
+    const auto space = std::filesystem::space(out_dir, ec);
+    const std::string what =
+        doctorSize(needed) + " needed to " +
+        (is_zip ? "extract and convert, " : "convert, ") +
+        (ec ? "unknown" : doctorSize(space.available)) + " free in " +
+        quotedPath(out_dir);
+    if (ec) {
+      report.warn(what, "Make sure there's enough space there");
+    } else if (space.available < needed) {
+      report.fail(what, options.stream_br
+                            ? "Free up some space, or write the images "
+                              "elsewhere with --out-template"
+                            : "Free up some space, write the images "
+                              "elsewhere with --out-template, or save the "
+                              "decompressed dats with --stream-br");
+    } else {
+      report.pass(what);
+    }
+  }
+  report.summary();
+  return report.ok();
+}
+
+// Watch a directory and convert every complete transfer list and new dat pair
+// which appears in it. A pair is complete once the sizes of both files stay
+// the same between two scans, so files still being copied are left alone.
+// Each is converted with the options given on the command line.
+[[noreturn]] void watch(const std::filesystem::path &dir,
+                        const std::chrono::seconds interval,
+                        const std::string &out_template,
+                        const ConvertOptions &options) {
+  using SizePair = std::pair<std::uintmax_t, std::uintmax_t>;
+  // Sizes seen on the previous scan, and sizes of pairs already handled.
+  std::map<std::string, SizePair> last_seen, handled;
+  constexpr std::string_view kSuffix = ".transfer.list";
+
+  std::cout << "Watching " << dir << " for new transfer lists, press Ctrl+C "
+            << "to stop." << std::endl;
+  while (true) {
+    std::error_code ec;
+    std::map<std::string, SizePair> current;
+
+    for (const auto &entry : std::filesystem::directory_iterator(dir, ec)) {
+      const std::string name = entry.path().filename().string();
+      if (name.size() <= kSuffix.size() ||
+          name.compare(name.size() - kSuffix.size(), kSuffix.size(),
+                       kSuffix) != 0) {
+        continue;
+      }
+      const std::string prefix = name.substr(0, name.size() - kSuffix.size());
+      const auto new_dat_file = findNewDat(dir, prefix);
+      const auto list_size = std::filesystem::file_size(entry.path(), ec);
+      if (ec) {
+        continue;
+      }
+      const auto dat_size = std::filesystem::file_size(new_dat_file, ec);
+      if (ec) {
+        continue;
+      }
+      current.emplace(prefix, SizePair{list_size, dat_size});
+    }
+    if (ec) {
+      std::cerr << "Warning: Couldn't scan " << dir << ": " << ec.message()
+                << std::endl;
+    }
+
+    for (const auto &[prefix, sizes] : current) {
+      const auto output_img = outputFor(dir, prefix, out_template);
+      const auto prev = last_seen.find(prefix);
+      const auto done = handled.find(prefix);
+      // Wait for the sizes to settle before touching the files.
+      if (prev == last_seen.end() || prev->second != sizes) {
+        continue;
+      }
+      if ((done != handled.end() && done->second == sizes) ||
+          std::filesystem::exists(output_img, ec)) {
+        continue;
+      }
+      std::cout << "Converting " << prefix << "..." << std::endl;
+      std::filesystem::create_directories(output_img.parent_path(), ec);
+      if (convert(dir / (prefix + std::string(kSuffix)),
+                  findNewDat(dir, prefix), output_img,
+                  options) != EXIT_SUCCESS) {
+        // The output wasn't there before, so what's left of it is of this
+        // attempt, and would keep the next one from happening.
+        std::filesystem::remove(output_img, ec);
+        std::cerr << "Failed to convert " << prefix
+                  << ", will retry once the files change." << std::endl;
+      }
+      handled[prefix] = sizes;
+    }
+
+    last_seen = std::move(current);
+    std::this_thread::sleep_for(interval);
+  }
+}
+
+// A whole number from 1 to max, or nothing if the text is anything else.
+std::optional<uint64_t> parseCount(const std::string &text,
+                                   const uint64_t max) {
+  uint64_t count = 0;
+  const char *end = text.data() + text.size();
+  if (std::from_chars(text.data(), end, count).ptr != end || count < 1 ||
+      count > max) {
+    return std::nullopt;
+  }
+  return count;
+}
+
+#ifdef HAS_SOCKETS
+
+// A TCP port number, 1 to 65535, or nothing if it isn't one.
+std::optional<int> parsePort(const std::string &text) {
+  const auto port = parseCount(text, 65535);
+  return port ? std::optional<int>(static_cast<int>(*port)) : std::nullopt;
+}
+
+// Listen for TCP connections on an IPv4 address, exiting if that fails.
+int listenTcp(const std::string &address, const int port) {
+  const int server_fd = socket(AF_INET, SOCK_STREAM, 0);
+  if (server_fd < 0) {
+    std::cerr << "Error: Couldn't create socket: " << strerror(errno)
+              << std::endl;
+    exit(EXIT_FAILURE);
+  }
+  const int yes = 1;
+  setsockopt(server_fd, SOL_SOCKET, SO_REUSEADDR, &yes, sizeof(yes));
+
+  sockaddr_in addr{};
+  addr.sin_family = AF_INET;
+  addr.sin_port = htons(static_cast<uint16_t>(port));
+  if (inet_pton(AF_INET, address.c_str(), &addr.sin_addr) != 1) {
+    std::cerr << "Error: Invalid listen address: " << address << std::endl;
+    exit(EXIT_FAILURE);
+  }
+  if (bind(server_fd, reinterpret_cast<sockaddr *>(&addr), sizeof(addr)) <
+          0 ||
+      listen(server_fd, 16) < 0) {
+    std::cerr << "Error: Couldn't listen on " << address << ":" << port
+              << ": " << strerror(errno) << std::endl;
+    exit(EXIT_FAILURE);
+  }
+  return server_fd;
+}
+
+// A tiny HTTP/1.0 front-end, so that web tools can drive conversions:
+//   PUT  /uploads/NAME    -> {"path":".."} to give a job, with the body
+//   POST /jobs?transfer_list=..&new_dat=..&output=..  -> {"id":N}
+//   GET  /jobs/N          -> state and progress of the job
+//   GET  /jobs/N/result   -> the output image, once the job is done
+// The paths are local to the machine running the server, uploads go into a
+// directory of its own there.
+// Every request needs "Authorization: Bearer <token>", which web pages can't
+// send to another site without its consent, so that they can't make it write
+// files.
+class HttpServer {
+public:
+  // At most max_jobs conversions run at once, the others wait their turn,
+  // all with the options. Without a token, a random one is made up.
+  HttpServer(std::string address, int port, unsigned max_jobs,
+             std::string token, ConvertOptions options)
+      : address(std::move(address)), port(port),
+        max_jobs(std::max(max_jobs, 1U)), token(std::move(token)),
+        options(std::move(options)) {
+    // There's nobody to answer the prompts.
+    this->options.assume_no = true;
+    if (this->token.empty()) {
+      std::random_device random;
+      for (int i = 0; i < 4; ++i) {
+        const uint32_t word = random();
+        this->token += toHex(reinterpret_cast<const uint8_t *>(&word),
+                             sizeof(word));
+      }
+    }
+  }
+
+  [[noreturn]] void run() {
+    const int server_fd = listenTcp(address, port);
+    uploads = std::filesystem::temp_directory_path() /
+              ("sdat2img-uploads-" + std::to_string(std::random_device{}()));
+    std::filesystem::create_directories(uploads);
+    std::cout << "Serving on http://" << address << ":" << port << "/"
+              << std::endl;
+    std::cout << "Uploads go into " << uploads << std::endl;
+    std::cout << "Send the header \"Authorization: Bearer " << token
+              << "\" with every request" << std::endl;
+
+    while (true) {
+      const int client_fd = accept(server_fd, nullptr, nullptr);
+      if (client_fd < 0) {
+        continue;
+      }
+      // A client which doesn't send its request, or downloads a large
+      // result, only holds up its own thread, and not for long if idle.
+      const timeval timeout{kRequestTimeout, 0};
+      setsockopt(client_fd, SOL_SOCKET, SO_RCVTIMEO, &timeout,
+                 sizeof(timeout));
+      std::thread([this, client_fd] {
+        handle(client_fd);
+        close(client_fd);
+      }).detach();
+    }
+  }
+
+private:
+  struct Job {
+    std::filesystem::path output;
+    ConversionProgress progress;
+    // One of "queued", "running", "done" or "failed".
+    std::atomic<const char *> state{"queued"};
+    // Why it failed, set before the state.
+    std::string error;
+  };
+
+  // Seconds to wait for the request of a client.
+  static constexpr time_t kRequestTimeout = 30;
+
+  std::string address;
+  int port;
+  unsigned max_jobs;
+  std::string token;
+  ConvertOptions options;
+  std::filesystem::path uploads;
+  unsigned running_jobs = 0;
+  std::mutex slots_lock;
+  std::condition_variable slot_freed;
+  std::mutex jobs_lock;
+  std::map<int, std::shared_ptr<Job>> jobs;
+  // The outputs of the jobs not done yet, so that no two write the same
+  // file.
+  std::set<std::filesystem::path> outputs;
+  int next_id = 1;
+  int next_upload = 1;
+
+  static std::string urlDecode(const std::string &src) {
+    std::string out;
+    for (size_t i = 0; i < src.size(); ++i) {
+      if (src[i] == '%' && i + 2 < src.size() && std::isxdigit(src[i + 1]) &&
+          std::isxdigit(src[i + 2])) {
+        out += static_cast<char>(
+            std::strtol(src.substr(i + 1, 2).c_str(), nullptr, 16));
+        i += 2;
+      } else {
+        out += src[i] == '+' ? ' ' : src[i];
+      }
+    }
+    return out;
+  }
+
+  static std::map<std::string, std::string> parseQuery(const std::string &q) {
+    std::map<std::string, std::string> params;
+    for (const auto &pair : split(q, '&')) {
+      const auto eq = pair.find('=');
+      if (eq != std::string::npos) {
+        params[urlDecode(pair.substr(0, eq))] = urlDecode(pair.substr(eq + 1));
+      }
+    }
+    return params;
+  }
+
+  static void sendAll(const int fd, const char *data, size_t size) {
+    while (size > 0) {
+      const ssize_t n = send(fd, data, size, MSG_NOSIGNAL);
+      if (n <= 0) {
+        return;
+      }
+      data += n;
+      size -= static_cast<size_t>(n);
+    }
+  }
+
+  static void respond(const int fd, const std::string &status,
+                      const std::string &body) {
+    const std::string reply = "HTTP/1.0 " + status +
+                              "\r\nContent-Type: application/json\r\n"
+                              "Content-Length: " +
+                              std::to_string(body.size()) + "\r\n\r\n" + body;
+    sendAll(fd, reply.data(), reply.size());
+  }
+
+  std::shared_ptr<Job> findJob(const std::string &id) {
+    std::lock_guard<std::mutex> guard(jobs_lock);
+    const auto it = jobs.find(std::atoi(id.c_str()));
+    return it == jobs.end() ? nullptr : it->second;
+  }
+
+  void handle(const int fd) {
+    std::string request;
+    std::array<char, 4096> buffer{};
+    while (request.find("\r\n\r\n") == std::string::npos &&
+           request.size() < 65536) {
+      const ssize_t n = recv(fd, buffer.data(), buffer.size(), 0);
+      if (n <= 0) {
+        return;
+      }
+      request.append(buffer.data(), static_cast<size_t>(n));
+    }
+
+    std::stringstream lines(request.substr(0, request.find("\r\n\r\n")));
+    std::string line, method, target;
+    std::getline(lines, line);
+    std::stringstream(line) >> method >> target;
+    bool authorized = false;
+    std::optional<uint64_t> content_length;
+    while (std::getline(lines, line)) {
+      const auto colon = line.find(':');
+      std::string name = line.substr(0, colon);
+      std::transform(name.begin(), name.end(), name.begin(),
+                     [](const unsigned char c) { return std::tolower(c); });
+      if (colon == std::string::npos) {
+        continue;
+      }
+      std::string value = line.substr(colon + 1);
+      value.erase(0, value.find_first_not_of(" \t"));
+      value.erase(value.find_last_not_of(" \t\r") + 1);
+      if (name == "authorization") {
+        authorized = value == "Bearer " + token;
+      } else if (name == "content-length") {
+        uint64_t length = 0;
+        const char *end = value.data() + value.size();
+        if (std::from_chars(value.data(), end, length).ptr == end) {
+          content_length = length;
+        }
+      }
+    }
+    if (!authorized) {
+      respond(fd, "401 Unauthorized",
+              R"({"error":"missing or wrong bearer token"})");
+      return;
+    }
+    const auto qmark = target.find('?');
+    const std::string path = target.substr(0, qmark);
+    const auto parts = split(path, '/');
+
+    if (method == "PUT" && parts.size() == 3 && parts[1] == "uploads") {
+      upload(fd, urlDecode(parts[2]), content_length,
+             request.substr(request.find("\r\n\r\n") + 4));
+    } else if (method == "POST" && path == "/jobs") {
+      submit(fd, qmark == std::string::npos
+                     ? std::map<std::string, std::string>{}
+                     : parseQuery(target.substr(qmark + 1)));
+    } else if (method == "GET" && parts.size() == 3 && parts[1] == "jobs") {
+      status(fd, findJob(parts[2]));
+    } else if (method == "GET" && parts.size() == 4 && parts[1] == "jobs" &&
+               parts[3] == "result") {
+      result(fd, findJob(parts[2]));
+    } else {
+      respond(fd, "404 Not Found", R"({"error":"unknown endpoint"})");
+    }
+  }
+
+  // Save the body of a PUT into the uploads, under a number so that uploads
+  // of the same name don't clash. The name keeps its extension, which tells
+  // a .br dat.
+  void upload(const int fd, const std::string &name,
+              const std::optional<uint64_t> &length, std::string body) {
+    const bool bad_name =
+        name.empty() || name[0] == '.' ||
+        name.find_first_not_of("ABCDEFGHIJKLMNOPQRSTUVWXYZ"
+                               "abcdefghijklmnopqrstuvwxyz0123456789._-") !=
+            std::string::npos;
+    if (bad_name || !length) {
+      respond(fd, "400 Bad Request",
+              bad_name ? R"({"error":"names are letters, digits, . _ and -"})"
+                       : R"({"error":"Content-Length is required"})");
+      return;
+    }
+    std::filesystem::path file;
+    {
+      std::lock_guard<std::mutex> guard(jobs_lock);
+      file = uploads / (std::to_string(next_upload++) + "-" + name);
+    }
+    std::ofstream out(file, std::ios::binary);
+    uint64_t left = *length;
+    std::array<char, 1 << 16> buffer{};
+    while (out && left > 0) {
+      if (body.empty()) {
+        const ssize_t n = recv(fd, buffer.data(),
+                               static_cast<size_t>(std::min<uint64_t>(
+                                   left, buffer.size())),
+                               0);
+        if (n <= 0) {
+          break;
+        }
+        body.assign(buffer.data(), static_cast<size_t>(n));
+      }
+      const auto n =
+          static_cast<size_t>(std::min<uint64_t>(left, body.size()));
+      out.write(body.data(), static_cast<std::streamsize>(n));
+      left -= n;
+      body.clear();
+    }
+    if (left != 0 || !out.flush()) {
+      out.close();
+      std::error_code ec;
+      std::filesystem::remove(file, ec);
+      respond(fd, "400 Bad Request",
+              left != 0 ? R"({"error":"the body was cut short"})"
+                        : R"({"error":"couldn't save the upload"})");
+      return;
+    }
+    respond(fd, "201 Created",
+            "{\"path\":" + jsonString(file.string()) + "}");
+  }
+
+  void submit(const int fd, const std::map<std::string, std::string> &params) {
+    const auto transfer_list = params.find("transfer_list");
+    const auto new_dat = params.find("new_dat");
+    const auto output = params.find("output");
+    if (transfer_list == params.end() || new_dat == params.end() ||
+        output == params.end()) {
+      respond(fd, "400 Bad Request",
+              R"({"error":"transfer_list, new_dat and output are required"})");
+      return;
+    }
+    // There's nobody to answer the overwrite prompt. The output is
+    // reserved along with the check, so that two requests for the same one
+    // can't both pass it.
+    std::error_code ec;
+    const auto output_path =
+        std::filesystem::absolute(output->second, ec).lexically_normal();
+    auto job = std::make_shared<Job>();
+    job->output = output->second;
+    int id = 0;
+    {
+      std::lock_guard<std::mutex> guard(jobs_lock);
+      if (!std::filesystem::exists(output_path, ec) &&
+          outputs.insert(output_path).second) {
+        id = next_id++;
+        jobs.emplace(id, job);
+      }
+    }
+    if (id == 0) {
+      respond(fd, "409 Conflict", R"({"error":"output already exists"})");
+      return;
+    }
+    std::thread([this, job, output_path, tl = transfer_list->second,
+                 dat = new_dat->second]() {
+      {
+        std::unique_lock<std::mutex> guard(slots_lock);
+        slot_freed.wait(guard, [this] { return running_jobs < max_jobs; });
+        ++running_jobs;
+      }
+      job->state = "running";
+      last_error.clear();
+      const int rc = convert(tl, dat, job->output, options, &job->progress);
+      if (rc != EXIT_SUCCESS) {
+        job->error = last_error.empty() ? "conversion failed" : last_error;
+        // It wasn't there before the job, so what's left is of this one.
+        std::error_code ec;
+        std::filesystem::remove(job->output, ec);
+      }
+      job->state = rc == EXIT_SUCCESS ? "done" : "failed";
+      {
+        std::lock_guard<std::mutex> guard(slots_lock);
+        --running_jobs;
+      }
+      slot_freed.notify_one();
+      // Another job may write it now, once the image is moved away.
+      std::lock_guard<std::mutex> guard(jobs_lock);
+      outputs.erase(output_path);
+    }).detach();
+    respond(fd, "202 Accepted", "{\"id\":" + std::to_string(id) + "}");
+  }
+
+  static void status(const int fd, const std::shared_ptr<Job> &job) {
+    if (!job) {
+      respond(fd, "404 Not Found", R"({"error":"no such job"})");
+      return;
+    }
+    const std::string_view state = job->state.load();
+    respond(fd, "200 OK",
+            "{\"state\":\"" + std::string(state) +
+                "\",\"output\":" + jsonString(job->output.string()) +
+                (state == "failed" ? ",\"error\":" + jsonString(job->error)
+                                   : "") +
+                ",\"written_blocks\":" +
+                std::to_string(job->progress.written_blocks) +
+                ",\"total_blocks\":" +
+                std::to_string(job->progress.total_blocks) +
+                ",\"stats\":" + statsJson(job->progress.stats) + "}");
+  }
+
+  static std::string statsJson(const ConversionStats &stats) {
+    std::ostringstream json;
+    json << "{\"bytes_read\":" << stats.bytes_read
+         << ",\"bytes_written\":" << stats.bytes_written
+         << ",\"segments\":" << stats.segments
+         << ",\"skipped_blocks\":" << stats.skipped_blocks
+         << ",\"retries\":" << stats.retries
+         << ",\"parse_seconds\":" << stats.parse_seconds
+         << ",\"decompress_seconds\":" << stats.decompress_seconds
+         << ",\"write_seconds\":" << stats.write_seconds
+         << ",\"sync_seconds\":" << stats.sync_seconds << "}";
+    return json.str();
+  }
+
+  static void result(const int fd, const std::shared_ptr<Job> &job) {
+    if (!job || std::string_view(job->state.load()) != "done") {
+      respond(fd, "404 Not Found", R"({"error":"no finished job"})");
+      return;
+    }
+    std::ifstream image(job->output, std::ios::binary);
+    std::error_code ec;
+    const auto size = std::filesystem::file_size(job->output, ec);
+    if (!image || ec) {
+      respond(fd, "500 Internal Server Error",
+              R"({"error":"couldn't read output"})");
+      return;
+    }
+    const std::string header = "HTTP/1.0 200 OK\r\n"
+                               "Content-Type: application/octet-stream\r\n"
+                               "Content-Length: " +
+                               std::to_string(size) + "\r\n\r\n";
+    sendAll(fd, header.data(), header.size());
+    std::vector<char> buffer(1 << 20);
+    while (image.read(buffer.data(), buffer.size()) || image.gcount() > 0) {
+      sendAll(fd, buffer.data(), static_cast<size_t>(image.gcount()));
+    }
+  }
+};
+
+// Serves a virtual image as a read-only network block device, for
+// nbd-client, qemu and nbdkit tools, speaking the fixed newstyle protocol
+// (https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md).
+// Any export name gives the image.
+class NbdServer {
+public:
+  NbdServer(std::unique_ptr<VirtualImage> image, std::string name)
+      : image(std::move(image)), name(std::move(name)) {}
+
+  [[noreturn]] void run(const std::string &address, const int port) {
+    const int server_fd = listenTcp(address, port);
+    std::cout << "Serving " << image->size() << " bytes as nbd://" << address
+              << ":" << port << "/" << name << std::endl;
+    while (true) {
+      const int client_fd = accept(server_fd, nullptr, nullptr);
+      if (client_fd < 0) {
+        continue;
+      }
+      // Reads don't change anything, so clients may come and go at will.
+      std::thread([this, client_fd] {
+        try {
+          handle(client_fd);
+        } catch (const std::exception &e) {
+          std::cerr << "Error: NBD client: " << e.what() << std::endl;
+        }
+        close(client_fd);
+      }).detach();
+    }
+  }
+
+private:
+  static constexpr uint64_t kMagic = 0x4e42444d41474943;  // NBDMAGIC
+  static constexpr uint64_t kOptMagic = 0x49484156454f5054;  // IHAVEOPT
+  static constexpr uint64_t kReplyMagic = 0x3e889045565a9;
+  static constexpr uint32_t kRequestMagic = 0x25609513;
+  static constexpr uint32_t kSimpleReplyMagic = 0x67446698;
+  // Handshake flags: fixed newstyle, and no zeros after the export info.
+  static constexpr uint16_t kFixedNewstyle = 1, kNoZeroes = 2;
+  // Transmission flags: has flags, read only, can serve multiple
+  // connections.
+  static constexpr uint16_t kTransmissionFlags = 1 | 2 | 0x100;
+  enum Option : uint32_t {
+    kExportName = 1,
+    kAbort = 2,
+    kList = 3,
+    kInfo = 6,
+    kGo = 7
+  };
+  enum Reply : uint32_t {
+    kAck = 1,
+    kServer = 2,
+    kInfoReply = 3,
+    kErrUnsup = 0x80000001,
+    kErrInvalid = 0x80000003
+  };
+  enum CommandType : uint16_t { kRead = 0, kWrite = 1, kDisc = 2 };
+  // Errno values of the protocol, the same on every system.
+  static constexpr uint32_t kEperm = 1, kEio = 5, kEinval = 22;
+  // The largest read a client gets to ask for.
+  static constexpr uint32_t kMaxRead = uint32_t{32} << 20;
+
+  std::unique_ptr<VirtualImage> image;
+  std::string name;
+
+  static void sendAll(const int fd, const std::string &data) {
+    for (size_t sent = 0; sent < data.size();) {
+      const ssize_t n =
+          send(fd, data.data() + sent, data.size() - sent, MSG_NOSIGNAL);
+      if (n < 0 && errno == EINTR) {
+        continue;
+      }
+      if (n <= 0) {
+        throw std::system_error(errno, std::generic_category(), "send");
+      }
+      sent += static_cast<size_t>(n);
+    }
+  }
+
+  // Receive exactly size bytes, or nothing if the client hung up.
+  static std::optional<std::string> receive(const int fd, const size_t size) {
+    std::string data(size, '\0');
+    for (size_t got = 0; got < size;) {
+      const ssize_t n = recv(fd, data.data() + got, size - got, 0);
+      if (n < 0 && errno == EINTR) {
+        continue;
+      }
+      if (n <= 0) {
+        return std::nullopt;
+      }
+      got += static_cast<size_t>(n);
+    }
+    return data;
+  }
+
+  static void replyOption(const int fd, const uint32_t option,
+                          const uint32_t type, const std::string &data = {}) {
+    std::string reply;
+    appendBigEndian<uint64_t>(reply, kReplyMagic);
+    appendBigEndian<uint32_t>(reply, option);
+    appendBigEndian<uint32_t>(reply, type);
+    appendBigEndian<uint32_t>(reply, static_cast<uint32_t>(data.size()));
+    sendAll(fd, reply + data);
+  }
+
+  // Negotiate the export, and go on to serve it if the client asked to.
+  void handle(const int fd) {
+    std::string greeting;
+    appendBigEndian<uint64_t>(greeting, kMagic);
+    appendBigEndian<uint64_t>(greeting, kOptMagic);
+    appendBigEndian<uint16_t>(greeting, kFixedNewstyle | kNoZeroes);
+    sendAll(fd, greeting);
+    const auto client_flags = receive(fd, 4);
+    if (!client_flags) {
+      return;
+    }
+    const bool no_zeroes =
+        readBigEndian<uint32_t>(client_flags->data()) & kNoZeroes;
+
+    while (true) {
+      const auto header = receive(fd, 16);
+      if (!header || readBigEndian<uint64_t>(header->data()) != kOptMagic) {
+        return;
+      }
+      const auto option = readBigEndian<uint32_t>(&(*header)[8]);
+      const auto length = readBigEndian<uint32_t>(&(*header)[12]);
+      if (length > 4096) {
+        return;
+      }
+      const auto data = receive(fd, length);
+      if (!data) {
+        return;
+      }
+      std::string info;
+      appendBigEndian<uint64_t>(info, image->size());
+      appendBigEndian<uint16_t>(info, kTransmissionFlags);
+      switch (option) {
+      case kExportName:
+        // The old way, which can't fail and goes straight on.
+        sendAll(fd, no_zeroes ? info : info + std::string(124, '\0'));
+        serve(fd);
+        return;
+      case kAbort:
+        replyOption(fd, option, kAck);
+        return;
+      case kList: {
+        std::string entry;
+        appendBigEndian<uint32_t>(entry, static_cast<uint32_t>(name.size()));
+        replyOption(fd, option, kServer, entry + name);
+        replyOption(fd, option, kAck);
+        break;
+      }
+      case kInfo:
+      case kGo: {
+        // The export name and its length, then the number of information
+        // requests and the requests, which get the same answer anyway.
+        const uint64_t name_size =
+            data->size() >= 4 ? readBigEndian<uint32_t>(data->data()) : 0;
+        if (data->size() < 6 + name_size ||
+            data->size() !=
+                6 + name_size +
+                    2 * uint64_t{readBigEndian<uint16_t>(
+                            &(*data)[static_cast<size_t>(4 + name_size)])}) {
+          replyOption(fd, option, kErrInvalid);
+          break;
+        }
+        // NBD_INFO_EXPORT, and NBD_INFO_BLOCK_SIZE: any size works, whole
+        // blocks work best, reads go up to kMaxRead.
+        std::string block_size;
+        appendBigEndian<uint16_t>(block_size, 3);
+        appendBigEndian<uint32_t>(block_size, 1);
+        appendBigEndian<uint32_t>(block_size, BLOCK_SIZE);
+        appendBigEndian<uint32_t>(block_size, kMaxRead);
+        replyOption(fd, option, kInfoReply, std::string(2, '\0') + info);
+        replyOption(fd, option, kInfoReply, block_size);
+        replyOption(fd, option, kAck);
+        if (option == kGo) {
+          serve(fd);
+          return;
+        }
+        break;
+      }
+      default:
+        replyOption(fd, option, kErrUnsup);
+      }
+    }
+  }
+
+  // Answer requests until the client disconnects.
+  void serve(const int fd) {
+    std::vector<char> buffer;
+    while (const auto request = receive(fd, 28)) {
+      if (readBigEndian<uint32_t>(request->data()) != kRequestMagic) {
+        return;
+      }
+      const auto type = readBigEndian<uint16_t>(&(*request)[6]);
+      const auto offset = readBigEndian<uint64_t>(&(*request)[16]);
+      const auto length = readBigEndian<uint32_t>(&(*request)[24]);
+      uint32_t error = 0;
+      if (type == kDisc) {
+        return;
+      } else if (type == kWrite) {
+        // The data comes along anyway, and must be read past.
+        if (!receive(fd, length)) {
+          return;
+        }
+        error = kEperm;
+      } else if (type != kRead || length > kMaxRead ||
+                 offset > image->size() || length > image->size() - offset) {
+        error = kEinval;
+      } else {
+        buffer.resize(length);
+        try {
+          image->read(buffer.data(), length, offset);
+        } catch (const std::exception &e) {
+          std::cerr << "Error: " << e.what() << std::endl;
+          error = kEio;
+        }
+      }
+      std::string reply;
+      appendBigEndian<uint32_t>(reply, kSimpleReplyMagic);
+      appendBigEndian<uint32_t>(reply, error);
+      reply.append(&(*request)[8], 8);
+      if (type == kRead && error == 0) {
+        reply.append(buffer.data(), length);
+      }
+      sendAll(fd, reply);
+    }
+  }
+};
+
+#endif
+
+// Run as sdat2img.py, through a link of that name: the arguments are taken
+// the way the Python script takes them, "<transfer_list> <system_new_file>
+// [system_img]" without options and with the same default output, and the
+// image comes out like its own, see --python-compat.
+bool invokedAsPython(const char *argv0) {
+  auto name = std::filesystem::path(argv0).filename();
+  if (name.extension() == ".exe") {
+    name = name.stem();
+  }
+  return name == "sdat2img.py";
+}
+
+int pythonMain(const int argc, const char *argv[]) {
+  if (argc < 3) {
+    std::cout << "\nUsage: sdat2img.py <transfer_list> <system_new_file> "
+                 "[system_img]\n"
+              << std::endl;
+    std::cout << "    <transfer_list>: transfer list file" << std::endl;
+    std::cout << "    <system_new_file>: system new dat file" << std::endl;
+    std::cout << "    [system_img]: output system image\n" << std::endl;
+    return EXIT_SUCCESS;
+  }
+  ConvertOptions options;
+  options.python_compat = true;
+  // Anything after the output is ignored, as the script does.
+  return convert(argv[1], argv[2],
+                 argc > 3 ? std::filesystem::path(argv[3])
+                          : std::filesystem::path(DEFAULT_OUTPUT),
+                 options);
+}
+
+int main(int argc, const char *argv[]) {
+  if (invokedAsPython(argv[0])) {
+    return pythonMain(argc, argv);
+  }
+  std::filesystem::path transfer_list_file, new_dat_file, output_img;
+  ConvertOptions options;
+  std::error_code ec;
+
+  options.command_line.assign(argv, argv + argc);
+  // Pick the options out, the rest are positional arguments.
+  options.threads = std::max(std::thread::hardware_concurrency(), 1U);
+  std::vector<std::string> args{argv[0]};
+  int to_version = 0;
+  // --format, of export and --version.
+  std::optional<std::string> format;
+  bool show_version = false;
+  bool check_only = false;
+  bool keep_download = false;
+  bool tui = false;
+  // Check the signature of OTA zips at URLs, by this signer if given.
+  bool verify_ota = false;
+  std::filesystem::path ota_cert;
+  std::optional<std::filesystem::path> fix_sort;
+  FixtureOptions fixture;
+  std::optional<std::filesystem::path> named_list, named_dat, named_out;
+  std::string out_template = "{partition}.img";
+  std::optional<std::string> listen_on;
+  std::optional<std::string> slot;
+  for (int i = 1; i < argc; ++i) {
+    std::string_view arg = argv[i];
+    if (arg.size() < 3 || arg.substr(0, 2) != "--") {
+      args.emplace_back(arg);
+      continue;
+    }
+    // Values come as either --name=value or --name value.
+    std::optional<std::string> inline_value;
+    if (const auto eq = arg.find('='); eq != std::string_view::npos) {
+      inline_value = arg.substr(eq + 1);
+      arg = arg.substr(0, eq);
+    }
+    const auto value = [&]() -> std::string {
+      if (inline_value) {
+        return *inline_value;
+      }
+      if (i + 1 >= argc) {
+        std::cerr << "Error: Option " << arg << " needs a value" << std::endl;
+        usage(argv[0]);
+      }
+      return argv[++i];
+    };
+
+    if (arg == "--strip-avb") {
+      options.strip_avb = true;
+    } else if (arg == "--version") {
+      show_version = true;
+    } else if (arg == "--check") {
+      check_only = true;
+    } else if (arg == "--keep-download") {
+      keep_download = true;
+    } else if (arg == "--tui") {
+      tui = true;
+    } else if (arg == "--verify-ota") {
+      verify_ota = true;
+    } else if (arg == "--ota-cert") {
+      ota_cert = value();
+      verify_ota = true;
+    } else if (arg == "--br-stats") {
+      options.br_stats = true;
+    } else if (arg == "--read-ahead") {
+      const std::string mib = value();
+      const auto count = parseCount(mib, SIZE_MAX >> 20);
+      if (!count) {
+        std::cerr << "Error: Invalid read-ahead " << mib
+                  << ", it must be a number of MiB" << std::endl;
+        usage(argv[0]);
+      }
+      options.read_ahead = static_cast<size_t>(*count) << 20;
+    } else if (arg == "--pad-to") {
+      // Bytes, or blocks with a "blocks" suffix.
+      const std::string size = value();
+      char *end = nullptr;
+      errno = 0;
+      options.partition_size = std::strtoll(size.c_str(), &end, 10);
+      if (std::string_view(end) == "blocks" && errno == 0 &&
+          options.partition_size > 0 &&
+          options.partition_size <=
+              std::numeric_limits<FileSizeT>::max() / BLOCK_SIZE) {
+        options.partition_size *= BLOCK_SIZE;
+      } else if (*end != '\0' || errno != 0 ||
+                 options.partition_size <= 0) {
+        std::cerr << "Error: Invalid size " << size << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--buffer-size") {
+      // Bytes, or KiB and MiB with a "K" or "M" suffix.
+      const std::string size = value();
+      char *end = nullptr;
+      options.buffer_size = std::strtoull(size.c_str(), &end, 10);
+      if (*end == 'K' || *end == 'M') {
+        options.buffer_size <<= *end++ == 'K' ? 10 : 20;
+      }
+      if (*end != '\0' || options.buffer_size == 0 ||
+          options.buffer_size % BLOCK_SIZE != 0 ||
+          options.buffer_size > size_t{1} << 30) {
+        std::cerr << "Error: Invalid buffer size " << size << ", it must be "
+                  << "a multiple of " << BLOCK_SIZE << " bytes, up to 1G"
+                  << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--split" || arg == "--chunk-size") {
+      // Bytes, or with a K, M or G suffix (KiB, MiB and GiB also do), or
+      // the largest size a FAT32 file can have.
+      const std::string size = value();
+      char *end = nullptr;
+      uint64_t &bytes =
+          arg == "--split" ? options.split_size : options.chunk_size;
+      bytes = std::strtoull(size.c_str(), &end, 10);
+      if (size == "fat32") {
+        bytes = (uint64_t{4} << 30) - BLOCK_SIZE;
+        end = nullptr;
+      } else if (*end == 'K' || *end == 'M' || *end == 'G') {
+        bytes <<= *end == 'K' ? 10 : *end == 'M' ? 20 : 30;
+        ++end;
+        if (std::string_view(end) == "iB") {
+          end += 2;
+        }
+      }
+      if ((end && *end != '\0') || bytes == 0 || bytes % BLOCK_SIZE != 0) {
+        std::cerr << "Error: Invalid " << (arg == "--split" ? "part" : "chunk")
+                  << " size " << size << ", it must be a multiple of "
+                  << BLOCK_SIZE << " bytes" << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--low-memory") {
+      options.low_memory = true;
+    } else if (arg == "--sidecar") {
+      options.sidecar = true;
+    } else if (arg == "--timings") {
+      options.timings = true;
+    } else if (arg == "--verify-after") {
+      options.verify_after = true;
+    } else if (arg == "--sign-key") {
+      options.sign_key = value();
+    } else if (arg == "--secure-erase") {
+      options.secure_erase = true;
+    } else if (arg == "--block-map") {
+      options.block_map = value();
+    } else if (arg == "--audit-log") {
+      options.audit_log = value();
+    } else if (arg == "--range-manifest") {
+      options.range_manifest = value();
+    } else if (arg == "--hash") {
+      const std::string hash = value();
+      if (hash == "blake3") {
+        options.hash = HashAlgorithm::Blake3;
+      } else if (hash == "sha256") {
+        options.hash = HashAlgorithm::Sha256;
+      } else {
+        std::cerr << "Error: Unknown hash " << hash << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--fsck") {
+      options.fsck = true;
+    } else if (arg == "--e2fsck") {
+      options.e2fsck = value();
+    } else if (arg == "--shrink") {
+      options.shrink = true;
+    } else if (arg == "--resize2fs") {
+      options.resize2fs = value();
+    } else if (arg == "--mke2fs") {
+      options.mke2fs = value();
+    } else if (arg == "--output-format") {
+      const std::string format = value();
+      if (format == "raw") {
+        options.output_format = OutputFormat::Raw;
+      } else if (format == "qcow2") {
+        options.output_format = OutputFormat::Qcow2;
+      } else if (format == "vhd") {
+        options.output_format = OutputFormat::Vhd;
+      } else if (format == "vmdk") {
+        options.output_format = OutputFormat::Vmdk;
+      } else if (format == "gpt") {
+        options.output_format = OutputFormat::Gpt;
+      } else if (format == "store") {
+        options.output_format = OutputFormat::Store;
+      } else if (format == "chunks") {
+        options.output_format = OutputFormat::Chunks;
+      } else {
+        std::cerr << "Error: Unknown output format " << format << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--order") {
+      const std::string order = value();
+      if (order == "transfer") {
+        options.order = SegmentOrder::Transfer;
+      } else if (order == "offset") {
+        options.order = SegmentOrder::Offset;
+      } else {
+        std::cerr << "Error: Unknown order " << order << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--store") {
+      options.store = value();
+    } else if (arg == "--resume") {
+      options.resume = true;
+    } else if (arg == "--continue") {
+      options.fill_in = true;
+    } else if (arg == "--checkpoint-seconds" || arg == "--checkpoint-mib") {
+      const std::string text = value();
+      const bool seconds = arg == "--checkpoint-seconds";
+      const auto count =
+          parseCount(text, seconds ? INT32_MAX : UINT64_MAX >> 20);
+      if (!count) {
+        std::cerr << "Error: Invalid checkpoint interval " << text
+                  << ", it must be a number of "
+                  << (seconds ? "seconds" : "MiB") << std::endl;
+        usage(argv[0]);
+      }
+      if (seconds) {
+        options.checkpoint_interval = std::chrono::seconds(*count);
+      } else {
+        options.checkpoint_bytes = *count << 20;
+      }
+    } else if (arg == "--threads") {
+      options.threads = static_cast<unsigned>(
+          std::strtoul(value().c_str(), nullptr, 10));
+      if (options.threads == 0) {
+        std::cerr << "Error: --threads needs at least 1" << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--stream-br") {
+      options.stream_br = true;
+    } else if (arg == "--cache-dat") {
+      options.cache_dat = true;
+    } else if (arg == "--skip-unchanged") {
+      options.skip_unchanged = true;
+    } else if (arg == "--force") {
+      options.force = true;
+    } else if (arg == "--really-force") {
+      options.really_force = true;
+    } else if (arg == "--lenient") {
+      options.lenient = true;
+    } else if (arg == "--python-compat") {
+      options.python_compat = true;
+    } else if (arg == "--source-img") {
+      options.source_img = value();
+    } else if (arg == "--exec-before") {
+      options.exec_before = value();
+    } else if (arg == "--exec-after") {
+      options.exec_after = value();
+    } else if (arg == "--error-format") {
+      const std::string format = value();
+      if (format == "text") {
+        error_format = ErrorFormat::Text;
+      } else if (format == "json") {
+        error_format = ErrorFormat::Json;
+      } else {
+        std::cerr << "Error: Unknown error format " << format << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--assume-no") {
+      options.assume_no = true;
+    } else if (arg == "--prompt-timeout") {
+      const std::string seconds = value();
+      const auto count = parseCount(seconds, INT32_MAX);
+      if (!count) {
+        std::cerr << "Error: Invalid timeout " << seconds
+                  << ", it must be a number of seconds" << std::endl;
+        usage(argv[0]);
+      }
+      options.prompt_timeout = std::chrono::seconds(*count);
+    } else if (arg == "--to-version") {
+      to_version = std::atoi(value().c_str());
+    } else if (arg == "--fix-sort") {
+      fix_sort = value();
+    } else if (arg == "--format") {
+      format = value();
+      if (format != "json" && format != "yaml") {
+        std::cerr << "Error: --format takes json or yaml" << std::endl;
+        usage(argv[0]);
+      }
+    } else if (arg == "--transfer-list") {
+      named_list = value();
+    } else if (arg == "--dat") {
+      named_dat = value();
+    } else if (arg == "--out") {
+      named_out = value();
+    } else if (arg == "--listen") {
+      listen_on = value();
+    } else if (arg == "--slot") {
+      slot = value();
+    } else if (arg == "--out-template") {
+      out_template = value();
+    } else if (arg == "--blocks") {
+      fixture.blocks = std::strtoull(value().c_str(), nullptr, 10);
+    } else if (arg == "--ranges") {
+      fixture.ranges = std::strtoull(value().c_str(), nullptr, 10);
+    } else if (arg == "--seed") {
+      fixture.seed = std::strtoull(value().c_str(), nullptr, 10);
+    } else if (arg == "--brotli") {
+      fixture.brotli = true;
+    } else {
+      std::cerr << "Error: Unknown option " << arg << std::endl;
+      usage(argv[0]);
+    }
+  }
+  if (options.low_memory) {
+    options.buffer_size =
+        std::min(options.buffer_size, ConvertOptions::kLowMemoryBuffer);
+    options.read_ahead =
+        std::min(options.read_ahead, ConvertOptions::kLowMemoryReadAhead);
+  }
+  if (show_version) {
+    printVersion(std::cout, format == "json");
+    return EXIT_SUCCESS;
+  }
+  // Subcommands, unless there is a file of the same name.
+  const auto subcommand = [&args, &ec](const std::string_view name) {
+    return args.size() >= 2 && args[1] == name &&
+           !std::filesystem::exists(args[1], ec);
+  };
+
+  if (subcommand("watch")) {
+    int interval = 2;
+    if (args.size() < 3 || args.size() > 4 ||
+        (args.size() == 4 && (interval = std::atoi(args[3].c_str())) <= 0) ||
+        !std::filesystem::is_directory(args[2], ec)) {
+      usage(argv[0]);
+    }
+    try {
+      // Catch a bad template before waiting for files.
+      outputFor(args[2], "", out_template);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    watch(args[2], std::chrono::seconds(interval), out_template, options);
+  }
+  if (subcommand("dynamic-partitions")) {
+    if (args.size() != 3) {
+      usage(argv[0]);
+    }
+    DynamicPartitions dynamic_partitions;
+    try {
+      dynamic_partitions.parse(args[2]);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    dynamic_partitions.print(std::cout);
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("metadata")) {
+    if (args.size() != 3) {
+      usage(argv[0]);
+    }
+    // The file itself, or the directory an OTA was extracted to.
+    std::filesystem::path metadata_file = args[2];
+    if (std::filesystem::is_directory(metadata_file, ec)) {
+      metadata_file /= "META-INF/com/android/metadata";
+    }
+    PackageMetadata metadata;
+    try {
+      metadata.parse(metadata_file);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    metadata.print(std::cout);
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("care-map")) {
+    if (args.size() != 4 && args.size() != 5) {
+      usage(argv[0]);
+    }
+    CareMap care_map;
+    try {
+      care_map.parse(args[2]);
+      // Pick the partition by name, or by the image name if not given.
+      const std::string name =
+          args.size() == 5 ? args[4]
+                           : std::filesystem::path(args[3]).stem().string();
+      const auto &list = care_map.list();
+      auto it = std::find_if(
+          list.begin(), list.end(),
+          [&name](const CareMap::Partition &p) { return p.name == name; });
+      if (it == list.end() && args.size() == 4 && list.size() == 1) {
+        it = list.begin();
+      }
+      if (it == list.end()) {
+        std::cerr << "Error: No partition " << name << " in care map, it has:";
+        for (const auto &p : list) {
+          std::cerr << " " << p.name;
+        }
+        std::cerr << std::endl;
+        return EXIT_FAILURE;
+      }
+      if (!it->fingerprint.empty()) {
+        std::cout << "Expected build (" << it->id << "): " << it->fingerprint
+                  << std::endl;
+      }
+      return CareMap::verify(*it, args[3]) == 0
+                 ? EXIT_SUCCESS
+                 : exitCode(ErrorKind::Verification);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+  }
+  if (subcommand("add-hashtree-footer")) {
+    if (args.size() != 5 && args.size() != 6) {
+      usage(argv[0]);
+    }
+    try {
+      std::vector<uint8_t> salt;
+      if (args.size() == 6) {
+        salt = fromHex(args[5]);
+      } else {
+        // Random salt of the digest size, like avbtool.
+        std::random_device random;
+        for (int i = 0; i < 32; ++i) {
+          salt.push_back(static_cast<uint8_t>(random()));
+        }
+      }
+      addHashtreeFooter(args[2], std::stoull(args[3]), args[4], salt,
+                        options.threads);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("verity-table")) {
+    if (args.size() != 3 && args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      std::optional<std::vector<uint8_t>> salt;
+      if (args.size() == 4) {
+        salt = fromHex(args[3]);
+      }
+      return printVerityTable(args[2], salt, options.threads) ? EXIT_SUCCESS
+                                                             : EXIT_FAILURE;
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+  }
+  if (subcommand("analyze")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    return analyzeDuplicates(args[2], args[3]);
+  }
+  if (subcommand("self-update")) {
+    if (args.size() != 2) {
+      usage(argv[0]);
+    }
+    try {
+      return selfUpdate(argv[0], check_only, options.force);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+  }
+  if (subcommand("export")) {
+    if (args.size() != 3) {
+      usage(argv[0]);
+    }
+    // Only the document goes to stdout, so that it can be piped into jq.
+    std::ostream document(std::cout.rdbuf());
+    std::cout.rdbuf(std::cerr.rdbuf());
+    try {
+      exportTransferList(args[2], format.value_or("json"), document,
+                         options.lenient);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("import")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      importTransferList(args[2], args[3], to_version);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("convert-list")) {
+    if (args.size() != 4 || to_version == 0) {
+      usage(argv[0]);
+    }
+    try {
+      convertTransferList(args[2], args[3], to_version);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("minimize-list")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      minimizeTransferList(args[2], args[3]);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("check-list")) {
+    if (args.size() != 3) {
+      usage(argv[0]);
+    }
+    try {
+      // Fixing is what was asked for, so that counts as success.
+      const size_t violations = checkRangeOrder(args[2], fix_sort);
+      return violations == 0 || fix_sort ? EXIT_SUCCESS : EXIT_FAILURE;
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+  }
+  if (subcommand("doctor")) {
+    if (args.size() != 3 && args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      return doctor(args[2], args.size() == 4 ? args[3] : "", out_template,
+                    options)
+                 ? EXIT_SUCCESS
+                 : EXIT_FAILURE;
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+  }
+  if (subcommand("cow-info")) {
+    if (args.size() != 3) {
+      usage(argv[0]);
+    }
+    try {
+      printCowInfo(args[2]);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("cow-merge")) {
+    if (args.size() != 5) {
+      usage(argv[0]);
+    }
+    if (std::filesystem::exists(args[4], ec) && !options.force) {
+      std::cerr << "Error: The output file " << args[4]
+                << " already exists, use --force to overwrite it"
+                << std::endl;
+      return EXIT_FAILURE;
+    }
+    try {
+      checkNotMounted(args[4], options.really_force);
+      mergeCow(args[2], args[3], args[4], options.buffer_size);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("join")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    if (std::filesystem::exists(args[3], ec) && !options.force) {
+      std::cerr << "Error: The output file " << args[3]
+                << " already exists, use --force to overwrite it"
+                << std::endl;
+      return EXIT_FAILURE;
+    }
+    try {
+      checkNotMounted(args[3], options.really_force);
+      joinImage(args[2], args[3], options.buffer_size);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("from-twrp")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    if (std::filesystem::exists(args[3], ec) && !options.force) {
+      std::cerr << "Error: The output file " << args[3]
+                << " already exists, use --force to overwrite it"
+                << std::endl;
+      return EXIT_FAILURE;
+    }
+    try {
+      checkNotMounted(args[3], options.really_force);
+      restoreTwrpBackup(args[2], args[3], options.buffer_size,
+                        options.mke2fs, options.partition_size);
+      bool fsck_ok = true;
+      if (options.shrink) {
+        fsck_ok =
+            shrinkFilesystem(options.resize2fs, options.e2fsck, args[3]);
+      }
+      if (options.fsck && fsck_ok) {
+        fsck_ok = checkFilesystem(options.e2fsck, args[3]);
+      }
+      if (!fsck_ok) {
+        return reportError(ErrorKind::Verification,
+                           std::string("The filesystem ") +
+                               (options.shrink ? "check or shrinking"
+                                               : "check") +
+                               " of " + quotedPath(args[3]) + " failed",
+                           args[3]);
+      }
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("to-twrp")) {
+    if (args.size() != 5) {
+      usage(argv[0]);
+    }
+    const auto info_file =
+        std::filesystem::path(args[3]) / (args[4] + ".info");
+    if (std::filesystem::exists(info_file, ec) && !options.force) {
+      std::cerr << "Error: " << args[3] << " already holds a backup of "
+                << args[4] << ", use --force to overwrite it" << std::endl;
+      return EXIT_FAILURE;
+    }
+    try {
+      makeTwrpBackup(args[2], args[3], args[4],
+                     options.split_size != 0 ? options.split_size
+                                             : TwrpBackup::kPartSize);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("materialize")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    if (std::filesystem::exists(args[3], ec) && !options.force) {
+      std::cerr << "Error: The output file " << args[3]
+                << " already exists, use --force to overwrite it"
+                << std::endl;
+      return EXIT_FAILURE;
+    }
+    try {
+      checkNotMounted(args[3], options.really_force);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    try {
+      materializeImage(args[2], StoreBuf::storeFor(args[2], options.store),
+                       args[3]);
+    } catch (const std::exception &e) {
+      const int code = reportError(e);
+      std::filesystem::remove(args[3], ec);
+      return code;
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("scrub")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      HashAlgorithm algorithm{};
+      const auto ranges = RangeManifest::load(args[3], &algorithm);
+      const auto bad = verifyRanges(args[2], ranges, options.buffer_size,
+                                    algorithm, options.threads);
+      if (!bad.empty()) {
+        return reportError(ErrorKind::Verification,
+                           std::to_string(bad.size()) + " ranges of " +
+                               args[2] +
+                               " changed since the manifest was written",
+                           args[2]);
+      }
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("block-crc")) {
+    if (args.size() != 3 && args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      if (args.size() == 3 || args[3] == "-") {
+        writeBlockCrcs(args[2], std::cout, options.buffer_size);
+      } else {
+        std::ofstream report(args[3]);
+        if (unlikely(!report)) {
+          throw IOException(args[3], "open");
+        }
+        writeBlockCrcs(args[2], report, options.buffer_size);
+        if (unlikely(!report)) {
+          throw IOException(args[3], "write");
+        }
+      }
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("keygen")) {
+    if (args.size() != 3) {
+      usage(argv[0]);
+    }
+    try {
+      generateSigningKey(args[2]);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("verify-manifest")) {
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      verifyFileSignature(args[2], loadKey<Ed25519::PublicKey>(args[3]));
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("verify-ota")) {
+    if (args.size() != 3 && args.size() != 4) {
+      usage(argv[0]);
+    }
+    try {
+      const std::filesystem::path cert =
+          args.size() == 4 ? std::filesystem::path(args[3]) : ota_cert;
+      verifyOtaSignature(args[2], cert);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+  if (subcommand("flash")) {
+    if (args.size() != 5) {
+      usage(argv[0]);
+    }
+    return flashPartition(args[2], args[3], args[4], slot, options);
+  }
+  if (subcommand("gen-fixture")) {
+    if (args.size() != 3) {
+      usage(argv[0]);
+    }
+    try {
+      generateFixture(args[2], fixture);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    return EXIT_SUCCESS;
+  }
+#ifdef HAVE_FUSE
+  if (subcommand("mount")) {
+    if (args.size() != 5) {
+      usage(argv[0]);
+    }
+    // Named like the output would be, after the transfer list.
+    std::string name = std::filesystem::path(args[2]).filename().string();
+    if (const auto suffix = name.rfind(".transfer.list");
+        suffix != std::string::npos && suffix != 0) {
+      name = name.substr(0, suffix) + ".img";
+    } else {
+      name = DEFAULT_OUTPUT;
+    }
+    return mountImage(args[2], args[3], args[4],
+                      named_out ? named_out->filename().string() : name,
+                      options);
+  }
+#endif
+#ifdef HAS_SOCKETS
+  if (subcommand("serve")) {
+    // serve [address:]port, listening on localhost unless told otherwise.
+    std::string address = "127.0.0.1";
+    std::string port = args.size() >= 3 ? args[2] : "8080";
+    if (const auto colon = port.rfind(':'); colon != std::string::npos) {
+      address = port.substr(0, colon);
+      port = port.substr(colon + 1);
+    }
+    if (args.size() > 3) {
+      usage(argv[0]);
+    }
+    const auto port_number = parsePort(port);
+    if (!port_number) {
+      std::cerr << "Error: Not a port number from 1 to 65535: " << port
+                << std::endl;
+      return EXIT_FAILURE;
+    }
+    const char *token = std::getenv("SDAT2IMG_TOKEN");
+    HttpServer(address, *port_number, options.threads, token ? token : "",
+               options)
+        .run();
+  }
+  if (subcommand("nbd")) {
+    // Same as serve, on the port registered for NBD.
+    std::string address = "127.0.0.1";
+    std::string port = listen_on.value_or("10809");
+    if (const auto colon = port.rfind(':'); colon != std::string::npos) {
+      address = port.substr(0, colon);
+      port = port.substr(colon + 1);
+    }
+    if (args.size() != 4) {
+      usage(argv[0]);
+    }
+    const auto port_number = parsePort(port);
+    if (!port_number) {
+      std::cerr << "Error: Not a port number from 1 to 65535: " << port
+                << std::endl;
+      return EXIT_FAILURE;
+    }
+    std::unique_ptr<VirtualImage> image;
+    try {
+      image = openVirtualImage(args[2], args[3], options);
+    } catch (const std::exception &e) {
+      return reportError(e);
+    }
+    std::string name = std::filesystem::path(args[2]).filename().string();
+    if (const auto suffix = name.rfind(".transfer.list");
+        suffix != std::string::npos && suffix != 0) {
+      name.resize(suffix);
+    }
+    NbdServer(std::move(image), name).run(address, *port_number);
+  }
+#endif
+
+  // The image going to stdout leaves no room for the messages there.
+  if (const std::filesystem::path out =
+          args.size() == 4 ? args[3] : named_out.value_or("").string();
+      out == "-" || (!out.empty() && isStdout(out))) {
+    std::cout.rdbuf(std::cerr.rdbuf());
+  }
+
+  // Named arguments instead, which can't be mixed up.
+  if (named_list || named_dat) {
+    if (!named_list || !named_dat || args.size() != 1) {
+      usage(argv[0]);
+    }
+    return convert(*named_list, *named_dat,
+                   named_out.value_or(DEFAULT_OUTPUT), options);
+  }
+
+  // Show what an extracted OTA is for, when its metadata is there.
+  const auto showMetadata = [&ec](const std::filesystem::path &dirObj) {
+    if (const auto metadata_file = dirObj / "META-INF/com/android/metadata";
+        std::filesystem::exists(metadata_file, ec)) {
+      PackageMetadata metadata;
+      try {
+        metadata.parse(metadata_file);
+        metadata.print(std::cout);
+      } catch (const std::exception &e) {
+        std::cerr << "Warning: Ignoring " << metadata_file << ": " << e.what()
+                  << std::endl;
+      }
+    }
+  };
+
+  // Convert one partition of an extracted OTA, into the given output or else
+  // the one of the template.
+  const auto convertFromDirectory =
+      [&ec, &out_template](const std::filesystem::path &dirObj,
+                           const std::string &commonPrefix,
+                           const std::optional<std::filesystem::path> &output,
+                           ConvertOptions options,
+                           ConversionProgress *progress = nullptr) {
+        const auto transfer_list_file =
+            dirObj / (commonPrefix + ".transfer.list");
+        const auto new_dat_file = findNewDat(dirObj, commonPrefix);
+        if (!std::filesystem::exists(transfer_list_file, ec)) {
+          const auto prefixes = transferListPrefixes(dirObj);
+          std::cerr << "Error: There is no " << transfer_list_file.filename()
+                    << " in " << dirObj << std::endl;
+          if (prefixes.empty()) {
+            std::cerr << "There are no transfer lists in there at all."
+                      << std::endl;
+          } else {
+            if (const auto closest = closestPrefix(commonPrefix, prefixes)) {
+              std::cerr << "Did you mean '" << *closest << "'?" << std::endl;
+            }
+            std::cerr << "Partitions found there:";
+            for (const auto &prefix : prefixes) {
+              std::cerr << ' ' << prefix;
+            }
+            std::cerr << std::endl;
+          }
+          return EXIT_FAILURE;
+        }
+
+        // Full Treble OTAs tell the size of each partition after the update,
+        // unless it was given with --pad-to.
+        if (const auto op_list_file = dirObj / "dynamic_partitions_op_list";
+            options.partition_size == 0 &&
+            std::filesystem::exists(op_list_file, ec)) {
+          DynamicPartitions dynamic_partitions;
+          try {
+            dynamic_partitions.parse(op_list_file);
+            options.partition_size = dynamic_partitions.sizeOf(commonPrefix);
+          } catch (const std::exception &e) {
+            std::cerr << "Warning: Ignoring " << op_list_file << ": "
+                      << e.what() << std::endl;
+          }
+          if (options.partition_size != 0) {
+            std::cout << "Partition size from " << op_list_file << ": "
+                      << options.partition_size << " bytes" << std::endl;
+          }
+        }
+
+        std::filesystem::path output_img;
+        if (output) {
+          output_img = *output;
+        } else {
+          try {
+            output_img = outputFor(dirObj, commonPrefix, out_template);
+          } catch (const std::exception &e) {
+            return reportError(e);
+          }
+          std::filesystem::create_directories(output_img.parent_path(), ec);
+        }
+        return convert(transfer_list_file, new_dat_file, output_img, options,
+                       progress);
+      };
+
+#ifdef HAS_MMAP
+  // Only the directory of a whole ROM, ask which partitions to convert when
+  // somebody is there to answer.
+  if (args.size() == 2 && std::filesystem::is_directory(args[1], ec) &&
+      isatty(STDIN_FILENO) && isatty(STDOUT_FILENO)) {
+    const std::filesystem::path dirObj = longPath(args[1]);
+    const auto prefixes = transferListPrefixes(dirObj);
+    if (prefixes.empty()) {
+      std::cerr << "Error: There are no transfer lists in " << dirObj
+                << std::endl;
+      return EXIT_FAILURE;
+    }
+    const auto partitions = prefixes.size() == 1
+                                ? prefixes
+                                : pickPartitions(dirObj, prefixes);
+    if (partitions.size() > 1 && named_out) {
+      std::cerr << "Error: --out names only one image, use --out-template "
+                << "for more partitions" << std::endl;
+      return EXIT_FAILURE;
+    }
+    showMetadata(dirObj);
+    if (tui) {
+      // Nobody can answer while the dashboard is up, so ask first.
+      std::vector<std::string> picked;
+      for (const auto &partition : partitions) {
+        std::filesystem::path output;
+        try {
+          output = named_out.value_or(
+              outputFor(dirObj, partition, out_template));
+        } catch (const std::exception &e) {
+          return reportError(e);
+        }
+        if (options.split_size != 0) {
+          output = SplitBuf::manifestFor(output);
+        }
+        if (!options.python_compat && std::filesystem::exists(output, ec)) {
+          std::cout << "The output file " << output << " already exists."
+                    << std::endl;
+          std::optional<std::string> answer;
+          if (!options.assume_no) {
+            std::cout << "Do you want to overwrite it? (y/N): " << std::flush;
+            answer = readAnswer(options.prompt_timeout);
+          }
+          if (answer != "y" && answer != "Y") {
+            std::cout << "Skipping " << partition << std::endl;
+            continue;
+          }
+          std::filesystem::remove(output, ec);
+        }
+        picked.push_back(partition);
+      }
+      if (picked.empty()) {
+        return EXIT_FAILURE;
+      }
+      options.assume_no = true;
+      std::cout << "Converting " << picked.size()
+                << (picked.size() == 1 ? " partition" : " partitions")
+                << " of " << dirObj << ":" << std::endl;
+      Dashboard dashboard(picked);
+      const bool converted = dashboard.run(
+          [&](const std::string &partition, ConversionProgress *progress) {
+            return convertFromDirectory(dirObj, partition, named_out, options,
+                                        progress);
+          });
+      return converted && picked.size() == partitions.size() ? EXIT_SUCCESS
+                                                             : EXIT_FAILURE;
+    }
+    int result = EXIT_SUCCESS;
+    for (const auto &partition : partitions) {
+      if (convertFromDirectory(dirObj, partition, named_out, options) !=
+          EXIT_SUCCESS) {
+        result = EXIT_FAILURE;
+      }
+    }
+    return result;
+  }
+#endif
+  if (tui) {
+    std::cerr << "Warning: --tui only shows partitions picked from a "
+              << "directory in a terminal" << std::endl;
+  }
+
+  // --out may stand in for the last positional argument.
+  if ((args.size() != 4 && args.size() != 3) ||
+      (args.size() == 4 && named_out)) {
+    usage(argv[0]);
+  }
+
+  // Scheme 3. The URL of an OTA zip and the partition, downloaded into the
+  // current directory. The image goes there too, unless told otherwise.
+  if (isUrl(args[1])) {
+    std::random_device random;
+    const auto dirObj = std::filesystem::temp_directory_path() /
+                        ("sdat2img-" + std::to_string(random()));
+    int result = EXIT_FAILURE;
+    std::filesystem::path zip;
+    try {
+      std::filesystem::create_directories(dirObj);
+      zip = fetchOta(args[1], args[2], dirObj, keep_download || verify_ota,
+                     verify_ota, ota_cert);
+      showMetadata(dirObj);
+      result = convertFromDirectory(
+          dirObj, args[2],
+          args.size() == 4
+              ? std::filesystem::path(args[3])
+              : named_out.value_or(outputFor(
+                    std::filesystem::current_path(), args[2], out_template)),
+          options);
+    } catch (const std::exception &e) {
+      result = reportError(e);
+    }
+    std::filesystem::remove_all(dirObj, ec);
+    if (!zip.empty() && !keep_download && result == EXIT_SUCCESS) {
+      std::filesystem::remove(zip, ec);
+    } else if (!zip.empty()) {
+      std::cout << "Kept " << zip << std::endl;
+    }
+    return result;
+  }
+
+  // The partition name given before the directory.
+  if (!std::filesystem::exists(args[1], ec) &&
+      std::filesystem::is_directory(args[2], ec)) {
+    std::cout << "Note: " << args[2] << " is the directory, swapping the "
+              << "first two arguments" << std::endl;
+    std::swap(args[1], args[2]);
+  }
+
+  // Scheme 1. The user provides all files, maybe through pipes
+  if (const auto first = stdioPath(args[1], false);
+      std::filesystem::is_regular_file(first, ec) || isStreamInput(first)) {
+    transfer_list_file = args[1];
+    new_dat_file = args[2];
+    if (args.size() == 3) {
+      output_img = named_out.value_or(DEFAULT_OUTPUT);
+    } else {
+      output_img = args[3];
+    }
+    if (!checkInputOrder(transfer_list_file, new_dat_file, output_img)) {
+      return EXIT_FAILURE;
+    }
+  }
+
+  // Scheme 2. The user provides a directory and filename
+  else if (const std::filesystem::path dirObj = longPath(args[1]);
+           std::filesystem::is_directory(dirObj)) {
+    showMetadata(dirObj);
+    return convertFromDirectory(
+        dirObj, args[2],
+        args.size() == 4 ? std::optional<std::filesystem::path>(args[3])
+                         : named_out,
+        options);
+  }
+
+  // Else, invalid arguments
+  else {
+    usage(argv[0]);
+  }
+
+  return convert(transfer_list_file, new_dat_file, output_img, options);
 }
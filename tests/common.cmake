@@ -0,0 +1,39 @@
+# Shared by the test scripts, which get SDAT2IMG, the binary to test,
+# SOURCE_DIR, this directory, and WORK_DIR, an empty directory of their own.
+
+file(REMOVE_RECURSE ${WORK_DIR})
+file(MAKE_DIRECTORY ${WORK_DIR})
+
+# Run the binary with the arguments, failing the test unless it exits with
+# EXPECT (0 if not given). The output is kept in OUTPUT, or only what went
+# to stderr if stdout goes into the file STDOUT.
+function(sdat2img)
+    cmake_parse_arguments(RUN "" "EXPECT;STDOUT" "" ${ARGN})
+    if (NOT DEFINED RUN_EXPECT)
+        set(RUN_EXPECT 0)
+    endif()
+    if (DEFINED RUN_STDOUT)
+        set(stdout OUTPUT_FILE ${WORK_DIR}/${RUN_STDOUT})
+    else()
+        set(stdout OUTPUT_VARIABLE output)
+    endif()
+    execute_process(COMMAND ${SDAT2IMG} ${RUN_UNPARSED_ARGUMENTS}
+        WORKING_DIRECTORY ${WORK_DIR}
+        RESULT_VARIABLE result
+        ${stdout}
+        ERROR_VARIABLE output)
+    if (NOT result STREQUAL RUN_EXPECT)
+        message(FATAL_ERROR "sdat2img ${RUN_UNPARSED_ARGUMENTS} exited with "
+            "${result} instead of ${RUN_EXPECT}:\n${output}")
+    endif()
+    set(OUTPUT "${output}" PARENT_SCOPE)
+endfunction()
+
+# Fail the test unless the file has the SHA-256.
+function(expect_sha256 FILE HASH)
+    file(SHA256 ${FILE} actual)
+    if (NOT actual STREQUAL HASH)
+        message(FATAL_ERROR "${FILE} has the SHA-256 ${actual} instead of "
+            "${HASH}")
+    endif()
+endfunction()
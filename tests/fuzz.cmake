@@ -0,0 +1,23 @@
+# Transfer lists which fuzzing found to crash or misbehave before they were
+# checked for. Those in bad/ have to be refused as malformed, by export and
+# by a conversion, those in good/ have to convert. Either way nothing may
+# crash, which a sanitizer build shows best.
+include(${SOURCE_DIR}/common.cmake)
+
+# Enough data for any of the lists which write something.
+string(REPEAT "sdat2img" 1024 data)
+file(WRITE ${WORK_DIR}/system.new.dat "${data}")
+
+file(GLOB bad ${SOURCE_DIR}/fuzz/bad/*.transfer.list)
+foreach (list IN LISTS bad)
+    sdat2img(export ${list} EXPECT 3)
+    file(REMOVE ${WORK_DIR}/system.img)
+    sdat2img(${list} system.new.dat system.img EXPECT 3)
+endforeach()
+
+file(GLOB good ${SOURCE_DIR}/fuzz/good/*.transfer.list)
+foreach (list IN LISTS good)
+    sdat2img(export ${list})
+    file(REMOVE ${WORK_DIR}/system.img)
+    sdat2img(${list} system.new.dat system.img)
+endforeach()
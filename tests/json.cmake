@@ -0,0 +1,26 @@
+# Importing an exported list gives the same list back, commands, ranges and
+# header alike. Without total_blocks in the document, import counts the
+# same total again from the new and zero ranges.
+include(${SOURCE_DIR}/common.cmake)
+
+function(expect_same_file A B)
+    execute_process(COMMAND ${CMAKE_COMMAND} -E compare_files ${A} ${B}
+        RESULT_VARIABLE different)
+    if (different)
+        file(READ ${B} actual)
+        message(FATAL_ERROR "${B} isn't the same as ${A}:\n${actual}")
+    endif()
+endfunction()
+
+foreach (version v1 v4)
+    set(list_file ${SOURCE_DIR}/lists/${version}.transfer.list)
+    sdat2img(export ${list_file} STDOUT ${version}.json)
+    sdat2img(import ${version}.json ${version}.transfer.list)
+    expect_same_file(${list_file} ${WORK_DIR}/${version}.transfer.list)
+
+    file(READ ${WORK_DIR}/${version}.json json)
+    string(JSON json REMOVE "${json}" total_blocks)
+    file(WRITE ${WORK_DIR}/${version}-no-total.json "${json}")
+    sdat2img(import ${version}-no-total.json ${version}-no-total.transfer.list)
+    expect_same_file(${list_file} ${WORK_DIR}/${version}-no-total.transfer.list)
+endforeach()
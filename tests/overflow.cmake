@@ -0,0 +1,34 @@
+# Block numbers up to the largest whose byte offset fits into 64 bits are
+# accepted, and sums of sizes past that have to fail with an overflow
+# error rather than wrap around. None of these get to writing anything.
+include(${SOURCE_DIR}/common.cmake)
+
+# The largest FileSizeT, 2^63 - 1, divided by the block size.
+set(max_block 2251799813685247)
+math(EXPR past_max "${max_block} + 1")
+set(header "4\n0\n0\n0\n")
+file(WRITE ${WORK_DIR}/system.new.dat "")
+
+file(WRITE ${WORK_DIR}/last.transfer.list "${header}new 2,0,${max_block}\n")
+sdat2img(export last.transfer.list)
+file(WRITE ${WORK_DIR}/past.transfer.list "${header}new 2,0,${past_max}\n")
+sdat2img(export past.transfer.list EXPECT 3)
+
+# Three ranges of 2^63 bytes take more new data than 64 bits can count.
+file(WRITE ${WORK_DIR}/sum.transfer.list
+    "${header}new 6,0,${max_block},0,${max_block},0,${max_block}\n")
+sdat2img(sum.transfer.list system.new.dat system.img EXPECT 1)
+if (NOT OUTPUT MATCHES "Size overflow")
+    message(FATAL_ERROR "Expected a size overflow:\n${OUTPUT}")
+endif()
+
+# Without total_blocks, import counts the blocks of the ranges, which 4097
+# of them overflow.
+string(REPEAT "[0,${max_block}]," 4096 ranges)
+string(CONCAT json "{\"version\":4,\"commands\":[{\"command\":\"new\","
+    "\"ranges\":[${ranges}[0,${max_block}]]}]}")
+file(WRITE ${WORK_DIR}/sum.json "${json}")
+sdat2img(import sum.json sum-imported.transfer.list EXPECT 1)
+if (NOT OUTPUT MATCHES "Size overflow")
+    message(FATAL_ERROR "Expected a size overflow:\n${OUTPUT}")
+endif()
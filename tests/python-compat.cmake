@@ -0,0 +1,19 @@
+# Each case is a transfer list and dat with the SHA-256 of the image xpirt's
+# sdat2img.py 1.2 makes of them, quirks and all. --python-compat has to make
+# the same image.
+include(${SOURCE_DIR}/common.cmake)
+
+file(GLOB hashes ${SOURCE_DIR}/python-compat/*.img.sha256)
+foreach (hash_file IN LISTS hashes)
+    get_filename_component(name ${hash_file} NAME)
+    string(REPLACE ".img.sha256" "" name ${name})
+    set(dat ${SOURCE_DIR}/python-compat/${name}.new.dat)
+    if (NOT EXISTS ${dat})
+        set(dat ${dat}.br)
+    endif()
+    sdat2img(--python-compat ${SOURCE_DIR}/python-compat/${name}.transfer.list
+        ${dat} ${name}.img)
+    file(READ ${hash_file} expected)
+    string(SUBSTRING "${expected}" 0 64 expected)
+    expect_sha256(${WORK_DIR}/${name}.img ${expected})
+endforeach()
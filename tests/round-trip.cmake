@@ -0,0 +1,61 @@
+# gen-fixture makes the same list and dat for the same seed. They have to
+# convert to the image it gives the hash of, and that hash is also checked
+# against a known one, so that a change on both sides still shows.
+include(${SOURCE_DIR}/common.cmake)
+
+set(IMAGE_SHA256
+    c9c899305bd3728be8bbff5da9f827aa270a7286ac427f29b8645814e04888b0)
+
+set(dat system.new.dat)
+if (BROTLI)
+    list(APPEND fixture_options --brotli)
+    set(dat system.new.dat.br)
+endif()
+sdat2img(gen-fixture fixture --blocks 64 --ranges 5 --seed 1
+    ${fixture_options})
+file(READ ${WORK_DIR}/fixture/system.img.sha256 listed)
+string(SUBSTRING "${listed}" 0 64 listed)
+if (NOT listed STREQUAL IMAGE_SHA256)
+    message(FATAL_ERROR "gen-fixture gave the hash ${listed} instead of "
+        "${IMAGE_SHA256}")
+endif()
+
+sdat2img(fixture/system.transfer.list fixture/${dat} system.img)
+expect_sha256(${WORK_DIR}/system.img ${IMAGE_SHA256})
+
+# The header's total is counted like the tool counts any list, so
+# minimizing leaves it alone.
+sdat2img(minimize-list fixture/system.transfer.list fixture.transfer.list)
+file(STRINGS ${WORK_DIR}/fixture/system.transfer.list header LIMIT_COUNT 2)
+file(STRINGS ${WORK_DIR}/fixture.transfer.list minimized LIMIT_COUNT 2)
+if (NOT header STREQUAL minimized)
+    message(FATAL_ERROR "minimize-list changed the header ${header} to "
+        "${minimized}")
+endif()
+
+# Minimizing keeps the order the dat is read in, so ranges going back start
+# another command rather than making one unsorted, and the list still
+# converts to the same image.
+file(WRITE ${WORK_DIR}/backwards.transfer.list
+    "4\n15\n0\n0\nnew 2,10,20\nnew 2,0,5\n")
+string(REPEAT "0123456789abcdef" 3840 dat)
+file(WRITE ${WORK_DIR}/backwards.new.dat "${dat}")
+sdat2img(minimize-list backwards.transfer.list minimized.transfer.list)
+file(READ ${WORK_DIR}/minimized.transfer.list minimized)
+if (NOT minimized STREQUAL "4\n15\n0\n0\nnew 2,10,20\nnew 2,0,5\n")
+    message(FATAL_ERROR "minimize-list gave\n${minimized}")
+endif()
+sdat2img(check-list minimized.transfer.list)
+sdat2img(backwards.transfer.list backwards.new.dat backwards.img)
+sdat2img(minimized.transfer.list backwards.new.dat minimized.img)
+file(SHA256 ${WORK_DIR}/backwards.img expected)
+expect_sha256(${WORK_DIR}/minimized.img ${expected})
+
+# A dat cut short fails as malformed, unless --lenient fills the rest of
+# what the list writes with zeros.
+file(WRITE ${WORK_DIR}/short.transfer.list "4\n15\n0\n0\nnew 2,0,15\n")
+string(SUBSTRING "${dat}" 0 4096 short)
+file(WRITE ${WORK_DIR}/short.new.dat "${short}")
+sdat2img(short.transfer.list short.new.dat short.img EXPECT 3)
+file(REMOVE ${WORK_DIR}/short.img)
+sdat2img(--lenient short.transfer.list short.new.dat short.img)
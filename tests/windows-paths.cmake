@@ -0,0 +1,84 @@
+# Paths only Windows has: reserved device names, paths longer than
+# MAX_PATH, the \\?\ prefix, drive roots and UNC shares. All of them have
+# to convert the gen-fixture inputs into the image it gives the hash of,
+# except the device names, which are refused.
+include(${SOURCE_DIR}/common.cmake)
+
+set(IMAGE_SHA256
+    c9c899305bd3728be8bbff5da9f827aa270a7286ac427f29b8645814e04888b0)
+sdat2img(gen-fixture fixture --seed 1)
+set(fixture ${WORK_DIR}/fixture)
+set(inputs fixture/system.transfer.list fixture/system.new.dat)
+
+# Devices are in every directory, with any extension, and trailing dots and
+# spaces don't count.
+foreach (name CON nul.img com1.img "aux .img" LPT9.raw)
+    sdat2img(${inputs} "${name}" EXPECT 7)
+endforeach()
+
+# Five directories of 60 characters are past MAX_PATH wherever the build is.
+string(REPEAT "d" 60 part)
+set(long ${WORK_DIR})
+foreach (i RANGE 4)
+    set(long ${long}/${part}${i})
+endforeach()
+file(MAKE_DIRECTORY ${long})
+sdat2img(${inputs} ${long}/system.img)
+expect_sha256(${long}/system.img ${IMAGE_SHA256})
+file(COPY ${fixture}/system.transfer.list ${fixture}/system.new.dat
+    DESTINATION ${long})
+sdat2img(${long} system ${long}/from-dir.img)
+expect_sha256(${long}/from-dir.img ${IMAGE_SHA256})
+
+# Paths which already have the prefix are taken as they are.
+file(TO_NATIVE_PATH ${fixture} native_fixture)
+sdat2img("\\\\?\\${native_fixture}\\system.transfer.list"
+    "\\\\?\\${native_fixture}\\system.new.dat" prefixed.img)
+expect_sha256(${WORK_DIR}/prefixed.img ${IMAGE_SHA256})
+
+# A drive root, the fixture mapped to a free letter with subst. Its {dir}
+# is the letter. The mapping is removed before anything is checked.
+foreach (letter Q R S T U V W X Y Z)
+    if (NOT EXISTS ${letter}:/)
+        set(drive ${letter})
+        break()
+    endif()
+endforeach()
+if (drive)
+    execute_process(COMMAND subst ${drive}: ${native_fixture}
+        RESULT_VARIABLE subst_result)
+    if (NOT subst_result EQUAL 0)
+        message(FATAL_ERROR "subst ${drive}: failed")
+    endif()
+    execute_process(
+        COMMAND ${SDAT2IMG} ${drive}:\\ system --out-template
+            "{dir}-{partition}.img"
+        WORKING_DIRECTORY ${WORK_DIR}
+        RESULT_VARIABLE result
+        OUTPUT_VARIABLE output
+        ERROR_VARIABLE output)
+    set(root_sha256 "")
+    if (EXISTS ${drive}:/${drive}-system.img)
+        file(SHA256 ${drive}:/${drive}-system.img root_sha256)
+    endif()
+    execute_process(COMMAND subst ${drive}: /D)
+    if (NOT result EQUAL 0 OR NOT root_sha256 STREQUAL IMAGE_SHA256)
+        message(FATAL_ERROR "Converting from ${drive}:\\ didn't give "
+            "${drive}-system.img:\n${output}")
+    endif()
+else()
+    message(STATUS "No free drive letter, drive roots aren't tested")
+endif()
+
+# The drive's administrative share, where there is one.
+string(SUBSTRING ${native_fixture} 0 1 letter)
+string(SUBSTRING ${native_fixture} 2 -1 share_path)
+set(unc "\\\\localhost\\${letter}$${share_path}")
+file(TO_CMAKE_PATH ${unc} unc_cmake)
+if (IS_DIRECTORY ${unc_cmake})
+    sdat2img(${unc}\\system.transfer.list ${unc}\\system.new.dat
+        ${unc}\\unc.img)
+    expect_sha256(${fixture}/unc.img ${IMAGE_SHA256})
+else()
+    message(STATUS "${unc} isn't shared, UNC paths aren't tested")
+endif()